@@ -5,8 +5,8 @@ use mistralrs_core::{
     initialize_logging, paged_attn_supported, Constraint, DefaultSchedulerMethod,
     DeviceLayerMapMetadata, DeviceMapMetadata, DrySamplingParams, Loader, LoaderBuilder,
     MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, ModelSelected, NormalRequest,
-    PagedAttentionConfig, Request, RequestMessage, Response, SamplingParams, SchedulerConfig,
-    TokenSource, Usage,
+    PagedAttentionConfig, Request, RequestMessage, RequestPriority, Response, SamplingParams,
+    SchedulerConfig, TokenSource, Usage,
 };
 use std::sync::Arc;
 use std::{fmt::Display, num::NonZeroUsize};
@@ -58,14 +58,23 @@ fn run_bench(
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
+        top_a: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_penalty_last_n: None,
         max_len: Some(n_gen),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        mirostat: None,
+        xtc: None,
+        sampler_order: None,
+        seed: None,
+        beam_search: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -83,6 +92,8 @@ fn run_bench(
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::Normal,
+        tenant: None,
     });
 
     let mut usages = Vec::new();
@@ -115,6 +126,7 @@ fn run_bench(
                     }
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::Embedding(_) => unreachable!(),
                 },
                 None => unreachable!("Expected a Done response, got None",),
             }
@@ -223,14 +235,23 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
+        top_a: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_penalty_last_n: None,
         max_len: Some(5),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        mirostat: None,
+        xtc: None,
+        sampler_order: None,
+        seed: None,
+        beam_search: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -241,6 +262,7 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
             text: "Hello!".to_string(),
             echo_prompt: false,
             best_of: 1,
+            prompt_logprobs: false,
         },
         sampling_params: sampling_params.clone(),
         response: tx,
@@ -252,6 +274,8 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::Normal,
+        tenant: None,
     });
 
     sender
@@ -295,28 +319,29 @@ struct Args {
     num_device_layers: Option<Vec<String>>,
 
     /// GPU memory to allocate for KV cache with PagedAttention in MBs. If this is not set and the device is CUDA, it will default to
-    /// using `pa-gpu-mem-usage` set to `0.9`. PagedAttention is only supported on CUDA and is always automatically activated.
+    /// using `pa-gpu-mem-usage` set to `0.9`. PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa-gpu-mem`, `pa-gpu-mem-usage` or `pa-ctxt-len`.
     #[arg(long = "pa-gpu-mem")]
     paged_attn_gpu_mem: Option<usize>,
 
     /// Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
-    /// If this is not set and the device is CUDA, it will default to `0.9`. PagedAttention is only supported on CUDA and is always automatically activated.
+    /// If this is not set and the device is CUDA, it will default to `0.9`. PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa-gpu-mem`, `pa-gpu-mem-usage` or `pa-ctxt-len`.
     /// This is always used over `pa-gpu-mem` if both are specified.
     #[arg(long = "pa-gpu-mem-usage")]
     paged_attn_gpu_mem_usage: Option<f32>,
 
     /// Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold)
-    /// when using PagedAttention, which is only supported on CUDA and is always automatically activated.
+    /// when using PagedAttention.
+    /// PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa-gpu-mem`, `pa-gpu-mem-usage` or `pa-ctxt-len`.
     /// The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
     #[arg(long = "pa-ctxt-len")]
     paged_ctxt_len: Option<usize>,
 
     /// Block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA, it will default to 32.
-    /// PagedAttention is only supported on CUDA and is always automatically activated.
+    /// PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa-gpu-mem`, `pa-gpu-mem-usage` or `pa-ctxt-len`.
     #[arg(long = "pa-blk-size")]
     paged_attn_block_size: Option<usize>,
 
-    /// Disable PagedAttention on CUDA.
+    /// Disable PagedAttention.
     #[arg(long = "no_paged_attn", default_value_t = false)]
     no_paged_attn: bool,
 
@@ -423,11 +448,13 @@ fn main() -> anyhow::Result<()> {
         paged_attn_supported(),
         args.no_paged_attn,
     ) {
-        (block_size, None, None, None, true, false) => Some(PagedAttentionConfig::new(
-            block_size,
-            512,
-            MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
-        )?),
+        (block_size, None, None, None, true, false) if device.is_cuda() => {
+            Some(PagedAttentionConfig::new(
+                block_size,
+                512,
+                MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
+            )?)
+        }
         (block_size, None, None, Some(ctxt), true, false) => Some(PagedAttentionConfig::new(
             block_size,
             512,
@@ -526,6 +553,7 @@ fn main() -> anyhow::Result<()> {
                     text: "Rust".to_string(),
                     echo_prompt: false,
                     best_of: 1,
+                    prompt_logprobs: false,
                 },
                 args.n_gen - 1,
                 *concurrency,
@@ -113,7 +113,20 @@ pub(crate) fn parse_yacc(yacc: &str) -> Result<YaccGrammar> {
 }
 
 impl CfgParser {
+    /// Build the parser of a Yacc grammar. As with Yacc, LR conflicts are resolved by the state
+    /// table: shifts win over reductions, and earlier productions over later ones.
     pub fn from_yacc(yacc: &str) -> Result<Self> {
+        Self::new(yacc, false)
+    }
+
+    /// Build the parser of a Yacc grammar, failing if the grammar has LR conflicts. Resolving
+    /// them makes the parser reject inputs that only one of the conflicting rules could have
+    /// accepted, so this is used for generated grammars which must accept all valid documents.
+    pub fn from_yacc_unambiguous(yacc: &str) -> Result<Self> {
+        Self::new(yacc, true)
+    }
+
+    fn new(yacc: &str, reject_conflicts: bool) -> Result<Self> {
         let grm = parse_yacc(yacc)?;
         // TIME: all these annotation are for native release x86 build for C grammar
         // TIME: 27ms
@@ -127,6 +140,14 @@ impl CfgParser {
                 anyhow::bail!("state table error:\n{e}");
             }
         };
+        if let Some(conflicts) = stable.conflicts().filter(|_| reject_conflicts) {
+            anyhow::bail!(
+                "grammar is ambiguous ({} shift/reduce and {} reduce/reduce conflicts):\n{}",
+                conflicts.sr_len(),
+                conflicts.rr_len(),
+                conflicts.pp(&grm)
+            );
+        }
 
         if false {
             println!("core\n{}\n\n", sgraph.pp(&grm, true));
@@ -467,13 +488,22 @@ impl Recognizer for CfgParser {
     fn special_allowed(&mut self, tok: SpecialToken) -> bool {
         match tok {
             SpecialToken::EndOfSentence => {
-                if let Some(st) = self.try_push(None) {
-                    let tidx = self.grm.eof_token_idx();
-                    let mut pstack = self.pstack_for(&st).clone();
-                    matches!(self.parse_lexeme(tidx, &mut pstack), ParseResult::Accept)
-                } else {
-                    false
+                // Flush the pending lexeme and then parse EOF. This does not go through `try_push`,
+                // as no lexeme needs to be viable after the end of the input (grammars without
+                // SKIP patterns have an empty viable set once they are complete).
+                let top = self.byte_states.last().unwrap().clone();
+                let Some((_, Some(pat_idx))) = self.lexer.advance(top.lexer_state, None) else {
+                    return false;
+                };
+                let mut pstack = self.pstack_for(&top).clone();
+                if !self.skip_patterns[pat_idx] {
+                    let tidx = self.pat_idx_to_tidx[pat_idx];
+                    if !matches!(self.parse_lexeme(tidx, &mut pstack), ParseResult::Continue) {
+                        return false;
+                    }
                 }
+                let tidx = self.grm.eof_token_idx();
+                matches!(self.parse_lexeme(tidx, &mut pstack), ParseResult::Accept)
             }
             _ => false,
         }
@@ -0,0 +1,705 @@
+//! Compile a JSON Schema into a Yacc grammar which can be used by [`CfgParser`].
+//!
+//! Every terminal of the generated grammar is a single byte (or the class of all non-ASCII bytes),
+//! so the terminals never overlap and the lexer of the [`CfgParser`] is unambiguous. Literal strings
+//! such as property names, `enum` and `const` values are spelled out byte by byte, with non-ASCII
+//! characters escaped as `\uXXXX`.
+//!
+//! The generated documents always emit properties in the iteration order of the `properties` map
+//! (sorted by name), except that properties with a `const` value come first, and never contain
+//! properties which are not declared. Whitespace is allowed between tokens.
+//!
+//! Overlapping `type`s, such as `integer` and `number`, are merged, including those of `anyOf` and
+//! `oneOf` alternatives which only have a `type`. Other alternatives must not overlap, as the
+//! resulting grammar is ambiguous and is rejected by [`CfgParser::from_yacc_unambiguous`].
+//!
+//! [`CfgParser`]: super::cfg::CfgParser
+//! [`CfgParser::from_yacc_unambiguous`]: super::cfg::CfgParser::from_yacc_unambiguous
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+/// Upper bound for `minItems` and `maxItems`, as bounded arrays are unrolled in the grammar.
+const MAX_ITEMS_BOUND: u64 = 256;
+
+/// Validation keywords which cannot be enforced by the generated grammar. Rather than producing
+/// documents which may not validate, schemas using these are rejected.
+const UNSUPPORTED_KEYWORDS: &[&str] = &[
+    "pattern",
+    "minLength",
+    "maxLength",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "multipleOf",
+    "patternProperties",
+    "propertyNames",
+    "minProperties",
+    "maxProperties",
+    "dependentRequired",
+    "dependentSchemas",
+    "uniqueItems",
+    "contains",
+    "prefixItems",
+    "not",
+    "if",
+];
+
+/// Remove the keywords which cannot be enforced by the generated grammar from `schema` and its
+/// subschemas, so that it compiles into a grammar which enforces the rest of the schema.
+pub(crate) fn remove_unsupported_keywords(schema: &mut Value) {
+    let Value::Object(obj) = schema else {
+        return;
+    };
+    for keyword in UNSUPPORTED_KEYWORDS {
+        obj.remove(*keyword);
+    }
+    for (keyword, value) in obj.iter_mut() {
+        match (keyword.as_str(), value) {
+            ("properties" | "$defs" | "definitions", Value::Object(schemas)) => {
+                schemas.values_mut().for_each(remove_unsupported_keywords)
+            }
+            ("anyOf" | "oneOf" | "allOf", Value::Array(schemas)) => {
+                schemas.iter_mut().for_each(remove_unsupported_keywords)
+            }
+            ("items" | "additionalProperties", schema) => remove_unsupported_keywords(schema),
+            _ => (),
+        }
+    }
+}
+
+/// The schema which accepts any value.
+static ANY: Value = Value::Bool(true);
+
+/// All bytes which are not ASCII. These are only allowed inside of strings.
+const NON_ASCII: &str = "'/[\\x80-\\xFF]/'";
+
+/// The terminal matching exactly the byte `b`.
+fn byte(b: u8) -> String {
+    format!("'/\\x{b:02X}/'")
+}
+
+/// Spell out the compact serialization of `value` as a sequence of terminals.
+fn literal(value: &Value) -> Vec<String> {
+    let mut escaped = String::new();
+    for ch in value.to_string().chars() {
+        if ch.is_ascii() {
+            escaped.push(ch);
+        } else {
+            // Non-ASCII characters can only appear in strings, where they may always be escaped.
+            for unit in ch.encode_utf16(&mut [0; 2]) {
+                write!(escaped, "\\u{unit:04x}").unwrap();
+            }
+        }
+    }
+    escaped.bytes().map(byte).collect()
+}
+
+/// The types whose grammars are fixed rules.
+const SCALAR_TYPES: &[&str] = &["string", "number", "integer", "boolean", "null"];
+
+/// The `type`s of a schema which only has a `type` (and annotations), if these are all scalar.
+fn scalar_types(schema: &Value) -> Option<Vec<&'static str>> {
+    let obj = schema.as_object()?;
+    if obj
+        .keys()
+        .any(|keyword| !["type", "title", "description"].contains(&keyword.as_str()))
+    {
+        return None;
+    }
+    let types = match obj.get("type")? {
+        Value::String(ty) => vec![ty.as_str()],
+        Value::Array(types) => types
+            .iter()
+            .map(|ty| ty.as_str())
+            .collect::<Option<Vec<_>>>()?,
+        _ => return None,
+    };
+    types
+        .into_iter()
+        .map(|ty| SCALAR_TYPES.iter().find(|scalar| **scalar == ty).copied())
+        .collect()
+}
+
+/// Remove the duplicate `types`, and `integer` if `number` is also allowed, as their grammars
+/// would overlap.
+fn merge_types(types: Vec<&str>) -> Vec<&str> {
+    let has_number = types.contains(&"number");
+    let mut merged = Vec::new();
+    for ty in types {
+        if !merged.contains(&ty) && !(ty == "integer" && has_number) {
+            merged.push(ty);
+        }
+    }
+    merged
+}
+
+struct GrammarBuilder<'a> {
+    root: &'a Value,
+    rules: Vec<(String, Vec<Vec<String>>)>,
+    helpers: HashSet<&'static str>,
+    refs: HashMap<String, String>,
+    n_rules: usize,
+}
+
+impl<'a> GrammarBuilder<'a> {
+    fn new(root: &'a Value) -> Self {
+        Self {
+            root,
+            rules: Vec::new(),
+            helpers: HashSet::new(),
+            refs: HashMap::new(),
+            n_rules: 0,
+        }
+    }
+
+    fn fresh(&mut self, prefix: &str) -> String {
+        let name = format!("{prefix}_{}", self.n_rules);
+        self.n_rules += 1;
+        name
+    }
+
+    fn add_rule(&mut self, name: String, alternatives: Vec<Vec<String>>) {
+        self.rules.push((name, alternatives));
+    }
+
+    /// Get a fixed rule, adding it (and the rules it depends on) on first use.
+    fn helper(&mut self, name: &'static str) -> String {
+        if !self.helpers.insert(name) {
+            return name.to_string();
+        }
+        let alternatives = match name {
+            "ws" => vec![vec![], vec![self.helper("ws"), self.helper("ws_char")]],
+            "ws_char" => b" \t\n\r".iter().map(|b| vec![byte(*b)]).collect(),
+            "string" => vec![vec![byte(b'"'), self.helper("str_chars"), byte(b'"')]],
+            "str_chars" => vec![
+                vec![],
+                vec![self.helper("str_chars"), self.helper("str_char")],
+            ],
+            "str_char" => {
+                let mut alternatives = (0x20..=0x7E)
+                    .filter(|b| *b != b'"' && *b != b'\\')
+                    .map(|b| vec![byte(b)])
+                    .collect::<Vec<_>>();
+                alternatives.push(vec![NON_ASCII.to_string()]);
+                alternatives.push(vec![byte(b'\\'), self.helper("str_escape")]);
+                alternatives
+            }
+            "str_escape" => {
+                let mut alternatives = b"\"\\/bfnrt"
+                    .iter()
+                    .map(|b| vec![byte(*b)])
+                    .collect::<Vec<_>>();
+                let hex = self.helper("hex");
+                alternatives.push(vec![byte(b'u'), hex.clone(), hex.clone(), hex.clone(), hex]);
+                alternatives
+            }
+            "hex" => (b'0'..=b'9')
+                .chain(b'A'..=b'F')
+                .chain(b'a'..=b'f')
+                .map(|b| vec![byte(b)])
+                .collect(),
+            "number" => vec![vec![
+                self.helper("integer"),
+                self.helper("frac"),
+                self.helper("exp"),
+            ]],
+            "integer" => vec![
+                vec![self.helper("int_digits")],
+                vec![byte(b'-'), self.helper("int_digits")],
+            ],
+            "int_digits" => vec![
+                vec![byte(b'0')],
+                vec![self.helper("digit19"), self.helper("digits")],
+            ],
+            "digits" => vec![vec![], vec![self.helper("digits"), self.helper("digit")]],
+            "digit" => vec![vec![byte(b'0')], vec![self.helper("digit19")]],
+            "digit19" => (b'1'..=b'9').map(|b| vec![byte(b)]).collect(),
+            "frac" => vec![
+                vec![],
+                vec![byte(b'.'), self.helper("digit"), self.helper("digits")],
+            ],
+            "exp" => vec![
+                vec![],
+                vec![
+                    self.helper("exp_e"),
+                    self.helper("exp_sign"),
+                    self.helper("digit"),
+                    self.helper("digits"),
+                ],
+            ],
+            "exp_e" => vec![vec![byte(b'e')], vec![byte(b'E')]],
+            "exp_sign" => vec![vec![], vec![byte(b'+')], vec![byte(b'-')]],
+            "boolean" => vec![literal(&Value::Bool(true)), literal(&Value::Bool(false))],
+            "null" => vec![literal(&Value::Null)],
+            "any_value" => vec![
+                vec![self.helper("string")],
+                vec![self.helper("number")],
+                vec![self.helper("any_object")],
+                vec![self.helper("any_array")],
+                vec![self.helper("boolean")],
+                vec![self.helper("null")],
+            ],
+            "any_object" => {
+                let value = self.helper("any_value");
+                self.map_rule(name.to_string(), Some(value));
+                return name.to_string();
+            }
+            "any_array" => {
+                let item = self.helper("any_value");
+                self.array_rule(name.to_string(), item, 0, None);
+                return name.to_string();
+            }
+            _ => unreachable!("Unknown helper rule `{name}`"),
+        };
+        self.add_rule(name.to_string(), alternatives);
+        name.to_string()
+    }
+
+    fn visit(&mut self, schema: &'a Value) -> Result<String> {
+        let obj = match schema {
+            Value::Bool(true) => return Ok(self.helper("any_value")),
+            Value::Bool(false) => bail!("The schema `false` does not match any value."),
+            Value::Object(obj) => obj,
+            other => bail!("Expected a JSON Schema object or boolean, got `{other}`."),
+        };
+        if let Some(keyword) = UNSUPPORTED_KEYWORDS
+            .iter()
+            .find(|keyword| obj.contains_key(**keyword))
+        {
+            bail!("JSON Schema keyword `{keyword}` is not supported.");
+        }
+
+        if let Some(reference) = obj.get("$ref") {
+            let reference = reference.as_str().context("`$ref` must be a string.")?;
+            return self.visit_ref(reference);
+        }
+
+        let name = self.fresh("value");
+        let mut alternatives = if let Some(value) = obj.get("const") {
+            vec![literal(value)]
+        } else if let Some(values) = obj.get("enum") {
+            let values = values.as_array().context("`enum` must be an array.")?;
+            if values.is_empty() {
+                bail!("`enum` must contain at least one value.");
+            }
+            values.iter().map(literal).collect()
+        } else if let Some(schemas) = obj.get("anyOf").or_else(|| obj.get("oneOf")) {
+            let schemas = schemas
+                .as_array()
+                .context("`anyOf` and `oneOf` must be arrays.")?;
+            if schemas.is_empty() {
+                bail!("`anyOf` and `oneOf` must contain at least one schema.");
+            }
+            let mut alternatives = Vec::new();
+            let mut types = Vec::new();
+            for schema in schemas {
+                match scalar_types(schema) {
+                    Some(scalars) => types.extend(scalars),
+                    None => alternatives.push(vec![self.visit(schema)?]),
+                }
+            }
+            for ty in merge_types(types) {
+                alternatives.push(vec![self.helper(ty)]);
+            }
+            alternatives
+        } else if let Some(schemas) = obj.get("allOf") {
+            match schemas.as_array().map(|x| x.as_slice()) {
+                Some([schema]) => vec![vec![self.visit(schema)?]],
+                _ => bail!("`allOf` is only supported with exactly one schema."),
+            }
+        } else {
+            let types = match obj.get("type") {
+                Some(Value::String(ty)) => vec![ty.as_str()],
+                Some(Value::Array(types)) => types
+                    .iter()
+                    .map(|ty| ty.as_str().context("`type` must contain strings."))
+                    .collect::<Result<Vec<_>>>()?,
+                Some(_) => bail!("`type` must be a string or an array of strings."),
+                None if ["properties", "additionalProperties", "required"]
+                    .iter()
+                    .any(|keyword| obj.contains_key(*keyword)) =>
+                {
+                    vec!["object"]
+                }
+                None if obj.contains_key("items") => vec!["array"],
+                None => vec![],
+            };
+            if types.is_empty() {
+                vec![vec![self.helper("any_value")]]
+            } else {
+                merge_types(types)
+                    .into_iter()
+                    .map(|ty| {
+                        Ok(vec![match ty {
+                            "string" => self.helper("string"),
+                            "number" => self.helper("number"),
+                            "integer" => self.helper("integer"),
+                            "boolean" => self.helper("boolean"),
+                            "null" => self.helper("null"),
+                            "object" => self.visit_object(obj)?,
+                            "array" => self.visit_array(obj)?,
+                            other => bail!("Unknown JSON Schema type `{other}`."),
+                        }])
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            }
+        };
+        if obj.get("nullable") == Some(&Value::Bool(true)) {
+            let null = vec![self.helper("null")];
+            if !alternatives.contains(&null) {
+                alternatives.push(null);
+            }
+        }
+        self.add_rule(name.clone(), alternatives);
+        Ok(name)
+    }
+
+    fn visit_ref(&mut self, reference: &str) -> Result<String> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let pointer = reference.strip_prefix('#').with_context(|| {
+            format!("Only local `$ref`s (starting with `#`) are supported, got `{reference}`.")
+        })?;
+        let root = self.root;
+        let target = root
+            .pointer(pointer)
+            .with_context(|| format!("Could not resolve `$ref` `{reference}`."))?;
+        // Register the rule before visiting so that recursive schemas refer back to it.
+        let name = self.fresh("ref");
+        self.refs.insert(reference.to_string(), name.clone());
+        let inner = self.visit(target)?;
+        self.add_rule(name.clone(), vec![vec![inner]]);
+        Ok(name)
+    }
+
+    fn visit_object(&mut self, obj: &'a Map<String, Value>) -> Result<String> {
+        let name = self.fresh("object");
+        let properties = match obj.get("properties") {
+            Some(properties) => properties
+                .as_object()
+                .context("`properties` must be an object.")?
+                .iter()
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        let required = match obj.get("required") {
+            Some(required) => required
+                .as_array()
+                .context("`required` must be an array.")?
+                .iter()
+                .map(|x| x.as_str().context("`required` must contain strings."))
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+
+        if properties.is_empty() && required.is_empty() {
+            let value = match obj.get("additionalProperties") {
+                None | Some(Value::Bool(true)) => Some(self.helper("any_value")),
+                Some(Value::Bool(false)) => None,
+                Some(schema) => Some(self.visit(schema)?),
+            };
+            self.map_rule(name.clone(), value);
+            return Ok(name);
+        }
+
+        // Required properties which are not declared may have any value.
+        let mut members = properties
+            .into_iter()
+            .map(|(key, schema)| (key.as_str(), schema, required.contains(&key.as_str())))
+            .collect::<Vec<_>>();
+        // A `const` property usually tells apart the alternatives of an `anyOf` (a tagged union).
+        // Emitting it first lets the parser pick the alternative before parsing the other values.
+        members.sort_by_key(|(_, schema, _)| schema.get("const").is_none());
+        for key in required {
+            if !members.iter().any(|(k, _, _)| *k == key) {
+                members.push((key, &ANY, true));
+            }
+        }
+
+        let ws = self.helper("ws");
+        let mut props = Vec::new();
+        for (i, &(key, schema, _)) in members.iter().enumerate() {
+            let value = self.visit(schema)?;
+            let prop = format!("{name}_prop_{i}");
+            let mut alternative = literal(&Value::String(key.to_string()));
+            alternative.extend([ws.clone(), byte(b':'), ws.clone(), value, ws.clone()]);
+            self.add_rule(prop.clone(), vec![alternative]);
+            props.push(prop);
+        }
+
+        // `rest_i` are the members from `i` onwards once at least one member has been emitted,
+        // `first_i` are the members from `i` onwards when no member has been emitted yet.
+        let n = members.len();
+        for i in (1..=n).rev() {
+            let rest = format!("{name}_rest_{i}");
+            if i == n {
+                self.add_rule(rest, vec![vec![]]);
+                continue;
+            }
+            let next = format!("{name}_rest_{}", i + 1);
+            let mut alternatives =
+                vec![vec![byte(b','), ws.clone(), props[i].clone(), next.clone()]];
+            if !members[i].2 {
+                alternatives.push(vec![next]);
+            }
+            self.add_rule(rest, alternatives);
+        }
+        for i in 0..=n {
+            let first = format!("{name}_first_{i}");
+            if i == n {
+                self.add_rule(first, vec![vec![]]);
+                break;
+            }
+            let mut alternatives = vec![vec![props[i].clone(), format!("{name}_rest_{}", i + 1)]];
+            let is_required = members[i].2;
+            if !is_required {
+                alternatives.push(vec![format!("{name}_first_{}", i + 1)]);
+            }
+            self.add_rule(first, alternatives);
+            if is_required {
+                break;
+            }
+        }
+
+        self.add_rule(
+            name.clone(),
+            vec![vec![byte(b'{'), ws, format!("{name}_first_0"), byte(b'}')]],
+        );
+        Ok(name)
+    }
+
+    /// An object with arbitrary keys. If `value` is `None`, only the empty object is allowed.
+    fn map_rule(&mut self, name: String, value: Option<String>) {
+        let ws = self.helper("ws");
+        let mut alternatives = vec![vec![byte(b'{'), ws.clone(), byte(b'}')]];
+        if let Some(value) = value {
+            let string = self.helper("string");
+            let member = format!("{name}_member");
+            let tail = format!("{name}_tail");
+            self.add_rule(
+                member.clone(),
+                vec![vec![
+                    string,
+                    ws.clone(),
+                    byte(b':'),
+                    ws.clone(),
+                    value,
+                    ws.clone(),
+                ]],
+            );
+            self.add_rule(
+                tail.clone(),
+                vec![
+                    vec![],
+                    vec![tail.clone(), byte(b','), ws.clone(), member.clone()],
+                ],
+            );
+            alternatives.push(vec![byte(b'{'), ws, member, tail, byte(b'}')]);
+        }
+        self.add_rule(name, alternatives);
+    }
+
+    fn visit_array(&mut self, obj: &'a Map<String, Value>) -> Result<String> {
+        let name = self.fresh("array");
+        let item = match obj.get("items") {
+            None | Some(Value::Bool(true)) => self.helper("any_value"),
+            Some(schema) => self.visit(schema)?,
+        };
+        let min_items = match obj.get("minItems") {
+            Some(min) => min.as_u64().context("`minItems` must be an integer.")?,
+            None => 0,
+        };
+        let max_items = match obj.get("maxItems") {
+            Some(max) => Some(max.as_u64().context("`maxItems` must be an integer.")?),
+            None => None,
+        };
+        if max_items.is_some_and(|max| max < min_items) {
+            bail!("`maxItems` must not be smaller than `minItems`.");
+        }
+        if min_items > MAX_ITEMS_BOUND || max_items.is_some_and(|max| max > MAX_ITEMS_BOUND) {
+            bail!("`minItems` and `maxItems` must be at most {MAX_ITEMS_BOUND}.");
+        }
+        self.array_rule(name.clone(), item, min_items, max_items);
+        Ok(name)
+    }
+
+    fn array_rule(&mut self, name: String, item: String, min_items: u64, max_items: Option<u64>) {
+        let ws = self.helper("ws");
+        let mut alternatives = Vec::new();
+        if min_items == 0 {
+            alternatives.push(vec![byte(b'['), ws.clone(), byte(b']')]);
+        }
+        if max_items != Some(0) {
+            let first = min_items.max(1);
+            let mut alternative = vec![byte(b'['), ws.clone(), item.clone(), ws.clone()];
+            for _ in 1..first {
+                alternative.extend([byte(b','), ws.clone(), item.clone(), ws.clone()]);
+            }
+            match max_items {
+                None => {
+                    let tail = format!("{name}_tail");
+                    self.add_rule(
+                        tail.clone(),
+                        vec![
+                            vec![],
+                            vec![tail.clone(), byte(b','), ws.clone(), item, ws.clone()],
+                        ],
+                    );
+                    alternative.push(tail);
+                }
+                Some(max_items) if max_items > first => {
+                    // Nested optional items, `opt_j` is the `j`th item.
+                    for j in (first + 1..=max_items).rev() {
+                        let mut more = vec![byte(b','), ws.clone(), item.clone(), ws.clone()];
+                        if j < max_items {
+                            more.push(format!("{name}_opt_{}", j + 1));
+                        }
+                        self.add_rule(format!("{name}_opt_{j}"), vec![vec![], more]);
+                    }
+                    alternative.push(format!("{name}_opt_{}", first + 1));
+                }
+                Some(_) => (),
+            }
+            alternative.push(byte(b']'));
+            alternatives.push(alternative);
+        }
+        self.add_rule(name, alternatives);
+    }
+
+    fn finish(self, start: String) -> String {
+        let mut grammar = format!("%start root\n%%\n\nroot : {start} ;\n");
+        for (name, alternatives) in self.rules {
+            let alternatives = alternatives
+                .iter()
+                .map(|symbols| symbols.join(" "))
+                .collect::<Vec<_>>()
+                .join("\n    | ");
+            write!(grammar, "\n{name}\n    : {alternatives}\n    ;\n").unwrap();
+        }
+        grammar
+    }
+}
+
+/// Compile a JSON Schema into a Yacc grammar which only accepts documents validating against it.
+pub fn json_schema_to_yacc(schema: &Value) -> Result<String> {
+    let mut builder = GrammarBuilder::new(schema);
+    let start = builder.visit(schema)?;
+    Ok(builder.finish(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::json_schema_to_yacc;
+    use crate::aici::{
+        cfg::CfgParser,
+        toktree::{Recognizer, SpecialToken},
+    };
+
+    fn accepts(schema: &serde_json::Value, document: &str) -> bool {
+        let yacc = json_schema_to_yacc(schema).unwrap();
+        let mut parser = CfgParser::from_yacc_unambiguous(&yacc).unwrap();
+        document.bytes().all(|b| parser.try_push_byte(b))
+            && parser.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn test_object_with_required_and_optional() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}, "maxItems": 2}
+            },
+            "required": ["name"]
+        });
+        assert!(accepts(&schema, r#"{"age": -12, "name": "x\"y"}"#));
+        assert!(accepts(&schema, r#"{"name":"", "tags": ["a", "b"]}"#));
+        assert!(!accepts(&schema, r#"{"age": 3}"#));
+        assert!(!accepts(&schema, r#"{"age": 1.5, "name": "x"}"#));
+        assert!(!accepts(
+            &schema,
+            r#"{"name": "x", "tags": ["a", "b", "a"]}"#
+        ));
+        assert!(!accepts(&schema, r#"{"name": "x""#));
+    }
+
+    #[test]
+    fn test_recursive_ref() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": {"type": "number"},
+                        "children": {"type": "array", "items": {"$ref": "#/$defs/node"}}
+                    },
+                    "required": ["value", "children"]
+                }
+            }
+        });
+        assert!(accepts(
+            &schema,
+            r#"{"children": [{"children": [], "value": 0.5}], "value": 1e3}"#
+        ));
+        assert!(!accepts(
+            &schema,
+            r#"{"children": [{"value": 2}], "value": 1}"#
+        ));
+    }
+
+    #[test]
+    fn test_tagged_union() {
+        // Without the tag first, the alternatives could only be told apart after `value`.
+        let tagged = |tag: &str| {
+            json!({
+                "type": "object",
+                "properties": {"value": {"type": "object"}, "tag": {"const": tag}},
+                "required": ["tag", "value"]
+            })
+        };
+        let schema = json!({"anyOf": [tagged("a"), tagged("b")]});
+        assert!(accepts(&schema, r#"{"tag": "b", "value": {"x": 1}}"#));
+        assert!(accepts(&schema, r#"{"tag": "a", "value": {}}"#));
+        assert!(!accepts(&schema, r#"{"value": {}, "tag": "a"}"#));
+    }
+
+    #[test]
+    fn test_overlapping_types() {
+        // Every integer is also a number, so the types are merged into `number`.
+        for schema in [
+            json!({"type": ["integer", "number"]}),
+            json!({"anyOf": [{"type": "integer"}, {"type": "number"}]}),
+            json!({"oneOf": [{"type": "integer", "description": "x"}, {"type": ["number", "null"]}], "nullable": true}),
+        ] {
+            assert!(accepts(&schema, "3"));
+            assert!(accepts(&schema, "-0.5e2"));
+            assert!(!accepts(&schema, r#""3""#));
+        }
+    }
+
+    #[test]
+    fn test_ambiguous_any_of() {
+        // Both alternatives accept any object.
+        let schema = json!({"anyOf": [{"type": "object"}, {"additionalProperties": true}]});
+        let yacc = json_schema_to_yacc(&schema).unwrap();
+        let err = CfgParser::from_yacc_unambiguous(&yacc).err().unwrap();
+        assert!(err.to_string().contains("ambiguous"));
+        // User grammars are not required to be unambiguous.
+        assert!(CfgParser::from_yacc(&yacc).is_ok());
+    }
+
+    #[test]
+    fn test_unsupported_keyword() {
+        assert!(json_schema_to_yacc(&json!({"type": "string", "pattern": "a+"})).is_err());
+    }
+}
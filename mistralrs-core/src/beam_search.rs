@@ -0,0 +1,150 @@
+//! Beam search: the `beam_width` sequences with the highest log probabilities are kept at each
+//! step, instead of sampling one token per sequence. The beams of a request are sequences of the
+//! same group which choose their tokens together, see `sample_and_add_toks`.
+
+use std::iter::zip;
+
+use crate::{sampler::Logprobs, sequence::BeamState, BeamSearchParams};
+
+/// A token which may extend a beam.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Candidate {
+    /// Index of the extended beam.
+    pub beam: usize,
+    pub token: u32,
+    /// Sum of the log probabilities of the generated tokens of the beam, including this token.
+    pub score: f32,
+}
+
+/// The `n` candidates with the highest scores over all beams, sorted by descending score.
+/// `logprobs` holds the log probabilities of the next token for each beam.
+pub(crate) fn top_candidates(
+    beam_scores: &[f32],
+    logprobs: &[Vec<f32>],
+    n: usize,
+) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for (beam, (score, logprobs)) in zip(beam_scores, logprobs).enumerate() {
+        // Only the top `n` tokens of each beam may be among the top `n` overall
+        let mut tokens = (0..logprobs.len()).collect::<Vec<_>>();
+        if n < tokens.len() {
+            tokens.select_nth_unstable_by(n, |a, b| logprobs[*b].total_cmp(&logprobs[*a]));
+            tokens.truncate(n);
+        }
+        candidates.extend(tokens.into_iter().map(|token| Candidate {
+            beam,
+            token: token as u32,
+            score: score + logprobs[token],
+        }));
+    }
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    candidates.truncate(n);
+    candidates
+}
+
+/// A finished beam: the state of the beam before its last token, and the last token.
+pub(crate) struct BeamHypothesis {
+    pub state: BeamState,
+    pub last: Logprobs,
+}
+
+/// The best `beam_width` finished beams of a request, by their length-normalized score.
+pub(crate) struct BeamHypotheses<T> {
+    params: BeamSearchParams,
+    /// Sorted by descending score.
+    hyps: Vec<(f32, T)>,
+}
+
+impl<T> BeamHypotheses<T> {
+    pub fn new(params: BeamSearchParams) -> Self {
+        Self {
+            params,
+            hyps: Vec::new(),
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn normalized_score(&self, score: f32, len: usize) -> f32 {
+        score / (len as f32).powf(self.params.length_penalty)
+    }
+
+    /// Add a finished beam with `len` generated tokens, keeping it only if it is among the best.
+    pub fn add(&mut self, score: f32, len: usize, hyp: T) {
+        let score = self.normalized_score(score, len);
+        if self.hyps.len() == self.params.beam_width
+            && self.hyps.last().is_some_and(|(worst, _)| *worst >= score)
+        {
+            return;
+        }
+        let pos = self.hyps.partition_point(|(other, _)| *other >= score);
+        self.hyps.insert(pos, (score, hyp));
+        self.hyps.truncate(self.params.beam_width);
+    }
+
+    /// Whether the search is done, given the best score of the running beams with `len`
+    /// generated tokens. Without early stopping, the search continues until no running beam can
+    /// become better than the finished beams.
+    pub fn is_done(&self, best_running_score: f32, len: usize) -> bool {
+        if self.hyps.len() < self.params.beam_width {
+            return false;
+        }
+        self.params.early_stopping
+            || self
+                .hyps
+                .last()
+                .is_some_and(|(worst, _)| *worst >= self.normalized_score(best_running_score, len))
+    }
+
+    /// The `n` best finished beams, best first.
+    pub fn into_best(self, n: usize) -> Vec<T> {
+        self.hyps.into_iter().take(n).map(|(_, hyp)| hyp).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{top_candidates, BeamHypotheses};
+    use crate::BeamSearchParams;
+
+    #[test]
+    fn test_top_candidates() {
+        let logprobs = vec![vec![-0.1, -3.0, -2.0], vec![-0.5, -0.2, -4.0]];
+        let candidates = top_candidates(&[-1.0, -0.5], &logprobs, 3);
+        let picked = candidates
+            .iter()
+            .map(|c| (c.beam, c.token))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![(1, 1), (1, 0), (0, 0)]);
+        assert!((candidates[0].score - -0.7).abs() < 1e-6);
+
+        // Fewer tokens than candidates
+        assert_eq!(top_candidates(&[0.0], &[vec![-1.0, -2.0]], 4).len(), 2);
+    }
+
+    #[test]
+    fn test_hypotheses() {
+        let params = BeamSearchParams {
+            beam_width: 2,
+            length_penalty: 1.0,
+            early_stopping: false,
+        };
+        let mut hyps = BeamHypotheses::new(params);
+        hyps.add(-4.0, 2, "a");
+        assert!(!hyps.is_done(-10.0, 2));
+        // Normalized by length: -3.0 / 3 > -4.0 / 2
+        hyps.add(-3.0, 3, "b");
+        hyps.add(-9.0, 3, "c");
+        // A running beam with -3.0 over 2 tokens could still beat the worst hypothesis
+        assert!(!hyps.is_done(-3.0, 2));
+        assert!(hyps.is_done(-5.0, 2));
+        assert_eq!(hyps.into_best(2), vec!["b", "a"]);
+
+        let mut hyps = BeamHypotheses::new(BeamSearchParams {
+            early_stopping: true,
+            ..params
+        });
+        hyps.add(-4.0, 2, "a");
+        hyps.add(-9.0, 3, "c");
+        assert!(hyps.is_done(0.0, 2));
+    }
+}
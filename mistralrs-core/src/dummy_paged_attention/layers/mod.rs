@@ -1,3 +0,0 @@
-pub mod paged_attention;
-
-pub use paged_attention::PagedAttention;
@@ -0,0 +1,115 @@
+//! Embeddings computed by pooling the final hidden states of a text model.
+
+use candle_core::{DType, Result, Tensor, D};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sequence::{Sequence, SequenceState, StopReason},
+    EmbeddingResponse, Response,
+};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+/// How the hidden states of the tokens are pooled into an embedding.
+pub enum EmbeddingPooling {
+    /// The mean of the hidden states of all tokens.
+    #[serde(rename = "mean")]
+    Mean,
+    /// The hidden state of the last token, which is the only token attending to the whole input
+    /// in a causal model.
+    #[default]
+    #[serde(rename = "last")]
+    LastToken,
+    /// The hidden state of the first token, such as a `[CLS]` token.
+    #[serde(rename = "cls")]
+    Cls,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EmbeddingParams {
+    pub pooling: EmbeddingPooling,
+    pub normalize: bool,
+}
+
+/// Pool the hidden states of shape (seq len, hidden size) of the first `len` tokens into an
+/// embedding of shape (hidden size).
+pub(crate) fn pool_hidden_states(
+    hidden_states: &Tensor,
+    len: usize,
+    params: EmbeddingParams,
+) -> Result<Tensor> {
+    let hidden_states = hidden_states.to_dtype(DType::F32)?;
+    let embedding = match params.pooling {
+        EmbeddingPooling::Mean => hidden_states.narrow(0, 0, len)?.mean(0)?,
+        EmbeddingPooling::LastToken => hidden_states.get(len - 1)?,
+        EmbeddingPooling::Cls => hidden_states.get(0)?,
+    };
+    if params.normalize {
+        let norm = embedding.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+        embedding.broadcast_div(&norm.maximum(1e-12)?)
+    } else {
+        Ok(embedding)
+    }
+}
+
+pub(crate) async fn send_responses(
+    input_seqs: &mut [&mut Sequence],
+    hidden_states: Vec<Tensor>,
+) -> Result<()> {
+    if input_seqs.len() != hidden_states.len() {
+        candle_core::bail!(
+            "Input seqs len ({}) does not match hidden states len ({})",
+            input_seqs.len(),
+            hidden_states.len()
+        );
+    }
+
+    for (seq, hidden_states) in input_seqs.iter_mut().zip(hidden_states) {
+        let Some(params) = seq.embedding_params() else {
+            candle_core::bail!("Sequence {} did not request an embedding.", seq.id());
+        };
+        let prompt_tokens = seq.get_toks().len();
+        let embedding = pool_hidden_states(&hidden_states, prompt_tokens, params)?;
+        seq.responder()
+            .send(Response::Embedding(EmbeddingResponse {
+                embedding: embedding.to_vec1()?,
+                prompt_tokens,
+            }))
+            .await
+            .map_err(candle_core::Error::msg)?;
+
+        seq.set_state(SequenceState::Done(StopReason::GeneratedEmbedding));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{pool_hidden_states, EmbeddingParams, EmbeddingPooling};
+
+    fn pool(pooling: EmbeddingPooling, normalize: bool) -> candle_core::Result<Vec<f32>> {
+        // The last token is padding
+        let hidden_states = Tensor::new(&[[3f32, 0.], [1., 4.], [2., 5.], [9., 9.]], &Device::Cpu)?;
+        pool_hidden_states(&hidden_states, 3, EmbeddingParams { pooling, normalize })?.to_vec1()
+    }
+
+    #[test]
+    fn test_pooling() -> candle_core::Result<()> {
+        assert_eq!(pool(EmbeddingPooling::Mean, false)?, [2., 3.]);
+        assert_eq!(pool(EmbeddingPooling::LastToken, false)?, [2., 5.]);
+        assert_eq!(pool(EmbeddingPooling::Cls, false)?, [3., 0.]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() -> candle_core::Result<()> {
+        assert_eq!(pool(EmbeddingPooling::Cls, true)?, [1., 0.]);
+        let mean = pool(EmbeddingPooling::Mean, true)?;
+        assert!((mean[0] - 2. / 13f32.sqrt()).abs() < 1e-6);
+        assert!((mean[1] - 3. / 13f32.sqrt()).abs() < 1e-6);
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+use anyhow::Context;
+use either::Either;
+use indexmap::IndexMap;
 use once_cell::sync::Lazy;
 use std::{
     collections::HashMap,
@@ -10,17 +13,21 @@ use std::{
 use tokio::sync::{mpsc::Receiver, Mutex};
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
+    aici::{
+        cfg::CfgParser, json_schema::json_schema_to_yacc, recognizer::StackRecognizer, rx::RecRx,
+    },
+    embedding::EmbeddingParams,
+    metrics::MetricsCollector,
     pipeline::{
         text_models_inputs_processor::PagedAttentionMeta, AdapterInstruction, CacheBackendMetadata,
-        CacheInstruction,
+        CacheInstruction, ModelCategory,
     },
-    request::NormalRequest,
+    request::{NormalRequest, Tokenization},
     response::CompletionChoice,
     scheduler::{Scheduler, SchedulerOutput},
     sequence::{SeqStepType, StopReason},
-    tools::{ToolCallingMatcher, ToolChoice},
-    CompletionResponse, RequestMessage, Response, SchedulerConfig, DEBUG,
+    tools::{forced_tool_call_schema, Tool, ToolCallFormat, ToolCallingMatcher, ToolChoice},
+    CompletionResponse, MessageContent, RequestMessage, Response, SchedulerConfig, DEBUG,
 };
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
@@ -53,6 +60,8 @@ pub struct Engine {
     rx: Receiver<Request>,
     pipeline: Arc<Mutex<dyn Pipeline>>,
     scheduler: Box<dyn Scheduler>,
+    /// ID of the engine in [`ENGINE_INSTRUCTIONS`].
+    engine_id: usize,
     id: usize,
     truncate_sequence: bool,
     no_kv_cache: bool,
@@ -60,11 +69,16 @@ pub struct Engine {
     is_debug: bool,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    tool_call_format: ToolCallFormat,
+    /// ID of the token which starts the tool calls in `tool_call_format`, if it is a token.
+    tool_call_marker: Option<u32>,
+    metrics: Arc<MetricsCollector>,
 }
 
 impl Engine {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
+        engine_id: usize,
         rx: Receiver<Request>,
         pipeline: Arc<Mutex<dyn Pipeline>>,
         config: SchedulerConfig,
@@ -74,23 +88,39 @@ impl Engine {
         prefix_cache_n: usize,
         disable_eos_stop: bool,
         throughput_logging_enabled: bool,
+        tool_call_format: Option<ToolCallFormat>,
+        metrics: Arc<MetricsCollector>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
         let has_no_kv_cache = get_mut_arcmutex!(pipeline).get_metadata().has_no_kv_cache;
+        let is_recurrent = get_mut_arcmutex!(pipeline).get_metadata().is_recurrent;
         if no_kv_cache {
             // Diffusion models...
             assert_eq!(has_no_kv_cache, no_kv_cache);
         }
-        // Prefix caching is always disabled if using PagedAttention for now.
-        // TODO
-        let no_prefix_cache = matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
-            || no_prefix_cache
-            || has_no_kv_cache;
+        // A recurrent state cannot be truncated to the length of a prefix
+        let no_prefix_cache = no_prefix_cache || has_no_kv_cache || is_recurrent;
+        let tool_call_format = tool_call_format.unwrap_or_else(|| {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            ToolCallFormat::detect(
+                pipeline.get_chat_template().as_deref(),
+                pipeline.tokenizer().as_deref(),
+            )
+        });
+        let tool_call_marker = get_mut_arcmutex!(pipeline)
+            .tokenizer()
+            .and_then(|tokenizer| tool_call_format.marker_token(&tokenizer));
+        // With PagedAttention, prefixes are cached by sharing KV cache blocks in the scheduler
+        // instead of by the prefix cacher.
+        let enable_block_prefix_caching = !no_prefix_cache && !is_xlora;
+        let no_prefix_cache =
+            matches!(config, SchedulerConfig::PagedAttentionMeta { .. }) || no_prefix_cache;
         Self {
             rx,
             pipeline,
-            scheduler: config.into_scheduler(),
+            scheduler: config.into_scheduler(enable_block_prefix_caching),
+            engine_id,
             id: 0,
             truncate_sequence,
             no_kv_cache: no_kv_cache & !has_no_kv_cache,
@@ -103,6 +133,9 @@ impl Engine {
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled,
+            tool_call_format,
+            tool_call_marker,
+            metrics,
         }
     }
 
@@ -114,7 +147,7 @@ impl Engine {
                 ENGINE_INSTRUCTIONS
                     .lock()
                     .expect("`ENGINE_INSTRUCTIONS` was poisioned")
-                    .get(&self.id),
+                    .get(&self.engine_id),
                 Some(Some(EngineInstruction::Terminate))
             ) {
                 break 'lp;
@@ -187,6 +220,8 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        self.metrics
+                            .record_tokens(scheduled.completion.iter_mut().map(|seq| &mut **seq));
 
                         let throughput_end = Instant::now();
                         #[allow(clippy::cast_precision_loss)]
@@ -249,6 +284,8 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        self.metrics
+                            .record_tokens(scheduled.prompt.iter_mut().map(|seq| &mut **seq));
 
                         let throughput_end = Instant::now();
                         #[allow(clippy::cast_precision_loss)]
@@ -267,6 +304,10 @@ impl Engine {
 
                         for seq in scheduled.prompt.iter_mut() {
                             match seq.sequence_stepping_type() {
+                                SeqStepType::OneShot if seq.embedding_params().is_some() => seq
+                                    .set_state(SequenceState::Done(StopReason::GeneratedEmbedding)),
+                                // Scoring sequences are finished by the prompt step
+                                SeqStepType::OneShot if seq.is_scoring() => (),
                                 SeqStepType::OneShot => {
                                     seq.set_state(SequenceState::Done(StopReason::GeneratedImage))
                                 }
@@ -287,6 +328,19 @@ impl Engine {
                         last_completion_ids = vec![];
                     }
 
+                    // Beams forked by beam search requests join the running sequences
+                    let mut beam_forks = Vec::new();
+                    for seq in scheduled
+                        .completion
+                        .iter_mut()
+                        .chain(scheduled.prompt.iter_mut())
+                    {
+                        for fork in seq.take_beam_forks() {
+                            beam_forks.push(seq.fork_beam(self.id, fork));
+                            self.id += 1;
+                        }
+                    }
+
                     if self.is_debug {
                         let ms_from_last_run = run_start.elapsed().as_secs_f64();
                         let total_len = scheduled.prompt.len() + scheduled.completion.len();
@@ -327,18 +381,32 @@ impl Engine {
                             }
                             (None, None) => (),
                         }
+                        if completion_ts.is_some() {
+                            if let Some(stats) =
+                                get_mut_arcmutex!(self.pipeline).speculative_stats()
+                            {
+                                info!(
+                                    "Speculative decoding: {:.1}% of {} proposed tokens accepted, {:.2} proposed per step",
+                                    stats.acceptance_rate() * 100.,
+                                    stats.drafted,
+                                    stats.drafted_per_step(),
+                                );
+                            }
+                        }
                     }
 
-                    if scheduled.prompt.len() == 0
-                        && scheduled.completion.len() == 0
-                        && self.scheduler.waiting_len() == 0
-                    {
-                        // If there is nothing to do, sleep until a request comes in
-                        if let Some(request) = self.rx.recv().await {
-                            if matches!(request, Request::Terminate) {
-                                break 'lp;
-                            }
-                            self.handle_request(request).await;
+                    let nothing_scheduled =
+                        scheduled.prompt.len() == 0 && scheduled.completion.len() == 0;
+                    for seq in beam_forks {
+                        self.scheduler.add_seq(seq);
+                    }
+
+                    if nothing_scheduled && self.scheduler.waiting_len() == 0 {
+                        // If there is nothing to do, sleep until a request comes in. No request
+                        // can come in once the sender is dropped.
+                        match self.rx.recv().await {
+                            Some(Request::Terminate) | None => break 'lp,
+                            Some(request) => self.handle_request(request).await,
                         }
                     }
                 }
@@ -393,6 +461,8 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        self.metrics
+                            .record_tokens(guards_mut.iter_mut().map(|seq| &mut **seq));
 
                         if self.is_debug {
                             let ms_from_last_run = run_start.elapsed().as_secs_f64();
@@ -452,7 +522,32 @@ impl Engine {
             }
 
             self.scheduler.free_finished_sequence_groups();
+            self.update_metrics();
         }
+
+        ENGINE_INSTRUCTIONS
+            .lock()
+            .expect("`ENGINE_INSTRUCTIONS` was poisioned")
+            .remove(&self.engine_id);
+    }
+
+    /// Update the metrics which reflect the current state of the engine.
+    fn update_metrics(&mut self) {
+        let waiting = self.scheduler.waiting_len();
+        let running = self.scheduler.running_len();
+        let (hits, misses) = self.prefix_cacher.hits_and_misses();
+        let kv_cache_blocks = self
+            .scheduler
+            .block_engine()
+            .map(|block_engine| block_engine.gpu_block_usage());
+        self.metrics.update(|metrics| {
+            metrics.waiting_sequences = waiting;
+            metrics.running_sequences = running;
+            metrics.prefix_cache_hits = hits;
+            metrics.prefix_cache_misses = misses;
+            metrics.kv_cache_blocks_used = kv_cache_blocks.map(|(used, _)| used);
+            metrics.kv_cache_blocks_total = kv_cache_blocks.map(|(_, total)| total);
+        });
     }
 
     fn build_sequence_recognizer(constraint: &Constraint) -> anyhow::Result<SequenceRecognizer> {
@@ -461,6 +556,9 @@ impl Engine {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx, None)?).into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+            Constraint::JsonSchema(schema) => SequenceRecognizer::Cfg(
+                CfgParser::from_yacc_unambiguous(&json_schema_to_yacc(schema)?)?.into(),
+            ),
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
@@ -480,11 +578,84 @@ impl Engine {
                     warn!("ISQ requantization failed: {e:?}");
                 }
             }
+            Request::Tokenize(request) => {
+                let result = self.tokenize(
+                    request.text,
+                    request.tools.unwrap_or_default(),
+                    request.add_generation_prompt,
+                    request.add_special_tokens,
+                );
+                // The requester may have gone away
+                let _ = request.response.send(result).await;
+            }
+            Request::Detokenize(request) => {
+                let result = get_mut_arcmutex!(self.pipeline)
+                    .tokenizer()
+                    .context("The model does not have a tokenizer.")
+                    .and_then(|tokenizer| {
+                        tokenizer
+                            .decode(&request.tokens, request.skip_special_tokens)
+                            .map_err(anyhow::Error::msg)
+                    });
+                let _ = request.response.send(result).await;
+            }
+            Request::Cancel { id } => {
+                let model = get_mut_arcmutex!(self.pipeline).name();
+                for (sender, response) in self.scheduler.cancel_request(id, &model) {
+                    // The receiver may already be dropped, as is usual for cancelled requests
+                    let _ = sender.send(response).await;
+                }
+            }
             Request::Terminate => panic!("This is unreachable in `handle_request`. Termination is handled in the `run` loop."),
         }
     }
 
-    async fn add_request(&mut self, request: NormalRequest) {
+    /// Tokenize a text, or messages rendered with the chat template, like the prompt of a request.
+    /// Messages with images are rejected.
+    fn tokenize(
+        &self,
+        text: Either<Vec<IndexMap<String, MessageContent>>, String>,
+        tools: Vec<Tool>,
+        add_generation_prompt: bool,
+        add_special_tokens: bool,
+    ) -> anyhow::Result<Tokenization> {
+        let pipeline = &*get_mut_arcmutex!(self.pipeline);
+        match text {
+            Either::Left(messages) => {
+                let has_image = messages
+                    .iter()
+                    .filter_map(|message| message.get("content")?.as_ref().right())
+                    .flatten()
+                    .any(|part| part.get("type").is_some_and(|ty| ty == "image"));
+                if has_image {
+                    // The chat template only renders placeholders, which the input processor of
+                    // a vision model expands into a number of tokens depending on the image.
+                    anyhow::bail!(
+                        "Messages with images cannot be tokenized, as the number of tokens of an image is only known when the request is run."
+                    );
+                }
+                let (tokens, prompt) = pipeline.get_processor().process(
+                    pipeline,
+                    messages,
+                    add_generation_prompt,
+                    tools,
+                )?;
+                Ok(Tokenization { tokens, prompt })
+            }
+            Either::Right(prompt) => {
+                let tokens = pipeline
+                    .tokenizer()
+                    .context("The model does not have a tokenizer.")?
+                    .encode(prompt.clone(), add_special_tokens)
+                    .map_err(anyhow::Error::msg)?
+                    .get_ids()
+                    .to_vec();
+                Ok(Tokenization { tokens, prompt })
+            }
+        }
+    }
+
+    async fn add_request(&mut self, mut request: NormalRequest) {
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
@@ -502,22 +673,75 @@ impl Engine {
             RequestMessage::Chat(_)
             | RequestMessage::CompletionTokens(_)
             | RequestMessage::VisionChat { .. }
-            | RequestMessage::ImageGeneration { .. } => 1,
+            | RequestMessage::ImageGeneration { .. }
+            | RequestMessage::Embedding { .. }
+            | RequestMessage::Score { .. } => 1,
         };
+        // Scoring requests compute the logprobs of the prompt, which includes the continuation
+        let is_scoring = matches!(request.messages, RequestMessage::Score { .. });
+        let return_prompt_logprobs = is_scoring
+            || matches!(
+                request.messages,
+                RequestMessage::Completion {
+                    prompt_logprobs: true,
+                    ..
+                }
+            );
         if is_chat
             && !get_mut_arcmutex!(self.pipeline)
                 .get_chat_template()
                 .as_ref()
                 .is_some_and(|ch_t| ch_t.has_chat_template())
         {
-            request
-                    .response
-                    .send(Response::ValidationError(
+            self.metrics
+                .send_error(
+                    &request.response,
+                    Response::ValidationError(
                         "Received messages for a model which does not have a chat template. Either use a different model or pass a single string as the prompt".into(),
-                    )).await.expect("Expected receiver.");
+                    ),
+                )
+                .await;
+            return;
+        }
+
+        if matches!(request.messages, RequestMessage::Embedding { .. })
+            && get_mut_arcmutex!(self.pipeline).category() != ModelCategory::Text
+        {
+            self.metrics
+                .send_error(
+                    &request.response,
+                    Response::ValidationError(
+                        "Embeddings are only supported for text models.".into(),
+                    ),
+                )
+                .await;
             return;
         }
 
+        if return_prompt_logprobs {
+            let error = if request.is_streaming {
+                Some("Prompt logprobs and scoring do not support streaming.")
+            } else if get_mut_arcmutex!(self.pipeline)
+                .speculative_stats()
+                .is_some()
+            {
+                Some("Prompt logprobs and scoring do not support speculative decoding.")
+            } else if is_scoring
+                && (request.sampling_params.n_choices != 1
+                    || request.sampling_params.beam_search.is_some())
+            {
+                Some("Scoring requests return one choice and do not support beam search.")
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                self.metrics
+                    .send_error(&request.response, Response::ValidationError(error.into()))
+                    .await;
+                return;
+            }
+        }
+
         let images = match request.messages {
             RequestMessage::VisionChat {
                 ref images,
@@ -526,10 +750,45 @@ impl Engine {
             _ => None,
         };
 
+        // Forced tool calls are constrained to the JSON Schema of the calls
+        if let (Some(tool_choice), Some(tools)) = (&request.tool_choice, &request.tools) {
+            let error = match forced_tool_call_schema(tool_choice, tools) {
+                Ok(Some(_)) if !matches!(request.constraint, Constraint::None) => {
+                    Some("Grammars are not supported when a tool call is forced.".to_string())
+                }
+                Ok(Some(schema)) => {
+                    let constraint = Constraint::JsonSchema(schema);
+                    // Tool calls were not constrained before, so a schema which cannot be
+                    // compiled leaves the call unconstrained instead of failing the request
+                    match Self::build_sequence_recognizer(&constraint) {
+                        Ok(_) => request.constraint = constraint,
+                        Err(e) => warn!(
+                            "The tool call of request {} is not constrained, as its schema is not supported: {e}",
+                            request.id
+                        ),
+                    }
+                    None
+                }
+                Ok(None) => None,
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(error) = error {
+                self.metrics
+                    .send_error(&request.response, Response::ValidationError(error.into()))
+                    .await;
+                return;
+            }
+        }
+
         let matcher = if request.tools.is_some() {
             Some(Arc::new(handle_seq_error!(
-                ToolCallingMatcher::new(request.tool_choice.unwrap_or(ToolChoice::Auto),),
-                request.response
+                ToolCallingMatcher::new(
+                    request.tool_choice.unwrap_or(ToolChoice::Auto),
+                    self.tool_call_format,
+                    self.tool_call_marker,
+                ),
+                request.response,
+                self.metrics
             )))
         } else {
             None
@@ -541,7 +800,9 @@ impl Engine {
         };
 
         let seq_step_type = match &request.messages {
-            RequestMessage::ImageGeneration { .. } => SeqStepType::OneShot,
+            RequestMessage::ImageGeneration { .. }
+            | RequestMessage::Embedding { .. }
+            | RequestMessage::Score { .. } => SeqStepType::OneShot,
             _ => SeqStepType::PromptAndDecode,
         };
 
@@ -552,6 +813,18 @@ impl Engine {
             _ => None,
         };
 
+        let embedding_params = match &request.messages {
+            RequestMessage::Embedding {
+                pooling, normalize, ..
+            } => Some(EmbeddingParams {
+                pooling: *pooling,
+                normalize: *normalize,
+            }),
+            _ => None,
+        };
+
+        // For scoring requests, the index of the first continuation token in the prompt
+        let mut continuation_start = None;
         let (mut prompt_tokens, prompt_text) = match request.messages {
             RequestMessage::Chat(messages)
             | RequestMessage::VisionChat {
@@ -565,65 +838,126 @@ impl Engine {
                     true,
                     request.tools.unwrap_or_default(),
                 );
-                handle_seq_error!(template, request.response)
+                handle_seq_error!(template, request.response, self.metrics)
             }
-            RequestMessage::Completion { text, .. } => {
+            RequestMessage::Completion { text, .. }
+            | RequestMessage::Embedding {
+                input: Either::Left(text),
+                ..
+            } => {
                 let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
-                    request
-                        .response
-                        .send(Response::ValidationError(
-                            "Completion requests require the pipeline to have a tokenizer".into(),
-                        ))
-                        .await
-                        .expect("Expected receiver.");
+                    self.metrics
+                        .send_error(
+                            &request.response,
+                            Response::ValidationError(
+                                "Completion requests require the pipeline to have a tokenizer"
+                                    .into(),
+                            ),
+                        )
+                        .await;
                     return;
                 };
                 let prompt = tokenizer
                     .encode(text.clone(), true)
                     .map_err(anyhow::Error::msg);
                 (
-                    handle_seq_error!(prompt, request.response)
+                    handle_seq_error!(prompt, request.response, self.metrics)
                         .get_ids()
                         .to_vec(),
                     text,
                 )
             }
+            RequestMessage::Score {
+                prompt,
+                continuation,
+            } => {
+                let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
+                    self.metrics
+                        .send_error(
+                            &request.response,
+                            Response::ValidationError(
+                                "Scoring requests require the pipeline to have a tokenizer".into(),
+                            ),
+                        )
+                        .await;
+                    return;
+                };
+                let prompt_toks = tokenizer
+                    .encode(prompt.clone(), true)
+                    .map_err(anyhow::Error::msg);
+                let mut toks = handle_seq_error!(prompt_toks, request.response, self.metrics)
+                    .get_ids()
+                    .to_vec();
+                // The continuation is tokenized on its own so that its tokens are known
+                let continuation_toks = tokenizer
+                    .encode(continuation.clone(), false)
+                    .map_err(anyhow::Error::msg);
+                let continuation_toks =
+                    handle_seq_error!(continuation_toks, request.response, self.metrics)
+                        .get_ids()
+                        .to_vec();
+                if toks.is_empty() || continuation_toks.is_empty() {
+                    self.metrics
+                        .send_error(
+                            &request.response,
+                            Response::ValidationError(
+                                "Scoring requests need a non-empty prompt and continuation.".into(),
+                            ),
+                        )
+                        .await;
+                    return;
+                }
+                continuation_start = Some(toks.len());
+                toks.extend(continuation_toks);
+                (toks, format!("{prompt}{continuation}"))
+            }
             RequestMessage::ImageGeneration { prompt, .. } => (vec![u32::MAX], prompt),
-            RequestMessage::CompletionTokens(it) => {
+            RequestMessage::CompletionTokens(it)
+            | RequestMessage::Embedding {
+                input: Either::Right(it),
+                ..
+            } => {
                 let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
-                    request
-                        .response
-                        .send(Response::ValidationError(
-                            "Completion requests w/ raw tokens require the pipeline to have a tokenizer".into(),
-                        ))
-                        .await
-                        .expect("Expected receiver.");
+                    self.metrics
+                        .send_error(
+                            &request.response,
+                            Response::ValidationError(
+                                "Completion requests w/ raw tokens require the pipeline to have a tokenizer".into(),
+                            ),
+                        )
+                        .await;
                     return;
                 };
                 let prompt = tokenizer
                     .decode(&it, false)
                     .map_err(|e| anyhow::Error::msg(e.to_string()));
-                (it, handle_seq_error!(prompt, request.response))
+                (
+                    it,
+                    handle_seq_error!(prompt, request.response, self.metrics),
+                )
             }
         };
         if prompt_tokens.is_empty() {
-            request
-                .response
-                .send(Response::ValidationError(
-                    "Received an empty prompt.".into(),
-                ))
-                .await
-                .expect("Expected receiver.");
+            self.metrics
+                .send_error(
+                    &request.response,
+                    Response::ValidationError("Received an empty prompt.".into()),
+                )
+                .await;
             return;
         }
 
         if prompt_tokens.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
-            if !self.truncate_sequence {
-                request
-                    .response
-                    .send(Response::ValidationError(
-                        format!("Prompt sequence length is greater than {}, perhaps consider using `truncate_sequence`?", get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len).into(),
-                    )).await.expect("Expected receiver.");
+            // Truncating a scoring request would change what is scored
+            if !self.truncate_sequence || is_scoring {
+                self.metrics
+                    .send_error(
+                        &request.response,
+                        Response::ValidationError(
+                            format!("Prompt sequence length is greater than {}, perhaps consider using `truncate_sequence`?", get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len).into(),
+                        ),
+                    )
+                    .await;
                 return;
             } else {
                 let prompt_len = prompt_tokens.len();
@@ -642,10 +976,16 @@ impl Engine {
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
             }
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
-            request.response
-        );
+        // Embeddings and prompt logprobs need the whole prompt to be run
+        let prefill_cache = if embedding_params.is_none() && !return_prompt_logprobs {
+            handle_seq_error!(
+                self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
+                request.response,
+                self.metrics
+            )
+        } else {
+            None
+        };
 
         let topk = request
             .sampling_params
@@ -654,6 +994,8 @@ impl Engine {
             .unwrap_or(-1);
         let topp = request.sampling_params.top_p.unwrap_or(1.0);
         let minp = request.sampling_params.min_p.unwrap_or(0.0);
+        let typicalp = request.sampling_params.typical_p.unwrap_or(1.0);
+        let topa = request.sampling_params.top_a.unwrap_or(0.0);
         let num_hidden_layers = get_mut_arcmutex!(self.pipeline)
             .get_metadata()
             .num_hidden_layers;
@@ -669,12 +1011,14 @@ impl Engine {
                     // We can't use ` ` (space) as a stop token because other tokens like ` moon` start with a space.
                     if let Some(tok_trie) = tok_trie.as_ref() {
                         if tok_trie.has_extensions(tok_trie.token(*id)) {
-                            request
-                                .response
-                                .send(Response::ValidationError(
-                                    format!("Stop token {:?} is also a prefix of other tokens and cannot be used as a stop token.", tok_trie.token_str(*id)).into(),
-                                ))
-                                .await .expect("Expected receiver.");
+                            self.metrics
+                                .send_error(
+                                    &request.response,
+                                    Response::ValidationError(
+                                        format!("Stop token {:?} is also a prefix of other tokens and cannot be used as a stop token.", tok_trie.token_str(*id)).into(),
+                                    ),
+                                )
+                                .await;
                             return;
                         }
                     }
@@ -695,18 +1039,19 @@ impl Engine {
 
                 for stop_txt in s {
                     let Some(tokenizer) = &tokenizer else {
-                        request
-                            .response
-                            .send(Response::ValidationError(
-                                "Completion requests require the pipeline to have a tokenizer"
-                                    .into(),
-                            ))
-                            .await
-                            .expect("Expected receiver.");
+                        self.metrics
+                            .send_error(
+                                &request.response,
+                                Response::ValidationError(
+                                    "Completion requests require the pipeline to have a tokenizer"
+                                        .into(),
+                                ),
+                            )
+                            .await;
                         return;
                     };
                     let encoded = tokenizer.encode(stop_txt.to_string(), true);
-                    let toks = handle_seq_error!(encoded, request.response)
+                    let toks = handle_seq_error!(encoded, request.response, self.metrics)
                         .get_ids()
                         .to_vec();
 
@@ -739,6 +1084,20 @@ impl Engine {
 
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
+        let has_logits_processors = request
+            .logits_processors
+            .as_ref()
+            .is_some_and(|processors| !processors.is_empty());
+        if let Err(error) = request
+            .sampling_params
+            .validate_sampler_order(has_logits_processors)
+        {
+            self.metrics
+                .send_error(&request.response, Response::ValidationError(error.into()))
+                .await;
+            return;
+        }
+
         let sampler = Sampler::new(
             Some(request.sampling_params.temperature.unwrap_or(1.0)),
             request.sampling_params.top_n_logprobs,
@@ -749,33 +1108,86 @@ impl Engine {
             topk,
             topp,
             minp,
+            typicalp,
+            topa,
+            request.sampling_params.repetition_penalty,
+            request.sampling_params.repetition_penalty_last_n,
+            request.sampling_params.mirostat,
+            request.sampling_params.xtc,
+            request.sampling_params.sampler_order,
             request.logits_processors.unwrap_or_default(),
         );
-        let sampler = handle_seq_error!(sampler, request.response);
+        let sampler = handle_seq_error!(sampler, request.response, self.metrics);
 
         if request.sampling_params.n_choices == 0 {
-            request
-                .response
-                .send(Response::ValidationError(
-                    "Number of choices must be greater than 0.".into(),
-                ))
-                .await
-                .expect("Expected receiver.");
+            self.metrics
+                .send_error(
+                    &request.response,
+                    Response::ValidationError("Number of choices must be greater than 0.".into()),
+                )
+                .await;
             return;
         }
 
+        if request
+            .sampling_params
+            .repetition_penalty
+            .is_some_and(|penalty| penalty <= 0.0)
+        {
+            self.metrics
+                .send_error(
+                    &request.response,
+                    Response::ValidationError("Repetition penalty must be greater than 0.".into()),
+                )
+                .await;
+            return;
+        }
+
+        // Beam search starts with one sequence, the other beams are forked from it
+        let n_seqs = if let Some(params) = request.sampling_params.beam_search {
+            let error = {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                if params.beam_width == 0 {
+                    Some("Beam width must be greater than 0.")
+                } else if request.sampling_params.n_choices > params.beam_width {
+                    Some("Number of choices must not be greater than the beam width.")
+                } else if request.is_streaming {
+                    Some("Beam search does not support streaming.")
+                } else if !matches!(request.constraint, Constraint::None) {
+                    Some("Beam search does not support grammars.")
+                } else if pipeline.get_metadata().cache_config.is_some() {
+                    Some("Beam search does not support PagedAttention.")
+                } else if pipeline.speculative_stats().is_some() {
+                    Some("Beam search does not support speculative decoding.")
+                } else {
+                    None
+                }
+            };
+            if let Some(error) = error {
+                self.metrics
+                    .send_error(&request.response, Response::ValidationError(error.into()))
+                    .await;
+                return;
+            }
+            1
+        } else {
+            request.sampling_params.n_choices
+        };
+
+        self.metrics
+            .update(|metrics| metrics.prompt_tokens += prompt_tokens.len() as u64);
+
         // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
+        for response_index in 0..n_seqs {
             let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
-                    request
-                        .response
-                        .send(Response::ValidationError(
-                            format!("Invalid grammar. {}", err).into(),
-                        ))
-                        .await
-                        .expect("Expected receiver.");
+                    self.metrics
+                        .send_error(
+                            &request.response,
+                            Response::ValidationError(format!("Invalid grammar. {}", err).into()),
+                        )
+                        .await;
                     return;
                 }
             };
@@ -794,10 +1206,17 @@ impl Engine {
                 prompt_tokens.clone(),
                 prompt_text.clone(),
                 self.id,
+                request.id,
                 now.as_millis(),
                 num_hidden_layers,
                 request.response.clone(),
                 sampler.clone(),
+                // Each choice gets its own stream, so the choices of a seeded request differ
+                request
+                    .sampling_params
+                    .seed
+                    .map(|seed| seed.wrapping_add(response_index as u64)),
+                request.sampling_params.beam_search,
                 stop_toks.clone(),
                 stop_strings.clone(),
                 request.sampling_params.max_len,
@@ -821,7 +1240,13 @@ impl Engine {
                 image_generation_format,
                 seq_step_type,
                 diffusion_params.clone(),
-            );
+                embedding_params,
+                return_prompt_logprobs,
+                continuation_start,
+                request.priority,
+                request.tenant.clone(),
+            )
+            .with_metrics(self.metrics.clone());
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
                     prefill_cache.normal,
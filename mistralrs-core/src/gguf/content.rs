@@ -3,6 +3,7 @@ use std::{collections::HashMap, fs};
 use anyhow::Context;
 use candle_core::{
     quantized::{
+        ggml_file::qtensor_from_ggml,
         gguf_file::{self, Value},
         QTensor,
     },
@@ -37,6 +38,20 @@ fn parse_gguf_value(value: &Value) -> String {
     }
 }
 
+/// Split a quantized tensor along its first dimension. Each expert is a contiguous run of blocks
+/// because the quantization blocks only span the last dimension.
+fn split_experts(stacked: &QTensor, n_expert: usize, device: &Device) -> Result<Vec<QTensor>> {
+    let (n, rows, cols) = stacked.shape().dims3()?;
+    if n != n_expert {
+        candle_core::bail!("Expected {n_expert} experts, the tensor has {n}");
+    }
+    let data = stacked.data()?;
+    let expert_len = data.len() / n_expert;
+    data.chunks_exact(expert_len)
+        .map(|expert| qtensor_from_ggml(stacked.dtype(), expert, vec![rows, cols], device))
+        .collect()
+}
+
 // Internal invariant: contents and readers must be paired.
 /// This abstracts the files for a GGUF model and enables multiple files to be used.
 pub struct Content<'a, R: std::io::Seek + std::io::Read> {
@@ -123,6 +138,18 @@ impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
         candle_core::bail!("Cannot find tensor info for {name}")
     }
 
+    /// Retrieve a tensor of stacked experts `(n_expert, rows, cols)` as one tensor per expert.
+    /// The experts are split in their quantized form, so they keep the quantization of the file.
+    pub fn expert_tensors(
+        &mut self,
+        name: &str,
+        n_expert: usize,
+        device: &Device,
+    ) -> Result<Vec<QTensor>> {
+        let stacked = self.tensor(name, &Device::Cpu)?;
+        split_experts(&stacked, n_expert, device)
+    }
+
     /// Check for a tensor, searching through each content.
     pub fn has_tensor(&mut self, name: &str) -> bool {
         for ct in self.contents.iter() {
@@ -188,3 +215,35 @@ impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
         &self.all_metadata
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{
+        quantized::{GgmlDType, QTensor},
+        Device, Result, Tensor,
+    };
+
+    use super::split_experts;
+
+    #[test]
+    fn split_experts_keeps_quantized_blocks() -> Result<()> {
+        let dev = Device::Cpu;
+        let stacked = Tensor::randn(0f32, 1., (4, 8, 64), &dev)?;
+        let qstacked = QTensor::quantize(&stacked, GgmlDType::Q8_0)?;
+        let expected = qstacked.dequantize(&dev)?;
+
+        let experts = split_experts(&qstacked, 4, &dev)?;
+        assert_eq!(experts.len(), 4);
+        for (i, expert) in experts.iter().enumerate() {
+            assert_eq!(expert.dtype(), GgmlDType::Q8_0);
+            assert_eq!(expert.shape().dims(), &[8, 64]);
+            let diff = (expert.dequantize(&dev)? - expected.get(i)?)?
+                .abs()?
+                .max_all()?
+                .to_scalar::<f32>()?;
+            assert_eq!(diff, 0.);
+        }
+        assert!(split_experts(&qstacked, 2, &dev).is_err());
+        Ok(())
+    }
+}
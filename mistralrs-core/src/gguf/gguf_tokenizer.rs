@@ -10,12 +10,17 @@ use tokenizers::{
     },
     models::{bpe::BpeBuilder, unigram::Unigram},
     normalizers::{self, Prepend, Replace},
-    pre_tokenizers,
+    pre_tokenizers::{
+        self,
+        sequence::Sequence,
+        split::{Split, SplitPattern},
+    },
     processors::{
         self,
         template::{self, TemplateProcessing},
     },
-    AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, Tokenizer,
+    AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, PreTokenizerWrapper,
+    SplitDelimiterBehavior, Tokenizer,
 };
 use tracing::info;
 
@@ -33,7 +38,9 @@ pub(crate) struct GgufTokenizerConversion {
 
 struct PropsGGUF {
     model: String,
+    pre: Option<String>,
     tokens: Vec<String>,
+    token_type: Option<Vec<i32>>,
     added_tokens: Option<Vec<String>>,
     scores: Option<Vec<f32>>,
     merges: Option<Vec<String>>,
@@ -41,6 +48,7 @@ struct PropsGGUF {
     eos: u32,
     bos: u32,
     add_bos_token: Option<bool>,
+    add_space_prefix: Option<bool>,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -52,7 +60,9 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
 
         let props = Self {
             model: c.get_value("model")?,
+            pre: c.get_value("pre").ok(),
             tokens: c.get_value("tokens")?,
+            token_type: c.get_value("token_type").ok(),
             added_tokens: c.get_value("added_tokens").ok(),
             scores: c.get_value("scores").ok(),
             merges: c.get_value("merges").ok(),
@@ -60,6 +70,7 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
             eos: c.get_value("eos_token_id")?,
             bos: c.get_value("bos_token_id")?,
             add_bos_token: c.get_value("add_bos_token").ok(),
+            add_space_prefix: c.get_value("add_space_prefix").ok(),
         };
 
         Ok(props)
@@ -81,13 +92,14 @@ pub fn convert_gguf_to_hf_tokenizer<R: std::io::Seek + std::io::Read>(
     };
     let props = PropsGGUF::try_from(metadata)?;
 
-    let (tokenizer, kind, special_tokens) = match props.model.as_str() {
+    let (mut tokenizer, kind, special_tokens) = match props.model.as_str() {
         "llama" | "replit" => unigram_tokenizer(&props)?,
         "gpt2" => bpe_tokenizer(&props)?,
         other => {
             anyhow::bail!("Tokenizer model `{other}` not supported.");
         }
     };
+    add_typed_tokens(&props, &mut tokenizer);
 
     info!(
         "GGUF tokenizer model is `{model}`, kind: `{kind:?}`, num tokens: {}, num added tokens: {}, num merges: {}, num scores: {}",
@@ -149,6 +161,30 @@ fn add_special_tokens(
     }
 }
 
+// Token types of `tokenizer.ggml.token_type`:
+// https://github.com/ggerganov/llama.cpp/blob/master/gguf-py/gguf/constants.py
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+/// Add the control tokens as special tokens and the user defined tokens as added tokens, so that
+/// chat template markers such as `<|im_start|>` or `<start_of_turn>` are not split by the model.
+fn add_typed_tokens(p: &PropsGGUF, tokenizer: &mut Tokenizer) {
+    let Some(token_type) = p.token_type.as_ref() else {
+        return;
+    };
+    let mut special = Vec::new();
+    let mut added = Vec::new();
+    for (token, ty) in p.tokens.iter().zip(token_type) {
+        match *ty {
+            TOKEN_TYPE_CONTROL => special.push(AddedToken::from(token.clone(), true)),
+            TOKEN_TYPE_USER_DEFINED => added.push(AddedToken::from(token.clone(), false)),
+            _ => (),
+        }
+    }
+    tokenizer.add_special_tokens(&special);
+    tokenizer.add_tokens(&added);
+}
+
 fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokensCollection)> {
     let PropsGGUF { unk, eos, bos, .. } = *p;
     // Unigram (SentencePiece) default UNK is 0
@@ -172,17 +208,30 @@ fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTo
 
     // Decoder + Normalizer config reference:
     // https://github.com/EricLBuehler/mistral.rs/pull/389#discussion_r1630620763
-    let decoder = Decoder::Sequence(vec![
-        Decoder::Replace("▁", " "),
-        Decoder::ByteFallback,
-        Decoder::Fuse,
-        Decoder::Strip(' ', 1, 0),
-    ]);
-
-    let normalizer = Normalizer::Sequence(vec![
-        Normalizer::Prepend("▁"),
-        Normalizer::Replace(" ", "▁"),
-    ]);
+    // Gemma does not add a space prefix, so there is nothing to strip when decoding.
+    let (decoder, normalizer) = if p.add_space_prefix.unwrap_or(true) {
+        (
+            Decoder::Sequence(vec![
+                Decoder::Replace("▁", " "),
+                Decoder::ByteFallback,
+                Decoder::Fuse,
+                Decoder::Strip(' ', 1, 0),
+            ]),
+            Normalizer::Sequence(vec![
+                Normalizer::Prepend("▁"),
+                Normalizer::Replace(" ", "▁"),
+            ]),
+        )
+    } else {
+        (
+            Decoder::Sequence(vec![
+                Decoder::Replace("▁", " "),
+                Decoder::ByteFallback,
+                Decoder::Fuse,
+            ]),
+            Normalizer::Replace(" ", "▁"),
+        )
+    };
 
     let mut tokenizer: Tokenizer = TokenizerX::try_builder()
         .with_model(model)
@@ -238,9 +287,27 @@ fn bpe_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokens
         .with_model(bpe)
         .with_decoder(Decoder::ByteLevel(true, true, true))
         .build()?;
-    tokenizer.with_pre_tokenizer(pre_tokenizers::byte_level::ByteLevel::new(
-        false, true, true,
-    ));
+    match bpe_pre_tokenizer_regex(p.pre.as_deref()) {
+        Some(regex) => {
+            let split = Split::new(
+                SplitPattern::Regex(regex.to_string()),
+                SplitDelimiterBehavior::Isolated,
+                false,
+            )
+            .map_err(anyhow::Error::msg)?;
+            tokenizer.with_pre_tokenizer(Sequence::new(vec![
+                PreTokenizerWrapper::Split(split),
+                PreTokenizerWrapper::ByteLevel(pre_tokenizers::byte_level::ByteLevel::new(
+                    false, false, false,
+                )),
+            ]));
+        }
+        None => {
+            tokenizer.with_pre_tokenizer(pre_tokenizers::byte_level::ByteLevel::new(
+                false, true, true,
+            ));
+        }
+    }
     if add_bos_token.is_some_and(|x| x) {
         let mut special_toks = HashMap::new();
         special_toks.insert(
@@ -271,6 +338,16 @@ fn bpe_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokens
     Ok((tokenizer, TokenizerKind::Bpe, special_tokens))
 }
 
+/// The split regex of the BPE pre-tokenizers (`tokenizer.ggml.pre`) which do not use the GPT-2 one.
+fn bpe_pre_tokenizer_regex(pre: Option<&str>) -> Option<&'static str> {
+    match pre? {
+        "qwen2" => Some(
+            r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+        ),
+        _ => None,
+    }
+}
+
 // This is a workaround to have a better builder API.
 // Upstream `TokenizerBuilder` is difficult to work with:
 // https://github.com/huggingface/tokenizers/issues/1549
@@ -26,6 +26,9 @@ pub enum GGUFArchitecture {
     Phi2,
     Phi3,
     Starcoder2,
+    Qwen2,
+    Gemma,
+    Gemma2,
 }
 
 // Wraps from_str() for some convenience:
@@ -0,0 +1,228 @@
+//! Quantization of the KV cache. Keys and values are quantized when they are written to the cache
+//! and dequantized to the activation dtype when they are read, trading a small amount of accuracy
+//! for a KV cache that takes 8 bits per element.
+//!
+//! Quantized caches are stored as `u8` tensors with the same shape as the keys and values, except
+//! for [`KvCacheDtype::Q8`] which appends the scale of each head to its last dimension.
+
+use std::{f64::consts::LN_2, fmt::Display, str::FromStr};
+
+use candle_core::{DType, Result, Tensor, D};
+use serde::Deserialize;
+
+/// Largest finite 8-bit float with 4 exponent and 3 mantissa bits.
+const F8E4M3_MAX: f32 = 448.;
+
+#[derive(Clone, Copy, Default, Debug, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+/// DType of the KV cache.
+pub enum KvCacheDtype {
+    /// Store the keys and values in the dtype of the activations.
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    /// 8-bit integers with a scale for each head of each token.
+    #[serde(rename = "q8")]
+    Q8,
+    /// 8-bit floats with 4 exponent and 3 mantissa bits, emulated with bytes so that they are
+    /// supported on every device. Values are clamped to +/-448.
+    #[serde(rename = "f8e4m3")]
+    F8E4M3,
+}
+
+impl Display for KvCacheDtype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Q8 => write!(f, "q8"),
+            Self::F8E4M3 => write!(f, "f8e4m3"),
+        }
+    }
+}
+
+impl FromStr for KvCacheDtype {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "q8" => Ok(Self::Q8),
+            "f8e4m3" => Ok(Self::F8E4M3),
+            other => Err(format!("KV cache DType `{other}` is not supported.")),
+        }
+    }
+}
+
+impl KvCacheDtype {
+    pub(crate) fn is_quantized(&self) -> bool {
+        !matches!(self, Self::Auto)
+    }
+
+    /// Size of the last dimension of the cache for keys or values with `head_dim` elements.
+    pub(crate) fn cache_head_dim(&self, head_dim: usize) -> usize {
+        match self {
+            Self::Auto | Self::F8E4M3 => head_dim,
+            Self::Q8 => head_dim + 2,
+        }
+    }
+
+    /// DType of the cache for activations of `dtype`.
+    pub(crate) fn cache_dtype(&self, dtype: DType) -> DType {
+        match self {
+            Self::Auto => dtype,
+            Self::Q8 | Self::F8E4M3 => DType::U8,
+        }
+    }
+
+    /// Quantize keys or values of shape `[..., head_dim]` for storage in the cache.
+    pub(crate) fn quantize(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Auto => Ok(xs.clone()),
+            Self::Q8 => quantize_q8(xs),
+            Self::F8E4M3 => quantize_f8e4m3(xs),
+        }
+    }
+
+    /// Dequantize keys or values read from the cache into `[..., head_dim]` of `dtype`.
+    pub(crate) fn dequantize(&self, xs: &Tensor, dtype: DType) -> Result<Tensor> {
+        match self {
+            Self::Auto => Ok(xs.clone()),
+            Self::Q8 => dequantize_q8(xs)?.to_dtype(dtype),
+            Self::F8E4M3 => dequantize_f8e4m3(xs)?.to_dtype(dtype),
+        }
+    }
+
+    /// The dtype a cache was quantized with, given the size of the heads it stores.
+    pub(crate) fn of_cache(cache: &Tensor, head_dim: usize) -> Result<Self> {
+        if cache.dtype() != DType::U8 {
+            Ok(Self::Auto)
+        } else if cache.dim(D::Minus1)? == head_dim {
+            Ok(Self::F8E4M3)
+        } else {
+            Ok(Self::Q8)
+        }
+    }
+}
+
+fn exp2(xs: &Tensor) -> Result<Tensor> {
+    (xs * LN_2)?.exp()
+}
+
+/// The scale of each row is stored in 2 bytes as a power of two and 8 bits of mantissa. It is
+/// rounded up before quantizing so that the stored scale is exact.
+fn quantize_q8(xs: &Tensor) -> Result<Tensor> {
+    let xs = xs.to_dtype(DType::F32)?;
+    let scale = (xs.abs()?.max_keepdim(D::Minus1)? / 127.)?.maximum(1e-30f32)?;
+    let exponent = (scale.log()? / LN_2)?.floor()?;
+    let mantissa = (((scale / exp2(&exponent)?)? - 1.)? * 256.)?
+        .ceil()?
+        .clamp(0f32, 255f32)?;
+    let scale = exp2(&exponent)?.mul(&((&mantissa / 256.)? + 1.)?)?;
+    let quantized = (xs.broadcast_div(&scale)?.round()?.clamp(-127f32, 127f32)? + 128.)?;
+    Tensor::cat(&[quantized, (exponent + 128.)?, mantissa], D::Minus1)?.to_dtype(DType::U8)
+}
+
+fn dequantize_q8(xs: &Tensor) -> Result<Tensor> {
+    let head_dim = xs.dim(D::Minus1)? - 2;
+    let xs = xs.to_dtype(DType::F32)?;
+    let exponent = (xs.narrow(D::Minus1, head_dim, 1)? - 128.)?;
+    let mantissa = xs.narrow(D::Minus1, head_dim + 1, 1)?;
+    let scale = exp2(&exponent)?.mul(&((mantissa / 256.)? + 1.)?)?;
+    (xs.narrow(D::Minus1, 0, head_dim)? - 128.)?.broadcast_mul(&scale)
+}
+
+/// The sign is the high bit, followed by 4 exponent bits with a bias of 7 and 3 mantissa bits.
+/// Exponent 0 holds the subnormals.
+fn quantize_f8e4m3(xs: &Tensor) -> Result<Tensor> {
+    let xs = xs.to_dtype(DType::F32)?;
+    let negative = xs.lt(0f32)?.to_dtype(DType::F32)?;
+    let abs = xs.abs()?.minimum(F8E4M3_MAX)?;
+    // Subnormals share the exponent of the smallest normal
+    let exponent = (abs.log()? / LN_2)?.floor()?.maximum(-6f32)?;
+    // Mantissa with the leading bit, in [8, 16] for normals. 16 carries into the exponent.
+    let mantissa = (abs.mul(&exp2(&exponent.neg()?)?)? * 8.)?.round()?;
+    let code = (((exponent + 6.)? * 8.)? + mantissa)?;
+    (code + (negative * 128.)?)?.to_dtype(DType::U8)
+}
+
+fn dequantize_f8e4m3(xs: &Tensor) -> Result<Tensor> {
+    let xs = xs.to_dtype(DType::F32)?;
+    let negative = xs.ge(128f32)?.to_dtype(DType::F32)?;
+    let code = (xs - (&negative * 128.)?)?;
+    let exponent = (&code / 8.)?.floor()?;
+    let mantissa = (code - (&exponent * 8.)?)?;
+    let is_normal = exponent.ge(1f32)?.to_dtype(DType::F32)?;
+    let value =
+        ((mantissa + (is_normal * 8.)?)? / 8.)?.mul(&exp2(&(exponent.maximum(1f32)? - 7.)?)?)?;
+    value.mul(&negative.affine(-2., 1.)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::KvCacheDtype;
+
+    fn assert_close(xs: &Tensor, expected: &[f32]) -> candle_core::Result<()> {
+        for (x, expected) in xs.to_vec1::<f32>()?.into_iter().zip(expected) {
+            assert!(
+                (x - expected).abs() <= expected.abs() * 1e-6,
+                "{x} != {expected}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_q8_round_trip() -> candle_core::Result<()> {
+        let xs = (Tensor::randn(0f32, 1., (2, 4, 3, 8), &Device::Cpu)? * 10.)?;
+        let quantized = KvCacheDtype::Q8.quantize(&xs)?;
+        assert_eq!(quantized.dims(), &[2, 4, 3, 10]);
+        assert_eq!(quantized.dtype(), DType::U8);
+        assert_eq!(KvCacheDtype::of_cache(&quantized, 8)?, KvCacheDtype::Q8);
+
+        let dequantized = KvCacheDtype::Q8.dequantize(&quantized, DType::F32)?;
+        // The error is at most half a step of the scale of each row
+        let max_err = (&dequantized - &xs)?.abs()?.max_keepdim(3)?;
+        let step = (xs.abs()?.max_keepdim(3)? / 127.)?;
+        let ratio = (max_err / step)?.max_all()?.to_scalar::<f32>()?;
+        assert!(ratio <= 0.51, "ratio {ratio}");
+
+        let zeros = Tensor::zeros((1, 8), DType::F32, &Device::Cpu)?;
+        let dequantized =
+            KvCacheDtype::Q8.dequantize(&KvCacheDtype::Q8.quantize(&zeros)?, DType::F32)?;
+        assert_eq!(dequantized.abs()?.max_all()?.to_scalar::<f32>()?, 0.);
+        Ok(())
+    }
+
+    #[test]
+    fn test_f8e4m3_exact_values() -> candle_core::Result<()> {
+        let values = [
+            0f32,
+            1.,
+            -0.5,
+            1.125,
+            -3.75,
+            448.,
+            // Smallest normal and subnormal
+            2f32.powi(-6),
+            2f32.powi(-9),
+            -7. * 2f32.powi(-9),
+        ];
+        let xs = Tensor::new(&values, &Device::Cpu)?;
+        let quantized = KvCacheDtype::F8E4M3.quantize(&xs)?;
+        assert_eq!(
+            quantized.to_vec1::<u8>()?,
+            [0, 56, 176, 57, 199, 126, 8, 1, 135]
+        );
+        let dequantized = KvCacheDtype::F8E4M3.dequantize(&quantized, DType::F32)?;
+        assert_close(&dequantized, &values)
+    }
+
+    #[test]
+    fn test_f8e4m3_rounding() -> candle_core::Result<()> {
+        let xs = Tensor::new(&[1.06f32, 1.99, 1000., -1000., 1e-5], &Device::Cpu)?;
+        let dequantized =
+            KvCacheDtype::F8E4M3.dequantize(&KvCacheDtype::F8E4M3.quantize(&xs)?, DType::F32)?;
+        assert_close(&dequantized, &[1.0, 2.0, 448., -448., 0.])
+    }
+}
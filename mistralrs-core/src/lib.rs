@@ -4,6 +4,7 @@ use cublaslt::setup_cublas_lt_wrapper;
 use engine::Engine;
 pub use engine::{EngineInstruction, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP};
 pub use lora::Ordering;
+use metrics::MetricsCollector;
 pub use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
 #[cfg(feature = "pyo3_macros")]
@@ -27,29 +28,32 @@ mod cuda;
 mod device_map;
 mod engine;
 mod lora;
+mod metrics;
 mod model_loader;
 mod ops;
-pub use model_loader::{get_model_dtype, get_tgt_non_granular_index, LoaderBuilder};
+pub use model_loader::{
+    get_kv_cache_dtype, get_model_dtype, get_tgt_non_granular_index, LoaderBuilder,
+};
 
 mod model_selected;
 pub use model_selected::ModelSelected;
-pub use toml_selector::get_toml_selected_model_dtype;
+pub use toml_selector::{
+    get_toml_selected_kv_cache_dtype, get_toml_selected_model_dtype, TomlLoaderArgs, TomlSelector,
+};
 
 mod amoe;
+mod attention;
+mod beam_search;
 mod cublaslt;
-#[cfg(not(all(feature = "cuda", target_family = "unix")))]
-mod dummy_paged_attention;
+mod diffusion_models;
+mod embedding;
 mod gguf;
+mod kv_quant;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
 mod models;
-#[cfg(all(feature = "cuda", target_family = "unix"))]
 mod paged_attention;
-#[cfg(not(all(feature = "cuda", target_family = "unix")))]
-use dummy_paged_attention as paged_attention;
-mod attention;
-mod diffusion_models;
 mod pipeline;
 mod prefix_cacher;
 mod request;
@@ -64,36 +68,41 @@ mod utils;
 mod vision_models;
 mod xlora_models;
 
+pub use aici::json_schema::json_schema_to_yacc;
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
 pub use device_map::{DeviceLayerMapMetadata, DeviceMapMetadata, LayerDeviceMapper};
+pub use embedding::EmbeddingPooling;
 pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
+pub use kv_quant::KvCacheDtype;
+pub use metrics::{render_prometheus, EngineMetrics, ErrorCounts, Histogram};
 pub use mistralrs_quant::IsqType;
 pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
 pub use pipeline::{
-    chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline,
+    chat_template::ChatTemplate, parse_isq_value, AdaptiveGamma, AnyMoeLoader, AnyMoePipeline,
     DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder, DiffusionLoaderType,
     DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader,
     GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, Idefics2Loader, IsqOrganization,
-    LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MistralLoader,
+    LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MambaLoader, MistralLoader,
     MixtralLoader, ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
     NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
-    SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource, VisionLoader,
-    VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
+    SpeculativeLoader, SpeculativeMethod, SpeculativePipeline, SpeculativeStats, Starcoder2Loader,
+    TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
 };
 pub use request::{
-    Constraint, ImageGenerationResponseFormat, MessageContent, NormalRequest, Request,
-    RequestMessage,
+    Constraint, DetokenizationRequest, ImageGenerationResponseFormat, MessageContent,
+    NormalRequest, Request, RequestMessage, RequestPriority, Tokenization, TokenizationRequest,
 };
 pub use response::*;
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    BeamSearchParams, CustomLogitsProcessor, DrySamplingParams, MirostatParams, SamplerStage,
+    SamplingParams, StopTokens, TopLogprob, XtcParams,
 };
 pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
 use serde::Serialize;
 use tokio::runtime::Runtime;
-use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub use tools::{
-    CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
+    CalledFunction, Function, Tool, ToolCallDelta, ToolCallFormat, ToolCallResponse, ToolCallType,
+    ToolChoice, ToolType,
 };
 pub use topology::{LayerTopology, Topology};
 pub use utils::debug::initialize_logging;
@@ -131,6 +140,8 @@ struct RebootState {
     prefix_cache_n: usize,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    tool_call_format: Option<ToolCallFormat>,
+    metrics: Arc<MetricsCollector>,
 }
 
 #[derive(Debug)]
@@ -168,6 +179,8 @@ pub struct MistralRsBuilder {
     disable_eos_stop: Option<bool>,
     gemm_full_precision_f16: Option<bool>,
     throughput_logging_enabled: Option<()>,
+    kv_cache_dtype: Option<KvCacheDtype>,
+    tool_call_format: Option<ToolCallFormat>,
 }
 
 impl MistralRsBuilder {
@@ -183,6 +196,8 @@ impl MistralRsBuilder {
             disable_eos_stop: None,
             gemm_full_precision_f16: None,
             throughput_logging_enabled: None,
+            kv_cache_dtype: None,
+            tool_call_format: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -221,6 +236,18 @@ impl MistralRsBuilder {
         self.throughput_logging_enabled = Some(());
         self
     }
+    /// Quantize the KV cache of this model if it does not use PagedAttention, whose KV cache dtype
+    /// is set with [`PagedAttentionConfig::with_kv_cache_dtype`].
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KvCacheDtype) -> Self {
+        self.kv_cache_dtype = Some(kv_cache_dtype);
+        self
+    }
+    /// Set the format used to parse tool calls. By default, it is detected from the chat template
+    /// and the tokenizer of the model.
+    pub fn with_tool_call_format(mut self, tool_call_format: ToolCallFormat) -> Self {
+        self.tool_call_format = Some(tool_call_format);
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -286,6 +313,8 @@ impl MistralRs {
             disable_eos_stop,
             gemm_full_precision_f16,
             throughput_logging_enabled,
+            kv_cache_dtype,
+            tool_call_format,
         } = config;
 
         let category = pipeline.try_lock().unwrap().category();
@@ -298,6 +327,10 @@ impl MistralRs {
             set_gemm_reduced_precision_f16();
         }
         setup_cublas_lt_wrapper();
+        pipeline
+            .try_lock()
+            .unwrap()
+            .set_kv_cache_dtype(kv_cache_dtype.unwrap_or_default());
 
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
@@ -315,17 +348,22 @@ impl MistralRs {
             prefix_cache_n,
             disable_eos_stop,
             throughput_logging_enabled,
+            tool_call_format,
+            metrics: Arc::new(MetricsCollector::default()),
         };
+        let metrics = reboot_state.metrics.clone();
 
         let (tx, rx) = channel(10_000);
 
         let sender = RwLock::new(tx);
         let id = pipeline.try_lock().unwrap().name();
+        let engine_id = ENGINE_ID.fetch_add(1, atomic::Ordering::SeqCst);
 
         let engine_handler = thread::spawn(move || {
             let rt = Runtime::new().unwrap();
             rt.block_on(async move {
                 let mut engine = Engine::new(
+                    engine_id,
                     rx,
                     pipeline,
                     method,
@@ -335,13 +373,13 @@ impl MistralRs {
                     prefix_cache_n,
                     disable_eos_stop,
                     throughput_logging_enabled,
+                    tool_call_format,
+                    metrics,
                 );
                 engine.run().await;
             });
         });
 
-        let engine_id = ENGINE_ID.fetch_add(1, atomic::Ordering::SeqCst);
-
         Arc::new(Self {
             engine_id,
             sender,
@@ -376,11 +414,13 @@ impl MistralRs {
             tracing::info!("Engine already running, returning ok");
             Ok(())
         } else {
+            let engine_id = self.engine_id;
             // critical section. A panic here could lead to poisoned locks
             let new_engine_handler = thread::spawn(move || {
                 let rt = Runtime::new().unwrap();
                 rt.block_on(async move {
                     let mut engine = Engine::new(
+                        engine_id,
                         rx,
                         reboot_state.pipeline.clone(),
                         reboot_state.method,
@@ -390,6 +430,8 @@ impl MistralRs {
                         reboot_state.prefix_cache_n,
                         reboot_state.disable_eos_stop,
                         reboot_state.throughput_logging_enabled,
+                        reboot_state.tool_call_format,
+                        reboot_state.metrics.clone(),
                     );
                     engine.run().await;
                 });
@@ -434,6 +476,11 @@ impl MistralRs {
         self.category
     }
 
+    /// The current metrics of the engine, such as queue depth, token counts and latencies.
+    pub fn metrics(&self) -> EngineMetrics {
+        self.reboot_state.metrics.snapshot()
+    }
+
     pub fn next_request_id(&self) -> usize {
         let l = self.next_request_id.lock().unwrap();
         let last = &mut *l.borrow_mut();
@@ -442,6 +489,16 @@ impl MistralRs {
         last_v
     }
 
+    /// Cancel the request with this ID, which was sent with `Request::Normal`. Its sequences
+    /// finish with a `canceled` stop reason and the text generated so far, and its final response
+    /// is sent as usual.
+    pub fn cancel_request(&self, id: usize) -> Result<(), MistralRsError> {
+        if let Err(e) = self.get_sender()?.try_send(Request::Cancel { id }) {
+            tracing::warn!("Could not cancel request {id}: {e}");
+        }
+        Ok(())
+    }
+
     pub fn maybe_log_request(this: Arc<Self>, repr: String) {
         if let Some(file) = &this.log {
             let mut f = OpenOptions::new()
@@ -482,3 +539,188 @@ impl MistralRs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        any::Any,
+        num::NonZeroUsize,
+        sync::Arc,
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    };
+
+    use candle_core::{DType, Device, Tensor};
+    use mistralrs_quant::IsqType;
+    use rand_isaac::Isaac64Rng;
+    use tokenizers::Tokenizer;
+    use tokio::{runtime::Runtime, sync::mpsc::channel};
+
+    use crate::{
+        engine::Engine,
+        metrics::MetricsCollector,
+        pipeline::{
+            chat_template::ChatTemplate, AdapterActivationMixin, AnyMoePipelineMixin, Cache,
+            CacheManagerMixin, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
+            MetadataMixin, ModelCategory, PreProcessingMixin,
+        },
+        prefix_cacher::PrefixCacheManager,
+        sequence::Sequence,
+        DefaultSchedulerMethod, MistralRsBuilder, ModelKind, Pipeline, SchedulerConfig, ENGINE_ID,
+    };
+
+    /// A pipeline which is never asked to run a model: the engines below get no requests.
+    struct DummyPipeline {
+        cache: Cache,
+        metadata: Arc<GeneralMetadata>,
+    }
+
+    impl DummyPipeline {
+        fn new() -> Arc<tokio::sync::Mutex<dyn Pipeline>> {
+            Arc::new(tokio::sync::Mutex::new(Self {
+                cache: Cache::new(0, false),
+                metadata: Arc::new(GeneralMetadata {
+                    max_seq_len: 16,
+                    tok_trie: None,
+                    has_no_kv_cache: false,
+                    is_recurrent: false,
+                    num_hidden_layers: 0,
+                    eos_tok: vec![],
+                    kind: ModelKind::Normal,
+                    is_xlora: false,
+                    activation_dtype: DType::F32,
+                    sliding_window: None,
+                    cache_config: None,
+                    cache_engine: None,
+                    prompt_batchsize: None,
+                }),
+            }))
+        }
+    }
+
+    impl PreProcessingMixin for DummyPipeline {
+        fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+            None
+        }
+        fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+            None
+        }
+    }
+
+    impl IsqPipelineMixin for DummyPipeline {
+        fn re_isq_model(&mut self, _dtype: IsqType) -> anyhow::Result<()> {
+            unreachable!()
+        }
+    }
+
+    impl CacheManagerMixin for DummyPipeline {
+        fn clone_in_cache(&self, _seqs: &mut [&mut Sequence], _modify_draft_cache: bool) {}
+        fn clone_out_cache(&self, _seqs: &mut [&mut Sequence], _modify_draft_cache: bool) {}
+        fn set_none_cache(&self, _reset_non_granular: bool, _modify_draft_cache: bool) {}
+        fn cache(&self) -> &Cache {
+            &self.cache
+        }
+    }
+
+    impl AdapterActivationMixin for DummyPipeline {
+        fn activate_adapters(&mut self, _adapters: Vec<String>) -> anyhow::Result<usize> {
+            unreachable!()
+        }
+    }
+
+    impl MetadataMixin for DummyPipeline {
+        fn device(&self) -> Device {
+            Device::Cpu
+        }
+        fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+            None
+        }
+        fn name(&self) -> String {
+            "dummy".to_string()
+        }
+        fn reset_non_granular_state(&self) {}
+        fn get_metadata(&self) -> Arc<GeneralMetadata> {
+            self.metadata.clone()
+        }
+    }
+
+    impl AnyMoePipelineMixin for DummyPipeline {}
+
+    #[async_trait::async_trait]
+    impl Pipeline for DummyPipeline {
+        fn forward_inputs(
+            &mut self,
+            _inputs: Box<dyn Any>,
+        ) -> candle_core::Result<ForwardInputsResult> {
+            unreachable!()
+        }
+        async fn sample_causal_gen(
+            &self,
+            _seqs: &mut [&mut Sequence],
+            _logits: Vec<Tensor>,
+            _prefix_cacher: &mut PrefixCacheManager,
+            _disable_eos_stop: bool,
+            _rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        ) -> candle_core::Result<()> {
+            unreachable!()
+        }
+        fn category(&self) -> ModelCategory {
+            ModelCategory::Text
+        }
+    }
+
+    fn scheduler_config() -> SchedulerConfig {
+        SchedulerConfig::DefaultScheduler {
+            method: DefaultSchedulerMethod::Fixed(NonZeroUsize::new(1).unwrap()),
+        }
+    }
+
+    fn wait_finished(handle: &JoinHandle<()>) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if handle.is_finished() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_engine_stops_when_sender_is_dropped() {
+        let (tx, rx) = channel(1);
+        let engine_id = ENGINE_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let engine_handler = thread::spawn(move || {
+            Runtime::new().unwrap().block_on(async move {
+                let mut engine = Engine::new(
+                    engine_id,
+                    rx,
+                    DummyPipeline::new(),
+                    scheduler_config(),
+                    false,
+                    false,
+                    false,
+                    16,
+                    false,
+                    false,
+                    None,
+                    Arc::new(MetricsCollector::default()),
+                );
+                engine.run().await;
+            });
+        });
+        drop(tx);
+        assert!(wait_finished(&engine_handler));
+    }
+
+    #[test]
+    fn test_engine_stops_when_mistralrs_is_dropped() {
+        let mistralrs = MistralRsBuilder::new(DummyPipeline::new(), scheduler_config()).build();
+        let engine_handler = std::mem::replace(
+            &mut *mistralrs.engine_handler.write().unwrap(),
+            thread::spawn(|| {}),
+        );
+        drop(mistralrs);
+        assert!(wait_finished(&engine_handler));
+    }
+}
@@ -0,0 +1,427 @@
+//! Metrics of the engine of a model. They are collected by the engine and can be read with
+//! [`MistralRs::metrics`](crate::MistralRs::metrics), or rendered in the Prometheus text format
+//! with [`render_prometheus`].
+
+use std::{fmt::Write, sync::Mutex};
+
+#[cfg(feature = "pyo3_macros")]
+use pyo3::pyclass;
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+
+use crate::{response::Response, sequence::Sequence};
+
+/// Upper bounds of the buckets of the latency histograms, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Histogram of observed values.
+pub struct Histogram {
+    /// Upper bounds of the buckets. The last bucket, for larger values, has no upper bound.
+    pub bounds: Vec<f64>,
+    /// Number of values in each bucket, with one more entry than `bounds`.
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Default, Serialize)]
+/// Number of error responses sent, by [`Response`] variant.
+pub struct ErrorCounts {
+    pub internal: u64,
+    pub validation: u64,
+    pub model: u64,
+    pub completion_model: u64,
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Metrics of the engine of a model since it was started.
+pub struct EngineMetrics {
+    /// Number of sequences waiting to be scheduled.
+    pub waiting_sequences: usize,
+    /// Number of sequences being run.
+    pub running_sequences: usize,
+    /// Number of prompt tokens of the requests.
+    pub prompt_tokens: u64,
+    /// Number of generated tokens.
+    pub completion_tokens: u64,
+    /// Time from receiving a request until the first token of a sequence is generated, in seconds.
+    pub time_to_first_token: Histogram,
+    /// Time between the generated tokens of a sequence, in seconds.
+    pub inter_token_latency: Histogram,
+    /// Number of prompts for which the prefix cacher had a matching cache.
+    pub prefix_cache_hits: u64,
+    /// Number of prompts for which the prefix cacher had no matching cache.
+    pub prefix_cache_misses: u64,
+    /// Number of PagedAttention KV cache blocks in use, if PagedAttention is used.
+    pub kv_cache_blocks_used: Option<usize>,
+    /// Number of PagedAttention KV cache blocks, if PagedAttention is used.
+    pub kv_cache_blocks_total: Option<usize>,
+    pub errors: ErrorCounts,
+}
+
+impl Default for EngineMetrics {
+    fn default() -> Self {
+        Self {
+            waiting_sequences: 0,
+            running_sequences: 0,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            time_to_first_token: Histogram::new(LATENCY_BUCKETS),
+            inter_token_latency: Histogram::new(LATENCY_BUCKETS),
+            prefix_cache_hits: 0,
+            prefix_cache_misses: 0,
+            kv_cache_blocks_used: None,
+            kv_cache_blocks_total: None,
+            errors: ErrorCounts::default(),
+        }
+    }
+}
+
+impl EngineMetrics {
+    /// Fraction of the prompts for which the prefix cacher had a matching cache.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn prefix_cache_hit_rate(&self) -> Option<f64> {
+        let searches = self.prefix_cache_hits + self.prefix_cache_misses;
+        (searches > 0).then(|| self.prefix_cache_hits as f64 / searches as f64)
+    }
+
+    /// Fraction of the PagedAttention KV cache blocks which are in use.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn kv_cache_utilization(&self) -> Option<f64> {
+        match (self.kv_cache_blocks_used, self.kv_cache_blocks_total) {
+            (Some(used), Some(total)) if total > 0 => Some(used as f64 / total as f64),
+            _ => None,
+        }
+    }
+}
+
+/// The metrics of an engine, shared by the engine which updates them and the [`MistralRs`]
+/// instance which reads them.
+///
+/// [`MistralRs`]: crate::MistralRs
+#[derive(Default)]
+pub(crate) struct MetricsCollector(Mutex<EngineMetrics>);
+
+impl MetricsCollector {
+    pub fn snapshot(&self) -> EngineMetrics {
+        self.0.lock().expect("Metrics were poisoned").clone()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut EngineMetrics)) {
+        f(&mut self.0.lock().expect("Metrics were poisoned"));
+    }
+
+    /// Record the tokens generated by the sequences in the last step.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn record_tokens<'a>(&self, seqs: impl IntoIterator<Item = &'a mut Sequence>) {
+        let mut metrics = self.0.lock().expect("Metrics were poisoned");
+        for seq in seqs {
+            let Some((n_new, is_first, elapsed)) = seq.record_new_toks() else {
+                continue;
+            };
+            metrics.completion_tokens += n_new as u64;
+            if is_first {
+                metrics.time_to_first_token.observe(elapsed.as_secs_f64());
+            } else {
+                // Tokens generated in one step, such as by speculative decoding, share the time
+                let latency = elapsed.as_secs_f64() / n_new as f64;
+                for _ in 0..n_new {
+                    metrics.inter_token_latency.observe(latency);
+                }
+            }
+        }
+    }
+
+    /// Count an error response. Other responses are ignored.
+    pub fn record_error(&self, response: &Response) {
+        self.update(|metrics| match response {
+            Response::InternalError(_) => metrics.errors.internal += 1,
+            Response::ValidationError(_) => metrics.errors.validation += 1,
+            Response::ModelError(..) => metrics.errors.model += 1,
+            Response::CompletionModelError(..) => metrics.errors.completion_model += 1,
+            _ => (),
+        });
+    }
+
+    /// Send an error response to `sender` and count it.
+    pub async fn send_error(&self, sender: &Sender<Response>, response: Response) {
+        self.record_error(&response);
+        sender.send(response).await.expect("Expected receiver.");
+    }
+}
+
+/// Escape a label value of the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// Write a gauge or counter with a sample for each model which has a value.
+fn write_metric(
+    out: &mut String,
+    models: &[(String, EngineMetrics)],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&EngineMetrics) -> Option<f64>,
+) {
+    let samples = models
+        .iter()
+        .filter_map(|(model, metrics)| Some((escape_label(model), value(metrics)?)))
+        .collect::<Vec<_>>();
+    if samples.is_empty() {
+        return;
+    }
+    write_header(out, name, kind, help);
+    for (model, value) in samples {
+        writeln!(out, "{name}{{model=\"{model}\"}} {value}").unwrap();
+    }
+}
+
+fn write_histogram(
+    out: &mut String,
+    models: &[(String, EngineMetrics)],
+    name: &str,
+    help: &str,
+    histogram: impl Fn(&EngineMetrics) -> &Histogram,
+) {
+    write_header(out, name, "histogram", help);
+    for (model, metrics) in models {
+        let model = escape_label(model);
+        let histogram = histogram(metrics);
+        let mut cumulative = 0;
+        for (i, count) in histogram.counts.iter().enumerate() {
+            cumulative += count;
+            let le = histogram
+                .bounds
+                .get(i)
+                .map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+            writeln!(
+                out,
+                "{name}_bucket{{model=\"{model}\",le=\"{le}\"}} {cumulative}"
+            )
+            .unwrap();
+        }
+        writeln!(out, "{name}_sum{{model=\"{model}\"}} {}", histogram.sum).unwrap();
+        writeln!(out, "{name}_count{{model=\"{model}\"}} {}", histogram.count).unwrap();
+    }
+}
+
+/// Render the metrics of models in the Prometheus text exposition format, with the ID of each
+/// model as the `model` label.
+#[allow(clippy::cast_precision_loss)]
+pub fn render_prometheus(models: &[(String, EngineMetrics)]) -> String {
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_waiting_sequences",
+        "gauge",
+        "Number of sequences waiting to be scheduled.",
+        |m| Some(m.waiting_sequences as f64),
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_running_sequences",
+        "gauge",
+        "Number of sequences being run.",
+        |m| Some(m.running_sequences as f64),
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_prompt_tokens_total",
+        "counter",
+        "Number of prompt tokens of the requests.",
+        |m| Some(m.prompt_tokens as f64),
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_completion_tokens_total",
+        "counter",
+        "Number of generated tokens.",
+        |m| Some(m.completion_tokens as f64),
+    );
+    write_histogram(
+        &mut out,
+        models,
+        "mistralrs_time_to_first_token_seconds",
+        "Time from receiving a request until the first token of a sequence is generated.",
+        |m| &m.time_to_first_token,
+    );
+    write_histogram(
+        &mut out,
+        models,
+        "mistralrs_inter_token_latency_seconds",
+        "Time between the generated tokens of a sequence.",
+        |m| &m.inter_token_latency,
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_prefix_cache_hits_total",
+        "counter",
+        "Number of prompts for which the prefix cacher had a matching cache.",
+        |m| Some(m.prefix_cache_hits as f64),
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_prefix_cache_misses_total",
+        "counter",
+        "Number of prompts for which the prefix cacher had no matching cache.",
+        |m| Some(m.prefix_cache_misses as f64),
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_prefix_cache_hit_rate",
+        "gauge",
+        "Fraction of the prompts for which the prefix cacher had a matching cache.",
+        EngineMetrics::prefix_cache_hit_rate,
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_kv_cache_blocks_used",
+        "gauge",
+        "Number of PagedAttention KV cache blocks in use.",
+        |m| m.kv_cache_blocks_used.map(|n| n as f64),
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_kv_cache_blocks_total",
+        "gauge",
+        "Number of PagedAttention KV cache blocks.",
+        |m| m.kv_cache_blocks_total.map(|n| n as f64),
+    );
+    write_metric(
+        &mut out,
+        models,
+        "mistralrs_kv_cache_utilization",
+        "gauge",
+        "Fraction of the PagedAttention KV cache blocks which are in use.",
+        EngineMetrics::kv_cache_utilization,
+    );
+
+    let name = "mistralrs_errors_total";
+    write_header(
+        &mut out,
+        name,
+        "counter",
+        "Number of error responses, by kind.",
+    );
+    for (model, metrics) in models {
+        let model = escape_label(model);
+        let errors = &metrics.errors;
+        for (kind, count) in [
+            ("internal", errors.internal),
+            ("validation", errors.validation),
+            ("model", errors.model),
+            ("completion_model", errors.completion_model),
+        ] {
+            writeln!(out, "{name}{{model=\"{model}\",kind=\"{kind}\"}} {count}").unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::{render_prometheus, EngineMetrics, Histogram, MetricsCollector};
+    use crate::response::Response;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        for value in [0.05, 0.1, 0.5, 2.0] {
+            histogram.observe(value);
+        }
+        assert_eq!(histogram.counts, vec![2, 1, 1]);
+        assert_eq!(histogram.count, 4);
+        assert!((histogram.sum - 2.65).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_render_prometheus() {
+        let mut metrics = EngineMetrics {
+            prompt_tokens: 12,
+            prefix_cache_hits: 1,
+            prefix_cache_misses: 3,
+            ..Default::default()
+        };
+        metrics.time_to_first_token.observe(0.2);
+        metrics.errors.validation = 2;
+        let text = render_prometheus(&[("a\"b".to_string(), metrics)]);
+
+        assert!(text.contains("# TYPE mistralrs_prompt_tokens_total counter\n"));
+        assert!(text.contains("mistralrs_prompt_tokens_total{model=\"a\\\"b\"} 12\n"));
+        assert!(text.contains("mistralrs_prefix_cache_hit_rate{model=\"a\\\"b\"} 0.25\n"));
+        assert!(text.contains(
+            "mistralrs_time_to_first_token_seconds_bucket{model=\"a\\\"b\",le=\"0.1\"} 0\n"
+        ));
+        assert!(text.contains(
+            "mistralrs_time_to_first_token_seconds_bucket{model=\"a\\\"b\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(text.contains("mistralrs_errors_total{model=\"a\\\"b\",kind=\"validation\"} 2\n"));
+        // Without PagedAttention, there are no KV cache block metrics
+        assert!(!text.contains("mistralrs_kv_cache_blocks_used"));
+    }
+
+    #[tokio::test]
+    async fn test_send_error() {
+        let metrics = MetricsCollector::default();
+        let (tx, mut rx) = channel(2);
+        metrics
+            .send_error(&tx, Response::ValidationError("invalid".into()))
+            .await;
+        metrics
+            .send_error(&tx, Response::InternalError("failed".into()))
+            .await;
+        let errors = metrics.snapshot().errors;
+        assert_eq!((errors.validation, errors.internal), (1, 1));
+        assert!(matches!(
+            rx.recv().await,
+            Some(Response::ValidationError(_))
+        ));
+    }
+}
@@ -4,7 +4,8 @@ use std::{
 };
 
 use crate::{
-    get_toml_selected_model_dtype,
+    get_toml_selected_kv_cache_dtype, get_toml_selected_model_dtype,
+    kv_quant::KvCacheDtype,
     pipeline::{GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, NormalSpecificConfig},
     DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig, Loader, ModelDType,
     ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
@@ -102,6 +103,19 @@ pub fn get_model_dtype(model: &ModelSelected) -> anyhow::Result<ModelDType> {
     }
 }
 
+pub fn get_kv_cache_dtype(model: &ModelSelected) -> anyhow::Result<KvCacheDtype> {
+    match model {
+        ModelSelected::Toml { file } => {
+            let selector: TomlSelector = toml::from_str(
+                &fs::read_to_string(file.clone())
+                    .unwrap_or_else(|_| panic!("Could not load toml selector file at {file}")),
+            )?;
+            Ok(get_toml_selected_kv_cache_dtype(&selector))
+        }
+        _ => Ok(KvCacheDtype::Auto),
+    }
+}
+
 fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loader>> {
     let use_flash_attn = args.use_flash_attn;
     let loader: Box<dyn Loader> = match args.model {
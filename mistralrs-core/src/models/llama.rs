@@ -14,11 +14,12 @@ use crate::{
     attention::SdpaParams,
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
+    kv_quant::KvCacheDtype,
     layers::{CausalMasker, Llama3RopeConfig, Llama3RotaryEmbedding, MatMul, RmsNorm, Sdpa},
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits,
+        apply_lm_head, extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
         IsqModel, NormalLoadingMetadata, NormalModel,
     },
@@ -70,6 +71,7 @@ impl CausalSelfAttention {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -136,8 +138,13 @@ impl CausalSelfAttention {
                 )?
             }
             None => {
-                let (k, v) =
-                    crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+                let (k, v) = crate::pipeline::Cache::update_kv_cache(
+                    &mut kv_cache[block_idx],
+                    k,
+                    v,
+                    kv_cache_dtype,
+                    false,
+                )?;
 
                 Sdpa.run_attention(
                     &q,
@@ -333,6 +340,7 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -345,6 +353,7 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            kv_cache_dtype,
             metadata,
             flash_params,
         )? + residual)?;
@@ -406,11 +415,31 @@ impl Llama {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let xs = self.forward_hidden_states(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            metadata,
+            flash_params,
+        )?;
+        extract_logits(&apply_lm_head(xs, &*self.lm_head)?, context_lens)
+    }
+
+    /// Hidden states of all tokens after the final norm.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut x = self.wte.forward(input_ids)?;
         let mut cache = self.kv_cache.lock();
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             metadata
@@ -429,19 +458,14 @@ impl Llama {
                 start_offsets_kernel.clone(),
                 block_idx,
                 &mut cache,
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[block_idx].clone(), &mut **metadata)),
                 flash_params,
             )?;
         }
-        let x = x.to_device(&self.device)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            x = x.to_dtype(t)?;
-        }
-        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
-        extract_logits(&xs, context_lens)
+        x.to_device(&self.device)?.apply(&self.ln_f)
     }
 
     pub fn new(
@@ -607,6 +631,23 @@ impl NormalModel for Llama {
             flash_params,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward_hidden_states(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            metadata,
+            flash_params,
+        )
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -0,0 +1,598 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+//! Mamba, a selective state space model: https://arxiv.org/abs/2312.00752
+//!
+//! There is no KV cache. Each layer carries a recurrent state of fixed size per sequence, which is
+//! stored in the layer cache as `(conv_state, ssm_state)` with the batch as the first dimension,
+//! so that it is moved into and out of the sequences like a KV cache.
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{Linear, VarBuilder};
+use mistralrs_quant::{QuantMethod, QuantMethodConfig, QuantizedConfig, UnquantLinear};
+use std::sync::Arc;
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    device_map::DeviceMapper,
+    layers::{MatMul, RmsNorm},
+    paged_attention::ModelConfigMetadata,
+    pipeline::{
+        extract_logits,
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+    },
+    utils::progress::NiceProgressBar,
+};
+
+/// Mamba has no maximum sequence length, this is the same limit as llama.cpp uses.
+pub const MAX_SEQ_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone, serde::Deserialize, Default)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) state_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) conv_kernel: usize,
+    pub(crate) time_step_rank: usize,
+    pub(crate) layer_norm_epsilon: f64,
+    pub(crate) use_bias: bool,
+    pub(crate) use_conv_bias: bool,
+    pub(crate) quantization_config: Option<QuantizedConfig>,
+    pub(crate) tie_word_embeddings: bool,
+}
+
+/// The selective state space mixer of a Mamba layer.
+pub(crate) struct Mixer {
+    in_proj: Arc<dyn QuantMethod>,
+    /// Depthwise convolution weight of shape (d_inner, d_conv)
+    conv1d_weight: Tensor,
+    conv1d_bias: Option<Tensor>,
+    x_proj: Arc<dyn QuantMethod>,
+    dt_proj: Arc<dyn QuantMethod>,
+    out_proj: Arc<dyn QuantMethod>,
+    /// `-exp(A_log)` of shape (d_inner, d_state), in F32
+    a: Tensor,
+    /// Skip connection of shape (d_inner), in F32
+    d: Tensor,
+    d_inner: usize,
+    d_state: usize,
+    d_conv: usize,
+    dt_rank: usize,
+}
+
+impl Mixer {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let (d_model, d_inner) = (cfg.hidden_size, cfg.intermediate_size);
+        let (d_state, d_conv, dt_rank) = (cfg.state_size, cfg.conv_kernel, cfg.time_step_rank);
+        let in_proj = mistralrs_quant::linear_b(
+            d_model,
+            d_inner * 2,
+            cfg.use_bias,
+            &cfg.quantization_config,
+            vb.pp("in_proj"),
+        )?;
+        let x_proj = mistralrs_quant::linear_no_bias(
+            d_inner,
+            dt_rank + d_state * 2,
+            &cfg.quantization_config,
+            vb.pp("x_proj"),
+        )?;
+        let dt_proj = mistralrs_quant::linear_b(
+            dt_rank,
+            d_inner,
+            true,
+            &cfg.quantization_config,
+            vb.pp("dt_proj"),
+        )?;
+        let out_proj = mistralrs_quant::linear_b(
+            d_inner,
+            d_model,
+            cfg.use_bias,
+            &cfg.quantization_config,
+            vb.pp("out_proj"),
+        )?;
+        let conv1d_weight = vb.pp("conv1d").get((d_inner, 1, d_conv), "weight")?;
+        let conv1d_bias = if cfg.use_conv_bias {
+            Some(vb.pp("conv1d").get(d_inner, "bias")?)
+        } else {
+            None
+        };
+        let a = vb
+            .get((d_inner, d_state), "A_log")?
+            .to_dtype(DType::F32)?
+            .exp()?
+            .neg()?;
+        let d = vb.get(d_inner, "D")?;
+        Self::from_parts(
+            in_proj,
+            conv1d_weight,
+            conv1d_bias,
+            x_proj,
+            dt_proj,
+            out_proj,
+            a,
+            d,
+            dt_rank,
+        )
+    }
+
+    /// Build a mixer from its weights. `a` is `-exp(A_log)`, and the convolution weight may have
+    /// any shape with `d_inner * d_conv` elements, as GGUF files drop its singleton dimension.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn from_parts(
+        in_proj: Arc<dyn QuantMethod>,
+        conv1d_weight: Tensor,
+        conv1d_bias: Option<Tensor>,
+        x_proj: Arc<dyn QuantMethod>,
+        dt_proj: Arc<dyn QuantMethod>,
+        out_proj: Arc<dyn QuantMethod>,
+        a: Tensor,
+        d: Tensor,
+        dt_rank: usize,
+    ) -> Result<Self> {
+        let (d_inner, d_state) = a.dims2()?;
+        let d_conv = conv1d_weight.elem_count() / d_inner;
+        Ok(Self {
+            in_proj,
+            conv1d_weight: conv1d_weight.reshape((d_inner, d_conv))?,
+            conv1d_bias,
+            x_proj,
+            dt_proj,
+            out_proj,
+            a: a.to_dtype(DType::F32)?,
+            d: d.to_dtype(DType::F32)?,
+            d_inner,
+            d_state,
+            d_conv,
+            dt_rank,
+        })
+    }
+
+    /// Run the mixer over `xs` of shape (batch size, seq len, hidden size), continuing from and
+    /// updating the recurrent `state`. Sequences which are shorter than the (padded) input have
+    /// their length in `seq_lens`: their state is not advanced by the padding tokens.
+    pub(crate) fn forward(
+        &self,
+        xs: &Tensor,
+        state: &mut Option<(Tensor, Tensor)>,
+        seq_lens: Option<&[usize]>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.in_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let xz = MatMul.qmethod_matmul(&xs, &*self.in_proj)?;
+        let x = xz.narrow(D::Minus1, 0, self.d_inner)?;
+        let z = xz.narrow(D::Minus1, self.d_inner, self.d_inner)?;
+
+        let (conv_state, ssm_state) = match state.take() {
+            Some(state) => state,
+            None => (
+                Tensor::zeros((b_sz, self.d_inner, self.d_conv - 1), x.dtype(), x.device())?,
+                Tensor::zeros((b_sz, self.d_inner, self.d_state), DType::F32, x.device())?,
+            ),
+        };
+
+        // Causal depthwise convolution over the last inputs of the previous step and this input
+        let x_padded = Tensor::cat(&[&conv_state, &x.transpose(1, 2)?], 2)?;
+        let weight = self.conv1d_weight.to_dtype(x.dtype())?;
+        let mut x_conv = x_padded
+            .narrow(2, 0, seq_len)?
+            .broadcast_mul(&weight.i((.., 0))?.reshape((1, self.d_inner, 1))?)?;
+        for k in 1..self.d_conv {
+            x_conv = (x_conv
+                + x_padded
+                    .narrow(2, k, seq_len)?
+                    .broadcast_mul(&weight.i((.., k))?.reshape((1, self.d_inner, 1))?)?)?;
+        }
+        if let Some(bias) = &self.conv1d_bias {
+            x_conv = x_conv.broadcast_add(&bias.to_dtype(x.dtype())?.reshape((
+                1,
+                self.d_inner,
+                1,
+            ))?)?;
+        }
+        let x = candle_nn::ops::silu(&x_conv)?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        // The convolution state holds the last `d_conv - 1` inputs of each sequence
+        let conv_state = match seq_lens {
+            Some(seq_lens) => {
+                let mut states = Vec::with_capacity(b_sz);
+                for (i, len) in seq_lens.iter().enumerate() {
+                    states.push(x_padded.i(i)?.narrow(1, *len, self.d_conv - 1)?);
+                }
+                Tensor::stack(&states, 0)?
+            }
+            None => x_padded.narrow(2, seq_len, self.d_conv - 1)?,
+        }
+        .contiguous()?;
+
+        // Input dependent step size and projections of the selective scan
+        let x_dbl = MatMul.qmethod_matmul(&x, &*self.x_proj)?;
+        let delta = x_dbl.narrow(D::Minus1, 0, self.dt_rank)?.contiguous()?;
+        let b = x_dbl
+            .narrow(D::Minus1, self.dt_rank, self.d_state)?
+            .to_dtype(DType::F32)?;
+        let c = x_dbl
+            .narrow(D::Minus1, self.dt_rank + self.d_state, self.d_state)?
+            .to_dtype(DType::F32)?;
+        let delta = MatMul.qmethod_matmul(&delta, &*self.dt_proj)?;
+        // Softplus
+        let delta = (delta.to_dtype(DType::F32)?.exp()? + 1.0)?.log()?;
+        let x = x.to_dtype(DType::F32)?;
+
+        let masks = match seq_lens {
+            Some(seq_lens) if seq_lens.iter().any(|len| *len < seq_len) => Some(
+                (0..seq_len)
+                    .map(|t| {
+                        let mask = seq_lens
+                            .iter()
+                            .map(|len| if t < *len { 1f32 } else { 0f32 })
+                            .collect::<Vec<_>>();
+                        Tensor::from_vec(mask, (b_sz, 1, 1), x.device())
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            _ => None,
+        };
+
+        let mut ssm_state = ssm_state;
+        let mut ys = Vec::with_capacity(seq_len);
+        for t in 0..seq_len {
+            let delta_t = delta.i((.., t))?.unsqueeze(D::Minus1)?;
+            let delta_a = delta_t.broadcast_mul(&self.a.unsqueeze(0)?)?.exp()?;
+            let delta_bx = delta_t
+                .broadcast_mul(&b.i((.., t))?.unsqueeze(1)?)?
+                .broadcast_mul(&x.i((.., t))?.unsqueeze(D::Minus1)?)?;
+            let new_state = ((delta_a * &ssm_state)? + delta_bx)?;
+            ssm_state = match &masks {
+                Some(masks) => {
+                    (&ssm_state + (new_state - &ssm_state)?.broadcast_mul(&masks[t])?)?
+                }
+                None => new_state,
+            };
+            ys.push(
+                ssm_state
+                    .matmul(&c.i((.., t))?.unsqueeze(D::Minus1)?.contiguous()?)?
+                    .squeeze(D::Minus1)?,
+            );
+        }
+        *state = Some((conv_state, ssm_state));
+
+        let y = (Tensor::stack(&ys, 1)? + x.broadcast_mul(&self.d)?)?;
+        let y = (y.to_dtype(z.dtype())? * candle_nn::ops::silu(&z)?)?;
+        let mut res = MatMul.qmethod_matmul(&y, &*self.out_proj)?;
+        if self.in_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+
+    pub(crate) fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        vec![
+            &mut self.in_proj,
+            &mut self.x_proj,
+            &mut self.dt_proj,
+            &mut self.out_proj,
+        ]
+    }
+}
+
+struct ResidualBlock {
+    norm: RmsNorm,
+    mixer: Mixer,
+}
+
+impl ResidualBlock {
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let norm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_device(layer_idx, vb.pp("norm"), false),
+        )?;
+        let mixer = Mixer::new(
+            cfg,
+            mapper.set_device(layer_idx, vb.pp("mixer"), loading_isq),
+        )?;
+        Ok(Self { norm, mixer })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        state: &mut Option<(Tensor, Tensor)>,
+        seq_lens: Option<&[usize]>,
+    ) -> Result<Tensor> {
+        self.mixer
+            .forward(&xs.apply(&self.norm)?, state, seq_lens)?
+            + xs
+    }
+}
+
+pub struct Model {
+    embeddings: candle_nn::Embedding,
+    layers: Vec<ResidualBlock>,
+    norm_f: RmsNorm,
+    lm_head: Arc<dyn QuantMethod>,
+    device: Device,
+    cache: Cache,
+    max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    cfg: ModelConfigMetadata,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+    ) -> Result<Self> {
+        if let Some(ref quant_cfg) = &cfg.quantization_config {
+            tracing::info!(
+                "Using {} quantization in {} bits.",
+                quant_cfg.quant_method.to_string(),
+                quant_cfg.bits
+            );
+        }
+        let mapper = normal_loading_metadata.mapper;
+        let vb_m = vb.pp("backbone");
+
+        let embeddings = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp("embeddings"), false),
+        )?;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in
+            NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
+        {
+            layers.push(ResidualBlock::new(
+                cfg,
+                vb_l.pp(layer_idx),
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+            )?);
+        }
+        let norm_f = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_nm_device(vb_m.pp("norm_f"), false),
+        )?;
+        let lm_head = if !cfg.tie_word_embeddings {
+            mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                &None,
+                mapper.set_nm_device(vb.pp("lm_head"), normal_loading_metadata.loading_isq),
+            )?
+        } else {
+            Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                Linear::new(
+                    mapper.cast_nm_device(
+                        embeddings.embeddings(),
+                        normal_loading_metadata.loading_isq,
+                    )?,
+                    None,
+                ),
+            ))?)
+        };
+        Ok(Self {
+            embeddings,
+            layers,
+            norm_f,
+            lm_head,
+            device: normal_loading_metadata.real_device,
+            cache: Cache::new(cfg.num_hidden_layers, false),
+            max_seq_len: MAX_SEQ_LEN,
+            mapper,
+            cfg: ModelConfigMetadata {
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_kv_heads: 1,
+                num_attn_heads: 1,
+                sliding_window: None,
+                head_dim: None,
+            },
+        })
+    }
+
+    pub fn forward(&self, input_ids: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        // The prompts are padded on the right to the longest one
+        let seq_lens = context_lens
+            .iter()
+            .map(|(start, len)| start + len)
+            .collect::<Vec<_>>();
+        let mut xs = self.forward_hidden_states(input_ids, Some(&seq_lens))?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+
+    /// Hidden states of all tokens after the final norm.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seq_lens: Option<&[usize]>,
+    ) -> Result<Tensor> {
+        let mut xs = self.embeddings.forward(input_ids)?;
+        let mut cache = self.cache.lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(&xs, &mut cache[i], seq_lens)?;
+        }
+        xs.to_device(&self.device)?.apply(&self.norm_f)
+    }
+}
+
+impl IsqModel for Model {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.extend(
+                layer
+                    .mixer
+                    .get_isq_layers()
+                    .into_iter()
+                    .map(|m| (m, Some(i)))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        (tensors, &*self.mapper)
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward(input_ids, context_lens)
+    }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        // The padding only follows the tokens, so it does not change their hidden states
+        self.forward_hidden_states(input_ids, None)
+    }
+    fn xlora_forward(
+        &self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _flash_params: &FlashParams,
+        _flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn is_recurrent(&self) -> bool {
+        true
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{Device, IndexOp, Result, Tensor};
+    use candle_nn::Linear;
+    use mistralrs_quant::{QuantMethod, QuantMethodConfig, UnquantLinear};
+
+    use super::Mixer;
+
+    const D_MODEL: usize = 8;
+    const D_INNER: usize = 16;
+    const D_STATE: usize = 4;
+    const D_CONV: usize = 4;
+    const DT_RANK: usize = 2;
+
+    fn linear(in_dim: usize, out_dim: usize, dev: &Device) -> Result<Arc<dyn QuantMethod>> {
+        let weight = Tensor::randn(0f32, 0.5, (out_dim, in_dim), dev)?;
+        let bias = Tensor::randn(0f32, 0.5, out_dim, dev)?;
+        Ok(Arc::new(UnquantLinear::new(
+            QuantMethodConfig::Unquantized(Linear::new(weight, Some(bias))),
+        )?))
+    }
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.max_all()?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn test_padded_batch() -> Result<()> {
+        let dev = Device::Cpu;
+        let mixer = Mixer::from_parts(
+            linear(D_MODEL, D_INNER * 2, &dev)?,
+            Tensor::randn(0f32, 0.5, (D_INNER, 1, D_CONV), &dev)?,
+            Some(Tensor::randn(0f32, 0.5, D_INNER, &dev)?),
+            linear(D_INNER, DT_RANK + D_STATE * 2, &dev)?,
+            linear(DT_RANK, D_INNER, &dev)?,
+            linear(D_INNER, D_MODEL, &dev)?,
+            Tensor::rand(0f32, 1., (D_INNER, D_STATE), &dev)?
+                .exp()?
+                .neg()?,
+            Tensor::randn(0f32, 1., D_INNER, &dev)?,
+            DT_RANK,
+        )?;
+
+        let long = Tensor::randn(0f32, 1., (1, 7, D_MODEL), &dev)?;
+        let short = Tensor::randn(0f32, 1., (1, 3, D_MODEL), &dev)?;
+        // The short prompt is padded on the right with garbage, which must not reach its state
+        let padding = Tensor::randn(0f32, 1., (1, 4, D_MODEL), &dev)?;
+        let batch = Tensor::cat(&[long.clone(), Tensor::cat(&[&short, &padding], 1)?], 0)?;
+
+        let mut batch_state = None;
+        let batch_out = mixer.forward(&batch, &mut batch_state, Some(&[7, 3]))?;
+        let (batch_conv, batch_ssm) = batch_state.unwrap();
+
+        for (i, (prompt, len)) in [(long, 7), (short, 3)].into_iter().enumerate() {
+            let mut state = None;
+            let out = mixer.forward(&prompt, &mut state, None)?;
+            let (conv, ssm) = state.unwrap();
+
+            let diff = max_diff(&batch_out.i((i, len - 1))?, &out.i((0, len - 1))?)?;
+            assert!(diff < 1e-5, "output diff of prompt {i}: {diff}");
+            let diff = max_diff(&batch_conv.i(i)?, &conv.i(0)?)?;
+            assert!(diff < 1e-5, "conv state diff of prompt {i}: {diff}");
+            let diff = max_diff(&batch_ssm.i(i)?, &ssm.i(0)?)?;
+            assert!(diff < 1e-5, "ssm state diff of prompt {i}: {diff}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,485 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use candle_core::quantized::QTensor;
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Embedding, Module};
+use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::kv_quant::KvCacheDtype;
+use crate::layers::{CausalMasker, MatMul, QRmsNorm, RotaryEmbedding, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::paged_attention::{AttentionImplementation, PagedAttention};
+use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
+use crate::pipeline::{extract_logits, Cache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+use crate::DeviceMapMetadata;
+use crate::Topology;
+
+const MAX_SEQ_LEN: u32 = 8192;
+const SLIDING_WINDOW: u32 = 4096;
+
+fn gguf_linear(w: QTensor) -> Result<Arc<dyn QuantMethod>> {
+    Ok(Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+        q_weight: Arc::new(w),
+        b: None,
+    })?))
+}
+
+struct Mlp {
+    ffn_gate: Arc<dyn QuantMethod>,
+    ffn_up: Arc<dyn QuantMethod>,
+    ffn_down: Arc<dyn QuantMethod>,
+}
+
+impl Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = MatMul
+            .qmethod_matmul(xs, &*self.ffn_gate)?
+            .apply(&candle_nn::Activation::GeluPytorchTanh)?;
+        let up = MatMul.qmethod_matmul(xs, &*self.ffn_up)?;
+        MatMul.qmethod_matmul(&(gate * up)?, &*self.ffn_down)
+    }
+}
+
+struct LayerWeights {
+    attn_q: Arc<dyn QuantMethod>,
+    attn_k: Arc<dyn QuantMethod>,
+    attn_v: Arc<dyn QuantMethod>,
+    attn_output: Arc<dyn QuantMethod>,
+    attn_norm: QRmsNorm,
+    post_attention_norm: QRmsNorm,
+    mlp: Mlp,
+    ffn_norm: QRmsNorm,
+    post_ffw_norm: QRmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rotary: Arc<RotaryEmbedding>,
+    attn_logit_softcapping: Option<f64>,
+    use_sliding_window: bool,
+    sliding_window: Option<usize>,
+    paged_attn: Option<PagedAttention>,
+    sdpa_params: SdpaParams,
+}
+
+impl LayerWeights {
+    #[allow(clippy::too_many_arguments)]
+    fn forward_attn(
+        &self,
+        x: &Tensor,
+        attention_mask: Option<&Tensor>,
+        sliding_attention_mask: Option<&Tensor>,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
+        metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let q = MatMul.qmethod_matmul(x, &*self.attn_q)?;
+        let k = MatMul.qmethod_matmul(x, &*self.attn_k)?;
+        let v = MatMul.qmethod_matmul(x, &*self.attn_v)?;
+
+        let mut q = q.reshape((b_sz * seq_len, self.n_head, self.head_dim))?;
+        let mut k = k.reshape((b_sz * seq_len, self.n_kv_head, self.head_dim))?;
+        let v = if seq_len != 1 {
+            v.reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?
+        } else {
+            // Optimization for seqlen = 1, avoid transpose and just modify reshape dims
+            v.reshape((b_sz, self.n_kv_head, seq_len, self.head_dim))?
+        };
+
+        self.rotary
+            .forward(start_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 && seq_len != 1 {
+            q = q
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+        } else if q.rank() == 3 {
+            // Optimization for seqlen = 1, avoid transpose and just modify reshape dims
+            q = q
+                .reshape((b_sz, self.n_head, seq_len, self.head_dim))?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, self.n_kv_head, seq_len, self.head_dim))?
+                .contiguous()?;
+        }
+
+        let mask = if self.use_sliding_window {
+            sliding_attention_mask
+        } else {
+            attention_mask
+        };
+
+        let y = match &self.paged_attn {
+            Some(paged_attn) => {
+                let ((key_cache, value_cache), input_metadata) = metadata.unwrap();
+                paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    attention_mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    self.attn_logit_softcapping,
+                )?
+            }
+            None => {
+                // self.sliding_window is None if !self.use_sliding_window
+                let (k, v, mask) = Cache::update_kv_cache_sliding_window(
+                    kv_cache,
+                    k,
+                    v,
+                    mask,
+                    self.sliding_window,
+                    kv_cache_dtype,
+                    false,
+                )?;
+
+                Sdpa.run_attention(&q, &k, &v, mask.as_ref(), None, &self.sdpa_params)?
+            }
+        };
+
+        let y = if attention_mask.is_some() {
+            y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?
+        } else {
+            y.reshape((b_sz, seq_len, ()))?
+        };
+
+        MatMul.qmethod_matmul(&y, &*self.attn_output)
+    }
+}
+
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: QRmsNorm,
+    output: Arc<dyn QuantMethod>,
+    hidden_size: usize,
+    sliding_window: usize,
+    final_logit_softcapping: Option<f64>,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+}
+
+// gemma2 `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub rms_norm_eps: f32,
+    pub max_seq_len: usize,
+    pub rope_freq_base: f32,
+    pub key_length: usize,
+    pub value_length: usize,
+    pub sliding_window: usize,
+    pub attn_logit_softcapping: Option<f64>,
+    pub final_logit_softcapping: Option<f64>,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("gemma2")?;
+
+        let required = [
+            "attention.head_count",
+            "attention.head_count_kv",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_rms_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        let embed_len = c.get_value::<u32>("embedding_length")? as usize;
+        let head_count = c.get_value::<u32>("attention.head_count")? as usize;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count,
+            head_count_kv: c.get_value::<u32>("attention.head_count_kv")? as usize,
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: embed_len,
+            rms_norm_eps: c.get_value("attention.layer_norm_rms_epsilon")?,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
+            key_length: c
+                .get_value::<u32>("attention.key_length")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(embed_len / head_count),
+            value_length: c
+                .get_value::<u32>("attention.value_length")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(embed_len / head_count),
+            sliding_window: c
+                .get_value::<u32>("attention.sliding_window")
+                .ok()
+                .unwrap_or(SLIDING_WINDOW) as usize,
+            attn_logit_softcapping: c
+                .get_value::<f32>("attn_logit_softcapping")
+                .ok()
+                .map(|x| x as f64),
+            final_logit_softcapping: c
+                .get_value::<f32>("final_logit_softcapping")
+                .ok()
+                .map(|x| x as f64),
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: DeviceMapMetadata,
+        topology: Option<&'_ Topology>,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "gemma2",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            head_count_kv,
+            block_count,
+            embedding_length,
+            rms_norm_eps,
+            max_seq_len,
+            rope_freq_base,
+            key_length,
+            value_length,
+            sliding_window,
+            attn_logit_softcapping,
+            final_logit_softcapping,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        // The conversion to GGUF already adds 1 to the norm weights, so they are plain RMS norms.
+        let tok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let norm = QRmsNorm::new(ct.tensor("output_norm.weight", device)?, rms_norm_eps)?;
+        // The output is tied to the embeddings
+        let output = gguf_linear(ct.tensor("token_embd.weight", device)?)?;
+        let mut layers = Vec::with_capacity(block_count);
+
+        let mapper = mapper.into_mapper(block_count, device, topology)?;
+
+        let head_dim = key_length;
+        if key_length != value_length {
+            candle_core::bail!(
+                "Expected key_length == value_length, got {key_length} != {value_length}"
+            );
+        }
+        // `query_pre_attn_scalar` is not in the GGUF metadata. It is the head dim except for the
+        // 27B model (46 layers), which uses `hidden_size / num_attention_heads`.
+        let query_pre_attn_scalar = if block_count == 46 {
+            embedding_length / head_count
+        } else {
+            head_dim
+        };
+
+        let mut ropes = HashMap::new();
+        for layer_idx in 0..block_count {
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new(
+                    rope_freq_base,
+                    head_dim,
+                    max_seq_len,
+                    device,
+                    true,
+                    DType::F32,
+                )?),
+            );
+        }
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(0..block_count, "Loading repeating layers") {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let rotary = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+
+            let mlp = Mlp {
+                ffn_gate: gguf_linear(ct.tensor(&format!("{prefix}.ffn_gate.weight"), device)?)?,
+                ffn_up: gguf_linear(ct.tensor(&format!("{prefix}.ffn_up.weight"), device)?)?,
+                ffn_down: gguf_linear(ct.tensor(&format!("{prefix}.ffn_down.weight"), device)?)?,
+            };
+            let attn_norm = ct.tensor(&format!("{prefix}.attn_norm.weight"), device)?;
+            let post_attention_norm =
+                ct.tensor(&format!("{prefix}.post_attention_norm.weight"), device)?;
+            let ffn_norm = ct.tensor(&format!("{prefix}.ffn_norm.weight"), device)?;
+            let post_ffw_norm = ct.tensor(&format!("{prefix}.post_ffw_norm.weight"), device)?;
+            let layer_sliding_window = if layer_idx % 2 == 0 {
+                // ^ Order is SWA, global, SWA
+                Some(sliding_window)
+            } else {
+                None
+            };
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => Some(PagedAttention::new(
+                    head_count,
+                    head_dim,
+                    (1.0 / (query_pre_attn_scalar as f64).sqrt()) as f32,
+                    Some(head_count_kv),
+                    layer_sliding_window,
+                    device,
+                    None,
+                )?),
+            };
+            layers.push(LayerWeights {
+                attn_q: gguf_linear(ct.tensor(&format!("{prefix}.attn_q.weight"), device)?)?,
+                attn_k: gguf_linear(ct.tensor(&format!("{prefix}.attn_k.weight"), device)?)?,
+                attn_v: gguf_linear(ct.tensor(&format!("{prefix}.attn_v.weight"), device)?)?,
+                attn_output: gguf_linear(
+                    ct.tensor(&format!("{prefix}.attn_output.weight"), device)?,
+                )?,
+                attn_norm: QRmsNorm::new(attn_norm, rms_norm_eps)?,
+                post_attention_norm: QRmsNorm::new(post_attention_norm, rms_norm_eps)?,
+                mlp,
+                ffn_norm: QRmsNorm::new(ffn_norm, rms_norm_eps)?,
+                post_ffw_norm: QRmsNorm::new(post_ffw_norm, rms_norm_eps)?,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                rotary,
+                attn_logit_softcapping,
+                use_sliding_window: layer_idx % 2 == 0,
+                sliding_window: layer_sliding_window,
+                paged_attn,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: head_count / head_count_kv,
+                    use_flash_attn: false,
+                    softcap: attn_logit_softcapping.map(|x| x as f32),
+                    softmax_scale: 1.0 / (query_pre_attn_scalar as f32).sqrt(),
+                    sliding_window: layer_sliding_window,
+                },
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output,
+            hidden_size: embedding_length,
+            sliding_window,
+            final_logit_softcapping,
+            device: device.clone(),
+            cache: Cache::new(block_count, false),
+            max_seq_len,
+            mapper,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(
+        &self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let xs = self.tok_embeddings.forward(x)?;
+        let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
+        let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
+        let past_kv_len_cache = metadata
+            .as_ref()
+            .map(|(_, _)| &start_offsets as &dyn PastKvLenCache)
+            .unwrap_or(&*cache as &dyn PastKvLenCache);
+        let attention_mask = CausalMasker.make_causal_mask_as_attn_bias(
+            x,
+            past_kv_len_cache,
+            DType::F32,
+            self.layers[0].n_head,
+        )?;
+        let sliding_attention_mask = CausalMasker
+            .make_causal_mask_with_sliding_window_as_attn_bias(
+                x,
+                past_kv_len_cache,
+                Some(self.sliding_window),
+                DType::F32,
+                self.layers[0].n_head,
+            )?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            let residual = &xs;
+            let ys = layer.attn_norm.forward(&xs)?;
+            let ys = layer.forward_attn(
+                &ys,
+                attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                sliding_attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                start_offsets,
+                start_offsets_kernel.clone(),
+                &mut cache[i],
+                kv_cache_dtype,
+                metadata
+                    .as_mut()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
+            )?;
+            let ys = layer.post_attention_norm.forward(&ys)?;
+            let ys = (ys + residual)?;
+
+            // MLP
+            let residual = &ys;
+            let zs = layer.ffn_norm.forward(&ys)?;
+            let zs = layer.mlp.forward(&zs)?;
+            let zs = layer.post_ffw_norm.forward(&zs)?;
+            xs = (zs + residual)?;
+        }
+        let xs = xs.to_device(&self.device)?;
+        let xs = self.norm.forward(&xs)?;
+        let mut logits = MatMul.qmethod_matmul(&xs.contiguous()?, &*self.output)?;
+
+        if let Some(final_logit_softcapping) = self.final_logit_softcapping {
+            logits = (logits / final_logit_softcapping)?;
+            logits = logits.tanh()?;
+            logits = (logits * final_logit_softcapping)?;
+        }
+
+        extract_logits(&logits, context_lens)
+    }
+}
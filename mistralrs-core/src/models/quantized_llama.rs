@@ -4,7 +4,6 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use candle_core::quantized::ggml_file;
-use candle_core::quantized::QTensor;
 use candle_core::{DType, Device, Result, Tensor};
 use candle_nn::{Embedding, Module, RotaryEmbedding};
 use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
@@ -12,6 +11,7 @@ use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
 use crate::attention::SdpaParams;
 use crate::device_map::DeviceMapper;
 use crate::gguf::Content;
+use crate::kv_quant::KvCacheDtype;
 use crate::layers::{CausalMasker, MatMul, QRmsNorm, Sdpa};
 use crate::layers_masker::PastKvLenCache;
 use crate::paged_attention::{AttentionImplementation, PagedAttention};
@@ -135,6 +135,7 @@ struct LayerWeights {
 }
 
 impl LayerWeights {
+    #[allow(clippy::too_many_arguments)]
     fn forward_attn(
         &self,
         x: &Tensor,
@@ -142,6 +143,7 @@ impl LayerWeights {
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -197,7 +199,7 @@ impl LayerWeights {
                 )?
             }
             None => {
-                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, kv_cache_dtype, false)?;
 
                 Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
             }
@@ -489,86 +491,63 @@ impl ModelConfig::FromGGUF for ModelWeights {
                 let feed_forward_gate_inp =
                     ct.tensor(&format!("{prefix}.ffn_gate_inp.weight"), device)?;
                 let mut experts = Vec::with_capacity(n_expert);
-                match ct.tensor(&format!("{prefix}.ffn_gate_exps.weight"), device) {
-                    Ok(feed_forward_gate_exps) => {
-                        let feed_forward_down_exps =
-                            ct.tensor(&format!("{prefix}.ffn_down_exps.weight"), device)?;
-                        let feed_forward_up_exps =
-                            ct.tensor(&format!("{prefix}.ffn_up_exps.weight"), device)?;
-
-                        let dequant_ffn_gate = feed_forward_gate_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-                        let dequant_ffn_down = feed_forward_down_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-                        let dequant_ffn_up = feed_forward_up_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-
-                        assert_eq!(dequant_ffn_up.len(), dequant_ffn_down.len());
-                        assert_eq!(dequant_ffn_gate.len(), dequant_ffn_down.len());
-                        assert_eq!(dequant_ffn_gate.len(), n_expert);
-
-                        let gate_type = feed_forward_gate_exps.dtype();
-                        let down_type = feed_forward_down_exps.dtype();
-                        let up_type = feed_forward_up_exps.dtype();
-
-                        for (ff_w1, (ff_w2, ff_w3)) in dequant_ffn_gate
-                            .into_iter()
-                            .zip(dequant_ffn_down.into_iter().zip(dequant_ffn_up))
-                        {
-                            experts.push(Mlp {
-                                feed_forward_w1: Arc::new(GgufMatMul::new(
-                                    QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w1, gate_type)?),
-                                        b: None,
-                                    },
-                                )?),
-                                feed_forward_w2: Arc::new(GgufMatMul::new(
-                                    QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w2, down_type)?),
-                                        b: None,
-                                    },
-                                )?),
-                                feed_forward_w3: Arc::new(GgufMatMul::new(
-                                    QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w3, up_type)?),
-                                        b: None,
-                                    },
-                                )?),
-                            })
-                        }
+                if ct.has_tensor(&format!("{prefix}.ffn_gate_exps.weight")) {
+                    let ffn_gate = ct.expert_tensors(
+                        &format!("{prefix}.ffn_gate_exps.weight"),
+                        n_expert,
+                        device,
+                    )?;
+                    let ffn_down = ct.expert_tensors(
+                        &format!("{prefix}.ffn_down_exps.weight"),
+                        n_expert,
+                        device,
+                    )?;
+                    let ffn_up = ct.expert_tensors(
+                        &format!("{prefix}.ffn_up_exps.weight"),
+                        n_expert,
+                        device,
+                    )?;
+
+                    for (ff_w1, (ff_w2, ff_w3)) in
+                        ffn_gate.into_iter().zip(ffn_down.into_iter().zip(ffn_up))
+                    {
+                        experts.push(Mlp {
+                            feed_forward_w1: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                                q_weight: Arc::new(ff_w1),
+                                b: None,
+                            })?),
+                            feed_forward_w2: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                                q_weight: Arc::new(ff_w2),
+                                b: None,
+                            })?),
+                            feed_forward_w3: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                                q_weight: Arc::new(ff_w3),
+                                b: None,
+                            })?),
+                        })
                     }
-                    Err(_) => {
-                        for i in 0..n_expert {
-                            let feed_forward_w1 =
-                                ct.tensor(&format!("{prefix}.ffn_gate.{i}.weight"), device)?;
-                            let feed_forward_w2 =
-                                ct.tensor(&format!("{prefix}.ffn_down.{i}.weight"), device)?;
-                            let feed_forward_w3 =
-                                ct.tensor(&format!("{prefix}.ffn_up.{i}.weight"), device)?;
-                            experts.push(Mlp {
-                                feed_forward_w1: Arc::new(GgufMatMul::new(
-                                    QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(feed_forward_w1),
-                                        b: None,
-                                    },
-                                )?),
-                                feed_forward_w2: Arc::new(GgufMatMul::new(
-                                    QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(feed_forward_w2),
-                                        b: None,
-                                    },
-                                )?),
-                                feed_forward_w3: Arc::new(GgufMatMul::new(
-                                    QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(feed_forward_w3),
-                                        b: None,
-                                    },
-                                )?),
-                            })
-                        }
+                } else {
+                    for i in 0..n_expert {
+                        let feed_forward_w1 =
+                            ct.tensor(&format!("{prefix}.ffn_gate.{i}.weight"), device)?;
+                        let feed_forward_w2 =
+                            ct.tensor(&format!("{prefix}.ffn_down.{i}.weight"), device)?;
+                        let feed_forward_w3 =
+                            ct.tensor(&format!("{prefix}.ffn_up.{i}.weight"), device)?;
+                        experts.push(Mlp {
+                            feed_forward_w1: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                                q_weight: Arc::new(feed_forward_w1),
+                                b: None,
+                            })?),
+                            feed_forward_w2: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                                q_weight: Arc::new(feed_forward_w2),
+                                b: None,
+                            })?),
+                            feed_forward_w3: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                                q_weight: Arc::new(feed_forward_w3),
+                                b: None,
+                            })?),
+                        })
                     }
                 }
                 MlpOrMoe::MoE {
@@ -655,6 +634,7 @@ impl ModelWeights {
     ) -> Result<Tensor> {
         let mut layer_in = self.tok_embeddings.forward(x)?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             x,
             metadata
@@ -679,6 +659,7 @@ impl ModelWeights {
                 start_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
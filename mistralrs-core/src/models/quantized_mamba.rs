@@ -0,0 +1,204 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::quantized::QTensor;
+use candle_core::{Device, Module, Result, Tensor};
+use candle_nn::Embedding;
+use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
+
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{MatMul, QRmsNorm};
+use crate::models::mamba::Mixer;
+use crate::paged_attention::AttentionImplementation;
+use crate::pipeline::{extract_logits, Cache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+use crate::DeviceMapMetadata;
+use crate::Topology;
+
+use crate::models::mamba::MAX_SEQ_LEN;
+
+struct LayerWeights {
+    attn_norm: QRmsNorm,
+    mixer: Mixer,
+}
+
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    output_norm: QRmsNorm,
+    output: Arc<dyn QuantMethod>,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+}
+
+// mamba `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+struct PropsGGUF {
+    block_count: usize,
+    embedding_length: usize,
+    time_step_rank: usize,
+    rms_norm_eps: f32,
+    max_seq_len: usize,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("mamba")?;
+
+        let required = [
+            "block_count",
+            "embedding_length",
+            "ssm.time_step_rank",
+            "attention.layer_norm_rms_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            time_step_rank: c.get_value::<u32>("ssm.time_step_rank")? as usize,
+            rms_norm_eps: c.get_value("attention.layer_norm_rms_epsilon")?,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+        };
+
+        Ok(props)
+    }
+}
+
+fn gguf_linear(w: QTensor, b: Option<Tensor>) -> Result<Arc<dyn QuantMethod>> {
+    Ok(Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+        q_weight: Arc::new(w),
+        b,
+    })?))
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: DeviceMapMetadata,
+        topology: Option<&'_ Topology>,
+        _attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "mamba",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            block_count,
+            embedding_length,
+            time_step_rank,
+            rms_norm_eps,
+            max_seq_len,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let tok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let output_norm = QRmsNorm::new(ct.tensor("output_norm.weight", device)?, rms_norm_eps)?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let mapper = mapper.into_mapper(block_count, device, topology)?;
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(0..block_count, "Loading repeating layers") {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            let attn_norm = QRmsNorm::new(
+                ct.tensor(&format!("{prefix}.attn_norm.weight"), device)?,
+                rms_norm_eps,
+            )?;
+            // The conversion to GGUF already computes `A = -exp(A_log)`
+            let a = ct
+                .tensor(&format!("{prefix}.ssm_a"), device)?
+                .dequantize(device)?;
+            let d = ct
+                .tensor(&format!("{prefix}.ssm_d"), device)?
+                .dequantize(device)?;
+            let conv1d_weight = ct
+                .tensor(&format!("{prefix}.ssm_conv1d.weight"), device)?
+                .dequantize(device)?;
+            let conv1d_bias = ct
+                .tensor(&format!("{prefix}.ssm_conv1d.bias"), device)?
+                .dequantize(device)?;
+            let dt_bias = ct
+                .tensor(&format!("{prefix}.ssm_dt.bias"), device)?
+                .dequantize(device)?;
+            let mixer = Mixer::from_parts(
+                gguf_linear(ct.tensor(&format!("{prefix}.ssm_in.weight"), device)?, None)?,
+                conv1d_weight,
+                Some(conv1d_bias),
+                gguf_linear(ct.tensor(&format!("{prefix}.ssm_x.weight"), device)?, None)?,
+                gguf_linear(
+                    ct.tensor(&format!("{prefix}.ssm_dt.weight"), device)?,
+                    Some(dt_bias),
+                )?,
+                gguf_linear(
+                    ct.tensor(&format!("{prefix}.ssm_out.weight"), device)?,
+                    None,
+                )?,
+                a,
+                d,
+                time_step_rank,
+            )?;
+            layers.push(LayerWeights { attn_norm, mixer })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            output_norm,
+            output: gguf_linear(output, None)?,
+            device: device.clone(),
+            cache: Cache::new(block_count, false),
+            max_seq_len,
+            mapper,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(&self, input_ids: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        // The prompts are padded on the right to the longest one
+        let seq_lens = context_lens
+            .iter()
+            .map(|(start, len)| start + len)
+            .collect::<Vec<_>>();
+        let mut xs = self.tok_embeddings.forward(input_ids)?;
+        let mut cache = self.cache.lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            let residual = &xs;
+            let ys = layer.mixer.forward(
+                &layer.attn_norm.forward(&xs)?,
+                &mut cache[i],
+                Some(&seq_lens),
+            )?;
+            xs = (ys + residual)?;
+        }
+        let xs = xs.to_device(&self.device)?;
+        let xs = self.output_norm.forward(&xs)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&xs.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
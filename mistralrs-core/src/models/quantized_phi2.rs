@@ -13,6 +13,7 @@ use mistralrs_quant::QuantMethodConfig;
 use crate::attention::SdpaParams;
 use crate::device_map::DeviceMapper;
 use crate::gguf::Content;
+use crate::kv_quant::KvCacheDtype;
 use crate::layers::MatMul;
 use crate::layers::Sdpa;
 use crate::layers::{CausalMasker, QLinear};
@@ -76,6 +77,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -110,7 +112,7 @@ impl LayerWeights {
                 )?
             }
             None => {
-                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, kv_cache_dtype, false)?;
 
                 Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
             }
@@ -347,6 +349,7 @@ impl ModelWeights {
     ) -> Result<Tensor> {
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             &*cache,
@@ -364,6 +367,7 @@ impl ModelWeights {
                     .as_ref(),
                 seqlen_offsets,
                 cache.get_mut(i).unwrap(),
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
@@ -81,6 +81,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -136,6 +137,7 @@ impl LayerWeights {
                     v,
                     mask,
                     Some(self.sliding_window),
+                    kv_cache_dtype,
                     true,
                 )?;
 
@@ -373,6 +375,7 @@ impl ModelWeights {
         let (_b_sz, seq_len) = input_ids.dims2()?;
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
             metadata
@@ -396,6 +399,7 @@ impl ModelWeights {
                     .as_ref(),
                 seqlen_offsets,
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
@@ -1,5 +1,6 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    cmp::Reverse,
+    collections::{hash_map::Entry, BTreeSet, HashMap},
     hash::Hash,
     marker::PhantomData,
     ops::Deref,
@@ -42,6 +43,10 @@ impl LogicalTokenBlock {
         self.tokens.pop();
         self.num_tokens -= 1;
     }
+
+    fn token_ids(&self) -> &[usize] {
+        &self.tokens[..self.num_tokens]
+    }
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -179,35 +184,225 @@ pub enum AllocStatus {
 
 type SeqID = usize;
 
+/// A block of the [`PrefixCache`].
+struct CachedBlock {
+    block: Arc<PhysicalTokenBlock>,
+    /// The ID of the previous block of the prompt, if any.
+    parent: Option<usize>,
+    tokens: Vec<usize>,
+    /// The IDs of the cached blocks which follow this one, keyed by their tokens.
+    children: HashMap<Vec<usize>, usize>,
+    depth: usize,
+    last_used: usize,
+}
+
+/// Computed GPU blocks of prompts, keyed by their block ID. The blocks form a tree in which a block
+/// is found from the previous block of its prompt by its tokens, so a block is only shared by
+/// sequences whose prompts start with exactly the same tokens. The cache holds a reference to each
+/// block so that it outlives its sequence and can be shared by later sequences with the same
+/// prefix. Blocks only referenced by the cache are evicted in least recently used order once the
+/// allocator runs out of free blocks.
+#[derive(Default)]
+struct PrefixCache {
+    blocks: HashMap<usize, CachedBlock>,
+    /// The IDs of the cached first blocks of prompts, keyed by their tokens.
+    roots: HashMap<Vec<usize>, usize>,
+    /// The blocks which are not used by any sequence, least recently used first. Blocks used at the
+    /// same time are ordered deepest first, so that a block is evicted after the blocks following
+    /// it, which are never used more recently.
+    evictable: BTreeSet<(usize, Reverse<usize>, usize)>,
+    clock: usize,
+}
+
+impl PrefixCache {
+    fn num_evictable(&self) -> usize {
+        self.evictable.len()
+    }
+
+    fn children(&self, parent: Option<usize>) -> &HashMap<Vec<usize>, usize> {
+        match parent {
+            Some(id) => &self.blocks[&id].children,
+            None => &self.roots,
+        }
+    }
+
+    fn children_mut(&mut self, parent: Option<usize>) -> &mut HashMap<Vec<usize>, usize> {
+        match parent {
+            Some(id) => &mut self.blocks.get_mut(&id).unwrap().children,
+            None => &mut self.roots,
+        }
+    }
+
+    /// The IDs of the longest run of cached blocks the prompt starts with.
+    fn find(&self, prompt: &[LogicalTokenBlock]) -> Vec<usize> {
+        let mut ids = Vec::new();
+        for block in prompt {
+            match self.children(ids.last().copied()).get(block.token_ids()) {
+                Some(id) => ids.push(*id),
+                None => break,
+            }
+        }
+        ids
+    }
+
+    /// Mark the blocks found for a prompt as used by a new sequence.
+    fn touch(&mut self, ids: &[usize]) {
+        self.clock += 1;
+        for id in ids {
+            let cached = self.blocks.get_mut(id).unwrap();
+            self.evictable
+                .remove(&(cached.last_used, Reverse(cached.depth), *id));
+            cached.last_used = self.clock;
+        }
+    }
+
+    /// Make the block evictable if it is cached and no longer used by any sequence.
+    fn release(&mut self, id: usize) {
+        if let Some(cached) = self.blocks.get(&id) {
+            if cached.block.deref_mut().refcount == 1 {
+                self.evictable
+                    .insert((cached.last_used, Reverse(cached.depth), id));
+            }
+        }
+    }
+
+    /// Add the blocks holding a computed prompt. Caching stops at a block whose tokens are already
+    /// cached in another block, as every cached block must follow the blocks its sequence uses.
+    fn insert(&mut self, prompt: &[LogicalTokenBlock], block_table: &[Arc<PhysicalTokenBlock>]) {
+        self.clock += 1;
+        let mut parent = None;
+        for (depth, (logical, block)) in prompt.iter().zip(block_table).enumerate() {
+            let id = block.deref_mut().block_id;
+            match self.children(parent).get(logical.token_ids()).copied() {
+                Some(cached) if cached != id => break,
+                Some(_) => (),
+                None => {
+                    block.deref_mut().refcount += 1;
+                    self.children_mut(parent)
+                        .insert(logical.token_ids().to_vec(), id);
+                    self.blocks.insert(
+                        id,
+                        CachedBlock {
+                            block: block.clone(),
+                            parent,
+                            tokens: logical.token_ids().to_vec(),
+                            children: HashMap::new(),
+                            depth,
+                            last_used: self.clock,
+                        },
+                    );
+                }
+            }
+            // The sequence uses the block, so it is not evictable
+            self.blocks.get_mut(&id).unwrap().last_used = self.clock;
+            parent = Some(id);
+        }
+    }
+
+    /// Remove the least recently used block which is not used by any sequence.
+    fn evict(&mut self) -> Option<Arc<PhysicalTokenBlock>> {
+        let (_, _, id) = self.evictable.pop_first()?;
+        let evicted = self.blocks.remove(&id).unwrap();
+        debug_assert!(evicted.children.is_empty());
+        self.children_mut(evicted.parent).remove(&evicted.tokens);
+        Some(evicted.block)
+    }
+}
+
 /// A BlockEngine maps each Sequence (identified by its SeqID), to physical token blocks.
 /// The physical token blocks may not match the logical token blocks because during
 /// scheduling, physical blocks are allocated to accommodate the new tokens generated.
 /// These new tokens will be added to the logical token block for each sequence.
 pub struct BlockEngine {
     num_gpu_blocks: usize,
+    block_size: usize,
     gpu_allocator: Allocator<GPUAllocator>,
     cpu_allocator: Allocator<CPUAllocator>,
+    prefix_cache: Option<PrefixCache>,
     pub block_tables: HashMap<SeqID, BlockTable>,
 }
 
 pub type BlockTables = HashMap<usize, BlockTable>;
 
 impl BlockEngine {
+    /// If `enable_prefix_caching`, full blocks of computed prompts are kept and shared with later
+    /// sequences which start with the same tokens.
     #[must_use]
-    pub fn new(block_size: usize, num_gpu_blocks: usize, num_cpu_blocks: usize) -> Self {
+    pub fn new(
+        block_size: usize,
+        num_gpu_blocks: usize,
+        num_cpu_blocks: usize,
+        enable_prefix_caching: bool,
+    ) -> Self {
         Self {
             num_gpu_blocks,
+            block_size,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
             cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
+            prefix_cache: enable_prefix_caching.then(PrefixCache::default),
             block_tables: HashMap::new(),
         }
     }
 
-    pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
-        let num_required_blocks = seq.get_logical_token_blocks();
-        let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
+    /// The longest run of cached blocks the sequence starts with.
+    fn find_cached_prefix(&self, seq: &impl BlockEngineSequence) -> Vec<Arc<PhysicalTokenBlock>> {
+        let Some(prefix_cache) = &self.prefix_cache else {
+            return Vec::new();
+        };
+        prefix_cache
+            .find(seq.get_prompt_prefix_blocks())
+            .into_iter()
+            .map(|id| prefix_cache.blocks[&id].block.clone())
+            .collect()
+    }
+
+    /// Free GPU blocks, including cached blocks which may be evicted.
+    fn num_available_gpu_blocks(&self) -> usize {
+        *self.gpu_allocator.get_num_free_blocks()
+            + self
+                .prefix_cache
+                .as_ref()
+                .map(PrefixCache::num_evictable)
+                .unwrap_or(0)
+    }
 
-        if *num_free_gpu_blocks < num_required_blocks {
+    fn allocate_gpu_block(&mut self) -> Arc<PhysicalTokenBlock> {
+        if self.gpu_allocator.free_blocks.is_empty() {
+            if let Some(block) = self.prefix_cache.as_mut().and_then(PrefixCache::evict) {
+                self.gpu_allocator.free_block(block);
+            }
+        }
+        self.gpu_allocator.allocate()
+    }
+
+    fn free_gpu_block(&mut self, block: Arc<PhysicalTokenBlock>) {
+        let id = block.deref_mut().block_id;
+        self.gpu_allocator.free_block(block);
+        if let Some(prefix_cache) = &mut self.prefix_cache {
+            prefix_cache.release(id);
+        }
+    }
+
+    /// Number of GPU blocks in use, and the total number of GPU blocks.
+    pub fn gpu_block_usage(&self) -> (usize, usize) {
+        (
+            self.num_gpu_blocks
+                .saturating_sub(self.num_available_gpu_blocks()),
+            self.num_gpu_blocks,
+        )
+    }
+
+    pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
+        let cached = self.find_cached_prefix(seq);
+        let num_required_blocks = seq.get_logical_token_blocks() - cached.len();
+        // Cached blocks which this sequence would use cannot be evicted for it.
+        let num_free_gpu_blocks = self.num_available_gpu_blocks()
+            - cached
+                .iter()
+                .filter(|block| block.deref_mut().refcount == 1)
+                .count();
+
+        if num_free_gpu_blocks < num_required_blocks {
             AllocStatus::Later
         } else if self.num_gpu_blocks < num_required_blocks {
             AllocStatus::Impossible
@@ -216,33 +411,57 @@ impl BlockEngine {
         }
     }
 
-    pub fn allocate(&mut self, seq: &impl BlockEngineSequence) {
-        let mut block_table = Vec::new();
-        for _logcical_idx in 0..seq.get_logical_token_blocks() {
-            block_table.push(self.gpu_allocator.allocate());
+    /// Allocate the blocks of the sequence, reusing cached blocks of its prompt. Returns the number
+    /// of tokens at the start of the sequence which are already in the KV cache.
+    pub fn allocate(&mut self, seq: &impl BlockEngineSequence) -> usize {
+        let cached = self.find_cached_prefix(seq);
+        let num_cached_toks = cached.len() * self.block_size;
+
+        if let Some(prefix_cache) = &mut self.prefix_cache {
+            let ids = cached
+                .iter()
+                .map(|block| block.deref_mut().block_id)
+                .collect::<Vec<_>>();
+            prefix_cache.touch(&ids);
+        }
+        let mut block_table = cached;
+        for block in &block_table {
+            block.deref_mut().refcount += 1;
+        }
+        for _logcical_idx in block_table.len()..seq.get_logical_token_blocks() {
+            block_table.push(self.allocate_gpu_block());
         }
         self.block_tables.insert(seq.get_id(), block_table.clone());
+        num_cached_toks
+    }
+
+    /// Add the blocks holding the computed prompt of the sequence to the prefix cache.
+    pub fn cache_prompt_blocks(&mut self, seq: &impl BlockEngineSequence) {
+        let (Some(prefix_cache), Some(block_table)) =
+            (&mut self.prefix_cache, self.block_tables.get(&seq.get_id()))
+        else {
+            return;
+        };
+        prefix_cache.insert(seq.get_prompt_prefix_blocks(), block_table);
     }
 
     pub fn can_append_token_to_seq(&self, seq: &impl BlockEngineSequence) -> bool {
-        let free_blocks = self.gpu_allocator.get_num_free_blocks();
+        let free_blocks = self.num_available_gpu_blocks();
         // Physical blocks = logical blocks
-        seq.blocks_to_add_new_tok() <= *free_blocks
+        seq.blocks_to_add_new_tok() <= free_blocks
     }
 
     pub fn free_sequence(&mut self, id: usize) {
         // Handle double free if run out of tokens
-        if let Some(block_table) = self.block_tables.get(&id) {
+        if let Some(block_table) = self.block_tables.remove(&id) {
             // Free from block table
             for block in block_table {
                 if block.deref_mut().is_gpu {
-                    self.gpu_allocator.free_block(block.clone())
+                    self.free_gpu_block(block)
                 } else {
-                    self.cpu_allocator.free_block(block.clone())
+                    self.cpu_allocator.free_block(block)
                 }
             }
-
-            self.block_tables.remove(&id);
         }
     }
 
@@ -285,7 +504,11 @@ impl BlockEngine {
                     cpu_block
                 };
             new_block_table.push(cpu_block);
+            let id = gpu_block.deref_mut().block_id;
             self.gpu_allocator.free_block(gpu_block.clone());
+            if let Some(prefix_cache) = &mut self.prefix_cache {
+                prefix_cache.release(id);
+            }
         }
         self.block_tables.insert(seq_id, new_block_table);
 
@@ -301,25 +524,34 @@ impl BlockEngine {
         &mut self,
         sequence: &impl BlockEngineSequence,
     ) -> Option<(usize, usize)> {
-        let table = self.block_tables.get_mut(&sequence.get_id())?;
+        let table = self.block_tables.get(&sequence.get_id())?;
 
         match sequence.blocks_to_add_new_tok() {
             1 => {
-                table.push(self.gpu_allocator.allocate());
+                let new_block = self.allocate_gpu_block();
+                self.block_tables
+                    .get_mut(&sequence.get_id())
+                    .unwrap()
+                    .push(new_block);
                 None
             }
             0 => {
-                let last_block = table.last_mut().unwrap();
+                let last_block = table.last().unwrap().clone();
                 assert!(last_block.deref_mut().is_gpu);
                 if last_block.deref_mut().refcount == 1 {
                     None
                 } else {
                     // We would be writing into shared, so COW.
-                    let new_block = self.gpu_allocator.allocate();
-                    self.gpu_allocator.free_block(last_block.clone());
+                    let new_block = self.allocate_gpu_block();
+                    self.free_gpu_block(last_block.clone());
                     let old_number = last_block.deref_mut().block_id;
                     let new_number = new_block.deref_mut().block_id;
-                    *last_block = new_block;
+                    *self
+                        .block_tables
+                        .get_mut(&sequence.get_id())
+                        .unwrap()
+                        .last_mut()
+                        .unwrap() = new_block;
                     Some((old_number, new_number))
                 }
             }
@@ -376,3 +608,161 @@ impl BlockEngine {
             .collect::<HashMap<_, _>>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocStatus, BlockEngine, LogicalTokenBlock};
+    use crate::paged_attention::BlockEngineSequence;
+
+    const BLOCK_SIZE: usize = 4;
+
+    struct TestSequence {
+        id: usize,
+        blocks: Vec<LogicalTokenBlock>,
+        num_prompt_prefix_blocks: usize,
+    }
+
+    impl TestSequence {
+        fn new(id: usize, prompt: &[usize]) -> Self {
+            let blocks = prompt
+                .chunks(BLOCK_SIZE)
+                .map(|chunk| {
+                    let mut block = LogicalTokenBlock::new(BLOCK_SIZE);
+                    for tok in chunk {
+                        block.append_token_id(*tok);
+                    }
+                    block
+                })
+                .collect();
+            Self {
+                id,
+                blocks,
+                num_prompt_prefix_blocks: (prompt.len() - 1) / BLOCK_SIZE,
+            }
+        }
+    }
+
+    impl BlockEngineSequence for TestSequence {
+        fn blocks_to_add_new_tok(&self) -> usize {
+            0
+        }
+        fn get_id(&self) -> usize {
+            self.id
+        }
+        fn get_logical_token_blocks(&self) -> usize {
+            self.blocks.len()
+        }
+        fn get_prompt_prefix_blocks(&self) -> &[LogicalTokenBlock] {
+            &self.blocks[..self.num_prompt_prefix_blocks]
+        }
+    }
+
+    fn block_ids(engine: &BlockEngine, id: usize) -> Vec<usize> {
+        engine.block_tables[&id]
+            .iter()
+            .map(|block| block.deref_mut().block_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_shares_cached_prompt_blocks() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 4, 0, true);
+
+        let first = TestSequence::new(0, &(0..10).collect::<Vec<_>>());
+        assert_eq!(engine.allocate(&first), 0);
+        engine.cache_prompt_blocks(&first);
+        let first_blocks = block_ids(&engine, 0);
+        engine.free_sequence(0);
+        // The two full prefix blocks stay cached but may be evicted
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 2);
+        assert_eq!(engine.num_available_gpu_blocks(), 4);
+
+        let second = TestSequence::new(1, &[0, 1, 2, 3, 4, 5, 6, 7, 100, 101]);
+        assert!(matches!(engine.can_allocate(&second), AllocStatus::Ok));
+        assert_eq!(engine.allocate(&second), 2 * BLOCK_SIZE);
+        assert_eq!(block_ids(&engine, 1)[..2], first_blocks[..2]);
+
+        // Blocks used by a sequence cannot be evicted
+        let other = TestSequence::new(2, &(200..210).collect::<Vec<_>>());
+        assert!(matches!(engine.can_allocate(&other), AllocStatus::Later));
+
+        engine.free_sequence(1);
+        assert!(matches!(engine.can_allocate(&other), AllocStatus::Ok));
+        assert_eq!(engine.allocate(&other), 0);
+        assert_eq!(engine.num_available_gpu_blocks(), 1);
+    }
+
+    #[test]
+    fn test_cache_is_keyed_on_tokens() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 8, 0, true);
+
+        let first = TestSequence::new(0, &(0..10).collect::<Vec<_>>());
+        engine.allocate(&first);
+        engine.cache_prompt_blocks(&first);
+        engine.free_sequence(0);
+
+        // The second block only matches after the same first block
+        let second = TestSequence::new(1, &[9, 9, 9, 9, 4, 5, 6, 7, 8, 9]);
+        assert!(engine.find_cached_prefix(&second).is_empty());
+        let third = TestSequence::new(2, &[0, 1, 2, 3, 4, 5, 6, 0, 8, 9]);
+        assert_eq!(engine.find_cached_prefix(&third).len(), 1);
+        let fourth = TestSequence::new(3, &(0..9).collect::<Vec<_>>());
+        assert_eq!(engine.find_cached_prefix(&fourth).len(), 2);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 4, 0, true);
+
+        let first = TestSequence::new(0, &(0..6).collect::<Vec<_>>());
+        engine.allocate(&first);
+        engine.cache_prompt_blocks(&first);
+        engine.free_sequence(0);
+        let second = TestSequence::new(1, &(10..16).collect::<Vec<_>>());
+        engine.allocate(&second);
+        engine.cache_prompt_blocks(&second);
+        engine.free_sequence(1);
+        // The first prompt is used again, so the second one is evicted first
+        let again = TestSequence::new(2, &(0..6).collect::<Vec<_>>());
+        assert_eq!(engine.allocate(&again), BLOCK_SIZE);
+        engine.free_sequence(2);
+        assert_eq!(engine.prefix_cache.as_ref().unwrap().num_evictable(), 2);
+
+        let other = TestSequence::new(3, &(100..112).collect::<Vec<_>>());
+        assert!(matches!(engine.can_allocate(&other), AllocStatus::Ok));
+        engine.allocate(&other);
+        assert_eq!(engine.find_cached_prefix(&first).len(), 1);
+        assert!(engine.find_cached_prefix(&second).is_empty());
+        assert_eq!(engine.num_available_gpu_blocks(), 1);
+    }
+
+    #[test]
+    fn test_evicts_later_blocks_first() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 4, 0, true);
+
+        let first = TestSequence::new(0, &(0..10).collect::<Vec<_>>());
+        engine.allocate(&first);
+        engine.cache_prompt_blocks(&first);
+        engine.free_sequence(0);
+
+        // Evicting the first block would leave the second one unreachable
+        let other = TestSequence::new(1, &(100..109).collect::<Vec<_>>());
+        engine.allocate(&other);
+        assert_eq!(engine.find_cached_prefix(&first).len(), 1);
+        assert_eq!(engine.prefix_cache.as_ref().unwrap().blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_no_sharing_without_prefix_caching() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 4, 0, false);
+
+        let first = TestSequence::new(0, &(0..10).collect::<Vec<_>>());
+        engine.allocate(&first);
+        engine.cache_prompt_blocks(&first);
+        engine.free_sequence(0);
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 4);
+
+        let second = TestSequence::new(1, &(0..10).collect::<Vec<_>>());
+        assert_eq!(engine.allocate(&second), 0);
+    }
+}
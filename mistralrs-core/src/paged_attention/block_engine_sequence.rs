@@ -1,5 +1,10 @@
+use super::LogicalTokenBlock;
+
 pub trait BlockEngineSequence {
     fn blocks_to_add_new_tok(&self) -> usize;
     fn get_id(&self) -> usize;
     fn get_logical_token_blocks(&self) -> usize;
+    /// The full logical token blocks of the prompt which may be shared with other sequences,
+    /// excluding the block holding the last prompt token. Empty if the prompt may not be shared.
+    fn get_prompt_prefix_blocks(&self) -> &[LogicalTokenBlock];
 }
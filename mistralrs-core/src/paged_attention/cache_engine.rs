@@ -4,15 +4,18 @@ use std::{
 };
 
 use candle_core::{DType, Device, Result, Tensor};
+#[cfg(all(feature = "cuda", target_family = "unix"))]
 use mistralrs_paged_attn::{copy_blocks, swap_blocks};
 
-use super::config::ModelConfigLike;
+use super::{config::ModelConfigLike, portable};
+use crate::kv_quant::KvCacheDtype;
 
 #[derive(Clone, Debug)]
 pub struct CacheConfig {
     pub block_size: usize,
     pub num_gpu_blocks: usize,
     pub num_cpu_blocks: usize,
+    pub cache_dtype: KvCacheDtype,
 }
 
 pub type KVCache = (Tensor, Tensor);
@@ -56,30 +59,18 @@ impl CacheEngine {
         dtype: DType,
         device: &Device,
     ) -> Result<Vec<KVCache>> {
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
+        let (key_block_shape, value_block_shape) =
+            Self::calculate_block_shapes(model_config, dtype, cache_config, device);
+        let dtype = cache_config.cache_dtype.cache_dtype(dtype);
         let mut gpu_cache = Vec::new();
         for _ in 0..model_config.num_layers() {
             let key_blocks = Tensor::zeros(
-                (
-                    cache_config.num_gpu_blocks,
-                    key_block_shape.0,
-                    key_block_shape.1,
-                    key_block_shape.2,
-                    key_block_shape.3,
-                ),
+                [&[cache_config.num_gpu_blocks], key_block_shape.as_slice()].concat(),
                 dtype,
                 device,
             )?;
             let value_blocks = Tensor::zeros(
-                (
-                    cache_config.num_gpu_blocks,
-                    value_block_shape.0,
-                    value_block_shape.1,
-                    value_block_shape.2,
-                ),
+                [&[cache_config.num_gpu_blocks], value_block_shape.as_slice()].concat(),
                 dtype,
                 device,
             )?;
@@ -94,30 +85,18 @@ impl CacheEngine {
         dtype: DType,
         device: &Device,
     ) -> Result<Vec<KVCache>> {
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
+        let (key_block_shape, value_block_shape) =
+            Self::calculate_block_shapes(model_config, dtype, cache_config, device);
+        let dtype = cache_config.cache_dtype.cache_dtype(dtype);
         let mut cpu_cache = Vec::new();
         for _ in 0..model_config.num_layers() {
             let key_blocks = Tensor::zeros(
-                (
-                    cache_config.num_cpu_blocks,
-                    key_block_shape.0,
-                    key_block_shape.1,
-                    key_block_shape.2,
-                    key_block_shape.3,
-                ),
+                [&[cache_config.num_cpu_blocks], key_block_shape.as_slice()].concat(),
                 dtype,
                 device,
             )?;
             let value_blocks = Tensor::zeros(
-                (
-                    cache_config.num_cpu_blocks,
-                    value_block_shape.0,
-                    value_block_shape.1,
-                    value_block_shape.2,
-                ),
+                [&[cache_config.num_cpu_blocks], value_block_shape.as_slice()].concat(),
                 dtype,
                 device,
             )?;
@@ -128,31 +107,40 @@ impl CacheEngine {
 }
 
 impl CacheEngine {
-    fn calculate_key_block_shape(
+    /// The shapes of a key block and a value block. The CUDA kernels use a layout where the
+    /// tokens of a block are innermost, the portable implementation one where they are outermost.
+    fn calculate_block_shapes(
         model_config: &dyn ModelConfigLike,
         dtype: DType,
-        block_size: usize,
-    ) -> (usize, usize, usize, usize) {
-        let element_size = dtype.size_in_bytes();
-        let x = 16 / element_size;
-        (
-            model_config.num_kv_heads(),
-            model_config.head_dim() / x,
-            block_size,
-            x,
-        )
+        cache_config: &CacheConfig,
+        device: &Device,
+    ) -> (Vec<usize>, Vec<usize>) {
+        let block_size = cache_config.block_size;
+        let num_kv_heads = model_config.num_kv_heads();
+        let head_dim = cache_config
+            .cache_dtype
+            .cache_head_dim(model_config.head_dim());
+        if uses_cuda_kernels(device, cache_config.cache_dtype) {
+            let element_size = dtype.size_in_bytes();
+            let x = 16 / element_size;
+            (
+                vec![num_kv_heads, head_dim / x, block_size, x],
+                vec![num_kv_heads, head_dim, block_size],
+            )
+        } else {
+            (
+                vec![block_size, num_kv_heads, head_dim],
+                vec![block_size, num_kv_heads, head_dim],
+            )
+        }
     }
+}
 
-    fn calculate_value_block_shape(
-        model_config: &dyn ModelConfigLike,
-        block_size: usize,
-    ) -> (usize, usize, usize) {
-        (
-            model_config.num_kv_heads(),
-            model_config.head_dim(),
-            block_size,
-        )
-    }
+/// Quantized caches are not supported by the CUDA kernels.
+fn uses_cuda_kernels(device: &Device, cache_dtype: KvCacheDtype) -> bool {
+    cfg!(all(feature = "cuda", target_family = "unix"))
+        && device.is_cuda()
+        && !cache_dtype.is_quantized()
 }
 
 impl CacheEngine {
@@ -179,10 +167,13 @@ impl CacheEngine {
             let (src_key_cache, src_value_cache) = self.cpu_cache.get(i).unwrap();
             let gpu_cache = self.get_kv_cache();
             let (dst_key_cache, dst_value_cache) = gpu_cache.get(i).unwrap();
-            // Swap (copy) key blocks
-            unsafe { swap_blocks(src_key_cache.clone(), dst_key_cache, src_to_dst.clone())? };
-            // Swap (copy) key blocks
-            unsafe { swap_blocks(src_value_cache.clone(), dst_value_cache, src_to_dst.clone())? };
+            Self::swap_layer(
+                src_key_cache,
+                src_value_cache,
+                dst_key_cache,
+                dst_value_cache,
+                &src_to_dst,
+            )?;
         }
         Ok(())
     }
@@ -194,24 +185,60 @@ impl CacheEngine {
             drop(gpu_cache);
 
             let (dst_key_cache, dst_value_cache) = self.cpu_cache.get(i).unwrap();
+            Self::swap_layer(
+                &src_key_cache,
+                &src_value_cache,
+                dst_key_cache,
+                dst_value_cache,
+                &src_to_dst,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn swap_layer(
+        src_key_cache: &Tensor,
+        src_value_cache: &Tensor,
+        dst_key_cache: &Tensor,
+        dst_value_cache: &Tensor,
+        src_to_dst: &HashMap<usize, usize>,
+    ) -> Result<()> {
+        #[cfg(all(feature = "cuda", target_family = "unix"))]
+        if (src_key_cache.device().is_cuda() || dst_key_cache.device().is_cuda())
+            && src_key_cache.dtype() != DType::U8
+        {
             // Swap (copy) key blocks
             unsafe { swap_blocks(src_key_cache.clone(), dst_key_cache, src_to_dst.clone())? };
-            // Swap (copy) key blocks
+            // Swap (copy) value blocks
             unsafe { swap_blocks(src_value_cache.clone(), dst_value_cache, src_to_dst.clone())? };
+            return Ok(());
         }
-        Ok(())
+        portable::swap_blocks(src_key_cache, dst_key_cache, src_to_dst)?;
+        portable::swap_blocks(src_value_cache, dst_value_cache, src_to_dst)
     }
 
     pub fn copy(&self, src_to_dst: HashMap<usize, Vec<usize>>) -> Result<()> {
+        #[allow(unused_mut)]
         let mut gpu_cache = self.get_kv_cache();
-        #[allow(clippy::map_identity)]
-        let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
-            gpu_cache.iter_mut().map(|(a, b)| (a, b)).unzip();
-        let (key_caches, value_caches) = caches;
 
-        // NOTE(EricLBuehler): This may synchronize the CPU and GPU
-        copy_blocks(key_caches, value_caches, src_to_dst)?;
+        #[cfg(all(feature = "cuda", target_family = "unix"))]
+        if gpu_cache.first().is_some_and(|(key_cache, _)| {
+            key_cache.device().is_cuda() && key_cache.dtype() != DType::U8
+        }) {
+            #[allow(clippy::map_identity)]
+            let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
+                gpu_cache.iter_mut().map(|(a, b)| (a, b)).unzip();
+            let (key_caches, value_caches) = caches;
+
+            // NOTE(EricLBuehler): This may synchronize the CPU and GPU
+            copy_blocks(key_caches, value_caches, src_to_dst)?;
+            return Ok(());
+        }
 
-        Ok(())
+        let caches = gpu_cache
+            .iter()
+            .flat_map(|(key_cache, value_cache)| [key_cache, value_cache])
+            .collect::<Vec<_>>();
+        portable::copy_blocks(&caches, &src_to_dst)
     }
 }
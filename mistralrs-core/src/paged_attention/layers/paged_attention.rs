@@ -1,8 +1,11 @@
 use candle_core::{Device, Result, Tensor};
 
+#[cfg(all(feature = "cuda", target_family = "unix"))]
 use mistralrs_paged_attn::{paged_attention, reshape_and_cache};
 
-use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
+use crate::{
+    paged_attention::portable, pipeline::text_models_inputs_processor::PagedAttentionInputMetadata,
+};
 
 const _PARTITION_SIZE: usize = 512;
 
@@ -47,13 +50,14 @@ impl PagedAttention {
 
     #[allow(clippy::too_many_arguments)]
     #[allow(unused_variables)]
+    #[cfg_attr(not(all(feature = "cuda", target_family = "unix")), allow(unused_mut))]
     /// query: shape = [batch_size, seq_len, num_heads * head_size]
     /// key: shape = [batch_size, seq_len, num_kv_heads * head_size]
     /// value: shape = [batch_size, num_kv_heads * head_size]
     /// key_cache: shape = [num_blocks, num_kv_heads, head_size/x,
-    ///     block_size, x]
+    ///     block_size, x], or [num_blocks, block_size, num_kv_heads, head_size] if not on CUDA
     /// value_cache: shape = [num_blocks, num_kv_heads, head_size,
-    ///     block_size]
+    ///     block_size], or [num_blocks, block_size, num_kv_heads, head_size] if not on CUDA
     /// input_metadata: metadata for paged attention.
     pub fn forward(
         &self,
@@ -80,6 +84,8 @@ impl PagedAttention {
 
         let att = match attention_mask {
             None => None,
+            // Prompts following a cached prefix also attend to the prefix in the KV cache
+            Some(_) if input_metadata.num_cached_tokens.is_some() => None,
             Some(mask) => {
                 //Only perform key/value repeat in prefiling stage, this will reduce kvcache
                 //and remove redundant repeat_kv in decoding stage
@@ -141,11 +147,30 @@ impl PagedAttention {
         // value_cache: &mut Tensor, // [num_blocks, num_heads, head_size, block_size] 48,32,128,16
         // slot_mapping: Tensor,     // [num_tokens]
         if key_cache.as_ref().is_some_and(|_| value_cache.is_some()) {
-            reshape_and_cache(
+            #[cfg(all(feature = "cuda", target_family = "unix"))]
+            if uses_cuda_kernels(key_cache.as_ref().unwrap()) {
+                reshape_and_cache(
+                    &key,
+                    &value,
+                    key_cache.as_mut().unwrap(),
+                    value_cache.as_mut().unwrap(),
+                    &slot_mapping,
+                )?;
+            } else {
+                portable::reshape_and_cache(
+                    &key,
+                    &value,
+                    key_cache.as_ref().unwrap(),
+                    value_cache.as_ref().unwrap(),
+                    &slot_mapping,
+                )?;
+            }
+            #[cfg(not(all(feature = "cuda", target_family = "unix")))]
+            portable::reshape_and_cache(
                 &key,
                 &value,
-                key_cache.as_mut().unwrap(),
-                value_cache.as_mut().unwrap(),
+                key_cache.as_ref().unwrap(),
+                value_cache.as_ref().unwrap(),
                 &slot_mapping,
             )?;
         }
@@ -154,6 +179,24 @@ impl PagedAttention {
             // Return result in prefill
             return Ok(att);
         }
+        if let (Some(_), Some(num_cached_tokens)) =
+            (attention_mask, &input_metadata.num_cached_tokens)
+        {
+            return portable::prompt_attention_with_cached_prefix(
+                &query
+                    .reshape((batch_size, seq_len, attention_heads, head_size))?
+                    .transpose(1, 2)?
+                    .contiguous()?,
+                key_cache.as_ref().unwrap(),
+                value_cache.as_ref().unwrap(),
+                input_metadata.block_tables.as_ref().unwrap(),
+                input_metadata.context_lens.as_ref().unwrap(),
+                num_cached_tokens,
+                self.scale,
+                softcapping,
+                self.sliding_window,
+            );
+        }
         //  Args:
         //  output: shape = [num_generation_tokens, num_heads, head_size]
         //
@@ -168,16 +211,34 @@ impl PagedAttention {
         //  input_metadata: metadata for paged attention.
         //
         //  alibi_slopes: shape = [num_heads]
-        #[allow(clippy::cast_possible_truncation)]
-        paged_attention(
+        #[cfg(all(feature = "cuda", target_family = "unix"))]
+        if uses_cuda_kernels(key_cache.as_ref().unwrap()) {
+            #[allow(clippy::cast_possible_truncation)]
+            return paged_attention(
+                &query,
+                key_cache.as_ref().unwrap(),
+                value_cache.as_ref().unwrap(),
+                input_metadata.block_tables.as_ref().unwrap(),
+                input_metadata.context_lens.as_ref().unwrap(),
+                input_metadata.max_context_len.unwrap(),
+                self.scale,
+                softcapping.unwrap_or(1.0f64) as f32,
+            );
+        }
+        portable::paged_attention(
             &query,
             key_cache.as_ref().unwrap(),
             value_cache.as_ref().unwrap(),
             input_metadata.block_tables.as_ref().unwrap(),
             input_metadata.context_lens.as_ref().unwrap(),
-            input_metadata.max_context_len.unwrap(),
             self.scale,
-            softcapping.unwrap_or(1.0f64) as f32,
+            softcapping,
         )
     }
 }
+
+/// Quantized caches are not supported by the CUDA kernels.
+#[cfg(all(feature = "cuda", target_family = "unix"))]
+fn uses_cuda_kernels(cache: &Tensor) -> bool {
+    cache.device().is_cuda() && cache.dtype() != candle_core::DType::U8
+}
@@ -8,6 +8,8 @@ mod block_engine_sequence;
 mod cache_engine;
 mod config;
 mod layers;
+/// PagedAttention operations for devices without the CUDA kernels.
+mod portable;
 mod scheduler;
 pub const _PAD_SLOT_ID: i64 = -1;
 
@@ -21,7 +23,7 @@ pub use scheduler::{
     PagedAttentionScheduler, PagedAttentionSchedulerConfig, PagedAttentionSchedulerOutput,
 };
 
-use crate::MemoryUsage;
+use crate::{kv_quant::KvCacheDtype, MemoryUsage};
 use tracing::info;
 
 /// All memory counts in MB. Default for block size is 32.
@@ -30,6 +32,7 @@ pub struct PagedAttentionConfig {
     pub(crate) block_size: Option<usize>,
     pub(crate) mem_cpu: usize,
     pub(crate) mem_gpu: MemoryGpuConfig,
+    pub(crate) cache_dtype: KvCacheDtype,
 }
 
 impl PagedAttentionConfig {
@@ -42,8 +45,15 @@ impl PagedAttentionConfig {
             block_size,
             mem_cpu,
             mem_gpu,
+            cache_dtype: KvCacheDtype::Auto,
         })
     }
+
+    /// Quantize the KV cache. Quantized caches do not use the CUDA kernels.
+    pub fn with_kv_cache_dtype(mut self, cache_dtype: KvCacheDtype) -> Self {
+        self.cache_dtype = cache_dtype;
+        self
+    }
 }
 
 pub enum AttentionImplementation {
@@ -93,6 +103,7 @@ pub fn calculate_cache_config(
     mem_cpu: usize,
     block_size: Option<usize>,
     dtype: DType,
+    cache_dtype: KvCacheDtype,
     config: &dyn ModelConfigLike,
     device: &Device,
 ) -> anyhow::Result<CacheConfig> {
@@ -100,7 +111,7 @@ pub fn calculate_cache_config(
     if !SUPPORTED_BLOCK_SIZE.contains(&block_size) {
         anyhow::bail!("Block size must be in {SUPPORTED_BLOCK_SIZE:?}, got {block_size}");
     }
-    let dtype_size = dtype.size_in_bytes();
+    let dtype_size = cache_dtype.cache_dtype(dtype).size_in_bytes();
 
     #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
     let mem_gpu = match mem_gpu {
@@ -127,5 +138,6 @@ pub fn calculate_cache_config(
         block_size,
         num_gpu_blocks,
         num_cpu_blocks,
+        cache_dtype,
     })
 }
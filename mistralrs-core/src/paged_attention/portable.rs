@@ -0,0 +1,517 @@
+//! Portable implementations of the PagedAttention cache and attention operations, written with
+//! candle tensor ops. These are used on every device without the fused CUDA kernels.
+//!
+//! The KV cache layout for these ops is `[num_blocks, block_size, num_kv_heads, head_size]` for both
+//! the keys and the values, so that the slots of a block are contiguous and can be written in place.
+//! Only [`prompt_attention_with_cached_prefix`] also supports the layout of the CUDA kernels.
+//!
+//! The caches may be quantized with a [`KvCacheDtype`], in which case they are `u8` tensors whose
+//! last dimension is [`KvCacheDtype::cache_head_dim`].
+
+use std::collections::HashMap;
+
+use candle_core::{Device, IndexOp, Result, Tensor};
+
+use crate::kv_quant::KvCacheDtype;
+
+/// Write the keys and values into their slots of the caches, in place. Slots of `-1` are padding.
+///
+/// - key, value: `[num_tokens, num_kv_heads, head_size]`
+/// - key_cache, value_cache: `[num_blocks, block_size, num_kv_heads, head_size]`
+/// - slot_mapping: `[num_tokens]`
+pub fn reshape_and_cache(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    let kv_cache_dtype = KvCacheDtype::of_cache(key_cache, key.dim(2)?)?;
+    let (key, value) = (
+        kv_cache_dtype.quantize(key)?,
+        kv_cache_dtype.quantize(value)?,
+    );
+    let (num_blocks, block_size, num_kv_heads, head_size) = key_cache.dims4()?;
+    let key_cache = key_cache.reshape((num_blocks * block_size, num_kv_heads, head_size))?;
+    let value_cache = value_cache.reshape((num_blocks * block_size, num_kv_heads, head_size))?;
+    let slots = slot_mapping.to_device(&Device::Cpu)?.to_vec1::<i64>()?;
+
+    // Copy each run of consecutive slots at once
+    let mut start = 0;
+    while start < slots.len() {
+        let mut end = start + 1;
+        while end < slots.len() && slots[start] >= 0 && slots[end] == slots[end - 1] + 1 {
+            end += 1;
+        }
+        if slots[start] >= 0 {
+            let offset = slots[start] as usize;
+            key_cache.slice_set(&key.narrow(0, start, end - start)?.contiguous()?, 0, offset)?;
+            value_cache.slice_set(
+                &value.narrow(0, start, end - start)?.contiguous()?,
+                0,
+                offset,
+            )?;
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// Attention of one query token per sequence over the tokens stored in the caches.
+///
+/// - query: `[num_seqs, num_heads, head_size]`
+/// - key_cache, value_cache: `[num_blocks, block_size, num_kv_heads, head_size]`
+/// - block_tables: `[num_seqs, max_num_blocks_per_seq]`
+/// - context_lens: `[num_seqs]`
+///
+/// Returns `[num_seqs, num_heads, head_size]`.
+pub fn paged_attention(
+    query: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    softmax_scale: f32,
+    softcapping: Option<f64>,
+) -> Result<Tensor> {
+    let block_size = key_cache.dim(1)?;
+    let (num_seqs, num_heads, head_size) = query.dims3()?;
+    let n_rep = num_heads / key_cache.dim(2)?;
+    let kv_cache_dtype = KvCacheDtype::of_cache(key_cache, head_size)?;
+    let block_tables = block_tables.to_device(&Device::Cpu)?.to_vec2::<u32>()?;
+    let context_lens = context_lens.to_device(&Device::Cpu)?.to_vec1::<u32>()?;
+
+    let mut outputs = Vec::with_capacity(num_seqs);
+    for (i, (block_table, context_len)) in block_tables.iter().zip(context_lens).enumerate() {
+        let context_len = context_len as usize;
+        let num_blocks = context_len.div_ceil(block_size);
+        let blocks = Tensor::new(&block_table[..num_blocks], key_cache.device())?;
+        let k = gather_blocks(key_cache, &blocks, context_len, n_rep, false)?;
+        let v = gather_blocks(value_cache, &blocks, context_len, n_rep, false)?;
+        let (k, v) = (
+            kv_cache_dtype.dequantize(&k, query.dtype())?,
+            kv_cache_dtype.dequantize(&v, query.dtype())?,
+        );
+
+        let q = query.i(i)?.unsqueeze(1)?;
+        let att = (q.matmul(&k.t()?)? * softmax_scale as f64)?;
+        let att = match softcapping {
+            None => att,
+            Some(sc) => ((att / sc)?.tanh()? * sc)?,
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        outputs.push(att.matmul(&v)?.squeeze(1)?);
+    }
+    Tensor::stack(&outputs, 0)
+}
+
+/// Causal attention of the prompt tokens of each sequence, which follow `num_cached_tokens` tokens
+/// whose KV cache was reused from the prefix cache. The keys and values of the prompt tokens must
+/// already be written to the caches.
+///
+/// - query: `[num_seqs, num_heads, q_len, head_size]`
+/// - block_tables: `[num_seqs, max_num_blocks_per_seq]`
+/// - context_lens: `[num_seqs]`, the number of cached and prompt tokens
+///
+/// Returns `[num_seqs, num_heads, q_len, head_size]`.
+#[allow(clippy::too_many_arguments)]
+pub fn prompt_attention_with_cached_prefix(
+    query: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    num_cached_tokens: &[usize],
+    softmax_scale: f32,
+    softcapping: Option<f64>,
+    sliding_window: Option<usize>,
+) -> Result<Tensor> {
+    let cuda_layout = key_cache.rank() == 5;
+    let (block_size, num_kv_heads) = if cuda_layout {
+        (key_cache.dim(3)?, key_cache.dim(1)?)
+    } else {
+        (key_cache.dim(1)?, key_cache.dim(2)?)
+    };
+    let (num_seqs, num_heads, q_len, head_size) = query.dims4()?;
+    let n_rep = num_heads / num_kv_heads;
+    let kv_cache_dtype = KvCacheDtype::of_cache(key_cache, head_size)?;
+    let block_tables = block_tables.to_device(&Device::Cpu)?.to_vec2::<u32>()?;
+    let context_lens = context_lens.to_device(&Device::Cpu)?.to_vec1::<u32>()?;
+
+    let mut outputs = Vec::with_capacity(num_seqs);
+    for (i, (block_table, context_len)) in block_tables.iter().zip(context_lens).enumerate() {
+        let context_len = context_len as usize;
+        let num_blocks = context_len.div_ceil(block_size);
+        let blocks = Tensor::new(&block_table[..num_blocks], key_cache.device())?;
+        let k = gather_blocks(key_cache, &blocks, context_len, n_rep, cuda_layout)?;
+        let v = gather_blocks(value_cache, &blocks, context_len, n_rep, cuda_layout)?;
+        let (k, v) = (
+            kv_cache_dtype.dequantize(&k, query.dtype())?,
+            kv_cache_dtype.dequantize(&v, query.dtype())?,
+        );
+
+        // Query token `r` is at position `num_cached_tokens + r`. Padding tokens past the end of
+        // the prompt attend like the last token.
+        let mask = (0..q_len)
+            .flat_map(|r| {
+                let pos = (num_cached_tokens[i] + r).min(context_len - 1);
+                (0..context_len).map(move |j| {
+                    if j <= pos && sliding_window.map_or(true, |window| pos - j < window) {
+                        0f32
+                    } else {
+                        f32::NEG_INFINITY
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mask = Tensor::from_vec(mask, (q_len, context_len), query.device())?
+            .to_dtype(query.dtype())?;
+
+        let att = (query.i(i)?.matmul(&k.t()?)? * softmax_scale as f64)?;
+        let att = match softcapping {
+            None => att,
+            Some(sc) => ((att / sc)?.tanh()? * sc)?,
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att.broadcast_add(&mask)?)?;
+        outputs.push(att.matmul(&v)?);
+    }
+    Tensor::stack(&outputs, 0)
+}
+
+/// Gather the first `context_len` tokens of the blocks, repeated for each query head:
+/// `[num_kv_heads * n_rep, context_len, head_size]`. The CUDA layout is
+/// `[num_blocks, num_kv_heads, head_size/x, block_size, x]` for keys and
+/// `[num_blocks, num_kv_heads, head_size, block_size]` for values.
+fn gather_blocks(
+    cache: &Tensor,
+    blocks: &Tensor,
+    context_len: usize,
+    n_rep: usize,
+    cuda_layout: bool,
+) -> Result<Tensor> {
+    let gathered = cache.index_select(blocks, 0)?;
+    // [num_kv_heads, num_blocks * block_size, head_size]
+    let gathered = if !cuda_layout {
+        let (num_blocks, block_size, num_kv_heads, head_size) = gathered.dims4()?;
+        gathered
+            .reshape((num_blocks * block_size, num_kv_heads, head_size))?
+            .transpose(0, 1)?
+    } else if gathered.rank() == 5 {
+        let (num_blocks, num_kv_heads, head_size_x, block_size, x) = gathered.dims5()?;
+        gathered.permute((1, 0, 3, 2, 4))?.reshape((
+            num_kv_heads,
+            num_blocks * block_size,
+            head_size_x * x,
+        ))?
+    } else {
+        let (num_blocks, num_kv_heads, head_size, block_size) = gathered.dims4()?;
+        gathered.permute((1, 0, 3, 2))?.reshape((
+            num_kv_heads,
+            num_blocks * block_size,
+            head_size,
+        ))?
+    };
+    let (num_kv_heads, _, head_size) = gathered.dims3()?;
+    gathered
+        .narrow(1, 0, context_len)?
+        .unsqueeze(1)?
+        .broadcast_as((num_kv_heads, n_rep, context_len, head_size))?
+        .reshape((num_kv_heads * n_rep, context_len, head_size))
+}
+
+/// Copy blocks of `src` into blocks of `dst`, which may be on different devices.
+pub fn swap_blocks(
+    src: &Tensor,
+    dst: &Tensor,
+    block_mapping: &HashMap<usize, usize>,
+) -> Result<()> {
+    for (&src_block, &dst_block) in block_mapping {
+        let block = src.narrow(0, src_block, 1)?.to_device(dst.device())?;
+        dst.slice_set(&block, 0, dst_block)?;
+    }
+    Ok(())
+}
+
+/// Copy blocks within each of the caches, from a source block to each of its destination blocks.
+pub fn copy_blocks(caches: &[&Tensor], block_mapping: &HashMap<usize, Vec<usize>>) -> Result<()> {
+    for cache in caches {
+        for (&src_block, dst_blocks) in block_mapping {
+            let block = cache.narrow(0, src_block, 1)?.copy()?;
+            for &dst_block in dst_blocks {
+                cache.slice_set(&block, 0, dst_block)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{
+        copy_blocks, paged_attention, prompt_attention_with_cached_prefix, reshape_and_cache,
+        swap_blocks,
+    };
+    use crate::{
+        attention::{Sdpa, SdpaParams},
+        kv_quant::KvCacheDtype,
+        paged_attention::PagedAttention,
+        pipeline::text_models_inputs_processor::PagedAttentionInputMetadata,
+    };
+
+    const NUM_HEADS: usize = 4;
+    const NUM_KV_HEADS: usize = 2;
+    const HEAD_SIZE: usize = 8;
+    const BLOCK_SIZE: usize = 4;
+    const NUM_BLOCKS: usize = 8;
+
+    fn causal_mask(seq_len: usize, device: &Device) -> candle_core::Result<Tensor> {
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        Tensor::from_slice(&mask, (1, 1, seq_len, seq_len), device)
+    }
+
+    #[test]
+    fn test_matches_non_paged_attention() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let scale = 1. / (HEAD_SIZE as f32).sqrt();
+        let paged_attn = PagedAttention::new(
+            NUM_HEADS,
+            HEAD_SIZE,
+            scale,
+            Some(NUM_KV_HEADS),
+            None,
+            &dev,
+            None,
+        )?;
+        let sdpa_params = SdpaParams {
+            n_kv_groups: NUM_HEADS / NUM_KV_HEADS,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: scale,
+            sliding_window: None,
+        };
+        let cache_shape = (NUM_BLOCKS, BLOCK_SIZE, NUM_KV_HEADS, HEAD_SIZE);
+        let key_cache = Tensor::zeros(cache_shape, DType::F32, &dev)?;
+        let value_cache = Tensor::zeros(cache_shape, DType::F32, &dev)?;
+
+        // Out of order blocks, so the last block is only partially filled
+        let block_table = [5u32, 2, 7];
+        let slot = |pos: usize| {
+            (block_table[pos / BLOCK_SIZE] as usize * BLOCK_SIZE + pos % BLOCK_SIZE) as i64
+        };
+        let prompt_len = 9;
+
+        let q = Tensor::randn(0f32, 1., (1, NUM_HEADS, prompt_len + 1, HEAD_SIZE), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, NUM_KV_HEADS, prompt_len + 1, HEAD_SIZE), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, NUM_KV_HEADS, prompt_len + 1, HEAD_SIZE), &dev)?;
+
+        // Prompt
+        let mask = causal_mask(prompt_len, &dev)?;
+        let mut metadata = PagedAttentionInputMetadata {
+            block_tables: None,
+            context_lens: None,
+            slot_mappings: Tensor::new((0..prompt_len).map(slot).collect::<Vec<_>>(), &dev)?,
+            max_context_len: None,
+            num_cached_tokens: None,
+        };
+        let (q_prompt, k_prompt, v_prompt) = (
+            q.narrow(2, 0, prompt_len)?.contiguous()?,
+            k.narrow(2, 0, prompt_len)?.contiguous()?,
+            v.narrow(2, 0, prompt_len)?.contiguous()?,
+        );
+        let paged = paged_attn.forward(
+            &q_prompt,
+            &k_prompt,
+            &v_prompt,
+            Some(&mask),
+            Some(key_cache.clone()),
+            Some(value_cache.clone()),
+            &mut metadata,
+            None,
+        )?;
+        let expected = Sdpa.run_attention(
+            &q_prompt,
+            &k_prompt,
+            &v_prompt,
+            Some(&mask),
+            None,
+            &sdpa_params,
+        )?;
+        let diff = (paged - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "prompt diff {diff}");
+
+        // Completion, attending to the cached prompt
+        let mut metadata = PagedAttentionInputMetadata {
+            block_tables: Some(Tensor::new(&[block_table], &dev)?),
+            context_lens: Some(Tensor::new(&[prompt_len as u32 + 1], &dev)?),
+            slot_mappings: Tensor::new(&[slot(prompt_len)], &dev)?,
+            max_context_len: Some(prompt_len + 1),
+            num_cached_tokens: None,
+        };
+        let q_completion = q.narrow(2, prompt_len, 1)?.contiguous()?;
+        let paged = paged_attn.forward(
+            &q_completion,
+            &k.narrow(2, prompt_len, 1)?.contiguous()?,
+            &v.narrow(2, prompt_len, 1)?.contiguous()?,
+            None,
+            Some(key_cache),
+            Some(value_cache),
+            &mut metadata,
+            None,
+        )?;
+        let expected = Sdpa.run_attention(&q_completion, &k, &v, None, None, &sdpa_params)?;
+        let diff = (paged.reshape(expected.shape())? - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "completion diff {diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantized_cache() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let scale = 1. / (HEAD_SIZE as f32).sqrt();
+        let sdpa_params = SdpaParams {
+            n_kv_groups: NUM_HEADS / NUM_KV_HEADS,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: scale,
+            sliding_window: None,
+        };
+        let num_toks = 6;
+        let k = Tensor::randn(0f32, 1., (num_toks, NUM_KV_HEADS, HEAD_SIZE), &dev)?;
+        let v = Tensor::randn(0f32, 1., (num_toks, NUM_KV_HEADS, HEAD_SIZE), &dev)?;
+        let q = Tensor::randn(0f32, 1., (1, NUM_HEADS, HEAD_SIZE), &dev)?;
+        let expected = Sdpa.run_attention(
+            &q.unsqueeze(2)?,
+            &k.transpose(0, 1)?.unsqueeze(0)?,
+            &v.transpose(0, 1)?.unsqueeze(0)?,
+            None,
+            None,
+            &sdpa_params,
+        )?;
+
+        for (kv_cache_dtype, tolerance) in [(KvCacheDtype::Q8, 0.02), (KvCacheDtype::F8E4M3, 0.2)] {
+            let cache_shape = (
+                NUM_BLOCKS,
+                BLOCK_SIZE,
+                NUM_KV_HEADS,
+                kv_cache_dtype.cache_head_dim(HEAD_SIZE),
+            );
+            let key_cache = Tensor::zeros(cache_shape, DType::U8, &dev)?;
+            let value_cache = Tensor::zeros(cache_shape, DType::U8, &dev)?;
+            let block_table = [3u32, 6];
+            let slots = (0..num_toks)
+                .map(|pos| {
+                    (block_table[pos / BLOCK_SIZE] as usize * BLOCK_SIZE + pos % BLOCK_SIZE) as i64
+                })
+                .collect::<Vec<_>>();
+            reshape_and_cache(&k, &v, &key_cache, &value_cache, &Tensor::new(slots, &dev)?)?;
+
+            let paged = paged_attention(
+                &q,
+                &key_cache,
+                &value_cache,
+                &Tensor::new(&[block_table], &dev)?,
+                &Tensor::new(&[num_toks as u32], &dev)?,
+                scale,
+                None,
+            )?;
+            let diff = (paged.reshape(expected.shape())? - &expected)?
+                .abs()?
+                .max_all()?
+                .to_scalar::<f32>()?;
+            assert!(diff < tolerance, "{kv_cache_dtype} diff {diff}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_prefix_sliding_window() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let scale = 1. / (HEAD_SIZE as f32).sqrt();
+        let sdpa_params = SdpaParams {
+            n_kv_groups: NUM_HEADS / NUM_KV_HEADS,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: scale,
+            sliding_window: None,
+        };
+        let (num_cached, q_len, window) = (3, 4, 2);
+        let num_toks = num_cached + q_len;
+        let k = Tensor::randn(0f32, 1., (num_toks, NUM_KV_HEADS, HEAD_SIZE), &dev)?;
+        let v = Tensor::randn(0f32, 1., (num_toks, NUM_KV_HEADS, HEAD_SIZE), &dev)?;
+        let q = Tensor::randn(0f32, 1., (1, NUM_HEADS, q_len, HEAD_SIZE), &dev)?;
+
+        let cache_shape = (NUM_BLOCKS, BLOCK_SIZE, NUM_KV_HEADS, HEAD_SIZE);
+        let key_cache = Tensor::zeros(cache_shape, DType::F32, &dev)?;
+        let value_cache = Tensor::zeros(cache_shape, DType::F32, &dev)?;
+        let block_table = [4u32, 1];
+        let slots = (0..num_toks)
+            .map(|pos| {
+                (block_table[pos / BLOCK_SIZE] as usize * BLOCK_SIZE + pos % BLOCK_SIZE) as i64
+            })
+            .collect::<Vec<_>>();
+        reshape_and_cache(&k, &v, &key_cache, &value_cache, &Tensor::new(slots, &dev)?)?;
+
+        let paged = prompt_attention_with_cached_prefix(
+            &q,
+            &key_cache,
+            &value_cache,
+            &Tensor::new(&[block_table], &dev)?,
+            &Tensor::new(&[num_toks as u32], &dev)?,
+            &[num_cached],
+            scale,
+            None,
+            Some(window),
+        )?;
+
+        // Each query token only attends to itself and the token before it
+        let mask: Vec<f32> = (0..q_len)
+            .flat_map(|r| {
+                let pos = num_cached + r;
+                (0..num_toks).map(move |j| {
+                    if j <= pos && j + window > pos {
+                        0.
+                    } else {
+                        f32::NEG_INFINITY
+                    }
+                })
+            })
+            .collect();
+        let mask = Tensor::from_slice(&mask, (1, 1, q_len, num_toks), &dev)?;
+        let expected = Sdpa.run_attention(
+            &q,
+            &k.transpose(0, 1)?.unsqueeze(0)?.contiguous()?,
+            &v.transpose(0, 1)?.unsqueeze(0)?.contiguous()?,
+            Some(&mask),
+            None,
+            &sdpa_params,
+        )?;
+        let diff = (paged - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "sliding window diff {diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_and_copy_blocks() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let src = Tensor::arange(0f32, 4. * 3., &dev)?.reshape((4, 3))?;
+        let dst = Tensor::zeros((4, 3), DType::F32, &dev)?;
+        swap_blocks(&src, &dst, &[(1, 0), (3, 2)].into())?;
+        assert_eq!(
+            dst.to_vec2::<f32>()?,
+            [[3., 4., 5.], [0., 0., 0.], [9., 10., 11.], [0., 0., 0.]]
+        );
+        copy_blocks(&[&dst], &[(0, vec![1, 3])].into())?;
+        assert_eq!(
+            dst.to_vec2::<f32>()?,
+            [[3., 4., 5.], [3., 4., 5.], [9., 10., 11.], [3., 4., 5.]]
+        );
+        Ok(())
+    }
+}
@@ -10,18 +10,20 @@ type SrcBlockFrom = usize;
 type DstBlocksTo = Vec<usize>;
 
 use std::{
-    collections::{HashMap, VecDeque},
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
     sync::{atomic::Ordering, Arc, Mutex},
 };
 
+use tokio::sync::mpsc::Sender;
 use tracing::warn;
 
 use crate::{
     get_mut_arcmutex,
     paged_attention::BlockEngine,
-    scheduler::{Scheduler, SchedulerOutput},
+    scheduler::{fair_order, Scheduler, SchedulerOutput},
     sequence::{Sequence, SequenceState, StopReason},
-    TERMINATE_ALL_NEXT_STEP,
+    RequestPriority, Response, TERMINATE_ALL_NEXT_STEP,
 };
 
 use super::{block_engine::AllocStatus, BlockEngineSequence, BlockTables, CacheConfig};
@@ -36,6 +38,8 @@ pub struct PagedAttentionSchedulerOutput {
 
 pub struct PagedAttentionSchedulerConfig {
     pub max_num_seqs: usize,
+    /// Share the KV cache blocks of common prompt prefixes between sequences.
+    pub enable_prefix_caching: bool,
 }
 
 pub struct PagedAttentionScheduler {
@@ -45,6 +49,9 @@ pub struct PagedAttentionScheduler {
     config: PagedAttentionSchedulerConfig,
     pub block_engine: BlockEngine,
     block_size: usize,
+    cancelled_requests: HashSet<usize>,
+    /// Sequences of the last prompt step, whose blocks are added to the prefix cache once computed.
+    last_prompt_seqs: Vec<Arc<Mutex<Sequence>>>,
 }
 
 impl PagedAttentionScheduler {
@@ -53,34 +60,88 @@ impl PagedAttentionScheduler {
             waiting: VecDeque::new(),
             running: VecDeque::new(),
             swapped_out: VecDeque::new(),
-            config,
             block_engine: BlockEngine::new(
                 cache_config.block_size,
                 cache_config.num_gpu_blocks,
                 cache_config.num_cpu_blocks,
+                config.enable_prefix_caching,
             ),
+            config,
             block_size: cache_config.block_size,
+            cancelled_requests: HashSet::new(),
+            last_prompt_seqs: Vec::new(),
         }
     }
 
     pub fn schedule(&mut self) -> PagedAttentionSchedulerOutput {
+        // Forget cancelled requests which have no sequences left
+        let (waiting, running, swapped_out) = (&self.waiting, &self.running, &self.swapped_out);
+        self.cancelled_requests.retain(|id| {
+            waiting
+                .iter()
+                .chain(running)
+                .chain(swapped_out)
+                .any(|seq| get_mut_arcmutex!(seq).request_id() == *id)
+        });
+
         // If there are no swapped seqs (they have higher priority), add seqs that are in the
         // waiting queue to the running queue.
         if self.swapped_out.is_empty() {
+            // Admit higher priority classes first, and share each class fairly between tenants.
+            let running_tenants = self
+                .running
+                .iter()
+                .map(|seq| get_mut_arcmutex!(seq).tenant().map(ToString::to_string))
+                .collect::<Vec<_>>();
+            let mut waiting: VecDeque<_> = fair_order(
+                Vec::from(std::mem::take(&mut self.waiting)),
+                running_tenants,
+                |seq| {
+                    let seq = get_mut_arcmutex!(seq);
+                    (seq.priority(), seq.tenant().map(ToString::to_string), 1)
+                },
+            )
+            .into();
+
             let mut scheduled = VecDeque::new();
             let mut did_ignore = false;
-            while !self.waiting.is_empty() {
-                let seq = self.waiting.front().unwrap().clone();
+            while let Some(seq) = waiting.pop_front() {
+                let priority = get_mut_arcmutex!(seq).priority();
+
+                // Embedding sequences are not batched with other sequences, nor are sequences
+                // which return prompt logprobs.
+                if scheduled
+                    .front()
+                    .is_some_and(|first: &Arc<Mutex<Sequence>>| {
+                        !get_mut_arcmutex!(first).can_batch_prompt_with(&get_mut_arcmutex!(seq))
+                    })
+                {
+                    waiting.push_front(seq);
+                    break;
+                }
 
-                // If adding this seq means we will have too many, stop as no more could be added.
-                if self.config.max_num_seqs == self.running.len() + 1 {
+                // If adding this seq means we will have too many, make room by preempting a
+                // sequence of a lower priority class, or stop as no more could be added.
+                if self.config.max_num_seqs == self.running.len() + 1
+                    && !self._preempt_lower_priority(priority)
+                {
+                    waiting.push_front(seq);
                     break;
                 }
 
                 // If we cannot allocate either now or in the future, either do not continue or remove the sequence.
-                let can_allocate = self.block_engine.can_allocate(&*get_mut_arcmutex!(seq));
+                let mut can_allocate = self.block_engine.can_allocate(&*get_mut_arcmutex!(seq));
+                while matches!(can_allocate, AllocStatus::Later)
+                    && self._preempt_lower_priority(priority)
+                {
+                    can_allocate = self.block_engine.can_allocate(&*get_mut_arcmutex!(seq));
+                }
                 match can_allocate {
-                    AllocStatus::Later => break, // If we can only allocate later, do not bother iterating over the rest.
+                    AllocStatus::Later => {
+                        // If we can only allocate later, do not bother iterating over the rest.
+                        waiting.push_front(seq);
+                        break;
+                    }
                     AllocStatus::Impossible => {
                         let id = *get_mut_arcmutex!(seq).id();
                         let len = get_mut_arcmutex!(seq).get_toks().len();
@@ -95,19 +156,21 @@ impl PagedAttentionScheduler {
 
                 if !did_ignore {
                     get_mut_arcmutex!(seq).set_state(SequenceState::RunningPrompt);
-                    let seq_handle = get_mut_arcmutex!(seq);
-                    self._allocate(&seq_handle);
+                    let mut seq_handle = get_mut_arcmutex!(seq);
+                    self._allocate(&mut seq_handle);
                 }
 
-                let seq = self.waiting.pop_front().unwrap();
                 self.running.push_back(seq.clone());
                 if !did_ignore {
                     scheduled.push_back(seq);
                 }
             }
+            // Sequences preempted for higher priority classes are already waiting in front.
+            self.waiting.extend(waiting);
 
             // If we did schedule, or we ignored sequences.
             if !scheduled.is_empty() || did_ignore {
+                self.last_prompt_seqs = scheduled.iter().cloned().collect();
                 return PagedAttentionSchedulerOutput {
                     scheduled: scheduled.into(),
                     blocks_to_swap_in: HashMap::new(),
@@ -127,7 +190,8 @@ impl PagedAttentionScheduler {
         // sequences, which will be put into the waiting or swapped out state depending on
         // the preemption method (recompute or swap, respectively).
 
-        // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
+        // Sorts by priority class and creation time, in descending order so that the lowest classes
+        // are preempted first.
         self.sort_running_by_priority_fcfs();
 
         let mut running = VecDeque::new();
@@ -168,7 +232,8 @@ impl PagedAttentionScheduler {
         // Try to swap in the swapped out sequences and add these to the
         // running state if possible.
 
-        // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
+        // Sorts by priority class and creation time, in descending order so that the highest classes
+        // are swapped in first.
         self.sort_swapped_out_by_priority_fcfs();
 
         if !did_preempt {
@@ -204,6 +269,14 @@ impl PagedAttentionScheduler {
             TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
         }
 
+        // Sequences of cancelled requests are finished on their next completion step
+        self.running.iter().for_each(|seq| {
+            let seq = get_mut_arcmutex!(seq);
+            if self.cancelled_requests.contains(&seq.request_id()) {
+                seq.set_state(SequenceState::Done(StopReason::Canceled))
+            }
+        });
+
         PagedAttentionSchedulerOutput {
             scheduled: self.running.clone().into(), // Clone should be cheap.
             blocks_to_swap_in,
@@ -213,6 +286,17 @@ impl PagedAttentionScheduler {
     }
 
     pub fn free_finished_sequence_groups(&mut self) {
+        // The prompts of the last step are now in the KV cache, so their blocks can be shared.
+        for seq in std::mem::take(&mut self.last_prompt_seqs) {
+            let seq = get_mut_arcmutex!(seq);
+            if !matches!(
+                seq.getstate(),
+                SequenceState::Error | SequenceState::FinishedAborted
+            ) {
+                self.block_engine.cache_prompt_blocks(&*seq);
+            }
+        }
+
         let mut to_free_ids = Vec::new();
         self.running.retain(|seq| {
             if get_mut_arcmutex!(seq).is_finished_paged_attn() {
@@ -289,6 +373,34 @@ impl PagedAttentionScheduler {
         self._preempt_by_recompute(seq)
     }
 
+    /// Preempt, by recomputation, the running sequence of the lowest priority class below
+    /// `priority`, the latest one first. Returns whether a sequence was preempted. Beams are not
+    /// preempted, as they choose their tokens together, and neither are sequences of cancelled
+    /// requests, which finish on their next step.
+    fn _preempt_lower_priority(&mut self, priority: RequestPriority) -> bool {
+        let preempted = self
+            .running
+            .iter()
+            .enumerate()
+            .filter_map(|(i, seq)| {
+                let seq = get_mut_arcmutex!(seq);
+                (seq.priority() < priority
+                    && !seq.is_finished_paged_attn()
+                    && seq.beam_search().is_none()
+                    && !self.cancelled_requests.contains(&seq.request_id()))
+                .then(|| (i, seq.priority(), seq.timestamp()))
+            })
+            .min_by_key(|(_, priority, timestamp)| (*priority, Reverse(*timestamp)));
+        match preempted {
+            Some((i, _, _)) => {
+                let seq = self.running.remove(i).unwrap();
+                self._preempt_by_recompute(seq);
+                true
+            }
+            None => false,
+        }
+    }
+
     fn _preempt_by_recompute(&mut self, seq: Arc<Mutex<Sequence>>) {
         get_mut_arcmutex!(seq).set_state(SequenceState::Waiting);
         self._free(get_mut_arcmutex!(seq).get_id());
@@ -313,8 +425,9 @@ impl PagedAttentionScheduler {
         self.swapped_out.push_back(seq);
     }
 
-    fn _allocate(&mut self, seq: &Sequence) {
-        self.block_engine.allocate(seq)
+    fn _allocate(&mut self, seq: &mut Sequence) {
+        let num_cached_toks = self.block_engine.allocate(seq);
+        seq.set_num_cached_prompt_toks(num_cached_toks);
     }
 
     fn _free(&mut self, seq_id: usize) {
@@ -322,16 +435,18 @@ impl PagedAttentionScheduler {
     }
 
     fn sort_running_by_priority_fcfs(&mut self) {
-        self.running
-            .make_contiguous()
-            .sort_by_key(|seq| get_mut_arcmutex!(seq).timestamp());
+        self.running.make_contiguous().sort_by_key(|seq| {
+            let seq = get_mut_arcmutex!(seq);
+            (seq.priority(), seq.timestamp())
+        });
         self.running.make_contiguous().reverse();
     }
 
     fn sort_swapped_out_by_priority_fcfs(&mut self) {
-        self.swapped_out
-            .make_contiguous()
-            .sort_by_key(|seq| get_mut_arcmutex!(seq).timestamp());
+        self.swapped_out.make_contiguous().sort_by_key(|seq| {
+            let seq = get_mut_arcmutex!(seq);
+            (seq.priority(), seq.timestamp())
+        });
         self.swapped_out.make_contiguous().reverse();
     }
 }
@@ -360,7 +475,130 @@ impl Scheduler for PagedAttentionScheduler {
     fn free_finished_sequence_groups(&mut self) {
         self.free_finished_sequence_groups()
     }
+    fn cancel_request(
+        &mut self,
+        request_id: usize,
+        model: &str,
+    ) -> Vec<(Sender<Response>, Response)> {
+        self.cancelled_requests.insert(request_id);
+        // Waiting sequences hold no blocks
+        let mut responses = Vec::new();
+        self.waiting.retain(|seq| {
+            let seq = get_mut_arcmutex!(seq);
+            if seq.request_id() != request_id {
+                return true;
+            }
+            if let Some(response) = seq.finish_cancelled(model) {
+                responses.push((seq.responder(), response));
+            }
+            false
+        });
+        responses
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PagedAttentionScheduler, PagedAttentionSchedulerConfig};
+    use crate::{
+        get_mut_arcmutex,
+        kv_quant::KvCacheDtype,
+        paged_attention::CacheConfig,
+        scheduler::{test_sequence, Scheduler},
+        RequestPriority, Response,
+    };
+
+    const BLOCK_SIZE: usize = 4;
+
+    fn new_scheduler(max_num_seqs: usize, num_gpu_blocks: usize) -> PagedAttentionScheduler {
+        PagedAttentionScheduler::new(
+            PagedAttentionSchedulerConfig {
+                max_num_seqs,
+                enable_prefix_caching: false,
+            },
+            CacheConfig {
+                block_size: BLOCK_SIZE,
+                num_gpu_blocks,
+                num_cpu_blocks: 0,
+                cache_dtype: KvCacheDtype::Auto,
+            },
+        )
+    }
+
+    #[test]
+    fn test_cancelled_waiting_request_is_finished() {
+        let mut scheduler = new_scheduler(4, 16);
+        scheduler.add_seq(test_sequence(
+            0,
+            0,
+            4,
+            RequestPriority::Normal,
+            Some(BLOCK_SIZE),
+        ));
+        scheduler.add_seq(test_sequence(
+            1,
+            1,
+            4,
+            RequestPriority::Normal,
+            Some(BLOCK_SIZE),
+        ));
+        let responses = scheduler.cancel_request(0, "model");
+        assert_eq!(responses.len(), 1);
+        match &responses[0].1 {
+            Response::CompletionDone(response) => {
+                assert_eq!(response.choices.len(), 1);
+                assert_eq!(response.choices[0].finish_reason, "canceled");
+            }
+            _ => panic!("Expected the final response of the cancelled request"),
+        }
+        assert_eq!(scheduler.waiting_len(), 1);
+
+        let output = scheduler.schedule();
+        let scheduled = output
+            .scheduled
+            .iter()
+            .map(|seq| *get_mut_arcmutex!(seq).id())
+            .collect::<Vec<_>>();
+        assert_eq!(scheduled, vec![1]);
+        assert_eq!(scheduler.waiting_len(), 0);
+        assert_eq!(scheduler.running_len(), 1);
+        // Only the scheduled sequence holds blocks
+        assert_eq!(scheduler.block_engine.block_tables.len(), 1);
+    }
+
+    #[test]
+    fn test_lower_priority_is_preempted() {
+        let mut scheduler = new_scheduler(2, 16);
+        scheduler.add_seq(test_sequence(
+            0,
+            0,
+            4,
+            RequestPriority::Low,
+            Some(BLOCK_SIZE),
+        ));
+        assert_eq!(scheduler.schedule().scheduled.len(), 1);
+
+        scheduler.add_seq(test_sequence(
+            1,
+            1,
+            4,
+            RequestPriority::High,
+            Some(BLOCK_SIZE),
+        ));
+        let output = scheduler.schedule();
+        let scheduled = output
+            .scheduled
+            .iter()
+            .map(|seq| *get_mut_arcmutex!(seq).id())
+            .collect::<Vec<_>>();
+        assert_eq!(scheduled, vec![1]);
+        assert_eq!(scheduler.running_len(), 1);
+        assert_eq!(scheduler.waiting_len(), 1);
+        // The preempted sequence is recomputed, so it does not keep its blocks
+        assert!(get_mut_arcmutex!(scheduler.waiting[0]).is_waiting());
+        assert_eq!(scheduler.block_engine.block_tables.len(), 1);
+    }
+}
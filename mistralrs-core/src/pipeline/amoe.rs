@@ -20,12 +20,13 @@ use tracing::{info, warn};
 use crate::{
     amoe::{AnyMoeConfig, AnyMoeTrainingInputRow, AnyMoeTrainingInputs, AnyMoeTrainingResult},
     get_mut_arcmutex,
+    kv_quant::KvCacheDtype,
     prefix_cacher::PrefixCacheManager,
     sampler::Sampler,
     sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
     utils::progress::NiceProgressBar,
     DeviceMapMetadata, Loader, ModelCategory, ModelKind, ModelPaths, PagedAttentionConfig,
-    Pipeline, Response, TokenSource, TryIntoDType,
+    Pipeline, RequestPriority, Response, TokenSource, TryIntoDType,
 };
 
 use super::{
@@ -197,6 +198,9 @@ impl CacheManagerMixin for AnyMoePipeline {
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
         get_mut_arcmutex!(self.target).set_none_cache(reset_non_granular, modify_draft_cache)
     }
+    fn set_kv_cache_dtype(&self, kv_cache_dtype: KvCacheDtype) {
+        get_mut_arcmutex!(self.target).set_kv_cache_dtype(kv_cache_dtype)
+    }
 }
 
 impl IsqPipelineMixin for AnyMoePipeline {
@@ -244,6 +248,13 @@ impl Pipeline for AnyMoePipeline {
         get_mut_arcmutex!(self.target).forward_inputs(inputs)
     }
 
+    fn forward_hidden_states(
+        &mut self,
+        inputs: Box<dyn Any>,
+    ) -> Result<Tensor, candle_core::Error> {
+        get_mut_arcmutex!(self.target).forward_hidden_states(inputs)
+    }
+
     async fn sample_causal_gen(
         &self,
         seqs: &mut [&mut Sequence],
@@ -364,6 +375,13 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             -1,
             0.0,
             0.0,
+            1.0,
+            0.0,
+            None,
+            None,
+            None,
+            None,
+            None,
             vec![],
         )
         .map_err(candle_core::Error::msg)?;
@@ -549,9 +567,12 @@ fn new_dummy_seq(
         prompt,
         0,
         0,
+        0,
         1,
         dummy_sender,
         dummy_sampler,
+        None,
+        None,
         vec![],
         vec![],
         None,
@@ -571,5 +592,10 @@ fn new_dummy_seq(
         None,
         SeqStepType::PromptAndDecode,
         None,
+        None,
+        false,
+        None,
+        RequestPriority::Normal,
+        None,
     )
 }
@@ -2,7 +2,7 @@ use std::sync::{Arc, Mutex, MutexGuard};
 
 use candle_core::{Tensor, D};
 
-use crate::{get_mut_arcmutex, sequence::Sequence};
+use crate::{get_mut_arcmutex, kv_quant::KvCacheDtype, sequence::Sequence};
 
 use super::{CacheManagerMixin, MetadataMixin};
 
@@ -25,6 +25,7 @@ pub struct Cache {
     xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
     draft_cache: Arc<Mutex<LayerCaches>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    kv_cache_dtype: Arc<Mutex<KvCacheDtype>>,
 }
 
 impl Cache {
@@ -42,9 +43,22 @@ impl Cache {
             } else {
                 None
             },
+            kv_cache_dtype: Arc::new(Mutex::new(KvCacheDtype::Auto)),
         }
     }
 
+    /// DType of the keys and values stored in this cache, which must be passed to
+    /// [`Cache::update_kv_cache`] and [`Cache::update_kv_cache_sliding_window`].
+    pub(crate) fn kv_cache_dtype(&self) -> KvCacheDtype {
+        *get_mut_arcmutex!(self.kv_cache_dtype)
+    }
+
+    /// Set the dtype of the keys and values stored in this cache. This must be set before the
+    /// cache holds any keys and values.
+    pub(crate) fn set_kv_cache_dtype(&self, kv_cache_dtype: KvCacheDtype) {
+        *get_mut_arcmutex!(self.kv_cache_dtype) = kv_cache_dtype;
+    }
+
     pub(crate) fn lock(&self) -> MutexGuard<'_, LayerCaches> {
         get_mut_arcmutex!(self.cache)
     }
@@ -72,17 +86,41 @@ impl Cache {
         self.xlora_cache.is_some()
     }
 
+    /// Reorder the sequences of the batch: sequence `i` of the new KV cache is sequence `rows[i]`
+    /// of the current one. Used by beam search when beams are pruned.
+    pub(crate) fn reorder_batch(&self, rows: &[usize]) -> candle_core::Result<()> {
+        fn reorder(cache: &mut LayerCaches, rows: &[u32]) -> candle_core::Result<()> {
+            // Layers without a KV cache are the cross attention layers of llama 3.2 vision
+            for (k, v) in cache.iter_mut().flatten() {
+                let rows = Tensor::from_slice(rows, rows.len(), k.device())?;
+                *k = k.index_select(&rows, 0)?;
+                *v = v.index_select(&rows, 0)?;
+            }
+            Ok(())
+        }
+
+        let rows = rows.iter().map(|row| *row as u32).collect::<Vec<_>>();
+        reorder(&mut self.lock(), &rows)?;
+        if self.is_xlora() {
+            reorder(&mut self.xlora_lock(), &rows)?;
+        }
+        Ok(())
+    }
+
     /// Update the KV cache and return (k,v)
     pub(crate) fn update_kv_cache(
         cache: &mut Option<(Tensor, Tensor)>,
         k: Tensor,
         v: Tensor,
+        kv_cache_dtype: KvCacheDtype,
         slow_cat: bool,
     ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        let dtype = k.dtype();
+        let (k, v) = (kv_cache_dtype.quantize(&k)?, kv_cache_dtype.quantize(&v)?);
         let (k, v) = match &*cache {
             None => (k, v),
             Some((k_cache, v_cache)) => {
-                if !slow_cat {
+                if !slow_cat && !kv_cache_dtype.is_quantized() {
                     let k = candle_nn::ops::kvconcat(k_cache, &k, 2)?.contiguous()?;
                     let v = candle_nn::ops::kvconcat(v_cache, &v, 2)?.contiguous()?;
                     (k, v)
@@ -94,7 +132,10 @@ impl Cache {
             }
         };
         *cache = Some((k.clone(), v.clone()));
-        Ok((k, v))
+        Ok((
+            kv_cache_dtype.dequantize(&k, dtype)?,
+            kv_cache_dtype.dequantize(&v, dtype)?,
+        ))
     }
 
     /// Update the KV cache and return (k,v,attn_mask)
@@ -104,8 +145,11 @@ impl Cache {
         v: Tensor,
         attention_mask: Option<&Tensor>,
         sliding_window: Option<usize>,
+        kv_cache_dtype: KvCacheDtype,
         slow_cat: bool,
     ) -> Result<(Tensor, Tensor, Option<Tensor>), candle_core::Error> {
+        let dtype = k.dtype();
+        let (k, v) = (kv_cache_dtype.quantize(&k)?, kv_cache_dtype.quantize(&v)?);
         let (k, v, attention_mask) = match cache.clone() {
             None => (k, v, attention_mask.cloned()),
             Some((mut prev_k, mut prev_v)) => {
@@ -137,7 +181,7 @@ impl Cache {
                         }
                     }
                 }
-                let (k, v) = if !slow_cat {
+                let (k, v) = if !slow_cat && !kv_cache_dtype.is_quantized() {
                     let k = candle_nn::ops::kvconcat(&prev_k, &k, 2)?;
                     let v = candle_nn::ops::kvconcat(&prev_v, &v, 2)?;
                     (k, v)
@@ -150,7 +194,11 @@ impl Cache {
             }
         };
         *cache = Some((k.clone(), v.clone()));
-        Ok((k, v, attention_mask))
+        Ok((
+            kv_cache_dtype.dequantize(&k, dtype)?,
+            kv_cache_dtype.dequantize(&v, dtype)?,
+            attention_mask,
+        ))
     }
 }
 
@@ -222,6 +222,7 @@ impl Loader for DiffusionLoader {
                 eos_tok: vec![],
                 kind: self.kind.clone(),
                 has_no_kv_cache: true, // NOTE(EricLBuehler): no cache for these.
+                is_recurrent: false,
                 activation_dtype: dtype,
                 sliding_window: None,
                 cache_config: None,
@@ -378,6 +378,7 @@ impl Loader for GGMLLoader {
                 max_seq_len,
                 tok_trie: Some(tok_trie),
                 has_no_kv_cache: self.no_kv_cache,
+                is_recurrent: false,
                 num_hidden_layers,
                 eos_tok: eos,
                 kind: self.kind.clone(),
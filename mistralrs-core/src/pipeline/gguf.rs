@@ -33,9 +33,13 @@ use crate::{
     Pipeline, Topology, TryIntoDType,
 };
 use crate::{
+    models::quantized_gemma::ModelWeights as QGemma,
+    models::quantized_gemma2::ModelWeights as QGemma2,
     models::quantized_llama::ModelWeights as QLlama,
+    models::quantized_mamba::ModelWeights as QMamba,
     models::quantized_phi2::ModelWeights as QPhi,
     models::quantized_phi3::ModelWeights as QPhi3,
+    models::quantized_qwen2::ModelWeights as QQwen2,
     models::quantized_starcoder2::ModelWeights as QStarcoder2,
     utils::tokens::get_token,
     xlora_models::{XLoraQLlama, XLoraQPhi3},
@@ -63,6 +67,10 @@ enum Model {
     XLoraPhi3(XLoraQPhi3),
     Phi3(QPhi3),
     Starcoder2(QStarcoder2),
+    Mamba(QMamba),
+    Qwen2(QQwen2),
+    Gemma(QGemma),
+    Gemma2(QGemma2),
 }
 
 pub struct GGUFPipeline {
@@ -252,6 +260,7 @@ struct ContentConfig {
     num_attn_heads: usize,
     num_kv_heads: usize,
     num_layers: usize,
+    head_dim: Option<usize>,
 }
 
 #[allow(clippy::cast_possible_truncation)]
@@ -270,6 +279,9 @@ impl<'a, R: std::io::Seek + std::io::Read> From<&Content<'a, R>> for ContentConf
                 .to_u64()
                 .unwrap() as usize,
             num_layers: metadata[&format!("{arch}.block_count")].to_u64().unwrap() as usize,
+            head_dim: metadata
+                .get(&format!("{arch}.attention.key_length"))
+                .map(|x| x.to_u64().unwrap() as usize),
         }
     }
 }
@@ -287,6 +299,10 @@ impl ModelConfigLike for ContentConfig {
     fn num_layers(&self) -> usize {
         self.num_layers
     }
+    fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attn_heads)
+    }
 }
 
 impl Loader for GGUFLoader {
@@ -397,11 +413,15 @@ impl Loader for GGUFLoader {
         let paged_attn_config = if matches!(self.kind, ModelKind::AdapterQuantized { .. }) {
             warn!("Adapter models do not currently support PagedAttention, running without");
             None
+        } else if paged_attn_config.is_some() && matches!(arch, GGUFArchitecture::Mamba) {
+            warn!("Recurrent models have no KV cache for PagedAttention, running without");
+            None
         } else {
             paged_attn_config
         };
 
-        let model_config_metadata: ContentConfig = (&model).into();
+        let model_config_metadata: Option<ContentConfig> =
+            paged_attn_config.is_some().then(|| (&model).into());
 
         let model_config = {
             // Base config (quantization only):
@@ -438,6 +458,11 @@ impl Loader for GGUFLoader {
                 GGUFArchitecture::Starcoder2 => {
                     Model::Starcoder2(QStarcoder2::try_from(model_config)?)
                 }
+                GGUFArchitecture::Mamba => Model::Mamba(QMamba::try_from(model_config)?),
+                GGUFArchitecture::Qwen2 => Model::Qwen2(QQwen2::try_from(model_config)?),
+                GGUFArchitecture::Gemma => Model::Gemma(QGemma::try_from(model_config)?),
+                GGUFArchitecture::Gemma2 => Model::Gemma2(QGemma2::try_from(model_config)?),
+                // TODO: RWKV, tracked in the "Follow-up: RWKV" section of docs/MAMBA.md.
                 a => bail!("Unsupported architecture `{a:?}` for GGUF"),
             },
             ModelKind::AdapterQuantized { adapter, .. } => match arch {
@@ -452,12 +477,15 @@ impl Loader for GGUFLoader {
         };
 
         let (cache_config, cache_engine) = if let Some(paged_attn_config) = paged_attn_config {
-            let model_config: &dyn ModelConfigLike = &model_config_metadata;
+            let model_config: &dyn ModelConfigLike = model_config_metadata
+                .as_ref()
+                .expect("No model config for PagedAttention.");
             let cache_config = calculate_cache_config(
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
                 DType::F32,
+                paged_attn_config.cache_dtype,
                 model_config,
                 device,
             )?;
@@ -479,6 +507,10 @@ impl Loader for GGUFLoader {
             Model::Phi3(ref p) => p.max_seq_len,
             Model::XLoraPhi3(ref p) => p.max_seq_len,
             Model::Starcoder2(ref p) => p.max_seq_len,
+            Model::Mamba(ref p) => p.max_seq_len,
+            Model::Qwen2(ref p) => p.max_seq_len,
+            Model::Gemma(ref p) => p.max_seq_len,
+            Model::Gemma2(ref p) => p.max_seq_len,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = match model {
@@ -488,6 +520,10 @@ impl Loader for GGUFLoader {
             Model::Phi3(ref model) => model.cache.lock().len(),
             Model::XLoraPhi3(ref model) => model.cache.lock().len(),
             Model::Starcoder2(ref model) => model.cache.lock().len(),
+            Model::Mamba(ref model) => model.cache.lock().len(),
+            Model::Qwen2(ref model) => model.cache.lock().len(),
+            Model::Gemma(ref model) => model.cache.lock().len(),
+            Model::Gemma2(ref model) => model.cache.lock().len(),
         };
 
         if chat_template.bos_token.is_none() && bos.is_some() {
@@ -501,6 +537,7 @@ impl Loader for GGUFLoader {
         }
 
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let is_recurrent = matches!(model, Model::Mamba(_));
         Ok(Arc::new(Mutex::new(GGUFPipeline {
             model,
             tokenizer: tokenizer.into(),
@@ -520,6 +557,7 @@ impl Loader for GGUFLoader {
                 max_seq_len,
                 tok_trie: Some(tok_trie),
                 has_no_kv_cache: self.no_kv_cache,
+                is_recurrent,
                 num_hidden_layers,
                 eos_tok: eos,
                 kind: self.kind.clone(),
@@ -583,6 +621,10 @@ impl CacheManagerMixin for GGUFPipeline {
             Model::Phi3(ref model) => &model.cache,
             Model::XLoraPhi3(ref model) => &model.cache,
             Model::Starcoder2(ref model) => &model.cache,
+            Model::Mamba(ref model) => &model.cache,
+            Model::Qwen2(ref model) => &model.cache,
+            Model::Gemma(ref model) => &model.cache,
+            Model::Gemma2(ref model) => &model.cache,
         }
     }
 }
@@ -615,6 +657,10 @@ impl MetadataMixin for GGUFPipeline {
             Model::Phi3(ref model) => model.device.clone(),
             Model::XLoraPhi3(ref model) => model.device.clone(),
             Model::Starcoder2(ref model) => model.device.clone(),
+            Model::Mamba(ref model) => model.device.clone(),
+            Model::Qwen2(ref model) => model.device.clone(),
+            Model::Gemma(ref model) => model.device.clone(),
+            Model::Gemma2(ref model) => model.device.clone(),
         }
     }
     fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
@@ -714,6 +760,28 @@ impl Pipeline for GGUFPipeline {
                 seqlen_offsets_kernel,
                 paged_attn_meta,
             )?,
+            Model::Mamba(ref model) => model.forward(&input_ids, context_lens)?,
+            Model::Qwen2(ref model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+                paged_attn_meta,
+            )?,
+            Model::Gemma(ref model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+                paged_attn_meta,
+            )?,
+            Model::Gemma2(ref model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+                paged_attn_meta,
+            )?,
         };
         Ok(ForwardInputsResult::CausalGeneration { logits })
     }
@@ -92,6 +92,9 @@ pub mod text_models_inputs_processor {
         pub context_lens: Option<Tensor>,
         pub slot_mappings: Tensor,
         pub max_context_len: Option<usize>,
+        /// For prompts, the number of tokens of each sequence which are already in the KV cache
+        /// through the prefix cache. Only set if any sequence has cached tokens.
+        pub num_cached_tokens: Option<Vec<usize>>,
     }
 
     #[derive(Clone, Debug)]
@@ -127,9 +130,21 @@ pub mod text_models_inputs_processor {
         last_n_context_len: Option<(usize, usize)>,
         mut paged_attn_metadata: Option<&mut PagedAttentionMeta<'_>>,
     ) -> Result<InputMetadata> {
+        // Tokens whose KV cache was reused from the PagedAttention prefix cache are skipped
+        let seq_num_cached_toks = input_seqs
+            .iter()
+            .map(|seq| {
+                if paged_attn_metadata.is_some() {
+                    seq.num_cached_prompt_toks()
+                } else {
+                    0
+                }
+            })
+            .collect::<Vec<_>>();
         let max_len = toks
             .iter()
-            .map(|seq| seq.len())
+            .zip(&seq_num_cached_toks)
+            .map(|(seq, num_cached_toks)| seq.len() - num_cached_toks)
             .max()
             .expect("No sequences");
         let padding_tok = T::zero();
@@ -141,22 +156,26 @@ pub mod text_models_inputs_processor {
         let mut slot_mappings = Vec::new();
         let mut block_tables = Vec::new();
         let mut paged_attn_context_lens = Vec::new();
+        let mut num_cached_tokens = Vec::new();
         let mut seqlens_q = vec![0];
         let mut seqlens_k = vec![0];
-        for (seq, mut ctxt) in input_seqs.iter().zip(toks) {
+        for ((seq, mut ctxt), num_cached_toks) in
+            input_seqs.iter().zip(toks).zip(seq_num_cached_toks)
+        {
+            ctxt.drain(..num_cached_toks);
             let prompt_len = ctxt.len();
             let offset = last_n_context_len.unwrap_or_default();
-            seqlen_offsets.push(offset.1 + chunk_offset_toks);
+            seqlen_offsets.push(offset.1 + chunk_offset_toks + num_cached_toks);
 
-            position_ids.push(ctxt.len() + chunk_offset_toks);
+            position_ids.push(ctxt.len() + chunk_offset_toks + num_cached_toks);
             ctxt.extend(repeat(padding_tok).take(max_len.saturating_sub(ctxt.len())));
             context_lens.push((
-                ctxt.len() - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
+                prompt_len - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
                 last_n_context_len.map(|(a, _)| a).unwrap_or(1),
             ));
 
             seqlens_q.push(ctxt.len() as u32);
-            seqlens_k.push((ctxt.len() + chunk_offset_toks) as u32);
+            seqlens_k.push((ctxt.len() + chunk_offset_toks + num_cached_toks) as u32);
 
             seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
 
@@ -184,14 +203,13 @@ pub mod text_models_inputs_processor {
                     chunk_offset_toks
                 };
 
+                let first_tok = chunk_offset_toks + num_cached_toks;
                 let mut slot_mapping = Vec::new();
-                let mut ctxt_len = Vec::new();
-                for i in chunk_offset_toks..prompt_len + chunk_offset_toks {
+                for i in first_tok..prompt_len + first_tok {
                     if i < start_idx {
                         // Pad [0,start_idx) with _PAD_TOKEN_ID
                         slot_mapping.push(_PAD_SLOT_ID);
                     }
-                    ctxt_len.push(i);
 
                     let block_number = if i / paged_attn_metadata.block_size >= table.len() {
                         panic!(
@@ -206,15 +224,16 @@ pub mod text_models_inputs_processor {
                     let block_offset = i % paged_attn_metadata.block_size;
                     let slot = block_number * paged_attn_metadata.block_size + block_offset;
                     slot_mapping.push(slot.try_into().unwrap());
-                    block_tables.push(table.clone());
                 }
                 slot_mappings.push(slot_mapping);
-                paged_attn_context_lens.push(ctxt_len);
+                block_tables.push(table);
+                paged_attn_context_lens.push(prompt_len + first_tok);
+                num_cached_tokens.push(num_cached_toks);
             }
         }
 
         let mut tmp = Vec::new();
-        if last_n_context_len.is_some() {
+        if last_n_context_len.is_some() || num_cached_tokens.iter().any(|n| *n > 0) {
             for pos in (0..seqs_tensors.len())
                 .map(|i| {
                     (*seqlen_offsets.get(i).unwrap() as i64
@@ -258,40 +277,47 @@ pub mod text_models_inputs_processor {
             let slot_mappings =
                 _make_tensor_with_pad(slot_mappings, max_slot_mapping_len, _PAD_SLOT_ID, device)?;
 
-            let max_block_table_len = block_tables.iter().map(|x| x.len()).max().unwrap();
-            let block_tables = _make_tensor_with_pad(
-                block_tables
-                    .iter()
-                    .map(|x| x.iter().map(|x| *x as u32).collect::<Vec<_>>())
-                    .collect::<Vec<_>>(),
-                max_block_table_len,
-                0,
-                device,
-            )?;
-            let block_tables = block_tables.reshape(((), max_block_table_len))?;
-
-            let max_context_len = paged_attn_context_lens
-                .iter()
-                .map(|x| x.len())
-                .max()
-                .unwrap();
-
-            let context_lens = _make_tensor_with_pad(
-                paged_attn_context_lens
-                    .iter()
-                    .map(|x| x.iter().map(|x| *x as u32).collect::<Vec<_>>())
-                    .collect::<Vec<_>>(),
-                max_context_len,
-                0,
-                device,
-            )?
-            .reshape(((),))?;
+            // The block tables are used to attend to cached tokens, or by a single token prompt
+            let (block_tables, context_lens, max_context_len) = if block_tables.is_empty() {
+                (None, None, None)
+            } else {
+                let max_block_table_len = block_tables.iter().map(|x| x.len()).max().unwrap();
+                let block_tables = _make_tensor_with_pad(
+                    block_tables
+                        .iter()
+                        .map(|x| x.iter().map(|x| *x as u32).collect::<Vec<_>>())
+                        .collect::<Vec<_>>(),
+                    max_block_table_len,
+                    0,
+                    device,
+                )?;
+                let block_tables = block_tables.reshape(((), max_block_table_len))?;
+
+                let max_context_len = *paged_attn_context_lens.iter().max().unwrap();
+                let context_lens = Tensor::from_vec(
+                    paged_attn_context_lens
+                        .iter()
+                        .map(|x| *x as u32)
+                        .collect::<Vec<_>>(),
+                    (paged_attn_context_lens.len(),),
+                    device,
+                )?;
+                (
+                    Some(block_tables),
+                    Some(context_lens),
+                    Some(max_context_len),
+                )
+            };
 
             Some(PagedAttentionInputMetadata {
                 slot_mappings,
-                block_tables: Some(block_tables),
-                context_lens: Some(context_lens),
-                max_context_len: Some(max_context_len),
+                block_tables,
+                context_lens,
+                max_context_len,
+                num_cached_tokens: num_cached_tokens
+                    .iter()
+                    .any(|n| *n > 0)
+                    .then_some(num_cached_tokens),
             })
         } else {
             None
@@ -440,6 +466,7 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(*max_context_len),
+                num_cached_tokens: None,
             })
         } else {
             None
@@ -17,7 +17,7 @@ use mistralrs_quant::IsqType;
 use tokio::sync::Mutex;
 
 pub use normal_loaders::{
-    AutoLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader,
+    AutoLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MambaLoader, MistralLoader, MixtralLoader,
     NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader,
     Phi3Loader, Phi3_5MoELoader, Qwen2Loader, Starcoder2Loader,
 };
@@ -257,6 +257,9 @@ pub enum ModelKind {
         draft: Box<ModelKind>,
     },
 
+    #[strum(to_string = "prompt lookup: target: `{target}`")]
+    PromptLookup { target: Box<ModelKind> },
+
     #[strum(to_string = "anymoe: target: `{target}`")]
     AnyMoe { target: Box<ModelKind> },
 }
@@ -318,7 +321,7 @@ impl ModelKind {
 
                 [t.quantized_kind(), d.quantized_kind()].concat()
             }
-            AnyMoe { target } => target.quantized_kind(),
+            PromptLookup { target } | AnyMoe { target } => target.quantized_kind(),
         }
     }
 
@@ -343,7 +346,7 @@ impl ModelKind {
 
                 [t.adapted_kind(), d.adapted_kind()].concat()
             }
-            AnyMoe { target } => target.adapted_kind(),
+            PromptLookup { target } | AnyMoe { target } => target.adapted_kind(),
         }
     }
 }
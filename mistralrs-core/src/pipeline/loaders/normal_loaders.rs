@@ -47,6 +47,19 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> candle_core::Result<Tensor>;
+    /// Hidden states of all tokens after the final norm, of shape (batch size, seq len, hidden size).
+    /// These are pooled to compute embeddings.
+    fn forward_hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Embeddings are not supported for this model.");
+    }
     #[allow(clippy::too_many_arguments)]
     fn xlora_forward(
         &self,
@@ -64,6 +77,11 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         flash_params_full: &FlashParams,
     ) -> candle_core::Result<Tensor>;
     fn is_xlora(&self) -> bool;
+    /// Whether the model carries a recurrent state per sequence in its cache instead of a KV
+    /// cache. Such a state cannot be truncated, so it cannot be shared by prefix or rolled back.
+    fn is_recurrent(&self) -> bool {
+        false
+    }
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
     fn max_seq_len(&self) -> usize;
@@ -137,6 +155,8 @@ pub enum NormalLoaderType {
     Starcoder2,
     #[serde(rename = "phi3.5moe")]
     Phi3_5MoE,
+    #[serde(rename = "mamba")]
+    Mamba,
 }
 
 // https://github.com/huggingface/transformers/blob/cff06aac6fad28019930be03f5d467055bf62177/src/transformers/models/auto/modeling_auto.py#L448
@@ -154,6 +174,7 @@ impl NormalLoaderType {
             "Qwen2ForCausalLM" => Ok(Self::Qwen2),
             "Starcoder2ForCausalLM" => Ok(Self::Starcoder2),
             "PhiMoEForCausalLM" => Ok(Self::Phi3_5MoE),
+            "MambaForCausalLM" => Ok(Self::Mamba),
             other => anyhow::bail!(
                 "Unsupported Huggging Face Transformers -CausalLM model class `{other}`. Please raise an issue."
             ),
@@ -175,7 +196,8 @@ impl FromStr for NormalLoaderType {
             "gemma2" => Ok(Self::Gemma2),
             "starcoder2" => Ok(Self::Starcoder2),
             "phi3.5moe" => Ok(Self::Phi3_5MoE),
-            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `phi3.5moe`.")),
+            "mamba" => Ok(Self::Mamba),
+            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `phi3.5moe`, `mamba`.")),
         }
     }
 }
@@ -193,6 +215,7 @@ impl Display for NormalLoaderType {
             Self::Phi3_5MoE => write!(f, "phi3.5moe"),
             Self::Qwen2 => write!(f, "qwen2"),
             Self::Starcoder2 => write!(f, "starcoder2"),
+            Self::Mamba => write!(f, "mamba"),
         }
     }
 }
@@ -229,6 +252,7 @@ impl AutoLoader {
             NormalLoaderType::Gemma2 => Ok(Box::new(Gemma2Loader)),
             NormalLoaderType::Starcoder2 => Ok(Box::new(Starcoder2Loader)),
             NormalLoaderType::Phi3_5MoE => Ok(Box::new(Phi3_5MoELoader)),
+            NormalLoaderType::Mamba => Ok(Box::new(MambaLoader)),
         }
     }
 }
@@ -1675,3 +1699,112 @@ impl IsqModelLoader for Phi3_5MoELoader {
         Ok(regexes)
     }
 }
+
+// ======================== Mamba loader
+
+// Unlike the other models, Mamba ties the word embeddings by default
+serde_default_fn!(bool, mamba_tie_word_embeddings_default, true);
+
+#[derive(Deserialize, Debug)]
+struct MambaBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    state_size: usize,
+    num_hidden_layers: usize,
+    conv_kernel: usize,
+    time_step_rank: usize,
+    layer_norm_epsilon: f64,
+    use_bias: bool,
+    use_conv_bias: bool,
+    quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "mamba_tie_word_embeddings_default")]
+    tie_word_embeddings: bool,
+}
+
+impl MambaBasicConfig {
+    fn deserialize(slice: &str) -> Result<models::mamba::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        Ok(models::mamba::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            state_size: basic_config.state_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            conv_kernel: basic_config.conv_kernel,
+            time_step_rank: basic_config.time_step_rank,
+            layer_norm_epsilon: basic_config.layer_norm_epsilon,
+            use_bias: basic_config.use_bias,
+            use_conv_bias: basic_config.use_conv_bias,
+            quantization_config: basic_config.quantization_config,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Mamba model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct MambaLoader;
+
+impl NormalModelLoader for MambaLoader {
+    fn load(
+        &self,
+        config: &str,
+        _use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        _attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::mamba::Model::new(
+            &MambaBasicConfig::deserialize(config)?,
+            vb,
+            normal_loading_metadata,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("Mamba models do not support LoRA or X-LoRA adapters.")
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        Ok(true)
+    }
+    fn get_config_repr(&self, config: &str, _use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(MambaBasicConfig::deserialize(config)?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(MambaBasicConfig::deserialize(config)?.num_hidden_layers)
+    }
+}
+
+impl IsqModelLoader for MambaLoader {
+    fn isq_layer_regexes(&self, config: &str) -> Result<Vec<Regex>> {
+        let mut regexes = Vec::new();
+        if MambaBasicConfig::deserialize(config)?.tie_word_embeddings {
+            regexes.push(Regex::new(r"(embeddings|lm_head)\.(weight|bias)$")?);
+        } else {
+            regexes.push(Regex::new(r"lm_head\.(weight|bias)$")?);
+        }
+        // Mixer
+        regexes.push(Regex::new(
+            r"layers\.(\d+)\.mixer\.in_proj\.(weight|bias)$",
+        )?);
+        regexes.push(Regex::new(r"layers\.(\d+)\.mixer\.x_proj\.(weight|bias)$")?);
+        regexes.push(Regex::new(
+            r"layers\.(\d+)\.mixer\.dt_proj\.(weight|bias)$",
+        )?);
+        regexes.push(Regex::new(
+            r"layers\.(\d+)\.mixer\.out_proj\.(weight|bias)$",
+        )?);
+        Ok(regexes)
+    }
+}
@@ -19,6 +19,8 @@ pub use super::diffusion_models::DiffusionGenerationParams;
 use crate::aici::toktree::TokTrie;
 use crate::amoe::{AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainingInputs, AnyMoeTrainingResult};
 use crate::diffusion_models::response::send_responses;
+use crate::kv_quant::KvCacheDtype;
+use crate::layers::MatMul;
 use crate::paged_attention::{CacheConfig, CacheEngine};
 use crate::prefix_cacher::PrefixCacheManager;
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
@@ -32,19 +34,22 @@ pub use isq::{parse_isq_value, IsqModel, IsqOrganization};
 pub use loaders::{
     AdapterKind, AutoLoader, DiffusionLoaderType, DiffusionModel, DiffusionModelLoader, FluxLoader,
     Gemma2Loader, GemmaLoader, Idefics2Loader, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
-    LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoaderType,
-    NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3VLoader,
-    Phi3_5MoELoader, PrettyName, QuantizationKind, Qwen2Loader, Starcoder2Loader, TokenSource,
-    VLlamaLoader, VisionLoaderType, VisionModel, VisionModelLoader,
+    LocalModelPaths, MambaLoader, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
+    NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader,
+    Phi3Loader, Phi3VLoader, Phi3_5MoELoader, PrettyName, QuantizationKind, Qwen2Loader,
+    Starcoder2Loader, TokenSource, VLlamaLoader, VisionLoaderType, VisionModel, VisionModelLoader,
 };
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{IsqType, QuantMethod};
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
 pub(crate) use paths::{get_chat_template, get_model_paths, get_xlora_paths, XLoraPaths};
 pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
-pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
+pub use speculative::{
+    AdaptiveGamma, SpeculativeConfig, SpeculativeLoader, SpeculativeMethod, SpeculativePipeline,
+    SpeculativeStats,
+};
 use std::any::Any;
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
@@ -68,6 +73,9 @@ pub struct GeneralMetadata {
     /// Only None if it doesnt make sense for the model
     pub tok_trie: Option<Arc<TokTrie>>,
     pub has_no_kv_cache: bool,
+    /// The model carries a recurrent state per sequence in its cache instead of a KV cache. The
+    /// state cannot be truncated, so it is not shared by prefix or rolled back.
+    pub is_recurrent: bool,
     pub num_hidden_layers: usize,
     pub eos_tok: Vec<u32>,
     pub kind: ModelKind,
@@ -121,6 +129,11 @@ pub trait CacheManagerMixin {
     /// This may also reset the non granular state if applicable.
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool);
     fn cache(&self) -> &Cache;
+    /// Set the dtype of the KV cache. This does not apply to PagedAttention, whose KV cache dtype
+    /// is part of its cache config.
+    fn set_kv_cache_dtype(&self, kv_cache_dtype: KvCacheDtype) {
+        self.cache().set_kv_cache_dtype(kv_cache_dtype)
+    }
 }
 
 pub trait AdapterActivationMixin {
@@ -216,6 +229,7 @@ pub enum CacheBackendMetadata<'a> {
 pub enum ForwardInputsResult {
     CausalGeneration { logits: Tensor },
     Image { images: Vec<DynamicImage> },
+    Embeddings { hidden_states: Tensor },
 }
 
 impl ForwardInputsResult {
@@ -227,6 +241,9 @@ impl ForwardInputsResult {
             Self::Image { images } => Ok(Self::Image {
                 images: vec![images[bs_idx].clone()],
             }),
+            Self::Embeddings { hidden_states } => Ok(Self::Embeddings {
+                hidden_states: hidden_states.i(bs_idx)?,
+            }),
         }
     }
 
@@ -236,6 +253,9 @@ impl ForwardInputsResult {
                 logits: logits.to_device(device)?,
             }),
             Self::Image { .. } => Ok(self.clone()),
+            Self::Embeddings { hidden_states } => Ok(Self::Embeddings {
+                hidden_states: hidden_states.to_device(device)?,
+            }),
         }
     }
 }
@@ -256,6 +276,37 @@ pub trait Pipeline:
         inputs: Box<dyn Any>,
     ) -> Result<ForwardInputsResult, candle_core::Error>;
 
+    /// Run the model on the inputs, returning the hidden states of all tokens after the final
+    /// norm. These are pooled to compute embeddings.
+    fn forward_hidden_states(
+        &mut self,
+        _inputs: Box<dyn Any>,
+    ) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!("Embeddings are not supported for this pipeline.");
+    }
+
+    /// Acceptance statistics of all sequences run by this pipeline, if it uses speculative
+    /// decoding.
+    fn speculative_stats(&self) -> Option<SpeculativeStats> {
+        None
+    }
+
+    /// Run the model on the inputs, computing the hidden states if the sequences requested
+    /// embeddings and the logits otherwise.
+    fn forward_step(
+        &mut self,
+        inputs: Box<dyn Any>,
+        is_embedding: bool,
+    ) -> Result<ForwardInputsResult, candle_core::Error> {
+        if is_embedding {
+            Ok(ForwardInputsResult::Embeddings {
+                hidden_states: self.forward_hidden_states(inputs)?,
+            })
+        } else {
+            self.forward_inputs(inputs)
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn step(
         &mut self,
@@ -266,6 +317,18 @@ pub trait Pipeline:
         rng: Arc<std::sync::Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<(), candle_core::Error> {
+        // The scheduler does not batch embedding sequences with other sequences
+        let is_embedding = input_seqs[0].embedding_params().is_some();
+        // Prompt logprobs need the logits of all prompt tokens. The scheduler only batches such
+        // sequences with sequences of the same length.
+        let prompt_logprobs_len =
+            (is_prompt && input_seqs[0].return_prompt_logprobs()).then(|| input_seqs[0].len());
+        // Embeddings pool the hidden states of the whole prompt, so it is not split into chunks
+        let prompt_batchsize = if is_embedding || prompt_logprobs_len.is_some() {
+            None
+        } else {
+            self.get_metadata().prompt_batchsize
+        };
         match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
                 let inputs_iter = self.get_processor().inputs_processor().process_inputs(
@@ -275,10 +338,10 @@ pub trait Pipeline:
                     self.get_metadata().is_xlora,
                     &self.device(),
                     self.get_metadata().has_no_kv_cache,
-                    None,
+                    prompt_logprobs_len.map(|len| (len, 0)),
                     self.get_input_processor_config(),
                     None,
-                    self.get_metadata().prompt_batchsize,
+                    prompt_batchsize,
                 );
 
                 let mut logits = vec![None; input_seqs.len()];
@@ -341,7 +404,7 @@ pub trait Pipeline:
                         }
                     }
 
-                    let raw_logits = self.forward_inputs(inputs)?;
+                    let raw_logits = self.forward_step(inputs, is_embedding)?;
 
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
                         logits[seq_idx] = Some(raw_logits.index_bs(logit_idx)?);
@@ -411,6 +474,24 @@ pub trait Pipeline:
                         )
                         .await?;
                     }
+                    ForwardInputsResult::Embeddings { .. } => {
+                        crate::embedding::send_responses(
+                            input_seqs,
+                            logits
+                                .into_iter()
+                                .map(|r| {
+                                    let ForwardInputsResult::Embeddings { hidden_states } = r
+                                    else {
+                                        unreachable!(
+                                            "All results must have same type, `Embeddings`"
+                                        )
+                                    };
+                                    hidden_states
+                                })
+                                .collect::<Vec<_>>(),
+                        )
+                        .await?;
+                    }
                 }
                 Ok(())
             }
@@ -433,10 +514,10 @@ pub trait Pipeline:
                     self.get_metadata().is_xlora,
                     &self.device(),
                     self.get_metadata().has_no_kv_cache,
-                    None,
+                    prompt_logprobs_len.map(|len| (len, 0)),
                     self.get_input_processor_config(),
                     Some(metadata),
-                    self.get_metadata().prompt_batchsize,
+                    prompt_batchsize,
                 );
 
                 let mut logits = vec![None; input_seqs.len()];
@@ -447,7 +528,7 @@ pub trait Pipeline:
                         seq_indices,
                     } = inputs.map_err(candle_core::Error::msg)?;
 
-                    let raw_logits = self.forward_inputs(inputs)?;
+                    let raw_logits = self.forward_step(inputs, is_embedding)?;
 
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
                         logits[seq_idx] = Some(raw_logits.index_bs(logit_idx)?);
@@ -505,6 +586,24 @@ pub trait Pipeline:
                         )
                         .await?;
                     }
+                    ForwardInputsResult::Embeddings { .. } => {
+                        crate::embedding::send_responses(
+                            input_seqs,
+                            logits
+                                .into_iter()
+                                .map(|r| {
+                                    let ForwardInputsResult::Embeddings { hidden_states } = r
+                                    else {
+                                        unreachable!(
+                                            "All results must have same type, `Embeddings`"
+                                        )
+                                    };
+                                    hidden_states
+                                })
+                                .collect::<Vec<_>>(),
+                        )
+                        .await?;
+                    }
                 }
                 Ok(())
             }
@@ -523,6 +622,16 @@ pub trait Pipeline:
     fn category(&self) -> ModelCategory;
 }
 
+/// Apply the LM head to the hidden states after the final norm, casting them to the activation type
+/// of the LM head if it is quantized.
+pub(crate) fn apply_lm_head(xs: Tensor, lm_head: &dyn QuantMethod) -> candle_core::Result<Tensor> {
+    let xs = match lm_head.quantized_act_type() {
+        Some(t) => xs.to_dtype(t)?,
+        None => xs,
+    };
+    MatMul.qmethod_matmul(&xs, lm_head)
+}
+
 pub(crate) fn extract_logits(
     logits: &Tensor,
     context_lens: Vec<(usize, usize)>,
@@ -1,15 +1,16 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource, XLoraPaths,
+    get_model_paths, get_xlora_paths,
+    text_models_inputs_processor::{ModelInputs, PagedAttentionInputMetadata},
+    AdapterKind, CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel,
+    NormalModelLoader, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
     IsqOrganization, IsqPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use super::{
-    AutoLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader,
+    AutoLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MambaLoader, MistralLoader, MixtralLoader,
     NormalLoaderType, Phi2Loader, Phi3Loader, Phi3_5MoELoader, Qwen2Loader, Starcoder2Loader,
 };
 use crate::aici::bintokens::build_tok_trie;
@@ -188,6 +189,7 @@ impl NormalLoaderBuilder {
             Some(NormalLoaderType::Gemma2) => Box::new(Gemma2Loader),
             Some(NormalLoaderType::Starcoder2) => Box::new(Starcoder2Loader),
             Some(NormalLoaderType::Phi3_5MoE) => Box::new(Phi3_5MoELoader),
+            Some(NormalLoaderType::Mamba) => Box::new(MambaLoader),
             None => Box::new(AutoLoader),
         };
         Ok(Box::new(NormalLoader {
@@ -388,6 +390,9 @@ impl Loader for NormalLoader {
         let paged_attn_config = if matches!(self.kind, ModelKind::Adapter { .. }) {
             warn!("Adapter models do not currently support PagedAttention, running without");
             None
+        } else if paged_attn_config.is_some() && model.is_recurrent() {
+            warn!("Recurrent models have no KV cache for PagedAttention, running without");
+            None
         } else {
             paged_attn_config
         };
@@ -398,6 +403,7 @@ impl Loader for NormalLoader {
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
                 dtype,
+                paged_attn_config.cache_dtype,
                 model.config(),
                 device,
             )?;
@@ -412,6 +418,7 @@ impl Loader for NormalLoader {
         let num_hidden_layers = model.cache().lock().len();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         let sliding_window = model.config().sliding_window;
+        let is_recurrent = model.is_recurrent();
         Ok(Arc::new(Mutex::new(NormalPipeline {
             model,
             tokenizer: tokenizer.into(),
@@ -428,6 +435,7 @@ impl Loader for NormalLoader {
                 max_seq_len,
                 tok_trie: Some(tok_trie),
                 has_no_kv_cache: self.no_kv_cache,
+                is_recurrent,
                 num_hidden_layers,
                 eos_tok: eos,
                 kind: self.kind.clone(),
@@ -547,21 +555,7 @@ impl Pipeline for NormalPipeline {
             flash_meta,
             flash_meta_full,
         } = *inputs.downcast().expect("Downcast failed.");
-        let paged_attn_meta = match (
-            self.get_metadata().cache_engine.as_ref(),
-            &mut paged_attn_meta,
-        ) {
-            (Some(engine), Some(meta)) => Some((engine.get_kv_cache().clone(), meta)),
-            (Some(_), None) => {
-                // This can happen if Rust-side user code is wrong
-                candle_core::bail!("Forward step expected a PagedAttention input metadata. This was not provided, please ensure that the scheduler config is correctly configured for PagedAttention.")
-            }
-            (None, Some(_)) => {
-                // This should never happen but we handle it anyway
-                candle_core::bail!("Forward step got a PagedAttention input metadata but there is no cache engine. Please raise an issue.")
-            }
-            (None, None) => None,
-        };
+        let paged_attn_meta = self.paged_attn_meta(&mut paged_attn_meta)?;
         let logits = match self.model.is_xlora() {
             false => self.model.forward(
                 &input_ids,
@@ -589,6 +583,32 @@ impl Pipeline for NormalPipeline {
         };
         Ok(ForwardInputsResult::CausalGeneration { logits })
     }
+    fn forward_hidden_states(
+        &mut self,
+        inputs: Box<dyn Any>,
+    ) -> Result<Tensor, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            position_ids,
+            mut paged_attn_meta,
+            flash_meta,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        if self.model.is_xlora() {
+            candle_core::bail!("Embeddings are not supported for X-LoRA models.");
+        }
+        let paged_attn_meta = self.paged_attn_meta(&mut paged_attn_meta)?;
+        self.model.forward_hidden_states(
+            &input_ids,
+            &seqlen_offsets,
+            seqlen_offsets_kernel,
+            position_ids,
+            paged_attn_meta,
+            &flash_meta,
+        )
+    }
     async fn sample_causal_gen(
         &self,
         seqs: &mut [&mut Sequence],
@@ -604,6 +624,30 @@ impl Pipeline for NormalPipeline {
     }
 }
 
+impl NormalPipeline {
+    /// The PagedAttention KV cache and input metadata for a forward step, if using PagedAttention.
+    fn paged_attn_meta<'a>(
+        &self,
+        paged_attn_meta: &'a mut Option<PagedAttentionInputMetadata>,
+    ) -> Result<
+        Option<(Vec<(Tensor, Tensor)>, &'a mut PagedAttentionInputMetadata)>,
+        candle_core::Error,
+    > {
+        match (self.get_metadata().cache_engine.as_ref(), paged_attn_meta) {
+            (Some(engine), Some(meta)) => Ok(Some((engine.get_kv_cache().clone(), meta))),
+            (Some(_), None) => {
+                // This can happen if Rust-side user code is wrong
+                candle_core::bail!("Forward step expected a PagedAttention input metadata. This was not provided, please ensure that the scheduler config is correctly configured for PagedAttention.")
+            }
+            (None, Some(_)) => {
+                // This should never happen but we handle it anyway
+                candle_core::bail!("Forward step got a PagedAttention input metadata but there is no cache engine. Please raise an issue.")
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
 impl AnyMoePipelineMixin for NormalPipeline {
     fn amoe_finish_training(&mut self, gate_model_id: Option<String>) -> candle_core::Result<()> {
         self.model.finish_training(gate_model_id)
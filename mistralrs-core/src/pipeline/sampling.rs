@@ -1,13 +1,15 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use candle_core::{DType, Device, Result, Tensor};
 use rand_isaac::Isaac64Rng;
 
 use crate::{
+    beam_search::{top_candidates, BeamHypotheses, BeamHypothesis},
     get_bias_if_not_allowed,
     prefix_cacher::PrefixCacheManager,
-    sampler::Logprobs,
-    sequence::{Sequence, SequenceRecognizer},
+    sampler::{Logprobs, PromptLogprob},
+    sequence::{BeamFork, Sequence, SequenceRecognizer, SequenceState, StopReason},
+    tools,
 };
 
 use super::Pipeline;
@@ -21,18 +23,18 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     use_prefix_cacher: bool,
 ) -> Result<()> {
     let is_done = seq.is_done(logprobs.token, eos_tok, this.get_metadata().max_seq_len);
-    seq.add_token(
-        logprobs.clone(),
+    let completion_bytes = tools::completion_bytes(
         this.get_metadata()
             .tok_trie
             .as_ref()
             .ok_or(candle_core::Error::Msg(
                 "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie"
                     .to_string(),
-            ))?
-            .decode(&[logprobs.token]),
-        &is_done,
+            ))?,
+        seq.tools.as_deref(),
+        logprobs.token,
     );
+    seq.add_token(logprobs.clone(), completion_bytes, &is_done);
     // Handle streaming requests
     if seq.get_mut_group().is_streaming {
         const STREAMING_RATE_LIMIT: usize = 3;
@@ -41,12 +43,26 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         let rate_limit_allowed = is_done.is_some() || token_index % STREAMING_RATE_LIMIT == 0;
 
         if rate_limit_allowed {
-            if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
+            if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq) {
                 if seq.get_mut_group().is_chat {
+                    // Tool calls are sent as tool call deltas instead of content
+                    let (content, tool_calls) = match seq.tool_call_stream() {
+                        Some(stream) => {
+                            let mut output = stream.push(&delta);
+                            if is_done.is_some() {
+                                let rest = stream.finish();
+                                output.content.push_str(&rest.content);
+                                output.tool_calls.extend(rest.tool_calls);
+                            }
+                            (output.content, Some(output.tool_calls))
+                        }
+                        None => (delta.clone(), None),
+                    };
                     seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
                         delta: crate::Delta {
-                            content: delta.clone(),
+                            content,
                             role: "assistant".to_string(),
+                            tool_calls: tool_calls.filter(|calls| !calls.is_empty()),
                         },
                         index: seq.get_response_index(),
                         finish_reason: is_done.map(|x| x.to_string()),
@@ -129,7 +145,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                                 "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
                                     .to_string(),
                             ))?.decode(&[logprob.token], false),
-                            seq.responder()
+                            seq
                         ),
                         bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
                         logprob: logprob.logprob,
@@ -162,15 +178,20 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 crate::sequence::StopReason::GeneratedImage => {
                     candle_core::bail!("Stop reason was `GeneratedImage`.")
                 }
+                crate::sequence::StopReason::GeneratedEmbedding => {
+                    candle_core::bail!("Stop reason was `GeneratedEmbedding`.")
+                }
             };
 
             if seq.get_mut_group().is_chat {
                 let mut tool_calls = Vec::new();
                 let mut text_new = Some(text.clone());
                 if let Some(ref matcher) = seq.tools {
-                    let calls = matcher.get_call(&text).map_err(candle_core::Error::msg)?;
+                    let (content, calls) = matcher
+                        .get_content_and_calls(&text)
+                        .map_err(candle_core::Error::msg)?;
                     if !calls.is_empty() {
-                        text_new = None;
+                        text_new = content;
                     }
                     tool_calls = calls;
                 }
@@ -191,6 +212,10 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     index: seq.get_response_index(),
                     text,
                     logprobs: None,
+                    prompt_logprobs: seq
+                        .return_prompt_logprobs()
+                        .then(|| response_logprobs(seq.prompt_logprobs())),
+                    score: None,
                 };
                 seq.add_completion_choice_to_group(choice);
             }
@@ -241,10 +266,66 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     Ok(())
 }
 
+/// Finish a scoring request after its prompt step, returning the score of the continuation.
+async fn finish_scoring(this: &dyn Pipeline, seq: &mut Sequence) -> Result<()> {
+    let reason = StopReason::Length(0);
+    seq.set_state(SequenceState::Done(reason));
+    let start = seq
+        .continuation_start()
+        .expect("Scoring sequence without a continuation.");
+    // The prompt logprobs start at the second token
+    let continuation = &seq.prompt_logprobs()[start - 1..];
+    let score = crate::ContinuationScore {
+        logprob: continuation.iter().map(|x| x.logprobs.logprob).sum(),
+        is_greedy: continuation.iter().all(|x| x.is_greedy),
+        tokens: response_logprobs(continuation),
+    };
+    let choice = crate::CompletionChoice {
+        finish_reason: reason.to_string(),
+        index: seq.get_response_index(),
+        text: String::new(),
+        logprobs: None,
+        prompt_logprobs: None,
+        score: Some(score),
+    };
+    seq.add_completion_choice_to_group(choice);
+
+    let group = seq.get_mut_group();
+    group
+        .maybe_send_completion_done_response(
+            crate::CompletionResponse {
+                id: seq.id().to_string(),
+                choices: group.get_completion_choices().to_vec(),
+                created: seq.creation_time(),
+                model: this.name(),
+                system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                object: "text_completion".to_string(),
+                usage: group.get_usage(),
+            },
+            seq.responder(),
+        )
+        .await
+        .map_err(candle_core::Error::msg)?;
+    this.reset_non_granular_state();
+    Ok(())
+}
+
+fn response_logprobs(logprobs: &[PromptLogprob]) -> Vec<crate::ResponseLogprob> {
+    logprobs
+        .iter()
+        .map(|x| crate::ResponseLogprob {
+            token: x.logprobs.bytes.clone().unwrap_or_default(),
+            bytes: x.logprobs.bytes.clone().map(|b| b.into_bytes()),
+            logprob: x.logprobs.logprob,
+            top_logprobs: x.logprobs.top_logprobs.clone().unwrap_or_default(),
+        })
+        .collect()
+}
+
 pub async fn sample_and_add_toks(
     this: &dyn Pipeline,
     seqs: &mut [&mut Sequence],
-    logits_seq: Vec<Tensor>,
+    mut logits_seq: Vec<Tensor>,
     prefix_cacher: &mut PrefixCacheManager,
     disable_eos_stop: bool,
     rng: Arc<std::sync::Mutex<Isaac64Rng>>,
@@ -252,9 +333,67 @@ pub async fn sample_and_add_toks(
     let seqs_len = seqs.len();
     debug_assert_eq!(logits_seq.len(), seqs_len);
 
+    let metadata = this.get_metadata();
+    let eos_tok = if disable_eos_stop {
+        None
+    } else {
+        Some(&metadata.eos_tok[..])
+    };
+
+    // Sequences which return prompt logprobs get the logits of all prompt tokens in their prompt
+    // step. The logits of the last token are sampled from as usual.
+    for (logits, seq) in std::iter::zip(&mut logits_seq, seqs.iter_mut()) {
+        if !(seq.is_prompt() && seq.return_prompt_logprobs()) {
+            continue;
+        }
+        let n_logits = logits.dim(0)?;
+        let toks = seq.get_toks();
+        if n_logits != toks.len() {
+            candle_core::bail!(
+                "Prompt logprobs are not supported by this model, which only returns the logits of the last token."
+            );
+        }
+        let prompt_logprobs = seq
+            .sampler()
+            .prompt_logprobs(logits.narrow(0, 0, n_logits - 1)?, &toks[1..])?;
+        seq.set_prompt_logprobs(prompt_logprobs);
+        *logits = logits.narrow(0, n_logits - 1, 1)?;
+
+        if seq.is_scoring() {
+            finish_scoring(this, seq).await?;
+        }
+    }
+
+    // The beams of a beam search request choose their tokens together
+    let mut beam_rows: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (i, seq) in seqs.iter().enumerate() {
+        if seq.beam_search().is_some() {
+            beam_rows.entry(seq.request_id()).or_default().push(i);
+        }
+    }
+    if !beam_rows.is_empty() {
+        let mut cache_rows = (0..seqs_len).collect::<Vec<_>>();
+        for rows in beam_rows.into_values() {
+            beam_search_step(
+                this,
+                seqs,
+                &logits_seq,
+                rows,
+                &mut cache_rows,
+                prefix_cacher,
+                eos_tok,
+            )
+            .await?;
+        }
+        if cache_rows.iter().enumerate().any(|(i, row)| i != *row) {
+            this.cache().reorder_batch(&cache_rows)?;
+        }
+    }
+
     let use_async_pool = seqs_len > 1;
 
     let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
+        .filter(|(_, seq)| seq.beam_search().is_none() && !seq.is_scoring())
         .map(|(logits_per_seq, seq)| {
             let return_logprobs = seq.return_logprobs();
             sample_sequence(
@@ -270,22 +409,169 @@ pub async fn sample_and_add_toks(
         .collect();
     let sampled_vec = futures::future::join_all(sampling_futures).await;
 
-    for (sampled, seq) in std::iter::zip(sampled_vec, seqs.iter_mut()) {
+    let seqs = seqs
+        .iter_mut()
+        .filter(|seq| seq.beam_search().is_none() && !seq.is_scoring());
+    for (sampled, seq) in std::iter::zip(sampled_vec, seqs) {
         let next_token = crate::handle_seq_error_stateaware_ok!(sampled, seq);
 
-        let metadata = this.get_metadata();
-        let eos_tok = if disable_eos_stop {
-            None
-        } else {
-            Some(&metadata.eos_tok[..])
-        };
-
         finish_or_add_toks_to_seq(this, prefix_cacher, seq, next_token, eos_tok, true).await?;
     }
 
     Ok(())
 }
 
+/// One step of beam search for the beams of one request, which are the sequences at `rows`.
+///
+/// The sequences are reused for the best beams after this step, and new beams are forked from the
+/// first sequence by the engine. `cache_rows` is updated with the row of the KV cache each
+/// sequence continues from. Once the search is done, the best beams are returned as the choices of
+/// the request.
+async fn beam_search_step(
+    this: &dyn Pipeline,
+    seqs: &mut [&mut Sequence],
+    logits_seq: &[Tensor],
+    mut rows: Vec<usize>,
+    cache_rows: &mut [usize],
+    prefix_cacher: &mut PrefixCacheManager,
+    eos_tok: Option<&[u32]>,
+) -> Result<()> {
+    rows.sort_by_key(|row| seqs[*row].get_response_index());
+    let first = rows[0];
+    let params = seqs[first]
+        .beam_search()
+        .expect("Beam search step on a sequence without beam search.");
+    let return_logprobs = seqs[first].return_logprobs();
+    let metadata = this.get_metadata();
+
+    let mut probs = Vec::new();
+    for row in &rows {
+        let logits = logits_seq[*row]
+            .squeeze(0)?
+            .squeeze(0)?
+            .to_dtype(DType::F32)?;
+        let sampler = seqs[*row].sampler();
+        probs.push(sampler.beam_search_probs(logits, seqs[*row].get_toks())?);
+    }
+    let logprobs = probs
+        .iter()
+        .map(|probs| probs.iter().map(|p| p.log(10.0)).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let scores = rows
+        .iter()
+        .map(|row| seqs[*row].cumulative_logprob())
+        .collect::<Vec<_>>();
+    // All beams have the same length, which is the number of generated tokens after this step
+    let len = seqs[first].logprobs().len() + 1;
+
+    let mut hyps = seqs[first]
+        .get_mut_group()
+        .beam_hypotheses
+        .take()
+        .unwrap_or_else(|| BeamHypotheses::new(params));
+    let mut next = Vec::new();
+    // Twice as many candidates as beams, so that there are enough running beams even if the best
+    // candidates finish
+    for (rank, candidate) in top_candidates(&scores, &logprobs, 2 * params.beam_width)
+        .into_iter()
+        .enumerate()
+    {
+        let parent = &seqs[rows[candidate.beam]];
+        match parent.is_done(candidate.token, eos_tok, metadata.max_seq_len) {
+            // Only finished beams which are among the best `beam_width` are kept
+            Some(_) if rank < params.beam_width => {
+                let last = parent.sampler().beam_search_logprobs(
+                    &probs[candidate.beam],
+                    candidate.token,
+                    return_logprobs,
+                )?;
+                hyps.add(
+                    candidate.score,
+                    len,
+                    BeamHypothesis {
+                        state: parent.beam_state(),
+                        last,
+                    },
+                );
+            }
+            Some(_) => (),
+            None => {
+                next.push(candidate);
+                if next.len() == params.beam_width {
+                    break;
+                }
+            }
+        }
+    }
+
+    if next.is_empty() || hyps.is_done(next[0].score, len) {
+        for row in &rows[1..] {
+            seqs[*row].set_state(SequenceState::Done(StopReason::Canceled));
+        }
+        // The first sequence returns the best beams as the choices of the request
+        let n_choices = seqs[first].get_mut_group().n_choices();
+        let seq = &mut *seqs[first];
+        for (response_index, BeamHypothesis { state, last }) in
+            hyps.into_best(n_choices).into_iter().enumerate()
+        {
+            seq.set_beam_state(state);
+            seq.set_response_index(response_index);
+            finish_or_add_toks_to_seq(this, prefix_cacher, seq, last, eos_tok, false).await?;
+        }
+        return Ok(());
+    }
+    seqs[first].get_mut_group().beam_hypotheses = Some(hyps);
+
+    let parents = rows
+        .iter()
+        .map(|row| {
+            let seq = &mut *seqs[*row];
+            let xlora_cache = seq.is_xlora().then(|| seq.xlora_cache().clone());
+            (seq.beam_state(), seq.cache().clone(), xlora_cache)
+        })
+        .collect::<Vec<_>>();
+    let tok_trie = metadata.tok_trie.as_ref().ok_or(candle_core::Error::Msg(
+        "Beam search requires the pipeline to have a token trie".to_string(),
+    ))?;
+    let n_next = next.len();
+    for (response_index, candidate) in next.into_iter().enumerate() {
+        let (state, cache, xlora_cache) = &parents[candidate.beam];
+        let token = seqs[rows[candidate.beam]].sampler().beam_search_logprobs(
+            &probs[candidate.beam],
+            candidate.token,
+            return_logprobs,
+        )?;
+        let completion_bytes =
+            tools::completion_bytes(tok_trie, seqs[first].tools.as_deref(), candidate.token);
+        match rows.get(response_index) {
+            Some(row) => {
+                let seq = &mut *seqs[*row];
+                seq.set_beam_state(state.clone());
+                *seq.cache() = cache.clone();
+                if let Some(xlora_cache) = xlora_cache {
+                    *seq.xlora_cache() = xlora_cache.clone();
+                }
+                seq.add_token(token, completion_bytes, &None);
+                cache_rows[*row] = rows[candidate.beam];
+            }
+            None => seqs[first].add_beam_fork(BeamFork {
+                response_index,
+                state: state.clone(),
+                token,
+                completion_bytes,
+                cache: cache.clone(),
+                xlora_cache: xlora_cache.clone(),
+            }),
+        }
+    }
+    // Beams without a running candidate are dropped
+    for row in rows.iter().skip(n_next) {
+        seqs[*row].set_state(SequenceState::Done(StopReason::Canceled));
+    }
+
+    Ok(())
+}
+
 /// Async sample optionally adding to trie.
 #[allow(clippy::too_many_arguments)]
 pub async fn sample_sequence(
@@ -298,10 +584,19 @@ pub async fn sample_sequence(
     sample_speculative: bool,
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    // Seeded sequences sample from their own RNG, independently of the other sequences
+    let rng = seq.rng().unwrap_or(rng);
+
+    // Mirostat updates its state when sampling, so it is restored if the token is resampled
+    let mirostat_mu = seq.mirostat_mu();
+    let initial_mirostat_mu = mirostat_mu
+        .as_ref()
+        .map(|mu| *mu.lock().expect("could not lock mirostat mutex"));
 
     let sampler = seq.sampler();
     let ctx_clone = seq.get_toks().to_vec();
     let rng_clone = rng.clone();
+    let mirostat_mu_clone = mirostat_mu.clone();
     let logits_clone = logits.clone();
     let first_lobprobs_response = if use_async_pool {
         tokio_rayon::spawn(move || {
@@ -310,6 +605,7 @@ pub async fn sample_sequence(
                 &ctx_clone,
                 return_logprobs,
                 rng_clone,
+                mirostat_mu_clone,
                 sample_speculative,
             )
         })
@@ -320,6 +616,7 @@ pub async fn sample_sequence(
             &ctx_clone,
             return_logprobs,
             rng_clone,
+            mirostat_mu_clone,
             sample_speculative,
         )?
     };
@@ -347,6 +644,10 @@ pub async fn sample_sequence(
             token_set.apply_to(&mut acc);
             let new_logits = (logits + Tensor::from_slice(&acc, acc.len(), &Device::Cpu)?)?;
 
+            if let (Some(mu), Some(initial)) = (&mirostat_mu, initial_mirostat_mu) {
+                *mu.lock().expect("could not lock mirostat mutex") = initial;
+            }
+
             let ctx_clone = seq.get_toks().to_vec();
             let rng_clone = rng.clone();
             let sampler = seq.sampler();
@@ -357,6 +658,7 @@ pub async fn sample_sequence(
                         &ctx_clone,
                         return_logprobs,
                         rng_clone,
+                        mirostat_mu,
                         sample_speculative,
                     )
                 })
@@ -367,6 +669,7 @@ pub async fn sample_sequence(
                     &ctx_clone,
                     return_logprobs,
                     rng_clone,
+                    mirostat_mu,
                     sample_speculative,
                 )?
             }
@@ -399,6 +702,8 @@ pub async fn sample_sequence(
 #[derive(Clone)]
 pub struct SpeculativeSample {
     pub sample: Logprobs,
+    /// The Mirostat state after this sample, if the sequence uses Mirostat.
+    pub mirostat_mu: Option<f32>,
 }
 
 /// Async sample without modifying sequence.
@@ -411,17 +716,21 @@ pub async fn sample_target_sequence_speculative(
 ) -> Result<Vec<SpeculativeSample>> {
     let mut sampled = Vec::new();
     for chunk in logits.chunk(n_toks, 1)? {
+        let sample = sample_sequence(
+            chunk,
+            seq,
+            return_logprobs,
+            rng.clone(),
+            true,  // TODO(EricLBuehler): does this hurt perf?
+            false, // Do not append to trie (yet)
+            true,
+        )
+        .await?;
         sampled.push(SpeculativeSample {
-            sample: sample_sequence(
-                chunk,
-                seq,
-                return_logprobs,
-                rng.clone(),
-                true,  // TODO(EricLBuehler): does this hurt perf?
-                false, // Do not append to trie (yet)
-                true,
-            )
-            .await?,
+            sample,
+            mirostat_mu: seq
+                .mirostat_mu()
+                .map(|mu| *mu.lock().expect("could not lock mirostat mutex")),
         });
     }
     Ok(sampled)
@@ -1,6 +1,5 @@
 use std::{
     any::Any,
-    iter::zip,
     sync::{Arc, Mutex},
 };
 
@@ -8,11 +7,13 @@ use anyhow::Result as anyhowResult;
 use candle_core::{Device, IndexOp, Result, Tensor};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
+use serde::Deserialize;
 use tokenizers::Tokenizer;
 use tracing::warn;
 
 use crate::{
     get_mut_arcmutex,
+    kv_quant::KvCacheDtype,
     pipeline::{
         sampling::{
             finish_or_add_toks_to_seq, sample_sequence, sample_target_sequence_speculative,
@@ -26,19 +27,41 @@ use crate::{
 };
 
 use super::{
-    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, sampling::SpeculativeSample,
-    AdapterActivationMixin, AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction,
-    CacheManager, CacheManagerMixin, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
-    MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
+    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, AdapterActivationMixin,
+    AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction, CacheManager, CacheManagerMixin,
+    ForwardInputsResult, GeneralMetadata, IsqPipelineMixin, MetadataMixin, ModelCategory,
+    ModelPaths, PreProcessingMixin,
 };
 
-/// A loader for a speculative pipeline using 2 [`Loader`]s.
+/// A loader for a speculative pipeline using 2 [`Loader`]s, or only the target [`Loader`] with
+/// [`SpeculativeMethod::PromptLookup`].
 pub struct SpeculativeLoader {
     pub target: Box<dyn Loader>,
-    pub draft: Box<dyn Loader>,
+    /// The draft model, which is required for [`SpeculativeMethod::DraftModel`] and must be
+    /// `None` for [`SpeculativeMethod::PromptLookup`].
+    pub draft: Option<Box<dyn Loader>>,
     pub config: SpeculativeConfig,
 }
 
+impl SpeculativeLoader {
+    /// PagedAttention is not supported, so the models are loaded without it, with a warning if it
+    /// was requested.
+    fn without_paged_attn(
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Option<PagedAttentionConfig> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+        None
+    }
+
+    fn check_config(&self) -> anyhowResult<()> {
+        self.config.check(self.draft.is_some())
+    }
+}
+
 impl Loader for SpeculativeLoader {
     #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     fn load_model_from_hf(
@@ -52,14 +75,8 @@ impl Loader for SpeculativeLoader {
         in_situ_quant: Option<IsqType>,
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
-        let paged_attn_config = if paged_attn_config.is_none() {
-            warn!(
-                "Speculative decoding does not currently support PagedAttention, running without"
-            );
-            None
-        } else {
-            paged_attn_config
-        };
+        self.check_config()?;
+        let paged_attn_config = Self::without_paged_attn(paged_attn_config);
 
         let target = self.target.load_model_from_hf(
             revision.clone(),
@@ -71,16 +88,22 @@ impl Loader for SpeculativeLoader {
             in_situ_quant,
             paged_attn_config,
         )?;
-        let draft = self.draft.load_model_from_hf(
-            revision,
-            token_source,
-            dtype,
-            device,
-            silent,
-            mapper,
-            in_situ_quant,
-            paged_attn_config,
-        )?;
+        let draft = self
+            .draft
+            .as_ref()
+            .map(|draft| {
+                draft.load_model_from_hf(
+                    revision,
+                    token_source,
+                    dtype,
+                    device,
+                    silent,
+                    mapper,
+                    in_situ_quant,
+                    paged_attn_config,
+                )
+            })
+            .transpose()?;
         Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
             target,
             draft,
@@ -99,14 +122,8 @@ impl Loader for SpeculativeLoader {
         in_situ_quant: Option<IsqType>,
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
-        let paged_attn_config = if paged_attn_config.is_none() {
-            warn!(
-                "Speculative decoding does not currently support PagedAttention, running without"
-            );
-            None
-        } else {
-            paged_attn_config
-        };
+        self.check_config()?;
+        let paged_attn_config = Self::without_paged_attn(paged_attn_config);
 
         let target = self.target.load_model_from_path(
             paths,
@@ -117,15 +134,21 @@ impl Loader for SpeculativeLoader {
             in_situ_quant,
             paged_attn_config,
         )?;
-        let draft = self.draft.load_model_from_path(
-            paths,
-            dtype,
-            device,
-            silent,
-            mapper.clone(),
-            in_situ_quant,
-            paged_attn_config,
-        )?;
+        let draft = self
+            .draft
+            .as_ref()
+            .map(|draft| {
+                draft.load_model_from_path(
+                    paths,
+                    dtype,
+                    device,
+                    silent,
+                    mapper.clone(),
+                    in_situ_quant,
+                    paged_attn_config,
+                )
+            })
+            .transpose()?;
         Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
             target,
             draft,
@@ -133,17 +156,29 @@ impl Loader for SpeculativeLoader {
         )?)))
     }
     fn get_id(&self) -> String {
-        format!(
-            "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
-            self.target.get_id(),
-            self.draft.get_id(),
-            self.config.gamma,
-        )
+        match &self.draft {
+            Some(draft) => format!(
+                "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
+                self.target.get_id(),
+                draft.get_id(),
+                self.config.gamma,
+            ),
+            None => format!(
+                "Speculative: tgt = `{}`, prompt lookup, gamma = `{}`",
+                self.target.get_id(),
+                self.config.gamma,
+            ),
+        }
     }
     fn get_kind(&self) -> ModelKind {
-        ModelKind::Speculative {
-            target: Box::new(self.target.get_kind()),
-            draft: Box::new(self.draft.get_kind()),
+        match &self.draft {
+            Some(draft) => ModelKind::Speculative {
+                target: Box::new(self.target.get_kind()),
+                draft: Box::new(draft.get_kind()),
+            },
+            None => ModelKind::PromptLookup {
+                target: Box::new(self.target.get_kind()),
+            },
         }
     }
 }
@@ -159,27 +194,196 @@ impl Loader for SpeculativeLoader {
 /// - Else (q_i(x) > p_i(x)) accept that token with prob p_i(x)/q_i(x)
 ///     - If rejected, sample token from from p'_i(x) = norm(max(0, p(x) − q(x))) and do not take any more'
 ///
+/// Without a draft model, the tokens are proposed by prompt lookup: <https://github.com/apoorvumang/prompt-lookup-decoding>
 pub struct SpeculativePipeline {
     target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    draft: Option<Arc<tokio::sync::Mutex<dyn Pipeline>>>,
     gamma: usize,
+    method: SpeculativeMethod,
+    adaptive_gamma: Option<AdaptiveGamma>,
+    stats: SpeculativeStats,
     metadata: Arc<GeneralMetadata>,
     category: ModelCategory,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// How the tokens which are verified by the target model are proposed.
+pub enum SpeculativeMethod {
+    /// Run a draft model γ times.
+    DraftModel,
+    /// Find the most recent earlier occurrence of the last tokens of the sequence, with at most
+    /// `max_ngram_size` tokens, and propose the (up to γ) tokens which followed it. This does not
+    /// need a draft model, and works well when the output copies from the prompt.
+    PromptLookup { max_ngram_size: usize },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+/// Bounds within which γ is adapted for each sequence. γ grows by 2 after a step in which all of
+/// the proposed tokens were accepted, and shrinks by 1 otherwise.
+pub struct AdaptiveGamma {
+    pub min_gamma: usize,
+    pub max_gamma: usize,
+}
+
+impl AdaptiveGamma {
+    fn next_gamma(&self, gamma: usize, drafted: usize, accepted: usize) -> usize {
+        if drafted == 0 {
+            // Nothing was proposed, so there is nothing to learn from
+            gamma
+        } else if accepted == drafted {
+            (gamma + 2).min(self.max_gamma)
+        } else {
+            gamma.saturating_sub(1).max(self.min_gamma)
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 /// Metadata for a speculative pipeline
 pub struct SpeculativeConfig {
-    /// γ completions to run of the draft model
+    /// γ tokens to propose for each step of the target model. With `adaptive_gamma`, this is the
+    /// initial γ of each sequence.
     pub gamma: usize,
+    pub method: SpeculativeMethod,
+    /// Adapt γ for each sequence to its acceptance rate.
+    pub adaptive_gamma: Option<AdaptiveGamma>,
+}
+
+impl SpeculativeConfig {
+    /// Check that the γ bounds are valid, and that a draft model is given if and only if the
+    /// method uses it.
+    fn check(&self, has_draft: bool) -> anyhowResult<()> {
+        if let Some(AdaptiveGamma {
+            min_gamma,
+            max_gamma,
+        }) = self.adaptive_gamma
+        {
+            if min_gamma == 0 || min_gamma > max_gamma {
+                anyhow::bail!("The adaptive gamma bounds must satisfy `0 < min_gamma <= max_gamma`, got {min_gamma} and {max_gamma}.");
+            }
+        }
+        match (&self.method, has_draft) {
+            (SpeculativeMethod::DraftModel, false) => {
+                anyhow::bail!("Speculative decoding with a draft model requires a draft model.")
+            }
+            (SpeculativeMethod::PromptLookup { .. }, true) => {
+                anyhow::bail!("Prompt lookup speculative decoding does not use a draft model.")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+/// Acceptance statistics of speculative decoding.
+pub struct SpeculativeStats {
+    /// Number of steps of the target model.
+    pub steps: usize,
+    /// Number of tokens proposed by the draft model or prompt lookup.
+    pub drafted: usize,
+    /// Number of proposed tokens accepted by the target model.
+    pub accepted: usize,
+}
+
+impl SpeculativeStats {
+    pub(crate) fn add_step(&mut self, drafted: usize, accepted: usize) {
+        self.steps += 1;
+        self.drafted += drafted;
+        self.accepted += accepted;
+    }
+
+    pub(crate) fn merge(&mut self, other: &Self) {
+        self.steps += other.steps;
+        self.drafted += other.drafted;
+        self.accepted += other.accepted;
+    }
+
+    /// Fraction of the proposed tokens which were accepted, or 0 if no tokens were proposed.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.drafted == 0 {
+            0.
+        } else {
+            self.accepted as f32 / self.drafted as f32
+        }
+    }
+
+    /// Average number of tokens proposed per step, or 0 if there were no steps.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn drafted_per_step(&self) -> f32 {
+        if self.steps == 0 {
+            0.
+        } else {
+            self.drafted as f32 / self.steps as f32
+        }
+    }
+}
+
+/// Propose up to `gamma` tokens which followed the most recent earlier occurrence of the longest
+/// suffix of `toks` with at most `max_ngram_size` tokens.
+fn prompt_lookup_proposals(toks: &[u32], max_ngram_size: usize, gamma: usize) -> Vec<u32> {
+    for n in (1..=max_ngram_size.min(toks.len().saturating_sub(1))).rev() {
+        let suffix = &toks[toks.len() - n..];
+        if let Some(start) = (0..toks.len() - n)
+            .rev()
+            .find(|start| &toks[*start..*start + n] == suffix)
+        {
+            let end = (start + n + gamma).min(toks.len());
+            return toks[start + n..end].to_vec();
+        }
+    }
+    Vec::new()
 }
 
 impl SpeculativePipeline {
+    /// Fails if the config is invalid, such as γ bounds with `min_gamma > max_gamma`, or if the
+    /// models cannot be used together.
     pub fn new(
         target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-        draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        draft: Option<Arc<tokio::sync::Mutex<dyn Pipeline>>>,
         config: SpeculativeConfig,
     ) -> Result<Self> {
+        config
+            .check(draft.is_some())
+            .map_err(candle_core::Error::msg)?;
+        if let Some(draft) = &draft {
+            Self::check_draft(&target, draft)?;
+        }
+        // Rejected draft tokens are removed by truncating the KV cache
+        if get_mut_arcmutex!(target).get_metadata().is_recurrent
+            || draft
+                .as_ref()
+                .is_some_and(|draft| get_mut_arcmutex!(draft).get_metadata().is_recurrent)
+        {
+            candle_core::bail!(
+                "Speculative decoding is not supported for recurrent models, as their state cannot be rolled back."
+            );
+        }
+        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let category = get_mut_arcmutex!(target).category();
+        let gamma = match config.adaptive_gamma {
+            Some(AdaptiveGamma {
+                min_gamma,
+                max_gamma,
+            }) => config.gamma.clamp(min_gamma, max_gamma),
+            None => config.gamma,
+        };
+        Ok(Self {
+            target,
+            draft,
+            gamma,
+            method: config.method,
+            adaptive_gamma: config.adaptive_gamma,
+            stats: SpeculativeStats::default(),
+            metadata,
+            category,
+        })
+    }
+
+    fn check_draft(
+        target: &Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        draft: &Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    ) -> Result<()> {
         if get_mut_arcmutex!(target)
             .tokenizer()
             .as_ref()
@@ -213,16 +417,7 @@ impl SpeculativePipeline {
         {
             candle_core::bail!("Target and draft models' input processors do not match. This is required for speculative decoding.");
         }
-        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
-        let category = get_mut_arcmutex!(target).category();
-        // TODO: some checks or relaxation here?
-        Ok(Self {
-            target,
-            draft,
-            gamma: config.gamma,
-            metadata,
-            category,
-        })
+        Ok(())
     }
 }
 
@@ -238,29 +433,38 @@ impl PreProcessingMixin for SpeculativePipeline {
 impl IsqPipelineMixin for SpeculativePipeline {
     fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+        if let Some(draft) = &self.draft {
+            get_mut_arcmutex!(draft).re_isq_model(dtype)?;
+        }
+        Ok(())
     }
 }
 
 impl CacheManagerMixin for SpeculativePipeline {
     fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_in_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-        );
+        if let Some(draft) = &self.draft {
+            DefaultCacheManager.clone_in_cache(
+                &*get_mut_arcmutex!(draft),
+                seqs,
+                modify_draft_cache,
+            );
+        }
         DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false);
     }
     fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_out_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-        );
+        if let Some(draft) = &self.draft {
+            DefaultCacheManager.clone_out_cache(
+                &*get_mut_arcmutex!(draft),
+                seqs,
+                modify_draft_cache,
+            );
+        }
         DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false);
     }
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
-        DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.draft), modify_draft_cache);
+        if let Some(draft) = &self.draft {
+            DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(draft), modify_draft_cache);
+        }
         DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.target), false);
         if reset_non_granular {
             self.reset_non_granular_state()
@@ -269,13 +473,21 @@ impl CacheManagerMixin for SpeculativePipeline {
     fn cache(&self) -> &Cache {
         unreachable!()
     }
+    fn set_kv_cache_dtype(&self, kv_cache_dtype: KvCacheDtype) {
+        if let Some(draft) = &self.draft {
+            get_mut_arcmutex!(draft).set_kv_cache_dtype(kv_cache_dtype);
+        }
+        get_mut_arcmutex!(self.target).set_kv_cache_dtype(kv_cache_dtype);
+    }
 }
 
 impl AdapterActivationMixin for SpeculativePipeline {
     /// Returns the number of activated adapters.
     fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
         let mut res = 0;
-        res += get_mut_arcmutex!(self.draft).activate_adapters(adapters.clone())?;
+        if let Some(draft) = &self.draft {
+            res += get_mut_arcmutex!(draft).activate_adapters(adapters.clone())?;
+        }
         res += get_mut_arcmutex!(self.target).activate_adapters(adapters)?;
         Ok(res)
     }
@@ -289,16 +501,25 @@ impl MetadataMixin for SpeculativePipeline {
         get_mut_arcmutex!(self.target).tokenizer()
     }
     fn name(&self) -> String {
-        format!(
-            "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
-            get_mut_arcmutex!(self.target).name(),
-            get_mut_arcmutex!(self.draft).name(),
-            self.gamma,
-        )
+        match &self.draft {
+            Some(draft) => format!(
+                "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                get_mut_arcmutex!(draft).name(),
+                self.gamma,
+            ),
+            None => format!(
+                "Speculative: tgt = `{}`, prompt lookup, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                self.gamma,
+            ),
+        }
     }
     fn reset_non_granular_state(&self) {
         get_mut_arcmutex!(self.target).reset_non_granular_state();
-        get_mut_arcmutex!(self.draft).reset_non_granular_state();
+        if let Some(draft) = &self.draft {
+            get_mut_arcmutex!(draft).reset_non_granular_state();
+        }
     }
     fn get_metadata(&self) -> Arc<GeneralMetadata> {
         self.metadata.clone()
@@ -329,6 +550,12 @@ impl Pipeline for SpeculativePipeline {
         rng: Arc<Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<()> {
+        if input_seqs
+            .iter()
+            .any(|seq| seq.embedding_params().is_some())
+        {
+            candle_core::bail!("Embeddings are not supported with speculative decoding.");
+        }
         match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
                 match pre_op {
@@ -385,69 +612,93 @@ impl Pipeline for SpeculativePipeline {
                 assert_eq!(input_seqs.len(), 1);
 
                 let seq = &mut input_seqs[0];
+                let gamma = seq.speculative_gamma().unwrap_or(self.gamma);
 
-                // ======================= Run draft model gamma times producing tokens ============================
-                // ======================= Sample the `gamma` logits. ============================
-                let mut draft_samples = Vec::new();
-                for i in 0..self.gamma {
-                    let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
-                    let device = get_mut_arcmutex!(self.draft).device();
-                    let has_no_kv_cache =
-                        get_mut_arcmutex!(self.draft).get_metadata().has_no_kv_cache;
-                    let inputs = self
-                        .get_processor()
-                        .inputs_processor()
-                        .process_inputs(
-                            self.tokenizer(),
-                            &mut [seq],
-                            is_prompt && i == 0, // Only prompt (no kv cache) if first
-                            is_xlora,
-                            &device,
-                            has_no_kv_cache,
-                            None,
-                            None,
-                            None, // TODO: get block tables/handle it
-                            None, // TODO: do we support???
-                        )
-                        .nth(0)
-                        .unwrap()
-                        .unwrap();
-                    let logits = get_mut_arcmutex!(self.draft).forward_inputs(Box::new(inputs))?;
-                    #[allow(irrefutable_let_patterns)]
-                    let ForwardInputsResult::CausalGeneration { logits } = logits
-                    else {
-                        candle_core::bail!(
-                            "Speculative decoding requires `CausalGeneration` forward results"
-                        );
-                    };
-
-                    let sample = sample_sequence(
-                        logits.clone(),
-                        seq,
-                        seq.return_logprobs(),
-                        rng.clone(),
-                        false, // todo tune
-                        false, // do not add to tok trie yet
-                        true,
-                    )
-                    .await?;
-                    seq.add_tmp_tok(sample.token);
-                    draft_samples.push(SpeculativeSample { sample });
+                // Mirostat updates its state with each sample, but only the samples of the target
+                // model which are accepted may do so
+                let mirostat_mu = seq.mirostat_mu();
+                let initial_mirostat_mu = mirostat_mu
+                    .as_ref()
+                    .map(|mu| *mu.lock().expect("could not lock mirostat mutex"));
+
+                // ======================= Propose up to `gamma` tokens ============================
+                let draft_tokens = match (&self.draft, self.method) {
+                    (Some(draft), SpeculativeMethod::DraftModel) => {
+                        // Run draft model gamma times producing tokens
+                        let mut draft_tokens = Vec::new();
+                        for i in 0..gamma {
+                            let is_xlora = get_mut_arcmutex!(draft).get_metadata().is_xlora;
+                            let device = get_mut_arcmutex!(draft).device();
+                            let has_no_kv_cache =
+                                get_mut_arcmutex!(draft).get_metadata().has_no_kv_cache;
+                            let inputs = self
+                                .get_processor()
+                                .inputs_processor()
+                                .process_inputs(
+                                    self.tokenizer(),
+                                    &mut [seq],
+                                    is_prompt && i == 0, // Only prompt (no kv cache) if first
+                                    is_xlora,
+                                    &device,
+                                    has_no_kv_cache,
+                                    None,
+                                    None,
+                                    None, // TODO: get block tables/handle it
+                                    None, // TODO: do we support???
+                                )
+                                .nth(0)
+                                .unwrap()
+                                .unwrap();
+                            let logits =
+                                get_mut_arcmutex!(draft).forward_inputs(Box::new(inputs))?;
+                            #[allow(irrefutable_let_patterns)]
+                            let ForwardInputsResult::CausalGeneration { logits } = logits
+                            else {
+                                candle_core::bail!(
+                                    "Speculative decoding requires `CausalGeneration` forward results"
+                                );
+                            };
+
+                            let sample = sample_sequence(
+                                logits.clone(),
+                                seq,
+                                seq.return_logprobs(),
+                                rng.clone(),
+                                false, // todo tune
+                                false, // do not add to tok trie yet
+                                true,
+                            )
+                            .await?;
+                            seq.add_tmp_tok(sample.token);
+                            draft_tokens.push(sample.token);
+                        }
+                        seq.remove_tmp_tok(gamma);
+                        draft_tokens
+                    }
+                    (None, SpeculativeMethod::PromptLookup { max_ngram_size }) => {
+                        prompt_lookup_proposals(seq.get_toks(), max_ngram_size, gamma)
+                    }
+                    _ => unreachable!("The draft model is checked when loading."),
+                };
+                if let (Some(mu), Some(initial)) = (&mirostat_mu, initial_mirostat_mu) {
+                    *mu.lock().expect("could not lock mirostat mutex") = initial;
                 }
-                seq.remove_tmp_tok(self.gamma);
 
-                // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
+                // The target model verifies every draft token. A draft model also proposes the
+                // last token, which the target model samples itself. Otherwise, the target model
+                // samples one more token after the draft tokens.
+                let n_target = match self.method {
+                    SpeculativeMethod::DraftModel => gamma,
+                    SpeculativeMethod::PromptLookup { .. } => draft_tokens.len() + 1,
+                };
+
+                // ======================= Add the draft tokens which the target model verifies. Add the last from the seq. ============================
                 let mut draft_prefill_tokens = if is_prompt {
                     seq.get_toks().to_vec()
                 } else {
                     vec![*seq.get_toks().last().unwrap()]
                 };
-                for (i, sample) in draft_samples.iter().enumerate() {
-                    if i == draft_samples.len() - 1 {
-                        continue;
-                    }
-                    draft_prefill_tokens.push(sample.sample.token);
-                }
+                draft_prefill_tokens.extend(&draft_tokens[..n_target - 1]);
                 seq.set_prefill_toks(draft_prefill_tokens);
 
                 // ======================= Run the model with all draft tokens. ============================
@@ -473,7 +724,7 @@ impl Pipeline for SpeculativePipeline {
                         is_xlora,
                         &device,
                         has_no_kv_cache,
-                        Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
+                        Some((n_target, initial_cache_len)), // Get the last n_target, see above
                         None,
                         None, // TODO: get block tables/handle it
                         None, // TODO: do we support???
@@ -501,40 +752,55 @@ impl Pipeline for SpeculativePipeline {
                     seq,
                     seq.return_logprobs(),
                     rng.clone(),
-                    self.gamma,
+                    n_target,
                 )
                 .await?;
 
                 let mut accepted_tokens = Vec::new();
-                for (target_sample, draft_sample) in zip(samples, draft_samples) {
+                let mut n_accepted_drafts = 0;
+                let mut accepted_mirostat_mu = initial_mirostat_mu;
+                for (i, target_sample) in samples.into_iter().enumerate() {
                     let tok = target_sample.sample.token;
                     accepted_tokens.push(target_sample.sample);
-                    if draft_sample.sample.token != tok {
+                    accepted_mirostat_mu = target_sample.mirostat_mu;
+                    if draft_tokens.get(i) != Some(&tok) {
                         break;
                     }
+                    n_accepted_drafts += 1;
+                }
+                if let (Some(mu), Some(accepted)) = (&mirostat_mu, accepted_mirostat_mu) {
+                    *mu.lock().expect("could not lock mirostat mutex") = accepted;
                 }
 
-                // ======================= Narrow caches to account for rejections ============================
-                let n_not_accepted = self.gamma - accepted_tokens.len();
-                for (k, v) in get_mut_arcmutex!(self.draft)
-                    .cache()
-                    .lock()
-                    .iter_mut()
-                    .flatten()
-                {
-                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                // ======================= Record the acceptance and adapt gamma ============================
+                self.stats.add_step(draft_tokens.len(), n_accepted_drafts);
+                seq.add_speculative_step(draft_tokens.len(), n_accepted_drafts);
+                if let Some(adaptive_gamma) = &self.adaptive_gamma {
+                    seq.set_speculative_gamma(adaptive_gamma.next_gamma(
+                        gamma,
+                        draft_tokens.len(),
+                        n_accepted_drafts,
+                    ));
                 }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    for (k, v) in get_mut_arcmutex!(self.draft)
-                        .cache()
-                        .xlora_lock()
-                        .iter_mut()
-                        .flatten()
-                    {
+
+                // ======================= Narrow caches to account for rejections ============================
+                let n_not_accepted = n_target - accepted_tokens.len();
+                if let Some(draft) = &self.draft {
+                    for (k, v) in get_mut_arcmutex!(draft).cache().lock().iter_mut().flatten() {
                         *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
                         *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
                     }
+                    if get_mut_arcmutex!(draft).get_metadata().is_xlora {
+                        for (k, v) in get_mut_arcmutex!(draft)
+                            .cache()
+                            .xlora_lock()
+                            .iter_mut()
+                            .flatten()
+                        {
+                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                        }
+                    }
                 }
                 for (k, v) in get_mut_arcmutex!(self.target)
                     .cache()
@@ -545,7 +811,7 @@ impl Pipeline for SpeculativePipeline {
                     *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
                     *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
                 }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
+                if get_mut_arcmutex!(self.target).get_metadata().is_xlora {
                     for (k, v) in get_mut_arcmutex!(self.target)
                         .cache()
                         .xlora_lock()
@@ -654,7 +920,94 @@ impl Pipeline for SpeculativePipeline {
     fn category(&self) -> ModelCategory {
         self.category
     }
+    fn speculative_stats(&self) -> Option<SpeculativeStats> {
+        Some(self.stats)
+    }
 }
 
 // TODO
 impl AnyMoePipelineMixin for SpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        prompt_lookup_proposals, AdaptiveGamma, SpeculativeConfig, SpeculativeLoader,
+        SpeculativeMethod, SpeculativeStats,
+    };
+    use crate::{MemoryGpuConfig, PagedAttentionConfig};
+
+    #[test]
+    fn test_without_paged_attn() {
+        let config = PagedAttentionConfig::new(None, 512, MemoryGpuConfig::Amount(1024)).unwrap();
+        assert!(SpeculativeLoader::without_paged_attn(Some(config)).is_none());
+        assert!(SpeculativeLoader::without_paged_attn(None).is_none());
+    }
+
+    #[test]
+    fn test_prompt_lookup_proposals() {
+        // The longest suffix `2 3` matches, not only `3`
+        let toks = [1, 2, 3, 4, 5, 3, 6, 2, 3];
+        assert_eq!(prompt_lookup_proposals(&toks, 3, 2), vec![4, 5]);
+        assert_eq!(prompt_lookup_proposals(&toks, 1, 2), vec![6, 2]);
+        // The most recent occurrence is used, and proposals stop at the end of the sequence
+        let toks = [7, 1, 8, 1, 9, 1];
+        assert_eq!(prompt_lookup_proposals(&toks, 3, 5), vec![9, 1]);
+        // No match
+        assert!(prompt_lookup_proposals(&[1, 2, 3], 3, 4).is_empty());
+        assert!(prompt_lookup_proposals(&[1], 3, 4).is_empty());
+    }
+
+    #[test]
+    fn test_adaptive_gamma() {
+        let adaptive = AdaptiveGamma {
+            min_gamma: 2,
+            max_gamma: 9,
+        };
+        assert_eq!(adaptive.next_gamma(4, 4, 4), 6);
+        assert_eq!(adaptive.next_gamma(8, 8, 8), 9);
+        assert_eq!(adaptive.next_gamma(4, 4, 1), 3);
+        assert_eq!(adaptive.next_gamma(2, 2, 0), 2);
+        // Prompt lookup may propose nothing
+        assert_eq!(adaptive.next_gamma(4, 0, 0), 4);
+    }
+
+    #[test]
+    fn test_check_config() {
+        let config = |method, min_gamma, max_gamma| SpeculativeConfig {
+            gamma: 4,
+            method,
+            adaptive_gamma: Some(AdaptiveGamma {
+                min_gamma,
+                max_gamma,
+            }),
+        };
+        let lookup = SpeculativeMethod::PromptLookup { max_ngram_size: 3 };
+        assert!(config(lookup, 1, 8).check(false).is_ok());
+        assert!(config(lookup, 2, 2).check(false).is_ok());
+        assert!(config(lookup, 8, 1).check(false).is_err());
+        assert!(config(lookup, 0, 8).check(false).is_err());
+        assert!(config(lookup, 1, 8).check(true).is_err());
+        assert!(config(SpeculativeMethod::DraftModel, 1, 8)
+            .check(false)
+            .is_err());
+        assert!(config(SpeculativeMethod::DraftModel, 1, 8)
+            .check(true)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = SpeculativeStats::default();
+        assert_eq!(stats.acceptance_rate(), 0.);
+        assert_eq!(stats.drafted_per_step(), 0.);
+        stats.add_step(4, 3);
+        stats.add_step(4, 1);
+        assert_eq!(stats.steps, 2);
+        assert_eq!(stats.acceptance_rate(), 0.5);
+        assert_eq!(stats.drafted_per_step(), 4.);
+        let mut total = SpeculativeStats::default();
+        total.merge(&stats);
+        total.merge(&stats);
+        assert_eq!((total.steps, total.drafted, total.accepted), (4, 16, 8));
+    }
+}
@@ -310,6 +310,7 @@ impl Loader for VisionLoader {
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
                 dtype,
+                paged_attn_config.cache_dtype,
                 model.config(),
                 device,
             )?;
@@ -337,6 +338,7 @@ impl Loader for VisionLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 has_no_kv_cache: false,
+                is_recurrent: false,
                 activation_dtype: dtype,
                 sliding_window,
                 cache_config,
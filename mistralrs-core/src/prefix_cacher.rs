@@ -32,6 +32,8 @@ pub struct PrefixCacheManager {
     pub n_on_device: usize,
     no_prefix_cache: bool,
     eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    n_hits: u64,
+    n_misses: u64,
 }
 
 #[derive(Clone)]
@@ -50,6 +52,8 @@ impl PrefixCacheManager {
             n_on_device,
             no_prefix_cache,
             eviction_cache_ptrs: Vec::new(),
+            n_hits: 0,
+            n_misses: 0,
         }
     }
 
@@ -167,6 +171,7 @@ impl PrefixCacheManager {
 
         let toks = Tokens(toks.to_vec());
         if let Some(cache) = self.caches.get(&toks) {
+            self.n_hits += 1;
             Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
             let cache = get_mut_arcmutex!(cache.as_ref()).clone();
             let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
@@ -190,7 +195,13 @@ impl PrefixCacheManager {
                 toks: toks.0[ancestor.len()..].to_vec(),
             }))
         } else {
+            self.n_misses += 1;
             Ok(None)
         }
     }
+
+    /// Number of searches which found a matching cache and which did not.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (self.n_hits, self.n_misses)
+    }
 }
@@ -7,19 +7,37 @@ use crate::{
     response::Response,
     sampler::SamplingParams,
     tools::{Tool, ToolChoice},
-    CustomLogitsProcessor, DiffusionGenerationParams,
+    CustomLogitsProcessor, DiffusionGenerationParams, EmbeddingPooling,
 };
 use std::{fmt::Debug, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, Yacc or a JSON Schema.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    /// A JSON Schema which the generated JSON document must conform to.
+    JsonSchema(serde_json::Value),
     None,
 }
 
+#[derive(
+    Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+#[serde(rename_all = "lowercase")]
+/// The priority class of a request. Waiting sequences of higher classes are scheduled first, and
+/// preempt running sequences of lower classes when there is no room for them.
+pub enum RequestPriority {
+    /// Bulk work, such as batch jobs.
+    Low,
+    #[default]
+    Normal,
+    /// Interactive requests.
+    High,
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
 #[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
 /// Image generation response format
@@ -38,6 +56,8 @@ pub enum RequestMessage {
         text: String,
         echo_prompt: bool,
         best_of: usize,
+        /// Return the logprobs of the prompt tokens, computed in the prompt step.
+        prompt_logprobs: bool,
     },
     CompletionTokens(Vec<u32>),
     VisionChat {
@@ -49,6 +69,17 @@ pub enum RequestMessage {
         format: ImageGenerationResponseFormat,
         generation_params: DiffusionGenerationParams,
     },
+    /// Pool the hidden states of a text or of tokens into an embedding.
+    Embedding {
+        input: Either<String, Vec<u32>>,
+        pooling: EmbeddingPooling,
+        normalize: bool,
+    },
+    /// Compute the log-likelihood of `continuation` given `prompt`, without generating tokens.
+    Score {
+        prompt: String,
+        continuation: String,
+    },
 }
 
 #[derive(Clone)]
@@ -69,6 +100,9 @@ pub enum RequestMessage {
 ///     2) Apply these custom logits processors sequentially
 ///     3) Apply temperature and softmax
 ///     4) Sample the next token (topk, topp, minp, etc)
+/// - `priority`: Priority class of the sequences of this request in the scheduler
+/// - `tenant`: Tenant (user) of this request. The scheduler shares the running sequences fairly
+///   between the tenants of a priority class
 pub struct NormalRequest {
     pub messages: RequestMessage,
     pub sampling_params: SamplingParams,
@@ -82,6 +116,8 @@ pub struct NormalRequest {
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
+    pub priority: RequestPriority,
+    pub tenant: Option<String>,
 }
 
 impl NormalRequest {
@@ -106,10 +142,41 @@ impl NormalRequest {
             suffix: None,
             adapters: None,
             logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
         }
     }
 }
 
+#[derive(Clone)]
+/// A request to tokenize a text, or chat messages with the chat template of the model.
+pub struct TokenizationRequest {
+    pub text: Either<Vec<IndexMap<String, MessageContent>>, String>,
+    /// Tools to render in the chat template of the messages.
+    pub tools: Option<Vec<Tool>>,
+    pub add_generation_prompt: bool,
+    /// Add the special tokens of the tokenizer, such as the BOS token, to a text. The chat
+    /// template adds them to messages.
+    pub add_special_tokens: bool,
+    pub response: Sender<anyhow::Result<Tokenization>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+/// The tokens of a [`TokenizationRequest`], and the prompt they encode: the text, or the messages
+/// rendered with the chat template.
+pub struct Tokenization {
+    pub tokens: Vec<u32>,
+    pub prompt: String,
+}
+
+#[derive(Clone)]
+/// A request to decode tokens into text.
+pub struct DetokenizationRequest {
+    pub tokens: Vec<u32>,
+    pub skip_special_tokens: bool,
+    pub response: Sender<anyhow::Result<String>>,
+}
+
 #[derive(Clone)]
 /// A request to the Engine, encapsulating the various parameters as well as
 /// the `mspc` response `Sender` used to return the [`Response`].
@@ -117,6 +184,15 @@ pub enum Request {
     Normal(NormalRequest),
     ReIsq(IsqType),
     ActivateAdapters(Vec<String>),
+    /// Tokenize with the tokenizer and chat template of the model, without running it.
+    Tokenize(TokenizationRequest),
+    Detokenize(DetokenizationRequest),
+    /// Cancel the sequences of the [`NormalRequest`] with this ID. They are finished with a
+    /// `canceled` stop reason and the text generated so far: waiting sequences at once, and running
+    /// ones on their next completion step. The final response is sent as usual.
+    Cancel {
+        id: usize,
+    },
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
@@ -144,6 +220,13 @@ impl Debug for Request {
             Request::ReIsq(tp) => {
                 write!(f, "Re ISQ Request {tp:?}",)
             }
+            Request::Tokenize(TokenizationRequest { text, .. }) => {
+                write!(f, "Tokenize Request {text:?}")
+            }
+            Request::Detokenize(DetokenizationRequest { tokens, .. }) => {
+                write!(f, "Detokenize Request {tokens:?}")
+            }
+            Request::Cancel { id } => write!(f, "Cancel Request {id}"),
             Request::Terminate => write!(f, "Termination Request"),
         }
     }
@@ -7,7 +7,10 @@ use std::{
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::{
+    sampler::TopLogprob,
+    tools::{ToolCallDelta, ToolCallResponse},
+};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -42,6 +45,9 @@ generate_repr!(ResponseMessage);
 pub struct Delta {
     pub content: String,
     pub role: String,
+    /// Tool calls which were completed by this delta.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
 generate_repr!(Delta);
@@ -122,6 +128,16 @@ pub struct Usage {
     pub total_time_sec: f32,
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
+    /// Number of tokens proposed by speculative decoding, if it is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speculative_drafted_tokens: Option<usize>,
+    /// Number of proposed tokens accepted by the target model, if speculative decoding is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speculative_accepted_tokens: Option<usize>,
+    /// Fraction of the proposed tokens accepted by the target model, if speculative decoding is
+    /// used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speculative_acceptance_rate: Option<f32>,
 }
 
 generate_repr!(Usage);
@@ -166,10 +182,31 @@ pub struct CompletionChoice {
     pub index: usize,
     pub text: String,
     pub logprobs: Option<()>,
+    /// Logprobs of the prompt tokens after the first one, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<Vec<ResponseLogprob>>,
+    /// Score of the continuation, for scoring requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<ContinuationScore>,
 }
 
 generate_repr!(CompletionChoice);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Log-likelihood of a continuation given a prompt.
+pub struct ContinuationScore {
+    /// Sum of the logprobs of the continuation tokens.
+    pub logprob: f32,
+    /// Whether every continuation token is the most likely token, i.e. the continuation is what
+    /// greedy decoding would generate.
+    pub is_greedy: bool,
+    pub tokens: Vec<ResponseLogprob>,
+}
+
+generate_repr!(ContinuationScore);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -221,6 +258,16 @@ pub struct ImageGenerationResponse {
 
 generate_repr!(ImageGenerationResponse);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingResponse {
+    pub embedding: Vec<f32>,
+    pub prompt_tokens: usize,
+}
+
+generate_repr!(EmbeddingResponse);
+
 /// The response enum contains 3 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
@@ -238,6 +285,8 @@ pub enum Response {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    // Embedding
+    Embedding(EmbeddingResponse),
 }
 
 #[derive(Debug, Clone)]
@@ -250,6 +299,8 @@ pub enum ResponseOk {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    // Embedding
+    Embedding(EmbeddingResponse),
 }
 
 pub enum ResponseErr {
@@ -312,6 +363,7 @@ impl Response {
                 Err(Box::new(ResponseErr::CompletionModelError(e, x)))
             }
             Self::ImageGeneration(x) => Ok(ResponseOk::ImageGeneration(x)),
+            Self::Embedding(x) => Ok(ResponseOk::Embedding(x)),
         }
     }
 }
@@ -3,18 +3,23 @@
 use std::{
     collections::{HashMap, HashSet},
     iter::zip,
+    str::FromStr,
     sync::{Arc, Mutex},
 };
 
-use candle_core::{Device, Error, Result, Tensor, D};
+use candle_core::{DType, Device, Error, Result, Tensor, D};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
 use once_cell::sync::Lazy;
-use rand::distributions::{Distribution, WeightedIndex};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    Rng,
+};
 use rand_isaac::Isaac64Rng;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
+use strum::{EnumString, VariantNames};
 use tokenizers::Tokenizer;
 
 static DRY_SEQUENCE_BREAKERS: Lazy<Vec<String>> =
@@ -34,35 +39,228 @@ pub struct SamplingParams {
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
     pub min_p: Option<f64>,
+    /// Locally typical sampling: keep the tokens whose surprise is closest to the entropy of the
+    /// distribution, up to this cumulative probability.
+    pub typical_p: Option<f64>,
+    /// Top-a sampling: remove the tokens with a probability below `top_a * max_prob^2`.
+    pub top_a: Option<f64>,
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    /// Multiplicative repetition penalty: the logits of the tokens in the context are divided by
+    /// it if positive, and multiplied by it if negative.
+    pub repetition_penalty: Option<f32>,
+    /// Number of most recent tokens considered by the repetition penalty. If `None`, the whole
+    /// context is considered.
+    pub repetition_penalty_last_n: Option<usize>,
     pub stop_toks: Option<StopTokens>,
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    pub mirostat: Option<MirostatParams>,
+    pub xtc: Option<XtcParams>,
+    /// Order in which the sampler stages are applied. Each enabled stage must be listed exactly
+    /// once. If `None`, `SamplerStage::DEFAULT_ORDER` is used.
+    pub sampler_order: Option<Vec<SamplerStage>>,
+    /// Seed for the RNG of each sequence. If `None`, the engine's shared RNG is used.
+    pub seed: Option<u64>,
+    /// Decode with beam search instead of sampling. The `n_choices` best beams are returned.
+    pub beam_search: Option<BeamSearchParams>,
 }
 
 impl SamplingParams {
     /// This sets up the parameters so that there is:
-    /// - No temperature, topk, topp, minp
+    /// - No temperature, topk, topp, minp, typical p, top a, Mirostat or XTC
     /// - No penalties, stop tokens, or logit bias
     /// - No maximum length
+    /// - No seed
+    /// - No beam search
     pub fn deterministic() -> Self {
         Self {
             temperature: None,
             top_k: None,
             top_p: None,
             min_p: None,
+            typical_p: None,
+            top_a: None,
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
+            repetition_penalty: None,
+            repetition_penalty_last_n: None,
             stop_toks: None,
             max_len: None,
             logits_bias: None,
             n_choices: 1,
             dry_params: None,
+            mirostat: None,
+            xtc: None,
+            sampler_order: None,
+            seed: None,
+            beam_search: None,
+        }
+    }
+
+    /// Check that `sampler_order` lists each stage at most once, and lists every enabled stage.
+    pub(crate) fn validate_sampler_order(
+        &self,
+        has_logits_processors: bool,
+    ) -> std::result::Result<(), String> {
+        let Some(order) = &self.sampler_order else {
+            return Ok(());
+        };
+        for (i, stage) in order.iter().enumerate() {
+            if order[..i].contains(stage) {
+                return Err(format!("Sampler stage `{stage}` is listed more than once."));
+            }
+        }
+
+        let in_range = |x: Option<f64>| x.is_some_and(|x| x > 0.0 && x < 1.0);
+        let enabled = [
+            (SamplerStage::Dry, self.dry_params.is_some()),
+            (
+                SamplerStage::RepetitionPenalty,
+                self.repetition_penalty.is_some(),
+            ),
+            (
+                SamplerStage::FrequencyPenalty,
+                self.frequency_penalty.is_some(),
+            ),
+            (
+                SamplerStage::PresencePenalty,
+                self.presence_penalty.is_some(),
+            ),
+            (SamplerStage::LogitsProcessors, has_logits_processors),
+            (SamplerStage::Temperature, self.temperature.is_some()),
+            (SamplerStage::TopK, self.top_k.is_some_and(|k| k > 0)),
+            (SamplerStage::TypicalP, in_range(self.typical_p)),
+            (SamplerStage::TopP, in_range(self.top_p)),
+            (SamplerStage::MinP, in_range(self.min_p)),
+            (SamplerStage::TopA, self.top_a.is_some_and(|a| a > 0.0)),
+            (SamplerStage::Xtc, self.xtc.is_some()),
+        ];
+        for (stage, enabled) in enabled {
+            if enabled && !order.contains(&stage) {
+                return Err(format!(
+                    "Sampler stage `{stage}` is enabled but missing from the sampler order."
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A stage of the sampler chain. The penalties and the logits processors modify the logits, the
+/// temperature scales them, and the other stages remove tokens from the distribution.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    EnumString,
+    VariantNames,
+    strum::Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SamplerStage {
+    Dry,
+    RepetitionPenalty,
+    FrequencyPenalty,
+    PresencePenalty,
+    LogitsProcessors,
+    Temperature,
+    TopK,
+    TypicalP,
+    TopP,
+    MinP,
+    TopA,
+    Xtc,
+}
+
+impl SamplerStage {
+    /// The order used if `SamplingParams::sampler_order` is `None`.
+    pub const DEFAULT_ORDER: [SamplerStage; 12] = [
+        Self::Dry,
+        Self::RepetitionPenalty,
+        Self::FrequencyPenalty,
+        Self::PresencePenalty,
+        Self::LogitsProcessors,
+        Self::Temperature,
+        Self::TopK,
+        Self::TypicalP,
+        Self::TopP,
+        Self::MinP,
+        Self::TopA,
+        Self::Xtc,
+    ];
+
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        Self::from_str(name).map_err(|_| {
+            anyhow::anyhow!(
+                "Unknown sampler stage `{name}`, expected one of {}.",
+                Self::VARIANTS.join(", ")
+            )
+        })
+    }
+}
+
+/// Mirostat v2 sampling, which truncates the distribution of each sequence so that the surprise of
+/// its tokens stays close to `tau`. Replaces the top-k, top-p, min-p, typical-p, top-a and XTC
+/// samplers.
+#[derive(Clone, Copy, Debug)]
+pub struct MirostatParams {
+    /// Target surprise, in bits.
+    pub tau: f32,
+    /// Learning rate of the maximum surprise.
+    pub eta: f32,
+}
+
+impl Default for MirostatParams {
+    fn default() -> Self {
+        Self { tau: 5.0, eta: 0.1 }
+    }
+}
+
+/// XTC ("exclude top choices"): with probability `probability`, the tokens with a probability of
+/// at least `threshold` are removed, except the least likely of them.
+#[derive(Clone, Copy, Debug)]
+pub struct XtcParams {
+    pub threshold: f32,
+    pub probability: f32,
+}
+
+impl Default for XtcParams {
+    fn default() -> Self {
+        Self {
+            threshold: 0.1,
+            probability: 0.5,
+        }
+    }
+}
+
+/// Beam search keeps the `beam_width` most likely sequences at each step. The sequences are
+/// ranked by the sum of their token log probabilities, divided by `length ^ length_penalty`.
+#[derive(Clone, Copy, Debug)]
+pub struct BeamSearchParams {
+    pub beam_width: usize,
+    /// Values above 0 favor longer sequences.
+    pub length_penalty: f32,
+    /// Stop as soon as `beam_width` finished sequences were found, instead of continuing until no
+    /// running beam can become better than them.
+    pub early_stopping: bool,
+}
+
+impl BeamSearchParams {
+    pub fn new(beam_width: usize) -> Self {
+        Self {
+            beam_width,
+            length_penalty: 1.0,
+            early_stopping: false,
         }
     }
 }
@@ -189,6 +387,13 @@ pub struct Sampler {
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    typical_p: f64,
+    top_a: f64,
+    repetition_penalty: Option<f32>,
+    repetition_penalty_last_n: Option<usize>,
+    mirostat: Option<MirostatParams>,
+    xtc: Option<XtcParams>,
+    sampler_order: Option<Vec<SamplerStage>>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
 }
 
@@ -210,6 +415,14 @@ pub struct Logprobs {
     pub top_logprobs: Option<Vec<TopLogprob>>,
 }
 
+/// The logprob of a prompt token, given the tokens before it.
+#[derive(Debug, Clone)]
+pub(crate) struct PromptLogprob {
+    pub logprobs: Logprobs,
+    /// Whether the token is the most likely token at its position.
+    pub is_greedy: bool,
+}
+
 fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
     logits.argmax(D::Minus1)
 }
@@ -226,6 +439,13 @@ impl Sampler {
         top_k: i64,
         top_p: f64,
         min_p: f64,
+        typical_p: f64,
+        top_a: f64,
+        repetition_penalty: Option<f32>,
+        repetition_penalty_last_n: Option<usize>,
+        mirostat: Option<MirostatParams>,
+        xtc: Option<XtcParams>,
+        sampler_order: Option<Vec<SamplerStage>>,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> anyhow::Result<Self> {
         let temperature = if temperature.map_or(true, |v| v < 1e-7) {
@@ -252,10 +472,21 @@ impl Sampler {
             top_k,
             top_p,
             min_p,
+            typical_p,
+            top_a,
+            repetition_penalty,
+            repetition_penalty_last_n,
+            mirostat,
+            xtc,
+            sampler_order,
             logits_processors,
         })
     }
 
+    pub(crate) fn mirostat(&self) -> Option<MirostatParams> {
+        self.mirostat
+    }
+
     fn get_top_logprobs(
         &self,
         probs: &[f32],
@@ -459,69 +690,205 @@ impl Sampler {
         })
     }
 
-    fn sample_top_kp_min_p(
+    /// Sample after truncating the distribution with each enabled sampler, in the documented
+    /// order: top-k, typical-p, top-p, min-p, top-a and XTC. Mirostat replaces all of them.
+    fn sample_truncated(
         &self,
         probs: &mut Vec<f32>,
-        top_k: i64,
-        top_p: f32,
-        min_p: f32,
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
+        mirostat_mu: Option<Arc<Mutex<f32>>>,
     ) -> Result<Logprobs> {
         let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
         // Sort by descending probability.
         argsort_indices
             .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
-        if top_k > 0 {
-            // Clamp smaller probabilities to zero.
-            for (index, val) in argsort_indices.iter().enumerate() {
-                if index >= top_k as usize {
-                    probs[*val] = 0.0;
-                }
-            }
+        if let (Some(params), Some(mu)) = (self.mirostat, mirostat_mu) {
+            let mut mu = mu.lock().expect("could not lock mirostat mutex");
+            return self.sample_mirostat(
+                probs,
+                argsort_indices,
+                params,
+                &mut mu,
+                return_logprobs,
+                rng,
+            );
         }
 
-        if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+        for stage in SamplerStage::DEFAULT_ORDER {
+            self.apply_truncation(stage, probs, &argsort_indices, &rng);
         }
 
-        // TOP P
-
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
+        // Sample with clamped probabilities.
+        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+    }
 
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index] = 0.0;
-            } else {
-                cumsum += probs[*index];
+    /// Remove tokens from the distribution if `stage` is an enabled truncation stage.
+    fn apply_truncation(
+        &self,
+        stage: SamplerStage,
+        probs: &mut [f32],
+        argsort_indices: &[usize],
+        rng: &Mutex<Isaac64Rng>,
+    ) {
+        match stage {
+            SamplerStage::TopK if self.top_k > 0 => {
+                apply_top_k(probs, argsort_indices, self.top_k as usize)
+            }
+            SamplerStage::TypicalP if self.typical_p > 0.0 && self.typical_p < 1.0 => {
+                apply_typical_p(probs, self.typical_p as f32)
+            }
+            SamplerStage::TopP if self.top_p > 0.0 && self.top_p < 1.0 => {
+                apply_top_p(probs, argsort_indices, self.top_p as f32)
+            }
+            SamplerStage::MinP if self.min_p > 0.0 && self.min_p < 1.0 => {
+                apply_min_p(probs, argsort_indices, self.min_p as f32)
+            }
+            SamplerStage::TopA if self.top_a > 0.0 => {
+                apply_top_a(probs, argsort_indices, self.top_a as f32)
+            }
+            SamplerStage::Xtc => {
+                if let Some(xtc) = self.xtc {
+                    let exclude = rng.lock().expect("could not lock rng mutex").gen::<f32>()
+                        < xtc.probability;
+                    if exclude {
+                        apply_xtc(probs, argsort_indices, xtc.threshold);
+                    }
+                }
             }
+            _ => (),
         }
+    }
 
-        if min_p <= 0.0 || min_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+    /// Apply the stages of a custom sampler order to the logits. The tokens removed by the
+    /// truncation stages get a logit of `-inf`.
+    fn apply_stages(
+        &self,
+        order: &[SamplerStage],
+        mut logits: Vec<f32>,
+        context: &[u32],
+        rng: &Mutex<Isaac64Rng>,
+    ) -> Result<Vec<f32>> {
+        if context.is_empty() {
+            candle_core::bail!("Penalty context is empty, this should not happen.");
         }
 
-        let max_p = probs[argsort_indices[0]];
+        for stage in order {
+            match stage {
+                SamplerStage::Dry => self.apply_dry_penalty(&mut logits, context)?,
+                SamplerStage::RepetitionPenalty => {
+                    self.apply_repetition_penalty(&mut logits, context)
+                }
+                SamplerStage::FrequencyPenalty => self.apply_freq_presc_penalty(
+                    &mut logits,
+                    context,
+                    self.frequency_penalty,
+                    None,
+                )?,
+                SamplerStage::PresencePenalty => self.apply_freq_presc_penalty(
+                    &mut logits,
+                    context,
+                    None,
+                    self.presence_penalty,
+                )?,
+                SamplerStage::LogitsProcessors => {
+                    let vocab_size = logits.len();
+                    let mut tensor = Tensor::from_vec(logits, vocab_size, &Device::Cpu)?;
+                    for processor in &self.logits_processors {
+                        tensor = processor.apply(&tensor, context)?;
+                    }
+                    logits = tensor.to_vec1()?;
+                }
+                SamplerStage::Temperature => {
+                    if let Some(temperature) = self.temperature {
+                        for logit in &mut logits {
+                            *logit /= temperature as f32;
+                        }
+                    }
+                }
+                SamplerStage::TopK
+                | SamplerStage::TypicalP
+                | SamplerStage::TopP
+                | SamplerStage::MinP
+                | SamplerStage::TopA
+                | SamplerStage::Xtc => {
+                    let mut probs = softmax(&logits);
+                    let argsort_indices = argsort_descending(&probs);
+                    self.apply_truncation(*stage, &mut probs, &argsort_indices, rng);
+                    for (logit, prob) in zip(&mut logits, probs) {
+                        if prob == 0.0 {
+                            *logit = f32::NEG_INFINITY;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(logits)
+    }
 
-        // MIN P
+    /// Sample with a custom sampler order. Without temperature, or for speculative decoding, the
+    /// most likely remaining token is chosen.
+    #[allow(clippy::too_many_arguments)]
+    fn sample_ordered(
+        &self,
+        order: &[SamplerStage],
+        logits: Vec<f32>,
+        context: &[u32],
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        mirostat_mu: Option<Arc<Mutex<f32>>>,
+        sample_speculative: bool,
+    ) -> Result<Logprobs> {
+        let logits = self.apply_stages(order, logits, context, &rng)?;
+        if sample_speculative || self.temperature.is_none() {
+            let vocab_size = logits.len();
+            return self.sample_argmax(
+                Tensor::from_vec(logits, vocab_size, &Device::Cpu)?,
+                return_logprobs,
+            );
+        }
 
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
+        let mut probs = softmax(&logits);
+        let argsort_indices = argsort_descending(&probs);
+        if let (Some(params), Some(mu)) = (self.mirostat, mirostat_mu) {
+            let mut mu = mu.lock().expect("could not lock mirostat mutex");
+            return self.sample_mirostat(
+                &mut probs,
+                argsort_indices,
+                params,
+                &mut mu,
+                return_logprobs,
+                rng,
+            );
+        }
+        self.sample_multinomial(&mut probs, argsort_indices, return_logprobs, rng)
+    }
 
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index] {
+    /// Mirostat v2: remove the tokens whose surprise is above `mu`, then move `mu` towards the
+    /// target surprise with the surprise of the sampled token.
+    fn sample_mirostat(
+        &self,
+        probs: &mut Vec<f32>,
+        argsort_indices: Vec<usize>,
+        params: MirostatParams,
+        mu: &mut f32,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        // The most likely token is always kept
+        for index in argsort_indices.iter().skip(1) {
+            if -probs[*index].log2() > *mu {
                 probs[*index] = 0.0;
             }
         }
+        let total = probs.iter().sum::<f32>();
 
-        // Sample with clamped probabilities.
-        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+        let next_token = self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)?;
+
+        let surprise = -(probs[next_token.token as usize] / total).log2();
+        *mu -= params.eta * (surprise - params.tau);
+        Ok(next_token)
     }
 
     fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
@@ -532,17 +899,54 @@ impl Sampler {
         // Dry penalty
         self.apply_dry_penalty(&mut logits, context)?;
 
+        // Repetition penalty
+        self.apply_repetition_penalty(&mut logits, context);
+
         // Frequency and Presence penalty
-        self.apply_freq_presc_penalty(&mut logits, context)?;
+        self.apply_freq_presc_penalty(
+            &mut logits,
+            context,
+            self.frequency_penalty,
+            self.presence_penalty,
+        )?;
 
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
-    fn apply_freq_presc_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
-        if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
-            let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
-            let presence_penalty = self.presence_penalty.unwrap_or(0.);
+    fn apply_repetition_penalty(&self, logits: &mut [f32], context: &[u32]) {
+        if let Some(penalty) = self.repetition_penalty {
+            let start = self
+                .repetition_penalty_last_n
+                .map_or(0, |last_n| context.len().saturating_sub(last_n));
+            let mut penalized = vec![false; logits.len()];
+            for ctx in &context[start..] {
+                let ctx = *ctx as usize;
+                // Llama 3.2 uses a hack triggering this error... we wouldn't want a weight on it anyway
+                if ctx >= logits.len() || penalized[ctx] {
+                    continue;
+                }
+                penalized[ctx] = true;
+                let logit = &mut logits[ctx];
+                *logit = if *logit > 0.0 {
+                    *logit / penalty
+                } else {
+                    *logit * penalty
+                };
+            }
+        }
+    }
+
+    fn apply_freq_presc_penalty(
+        &self,
+        logits: &mut [f32],
+        context: &[u32],
+        frequency_penalty: Option<f32>,
+        presence_penalty: Option<f32>,
+    ) -> Result<()> {
+        if frequency_penalty.is_some() || presence_penalty.is_some() {
+            let frequency_penalty = frequency_penalty.unwrap_or(0.);
+            let presence_penalty = presence_penalty.unwrap_or(0.);
 
             //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
 
@@ -633,19 +1037,128 @@ impl Sampler {
         Ok(())
     }
 
+    /// Probabilities of the next token for beam search, after the penalties and logits
+    /// processors. The temperature does not change the ranking of the beams, so it is not applied.
+    pub(crate) fn beam_search_probs(&self, logits: Tensor, context: &[u32]) -> Result<Vec<f32>> {
+        let logits = logits.to_vec1()?;
+        let mut logits = self.apply_penalties(logits, context)?;
+        for processor in &self.logits_processors {
+            logits = processor.apply(&logits, context)?;
+        }
+        candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()
+    }
+
+    /// The logprobs of a token chosen by beam search, from the output of `beam_search_probs`.
+    pub(crate) fn beam_search_logprobs(
+        &self,
+        probs: &[f32],
+        token: u32,
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
+        let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        let top_logprobs = if return_logprobs {
+            Some(self.get_top_logprobs(probs, &argsort_indices)?)
+        } else {
+            None
+        };
+
+        let bytes = if let Some(tokenizer) = &self.tokenizer {
+            Some(
+                tokenizer
+                    .decode(&[token], false)
+                    .map_err(|x| Error::Msg(x.to_string()))?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Logprobs {
+            token,
+            logprob: probs[token as usize].log(10.0),
+            top_logprobs,
+            bytes,
+        })
+    }
+
+    /// The logprobs of `tokens`, the prompt tokens after the first one, from `logits`, the logits
+    /// of the positions before them. These are the probabilities of the model: the penalties,
+    /// logits processors and temperature are not applied.
+    pub(crate) fn prompt_logprobs(
+        &self,
+        logits: Tensor,
+        tokens: &[u32],
+    ) -> Result<Vec<PromptLogprob>> {
+        let probs =
+            candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)?.to_vec2::<f32>()?;
+        if probs.len() != tokens.len() {
+            candle_core::bail!(
+                "Expected the logits of {} prompt tokens, got {}.",
+                tokens.len(),
+                probs.len()
+            );
+        }
+        zip(probs, tokens)
+            .map(|(probs, token)| {
+                let prob = probs[*token as usize];
+                let is_greedy = probs.iter().all(|p| *p <= prob);
+                // Only the top n tokens are sorted
+                let mut top_n = (0..probs.len()).collect::<Vec<_>>();
+                let n = self.top_n_logprobs.min(probs.len());
+                if n > 0 && n < top_n.len() {
+                    top_n.select_nth_unstable_by(n - 1, |a, b| probs[*b].total_cmp(&probs[*a]));
+                }
+                top_n.truncate(n);
+                top_n.sort_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+                let bytes = if let Some(tokenizer) = &self.tokenizer {
+                    Some(
+                        tokenizer
+                            .decode(&[*token], false)
+                            .map_err(|x| Error::Msg(x.to_string()))?,
+                    )
+                } else {
+                    None
+                };
+                Ok(PromptLogprob {
+                    logprobs: Logprobs {
+                        token: *token,
+                        logprob: prob.log(10.0),
+                        bytes,
+                        top_logprobs: Some(self.get_top_logprobs(&probs, &top_n)?),
+                    },
+                    is_greedy,
+                })
+            })
+            .collect()
+    }
+
     /// Sample the provided tokens.
     ///
-    /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
-    /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
+    /// The stages are applied in `SamplerStage::DEFAULT_ORDER`, unless a custom order was given.
+    /// The penalties (DRY, repetition, frequency and presence) and the logits processors are applied
+    /// first. If the temperature is `None`, argmax sampling is used. Otherwise, the distribution is
+    /// truncated by the enabled samplers, see `sample_truncated`, and a token is sampled from it.
+    /// The state of Mirostat is `mirostat_mu`, which is updated after sampling.
     pub fn sample(
         &self,
         logits: Tensor,
         context: &[u32],
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
+        mirostat_mu: Option<Arc<Mutex<f32>>>,
         sample_speculative: bool,
     ) -> Result<Logprobs> {
         let logits = logits.to_vec1()?;
+        if let Some(order) = &self.sampler_order {
+            return self.sample_ordered(
+                order,
+                logits,
+                context,
+                return_logprobs,
+                rng,
+                mirostat_mu,
+                sample_speculative,
+            );
+        }
         let mut logits = self.apply_penalties(logits, context)?;
         for processor in &self.logits_processors {
             logits = processor.apply(&logits, context)?;
@@ -680,14 +1193,7 @@ impl Sampler {
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
                     let mut probs: Vec<f32> = probs.to_vec1()?;
 
-                    self.sample_top_kp_min_p(
-                        &mut probs,
-                        self.top_k,
-                        self.top_p as f32,
-                        self.min_p as f32,
-                        return_logprobs,
-                        rng,
-                    )?
+                    self.sample_truncated(&mut probs, return_logprobs, rng, mirostat_mu)?
                 }
             }
         };
@@ -695,6 +1201,112 @@ impl Sampler {
     }
 }
 
+/// Softmax of logits which may be `-inf`.
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut probs = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+    let total = probs.iter().sum::<f32>();
+    for prob in &mut probs {
+        *prob /= total;
+    }
+    probs
+}
+
+fn argsort_descending(probs: &[f32]) -> Vec<usize> {
+    let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+    argsort_indices.sort_unstable_by(|&i, &j| probs[j].total_cmp(&probs[i]));
+    argsort_indices
+}
+
+fn apply_top_k(probs: &mut [f32], argsort_indices: &[usize], top_k: usize) {
+    // Clamp smaller probabilities to zero.
+    for index in argsort_indices.iter().skip(top_k) {
+        probs[*index] = 0.0;
+    }
+}
+
+/// Locally typical sampling keeps the tokens whose surprise is the closest to the entropy of the
+/// distribution, until their cumulative probability reaches `typical_p`.
+fn apply_typical_p(probs: &mut [f32], typical_p: f32) {
+    let total = probs.iter().sum::<f32>();
+    let entropy = -probs
+        .iter()
+        .filter(|p| **p > 0.0)
+        .map(|p| (p / total) * (p / total).ln())
+        .sum::<f32>();
+    let distance = |p: f32| (-(p / total).ln() - entropy).abs();
+
+    let mut indices = (0..probs.len())
+        .filter(|i| probs[*i] > 0.0)
+        .collect::<Vec<_>>();
+    indices.sort_unstable_by(|&i, &j| distance(probs[i]).total_cmp(&distance(probs[j])));
+    let mut cumsum = 0.;
+    for index in indices {
+        if cumsum >= typical_p {
+            probs[index] = 0.0;
+        } else {
+            cumsum += probs[index] / total;
+        }
+    }
+}
+
+/// Top-p sampling (or "nucleus sampling") samples from the smallest set of tokens that exceed
+/// probability top_p. This way we never sample tokens that have very low probabilities and are
+/// less likely to go "off the rails".
+fn apply_top_p(probs: &mut [f32], argsort_indices: &[usize], top_p: f32) {
+    // Clamp smaller probabilities to zero.
+    let mut cumsum = 0.;
+    for index in argsort_indices {
+        if cumsum >= top_p {
+            probs[*index] = 0.0;
+        } else {
+            cumsum += probs[*index];
+        }
+    }
+}
+
+/// Min-p sampling samples from the tokens whose prob are greater than
+/// (max prob of token in dist) * min_p
+fn apply_min_p(probs: &mut [f32], argsort_indices: &[usize], min_p: f32) {
+    let max_p = probs.iter().copied().fold(0.0, f32::max);
+    // Clamp smaller probabilities to zero.
+    for index in argsort_indices {
+        if max_p * min_p >= probs[*index] {
+            probs[*index] = 0.0;
+        }
+    }
+}
+
+/// Top-a sampling removes the tokens with a probability below `top_a * max_prob^2`, so it
+/// truncates more when the model is confident.
+fn apply_top_a(probs: &mut [f32], argsort_indices: &[usize], top_a: f32) {
+    let total = probs.iter().sum::<f32>();
+    let max_p = probs.iter().copied().fold(0.0, f32::max) / total;
+    let threshold = top_a * max_p * max_p * total;
+    // The most likely token is always kept
+    for index in argsort_indices.iter().skip(1) {
+        if probs[*index] < threshold {
+            probs[*index] = 0.0;
+        }
+    }
+}
+
+/// XTC removes the tokens with a probability of at least `threshold`, except the least likely of
+/// them, so that the most predictable continuations are avoided.
+fn apply_xtc(probs: &mut [f32], argsort_indices: &[usize], threshold: f32) {
+    let total = probs.iter().sum::<f32>();
+    let above = argsort_indices
+        .iter()
+        .copied()
+        .filter(|index| probs[*index] > 0.0 && probs[*index] >= threshold * total)
+        .collect::<Vec<_>>();
+    if above.len() >= 2 {
+        for index in &above[..above.len() - 1] {
+            probs[*index] = 0.0;
+        }
+    }
+}
+
 mod tests {
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
     use tokenizers::Tokenizer;
@@ -731,13 +1343,27 @@ mod tests {
             32,
             0.1,
             0.05,
+            1.0,
+            0.0,
+            None,
+            None,
+            None,
+            None,
+            None,
             vec![],
         )
         .unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
-            .sample(logits, &(0..1024).collect::<Vec<_>>(), false, rng, false)
+            .sample(
+                logits,
+                &(0..1024).collect::<Vec<_>>(),
+                false,
+                rng,
+                None,
+                false,
+            )
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
@@ -763,16 +1389,310 @@ mod tests {
             32,
             0.1,
             0.05,
+            1.0,
+            0.0,
+            None,
+            None,
+            None,
+            None,
+            None,
             vec![],
         )
         .unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
-            .sample(logits, &(0..1024).collect::<Vec<_>>(), false, rng, true)
+            .sample(
+                logits,
+                &(0..1024).collect::<Vec<_>>(),
+                false,
+                rng,
+                None,
+                true,
+            )
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_typical_p() {
+        use super::apply_typical_p;
+
+        // Entropy is ~0.94 nats: the token with p=0.4 is the most typical, then p=0.5
+        let mut probs = vec![0.5, 0.4, 0.1];
+        apply_typical_p(&mut probs, 0.5);
+        assert_eq!(probs, vec![0.5, 0.4, 0.0]);
+    }
+
+    #[test]
+    fn test_top_a() {
+        use super::apply_top_a;
+
+        // Threshold is 0.5 * 0.6^2 = 0.18
+        let mut probs = vec![0.2, 0.6, 0.15, 0.05];
+        apply_top_a(&mut probs, &[1, 0, 2, 3], 0.5);
+        assert_eq!(probs, vec![0.2, 0.6, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_xtc() {
+        use super::apply_xtc;
+
+        // All tokens above the threshold except the least likely are removed
+        let mut probs = vec![0.2, 0.5, 0.25, 0.05];
+        apply_xtc(&mut probs, &[1, 2, 0, 3], 0.1);
+        assert_eq!(probs, vec![0.2, 0.0, 0.0, 0.05]);
+
+        // Nothing is removed if only one token is above the threshold
+        let mut probs = vec![0.1, 0.8, 0.1];
+        apply_xtc(&mut probs, &[1, 0, 2], 0.5);
+        assert_eq!(probs, vec![0.1, 0.8, 0.1]);
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        use super::Sampler;
+
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            0.0,
+            Some(2.0),
+            Some(2),
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let mut logits = vec![1.0, -1.0, 2.0, 4.0];
+        // Token 0 is outside of the window, token 2 is penalized once
+        sampler.apply_repetition_penalty(&mut logits, &[0, 2, 1, 2, 1]);
+        assert_eq!(logits, vec![1.0, -2.0, 1.0, 4.0]);
+    }
+
+    #[test]
+    fn test_mirostat() {
+        use super::{MirostatParams, Sampler};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let params = MirostatParams { tau: 1.0, eta: 0.5 };
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            0.0,
+            None,
+            None,
+            Some(params),
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        // With mu = 1 bit, only the tokens with p > 0.5 are kept
+        let mu = Arc::new(Mutex::new(1.0));
+        let mut probs = vec![0.3, 0.6, 0.1];
+        let res = sampler
+            .sample_truncated(&mut probs, false, rng, Some(mu.clone()))
+            .unwrap();
+        assert_eq!(res.token, 1);
+        assert_eq!(probs, vec![0.0, 0.6, 0.0]);
+        // The surprise of the token is 0 bits, so mu increases by eta * tau
+        assert!((*mu.lock().unwrap() - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_seeded_sampling_is_reproducible() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            0.0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let logits =
+            Tensor::new(&[0.5f32, 0.4, 0.3, 0.2, 0.1, 0.0, -0.1, -0.2], &Device::Cpu).unwrap();
+        // Seeded sequences each sample from an RNG seeded like this
+        let sample = |seed: u64| {
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(seed)));
+            (0..32)
+                .map(|_| {
+                    sampler
+                        .sample(logits.clone(), &[], false, rng.clone(), None, false)
+                        .unwrap()
+                        .token
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let tokens = sample(1234);
+        assert_eq!(tokens, sample(1234));
+        // The tokens are sampled, not always the most likely one
+        assert!(tokens.iter().any(|token| *token != tokens[0]));
+    }
+
+    #[test]
+    fn test_sampler_order() {
+        use super::{Sampler, SamplerStage};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(
+            Some(100.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.5,
+            1.0,
+            0.0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let rng = Mutex::new(Isaac64Rng::seed_from_u64(42));
+
+        // Before the temperature, token 1 is far less likely than min-p allows
+        let logits = sampler
+            .apply_stages(
+                &[SamplerStage::MinP, SamplerStage::Temperature],
+                vec![10.0, 0.0],
+                &[0],
+                &rng,
+            )
+            .unwrap();
+        assert_eq!(logits, vec![0.1, f32::NEG_INFINITY]);
+
+        // After the temperature, the distribution is almost uniform
+        let logits = sampler
+            .apply_stages(
+                &[SamplerStage::Temperature, SamplerStage::MinP],
+                vec![10.0, 0.0],
+                &[0],
+                &rng,
+            )
+            .unwrap();
+        assert_eq!(logits, vec![0.1, 0.0]);
+    }
+
+    #[test]
+    fn test_prompt_logprobs() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+
+        let sampler = Sampler::new(
+            None,
+            2,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            0.0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let logits = Tensor::new(
+            &[
+                [0f32, 2f32.ln(), 5f32.ln()],
+                [6f32.ln(), 3f32.ln(), 1f32.ln()],
+            ],
+            &Device::Cpu,
+        )
+        .unwrap();
+        let res = sampler.prompt_logprobs(logits.clone(), &[2, 1]).unwrap();
+        assert!(res[0].is_greedy);
+        assert!((res[0].logprobs.logprob - 0.625f32.log10()).abs() < 1e-6);
+        assert!(!res[1].is_greedy);
+        assert!((res[1].logprobs.logprob - 0.3f32.log10()).abs() < 1e-6);
+        let top = res[1]
+            .logprobs
+            .top_logprobs
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|x| x.token)
+            .collect::<Vec<_>>();
+        assert_eq!(top, vec![0, 1]);
+
+        assert!(sampler.prompt_logprobs(logits, &[2]).is_err());
+    }
+
+    #[test]
+    fn test_validate_sampler_order() {
+        use super::{SamplerStage, SamplingParams};
+
+        let mut params = SamplingParams::deterministic();
+        params.top_k = Some(10);
+        params.sampler_order = Some(vec![SamplerStage::TopK, SamplerStage::TopK]);
+        assert!(params.validate_sampler_order(false).is_err());
+        params.sampler_order = Some(vec![SamplerStage::MinP]);
+        assert!(params.validate_sampler_order(false).is_err());
+        params.sampler_order = Some(vec![SamplerStage::TopK, SamplerStage::MinP]);
+        assert!(params.validate_sampler_order(false).is_ok());
+        assert!(params.validate_sampler_order(true).is_err());
+
+        assert_eq!(
+            SamplerStage::from_name("top_k").unwrap(),
+            SamplerStage::TopK
+        );
+        assert!(SamplerStage::from_name("top_z").is_err());
+    }
 }
@@ -1,21 +1,26 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
     num::NonZeroUsize,
     sync::atomic::Ordering,
 };
 
+use tokio::sync::mpsc::Sender;
+
 use crate::{
     engine::TERMINATE_ALL_NEXT_STEP,
     paged_attention::{BlockEngine, BlockTables},
     sequence::{Sequence, SequenceState, StopReason},
+    RequestPriority, Response,
 };
 
-use super::{Scheduler, SchedulerOutput};
+use super::{fair_order, Scheduler, SchedulerOutput};
 
 pub trait FcfsBacker: Default {
     fn new() -> Self;
     fn add(&mut self, item: Sequence);
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
+    fn iter(&self) -> impl Iterator<Item = &Sequence>;
     fn len(&self) -> usize;
     fn sort_ascending_ids(&mut self);
 }
@@ -30,6 +35,9 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn into_iter(self) -> impl Iterator<Item = Sequence> {
         <Self as IntoIterator>::into_iter(self)
     }
+    fn iter(&self) -> impl Iterator<Item = &Sequence> {
+        VecDeque::iter(self)
+    }
     fn sort_ascending_ids(&mut self) {
         let slice = self.make_contiguous();
         slice.sort_by_key(|seq| *seq.id());
@@ -68,9 +76,9 @@ pub trait BucketingManager<Backer: FcfsBacker> {
     ) -> BucketedSeqs<Backer>;
 }
 
-// (adapters, cache length, (has_imgs && is_prompt))
+// (adapters, cache length, is_prompt, (has_imgs && is_prompt), is_embedding, (return_prompt_logprobs && is_prompt))
 // Buckey by that metric for images because if we are not a prompt, then this doesn't apply
-type BucketKey = (Option<Vec<String>>, usize, bool);
+type BucketKey = (Option<Vec<String>>, usize, bool, bool, bool, bool);
 
 struct FixedBucketingManager;
 
@@ -87,44 +95,31 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
         // Now, get the sequences with the smallest sequence lengths, and allow them to catch up.
         let mut seq_buckets: HashMap<BucketKey, Vec<Sequence>> = HashMap::new();
         let mut seq_priorities: HashMap<BucketKey, f64> = HashMap::new();
+        let mut seq_classes: HashMap<BucketKey, RequestPriority> = HashMap::new();
         for seq in running {
             let len = seq.len();
-            match seq_buckets.get_mut(&(
+            let key = (
                 seq.get_adapters(),
                 len,
+                seq.is_prompt(),
                 seq.images().is_some() && seq.is_prompt(),
-            )) {
+                seq.embedding_params().is_some(),
+                seq.return_prompt_logprobs() && seq.is_prompt(),
+            );
+            let class = seq_classes.entry(key.clone()).or_insert(seq.priority());
+            *class = (*class).max(seq.priority());
+            match seq_buckets.get_mut(&key) {
                 Some(bucket) => {
                     if !discrete {
-                        *seq_priorities
-                            .get_mut(&(
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ))
-                            .unwrap() += seq.compute_priority();
+                        *seq_priorities.get_mut(&key).unwrap() += seq.compute_priority();
                     }
                     bucket.push(seq);
                 }
                 None => {
                     if !discrete {
-                        seq_priorities.insert(
-                            (
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ),
-                            seq.compute_priority(),
-                        );
+                        seq_priorities.insert(key.clone(), seq.compute_priority());
                     }
-                    seq_buckets.insert(
-                        (
-                            seq.get_adapters(),
-                            len,
-                            seq.images().is_some() && seq.is_prompt(),
-                        ),
-                        vec![seq],
-                    );
+                    seq_buckets.insert(key, vec![seq]);
                 }
             }
         }
@@ -136,11 +131,16 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                 .map(|s| s.reset_urgency())
                 .collect::<Vec<_>>()
         } else {
+            // Only the buckets with sequences of the highest priority class may run.
+            let top_class = *seq_classes.values().max().expect("No sequence buckets.");
+            seq_priorities.retain(|key, _| seq_classes[key] == top_class);
+
             // Set the min seqs to be the running ones, and the rest to be waiting (but their states are not changed!)
             // Allow the min seqs to catch up.
             let min = seq_buckets
                 .keys()
-                .min_by_key(|(_, x, _)| *x)
+                .filter(|key| seq_classes[*key] == top_class)
+                .min_by_key(|(_, x, _, _, _, _)| *x)
                 .expect("No sequence buckets.")
                 .clone();
             let len = if !discrete {
@@ -176,6 +176,7 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    cancelled_requests: HashSet<usize>,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
@@ -188,6 +189,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            cancelled_requests: HashSet::new(),
         }
     }
 
@@ -207,12 +209,21 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
     pub fn schedule(&mut self) -> DefaultSchedulerOutput {
         // Filter out all done sequences
         let running = std::mem::take(&mut self.running);
-        let mut waiting = std::mem::take(&mut self.waiting);
         let mut running = running
             .into_iter()
             .filter(|seq| seq.is_running())
             .collect::<Vec<_>>();
 
+        let waiting = std::mem::take(&mut self.waiting);
+
+        // Forget cancelled requests which have no sequences left
+        self.cancelled_requests.retain(|id| {
+            running
+                .iter()
+                .chain(waiting.iter())
+                .any(|seq| seq.request_id() == *id)
+        });
+
         match (waiting.len(), running.len()) {
             (0, 0) => {
                 self.running = running;
@@ -223,12 +234,23 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             }
             (_, 0) => {
                 for seq in waiting.into_iter() {
-                    seq.set_state(SequenceState::RunningPrompt);
+                    // Preempted sequences continue where they stopped
+                    if seq.is_waiting() {
+                        seq.set_state(SequenceState::RunningPrompt);
+                    }
                     self.running.push(seq);
                 }
                 self.waiting = Backer::new();
                 let running = std::mem::take(&mut self.running);
                 self.running = self.bucket_and_waitlist_seqs(running);
+                // A bucket only holds prompt or completion sequences, and preempted sequences
+                // resume as completions
+                if self.running.iter().all(|seq| seq.is_completion()) {
+                    return DefaultSchedulerOutput {
+                        prompt: vec![].into(),
+                        completion: self.running.iter_mut().collect::<Vec<_>>().into(),
+                    };
+                }
                 return DefaultSchedulerOutput {
                     prompt: self.running.iter_mut().collect::<Vec<_>>().into(),
                     completion: vec![].into(),
@@ -242,6 +264,11 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                         .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
                     TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
                 }
+                for seq in &self.running {
+                    if self.cancelled_requests.contains(&seq.request_id()) {
+                        seq.set_state(SequenceState::Done(StopReason::Canceled));
+                    }
+                }
                 return DefaultSchedulerOutput {
                     prompt: vec![].into(),
                     completion: self.running.iter_mut().collect::<Vec<_>>().into(),
@@ -252,17 +279,39 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
 
         // Sort the waiting seqs
         waiting.sort_ascending_ids();
+        let units = fair_order(
+            scheduling_units(waiting.into_iter()),
+            running
+                .iter()
+                .map(|seq| seq.tenant().map(ToString::to_string)),
+            |unit| {
+                (
+                    unit[0].priority(),
+                    unit[0].tenant().map(ToString::to_string),
+                    unit.len(),
+                )
+            },
+        );
 
-        // If the waiting sequence will fit, add it. Otherwise remove it
+        // If the waiting sequences will fit, add them. Otherwise remove them
         let mut new_waiting = Backer::new();
-        for seq in waiting.into_iter() {
-            if self.sequence_fits(&running, &seq) {
-                if seq.is_waiting() {
-                    seq.set_state(SequenceState::RunningPrompt);
+        for unit in units {
+            if !self.sequences_fit(running.len(), unit.len()) {
+                for seq in self.preempt_for(&mut running, unit[0].priority(), unit.len()) {
+                    new_waiting.add(seq);
+                }
+            }
+            if self.sequences_fit(running.len(), unit.len()) {
+                for seq in unit {
+                    if seq.is_waiting() {
+                        seq.set_state(SequenceState::RunningPrompt);
+                    }
+                    running.push(seq);
                 }
-                running.push(seq);
             } else {
-                new_waiting.add(seq);
+                for seq in unit {
+                    new_waiting.add(seq);
+                }
             }
         }
 
@@ -280,6 +329,10 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         let mut prompt = Vec::new();
         for seq in &mut self.running {
             if seq.is_completion() {
+                // Sequences of cancelled requests are finished on their next completion step
+                if self.cancelled_requests.contains(&seq.request_id()) {
+                    seq.set_state(SequenceState::Done(StopReason::Canceled));
+                }
                 completion.push(seq);
             } else {
                 prompt.push(seq);
@@ -292,11 +345,67 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         }
     }
 
-    fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
+    fn sequences_fit(&self, n_running: usize, n_seqs: usize) -> bool {
         match &self.method {
-            DefaultSchedulerMethod::Fixed(n) => (running.len() + 1) <= (*n).into(),
+            DefaultSchedulerMethod::Fixed(n) => (n_running + n_seqs) <= (*n).into(),
+        }
+    }
+
+    /// Preempt running sequences of lower priority classes than `priority`, the lowest class and
+    /// latest first, to make room for `n_seqs` sequences. Nothing is preempted if that cannot make
+    /// enough room. The preempted sequences keep their state and cache, and continue when they are
+    /// scheduled again. Beams are not preempted, as they choose their tokens together, and neither
+    /// are sequences of cancelled requests, which finish on their next step.
+    fn preempt_for(
+        &self,
+        running: &mut Vec<Sequence>,
+        priority: RequestPriority,
+        n_seqs: usize,
+    ) -> Vec<Sequence> {
+        let mut candidates = running
+            .iter()
+            .enumerate()
+            .filter(|(_, seq)| {
+                seq.priority() < priority
+                    && seq.beam_search().is_none()
+                    && !self.cancelled_requests.contains(&seq.request_id())
+            })
+            .map(|(i, seq)| (i, seq.priority(), seq.timestamp()))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, priority, timestamp)| (*priority, Reverse(*timestamp)));
+
+        let mut preempted = Vec::new();
+        for (i, _, _) in candidates {
+            if self.sequences_fit(running.len() - preempted.len(), n_seqs) {
+                break;
+            }
+            preempted.push(i);
+        }
+        if !self.sequences_fit(running.len() - preempted.len(), n_seqs) {
+            return Vec::new();
+        }
+
+        preempted.sort_unstable_by_key(|i| Reverse(*i));
+        preempted.into_iter().map(|i| running.remove(i)).collect()
+    }
+}
+
+/// Group the waiting sequences into the units which are scheduled together, keeping their order.
+/// The beams of a beam search request choose their tokens together, so they form one unit.
+fn scheduling_units(waiting: impl Iterator<Item = Sequence>) -> Vec<Vec<Sequence>> {
+    let mut units: Vec<Vec<Sequence>> = Vec::new();
+    let mut beam_units = HashMap::new();
+    for seq in waiting {
+        if seq.beam_search().is_some() {
+            if let Some(unit) = beam_units.get(&seq.request_id()) {
+                units[*unit].push(seq);
+                continue;
+            }
+            beam_units.insert(seq.request_id(), units.len());
         }
+        units.push(vec![seq]);
     }
+    units
 }
 
 impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
@@ -326,7 +435,102 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
         None
     }
     fn free_finished_sequence_groups(&mut self) {}
+    fn cancel_request(
+        &mut self,
+        request_id: usize,
+        model: &str,
+    ) -> Vec<(Sender<Response>, Response)> {
+        self.cancelled_requests.insert(request_id);
+        let mut responses = Vec::new();
+        for seq in std::mem::take(&mut self.waiting).into_iter() {
+            if seq.request_id() == request_id {
+                if let Some(response) = seq.finish_cancelled(model) {
+                    responses.push((seq.responder(), response));
+                }
+            } else {
+                self.waiting.add(seq);
+            }
+        }
+        responses
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, num::NonZeroUsize};
+
+    use super::{DefaultScheduler, DefaultSchedulerMethod};
+    use crate::{
+        scheduler::{test_sequence, Scheduler},
+        sequence::{Sequence, SequenceState, StopReason},
+        RequestPriority, Response,
+    };
+
+    fn new_scheduler(max_seqs: usize) -> DefaultScheduler<VecDeque<Sequence>> {
+        DefaultScheduler::new(DefaultSchedulerMethod::Fixed(
+            NonZeroUsize::new(max_seqs).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn test_cancelled_waiting_request_is_finished() {
+        let mut scheduler = new_scheduler(4);
+        scheduler.add_seq(test_sequence(0, 0, 4, RequestPriority::Normal, None));
+        scheduler.add_seq(test_sequence(1, 1, 4, RequestPriority::Normal, None));
+        let responses = scheduler.cancel_request(0, "model");
+        assert_eq!(responses.len(), 1);
+        match &responses[0].1 {
+            Response::CompletionDone(response) => {
+                assert_eq!(response.choices.len(), 1);
+                assert_eq!(response.choices[0].finish_reason, "canceled");
+            }
+            _ => panic!("Expected the final response of the cancelled request"),
+        }
+        assert_eq!(scheduler.waiting_len(), 1);
+
+        let output = scheduler.schedule();
+        let prompt = output
+            .prompt
+            .iter()
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        assert_eq!(prompt, vec![1]);
+        assert!(output.completion.is_empty());
+        assert_eq!(scheduler.waiting_len(), 0);
+        assert_eq!(scheduler.running_len(), 1);
+        assert!(scheduler.cancelled_requests.is_empty());
+    }
+
+    #[test]
+    fn test_lower_priority_is_preempted() {
+        let mut scheduler = new_scheduler(1);
+        scheduler.add_seq(test_sequence(0, 0, 4, RequestPriority::Low, None));
+        let output = scheduler.schedule();
+        assert_eq!(output.prompt.len(), 1);
+        // The prompt step ran
+        output.prompt[0].set_state(SequenceState::RunningCompletion);
+
+        scheduler.add_seq(test_sequence(1, 1, 4, RequestPriority::High, None));
+        let output = scheduler.schedule();
+        let prompt = output
+            .prompt
+            .iter()
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        assert_eq!(prompt, vec![1]);
+        assert!(output.completion.is_empty());
+        assert_eq!(scheduler.waiting_len(), 1);
+        assert_eq!(scheduler.running_len(), 1);
+        scheduler.running[0].set_state(SequenceState::Done(StopReason::Eos));
+
+        // The preempted sequence resumes as a completion, and is not batched with a new prompt
+        scheduler.add_seq(test_sequence(2, 2, 4, RequestPriority::Low, None));
+        let output = scheduler.schedule();
+        assert!(output.prompt.iter().all(|seq| seq.is_prompt()));
+        assert!(output.completion.iter().all(|seq| seq.is_completion()));
+        assert_eq!(output.prompt.len() + output.completion.len(), 1);
+    }
+}
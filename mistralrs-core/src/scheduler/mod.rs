@@ -2,12 +2,17 @@ mod default_scheduler;
 
 pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
 
+use std::{cmp::Reverse, collections::HashMap};
+
+use tokio::sync::mpsc::Sender;
+
 use crate::{
     paged_attention::{
         BlockEngine, BlockTables, CacheConfig, PagedAttentionScheduler,
         PagedAttentionSchedulerConfig, PagedAttentionSchedulerOutput,
     },
     sequence::Sequence,
+    RequestPriority, Response,
 };
 
 #[derive(Clone)]
@@ -22,14 +27,19 @@ pub enum SchedulerConfig {
 }
 
 impl SchedulerConfig {
-    pub fn into_scheduler(self) -> Box<dyn Scheduler> {
+    /// With PagedAttention, `enable_prefix_caching` shares the KV cache blocks of common prompt
+    /// prefixes between sequences.
+    pub fn into_scheduler(self, enable_prefix_caching: bool) -> Box<dyn Scheduler> {
         match self {
             Self::DefaultScheduler { method } => Box::new(DefaultScheduler::new(method)),
             Self::PagedAttentionMeta {
                 max_num_seqs,
                 config,
             } => Box::new(PagedAttentionScheduler::new(
-                PagedAttentionSchedulerConfig { max_num_seqs },
+                PagedAttentionSchedulerConfig {
+                    max_num_seqs,
+                    enable_prefix_caching,
+                },
                 config,
             )),
         }
@@ -52,9 +62,170 @@ pub trait Scheduler {
     fn add_seq(&mut self, seq: Sequence);
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
+    /// Cancel all sequences of the request with this ID. Waiting sequences are removed and
+    /// finished at once with `StopReason::Canceled`, returning the responses which this completes
+    /// with their senders. The other sequences are marked as done once they are next scheduled
+    /// for a completion step, which sends their final response.
+    fn cancel_request(
+        &mut self,
+        request_id: usize,
+        model: &str,
+    ) -> Vec<(Sender<Response>, Response)>;
 
     // PagedAttention metadata
     fn block_tables(&self) -> Option<&BlockTables>;
     fn block_size(&self) -> Option<usize>;
     fn block_engine(&mut self) -> Option<&mut BlockEngine>;
 }
+
+/// The scheduling key of a unit of waiting sequences: its priority class, its tenant and its
+/// number of sequences.
+pub(crate) type FairnessKey = (RequestPriority, Option<String>, usize);
+
+/// Order units of waiting sequences for admission: higher priority classes first, then within a
+/// class the units of the tenants with the fewest running or already admitted sequences, so that
+/// one tenant cannot starve the others, then in their current order.
+pub(crate) fn fair_order<T>(
+    units: Vec<T>,
+    running_tenants: impl IntoIterator<Item = Option<String>>,
+    key: impl Fn(&T) -> FairnessKey,
+) -> Vec<T> {
+    let mut tenant_seqs: HashMap<Option<String>, usize> = HashMap::new();
+    for tenant in running_tenants {
+        *tenant_seqs.entry(tenant).or_default() += 1;
+    }
+
+    let mut remaining = units
+        .into_iter()
+        .map(|unit| {
+            let key = key(&unit);
+            (unit, key)
+        })
+        .collect::<Vec<_>>();
+    let mut ordered = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let next = remaining
+            .iter()
+            .enumerate()
+            .min_by_key(|(i, (_, (priority, tenant, _)))| {
+                (
+                    Reverse(*priority),
+                    tenant_seqs.get(tenant).copied().unwrap_or(0),
+                    *i,
+                )
+            })
+            .map(|(i, _)| i)
+            .expect("No remaining units.");
+        let (unit, (_, tenant, n_seqs)) = remaining.remove(next);
+        *tenant_seqs.entry(tenant).or_default() += n_seqs;
+        ordered.push(unit);
+    }
+    ordered
+}
+
+/// A waiting sequence of `n_toks` prompt tokens for the scheduler tests. Its ID is also its
+/// timestamp, so later sequences are newer. `block_size` is set for PagedAttention.
+#[cfg(test)]
+pub(crate) fn test_sequence(
+    id: usize,
+    request_id: usize,
+    n_toks: usize,
+    priority: RequestPriority,
+    block_size: Option<usize>,
+) -> Sequence {
+    use std::sync::Arc;
+
+    use crate::{
+        sampler::Sampler,
+        sequence::{SeqStepType, SequenceGroup, SequenceRecognizer},
+    };
+
+    let (responder, _) = tokio::sync::mpsc::channel(1);
+    let sampler = Sampler::new(
+        None,
+        0,
+        None,
+        None,
+        None,
+        None,
+        -1,
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+        None,
+        None,
+        None,
+        None,
+        None,
+        vec![],
+    )
+    .unwrap();
+    Sequence::new_waiting(
+        vec![0; n_toks],
+        String::new(),
+        id,
+        request_id,
+        id as u128,
+        1,
+        responder,
+        sampler,
+        None,
+        None,
+        vec![],
+        vec![],
+        None,
+        false,
+        false,
+        Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, 1,
+        ))),
+        0,
+        0,
+        SequenceRecognizer::None,
+        None,
+        None,
+        None,
+        None,
+        block_size,
+        None,
+        None,
+        None,
+        SeqStepType::PromptAndDecode,
+        None,
+        None,
+        false,
+        None,
+        priority,
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fair_order;
+    use crate::RequestPriority;
+
+    #[test]
+    fn fair_order_prefers_priority_then_light_tenants() {
+        let unit = |id: usize, priority, tenant: &str| (id, priority, Some(tenant.to_string()));
+        let units = vec![
+            unit(0, RequestPriority::Normal, "heavy"),
+            unit(1, RequestPriority::Normal, "heavy"),
+            unit(2, RequestPriority::Low, "light"),
+            unit(3, RequestPriority::Normal, "light"),
+            unit(4, RequestPriority::High, "heavy"),
+            unit(5, RequestPriority::Normal, "other"),
+        ];
+        let running = vec![Some("heavy".to_string())];
+        let order = fair_order(units, running, |(_, priority, tenant)| {
+            (*priority, tenant.clone(), 1)
+        })
+        .into_iter()
+        .map(|(id, _, _)| id)
+        .collect::<Vec<_>>();
+        // `heavy` already has running sequences, so the normal units of the other tenants go
+        // before its own.
+        assert_eq!(order, vec![4, 3, 5, 0, 1, 2]);
+    }
+}
@@ -1,7 +1,7 @@
 use std::{
     fmt::Display,
     sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
     mpsc::{error::SendError, Sender},
@@ -10,21 +10,29 @@ use tokio::sync::{
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
+    beam_search::{BeamHypotheses, BeamHypothesis},
+    embedding::EmbeddingParams,
+    metrics::MetricsCollector,
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
-    pipeline::DiffusionGenerationParams,
+    pipeline::{DiffusionGenerationParams, SpeculativeStats},
     response::CompletionChoice,
-    tools::ToolCallingMatcher,
+    tools::{ToolCallStream, ToolCallingMatcher},
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
-    ImageGenerationResponse, ImageGenerationResponseFormat,
+    ImageGenerationResponse, ImageGenerationResponseFormat, RequestPriority,
 };
 use crate::{
     get_mut_group,
     pipeline::LayerCaches,
-    response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
-    sampler::{Logprobs, Sampler},
-    ChatCompletionResponse, Usage,
+    response::{
+        ChatCompletionChunkResponse, Choice, ChunkChoice, Delta, Response, ResponseMessage,
+        SYSTEM_FINGERPRINT,
+    },
+    sampler::{Logprobs, PromptLogprob, Sampler},
+    BeamSearchParams, ChatCompletionResponse, Usage,
 };
 use candle_core::Tensor;
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use regex_automata::util::primitives::StateID;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -39,6 +47,7 @@ pub enum StopReason {
     },
     Canceled,
     GeneratedImage,
+    GeneratedEmbedding,
 }
 
 impl Display for StopReason {
@@ -49,6 +58,7 @@ impl Display for StopReason {
             StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
             StopReason::GeneratedImage => write!(f, "generated-image"),
+            StopReason::GeneratedEmbedding => write!(f, "generated-embedding"),
         }
     }
 }
@@ -77,6 +87,10 @@ enum SequenceCustomMetadata {
     PagedAttention {
         logical_token_blocks: Vec<LogicalTokenBlock>,
         block_size: usize,
+        /// Whether the prompt blocks may be shared through the prefix cache.
+        share_prompt_blocks: bool,
+        /// Number of prompt tokens whose KV cache was reused from the prefix cache.
+        num_cached_toks: usize,
     },
     None,
 }
@@ -99,6 +113,7 @@ impl SequenceCustomMetadata {
             Self::PagedAttention {
                 logical_token_blocks,
                 block_size,
+                ..
             } => {
                 let last = logical_token_blocks.last_mut();
                 match last {
@@ -125,7 +140,7 @@ impl SequenceCustomMetadata {
         match self {
             Self::PagedAttention {
                 logical_token_blocks,
-                block_size: _,
+                ..
             } => {
                 let last = logical_token_blocks.last_mut().unwrap();
                 last.pop_token();
@@ -147,6 +162,30 @@ impl SequenceCustomMetadata {
     }
 }
 
+/// The generated tokens of a beam, which are swapped between the sequences of a beam search
+/// request as beams are pruned.
+#[derive(Clone)]
+pub(crate) struct BeamState {
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    last_logprob: f32,
+    last_completion_bytes_len: usize,
+    last_is_done: Option<StopReason>,
+    completion_bytes: Vec<u8>,
+}
+
+/// A new beam which extends a running beam by one token, see [`Sequence::fork_beam`].
+pub(crate) struct BeamFork {
+    pub response_index: usize,
+    /// State of the extended beam, before the token.
+    pub state: BeamState,
+    pub token: Logprobs,
+    pub completion_bytes: Vec<u8>,
+    pub cache: LayerCaches,
+    pub xlora_cache: Option<LayerCaches>,
+}
+
 #[derive(Clone, Copy)]
 pub enum SeqStepType {
     PromptAndDecode,
@@ -156,10 +195,13 @@ pub enum SeqStepType {
 pub struct Sequence {
     // Metadata, const
     id: usize,
+    request_id: usize,
     prompt_len: usize,
     max_len: Option<usize>,
     timestamp: u128,
     sampler: Arc<Sampler>,
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
+    mirostat_mu: Option<Arc<std::sync::Mutex<f32>>>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
     return_logprobs: bool,
@@ -173,6 +215,19 @@ pub struct Sequence {
     image_gen_response_format: Option<ImageGenerationResponseFormat>,
     diffusion_params: Option<DiffusionGenerationParams>,
 
+    // Embedding
+    embedding_params: Option<EmbeddingParams>,
+
+    // Scheduling
+    priority: RequestPriority,
+    tenant: Option<String>,
+
+    // Prompt logprobs
+    return_prompt_logprobs: bool,
+    /// For scoring requests, the index of the first token of the continuation in the prompt.
+    continuation_start: Option<usize>,
+    prompt_logprobs: Vec<PromptLogprob>,
+
     // Grammars
     pub(crate) tok_trie: Option<TokTrie>,
 
@@ -182,6 +237,17 @@ pub struct Sequence {
 
     // Speculative
     is_tmp: bool,
+    speculative_gamma: Option<usize>,
+    speculative_stats: Option<SpeculativeStats>,
+
+    // Beam search
+    beam_search: Option<BeamSearchParams>,
+    beam_forks: Vec<BeamFork>,
+
+    // Metrics
+    n_recorded_toks: usize,
+    last_token_time: Option<Instant>,
+    metrics: Option<Arc<MetricsCollector>>,
 
     // Prefix caching
     prefill_prompt_toks: Option<Vec<u32>>,
@@ -219,6 +285,7 @@ pub struct Sequence {
 
     // Tool calls
     pub tools: Option<Arc<ToolCallingMatcher>>,
+    tool_call_stream: Option<ToolCallStream>,
 }
 
 impl BlockEngineSequence for Sequence {
@@ -226,7 +293,7 @@ impl BlockEngineSequence for Sequence {
         match &self.custom_metadata {
             SequenceCustomMetadata::PagedAttention {
                 logical_token_blocks,
-                block_size: _,
+                ..
             } => {
                 blocks_to_add_new_tok!(logical_token_blocks)
             }
@@ -242,11 +309,24 @@ impl BlockEngineSequence for Sequence {
         match &self.custom_metadata {
             SequenceCustomMetadata::PagedAttention {
                 logical_token_blocks,
-                block_size: _,
+                ..
             } => logical_token_blocks.len(),
             SequenceCustomMetadata::None => unreachable!(),
         }
     }
+
+    fn get_prompt_prefix_blocks(&self) -> &[LogicalTokenBlock] {
+        match &self.custom_metadata {
+            SequenceCustomMetadata::PagedAttention {
+                logical_token_blocks,
+                block_size,
+                share_prompt_blocks: true,
+                ..
+            } => &logical_token_blocks[..self.prompt_len.saturating_sub(1) / block_size],
+            SequenceCustomMetadata::PagedAttention { .. } => &[],
+            SequenceCustomMetadata::None => unreachable!(),
+        }
+    }
 }
 
 impl Sequence {
@@ -255,10 +335,13 @@ impl Sequence {
         tokens: Vec<u32>,
         prompt: String,
         id: usize,
+        request_id: usize,
         timestamp: u128,
         layers: usize,
         responder: Sender<Response>,
         sampler: Sampler,
+        seed: Option<u64>,
+        beam_search: Option<BeamSearchParams>,
         stop_tokens: Vec<u32>,
         stop_strings: Vec<String>,
         max_len: Option<usize>,
@@ -280,24 +363,41 @@ impl Sequence {
         image_gen_response_format: Option<ImageGenerationResponseFormat>,
         sequence_stepping_type: SeqStepType,
         diffusion_params: Option<DiffusionGenerationParams>,
+        embedding_params: Option<EmbeddingParams>,
+        return_prompt_logprobs: bool,
+        continuation_start: Option<usize>,
+        priority: RequestPriority,
+        tenant: Option<String>,
     ) -> Self {
         let prompt_len = tokens.len();
         let mut custom_metadata = if let Some(block_size) = block_size {
             SequenceCustomMetadata::PagedAttention {
                 logical_token_blocks: Vec::new(),
                 block_size,
+                // Image tokens do not identify the image, so such prompts cannot be shared.
+                // Embeddings and prompt logprobs need the whole prompt to be run.
+                share_prompt_blocks: input_images.is_none()
+                    && embedding_params.is_none()
+                    && !return_prompt_logprobs,
+                num_cached_toks: 0,
             }
         } else {
             SequenceCustomMetadata::None
         };
         custom_metadata
             .append_tokens_to_blocks(tokens.iter().map(|x| *x as usize).collect::<Vec<_>>());
+        let tool_call_stream = tools.as_ref().and_then(|tools| tools.stream());
+        // Mirostat starts with a maximum surprise of twice the target surprise
+        let mirostat_mu = sampler
+            .mirostat()
+            .map(|params| Arc::new(std::sync::Mutex::new(2. * params.tau)));
         Self {
             tokens,
             prompt,
             logprobs: Vec::new(),
             prompt_len,
             id,
+            request_id,
             timestamp,
             state: RwLock::new(SequenceState::Waiting),
             cache: vec![None; layers],
@@ -309,6 +409,10 @@ impl Sequence {
             },
             responder,
             sampler: sampler.into(),
+            rng: seed.map(|seed| Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(seed)))),
+            mirostat_mu,
+            beam_search,
+            beam_forks: Vec::new(),
             stop_tokens,
             stop_strings,
             max_len,
@@ -330,18 +434,36 @@ impl Sequence {
             last_logprob: 0.0,
             last_is_done: None,
             is_tmp: false,
+            speculative_gamma: None,
+            speculative_stats: None,
             scheduling_urgency: 0,
             adapters,
             input_images,
             custom_metadata,
             tok_trie,
             tools,
+            tool_call_stream,
             image_gen_response_format,
             sequence_stepping_type,
             diffusion_params,
+            embedding_params,
+            return_prompt_logprobs,
+            continuation_start,
+            priority,
+            tenant,
+            prompt_logprobs: Vec::new(),
+            n_recorded_toks: 0,
+            last_token_time: None,
+            metrics: None,
         }
     }
 
+    /// Count the error responses of this sequence in the metrics of its engine.
+    pub(crate) fn with_metrics(mut self, metrics: Arc<MetricsCollector>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
     pub fn add_urgency(mut self) -> Self {
         self.scheduling_urgency += 1;
         self
@@ -400,6 +522,31 @@ impl Sequence {
         &self.id
     }
 
+    /// ID of the [`crate::NormalRequest`] this sequence was created for.
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
+    /// Number of tokens at the start of the prompt whose KV cache is reused from the PagedAttention
+    /// prefix cache, so they are skipped in the prompt step.
+    pub(crate) fn num_cached_prompt_toks(&self) -> usize {
+        match &self.custom_metadata {
+            SequenceCustomMetadata::PagedAttention {
+                num_cached_toks, ..
+            } => *num_cached_toks,
+            SequenceCustomMetadata::None => 0,
+        }
+    }
+
+    pub(crate) fn set_num_cached_prompt_toks(&mut self, n: usize) {
+        if let SequenceCustomMetadata::PagedAttention {
+            num_cached_toks, ..
+        } = &mut self.custom_metadata
+        {
+            *num_cached_toks = n;
+        }
+    }
+
     pub fn is_running(&self) -> bool {
         matches!(
             *self.state.read().unwrap(),
@@ -455,7 +602,7 @@ impl Sequence {
         match &mut self.custom_metadata {
             SequenceCustomMetadata::PagedAttention {
                 logical_token_blocks,
-                block_size: _,
+                ..
             } => {
                 logical_token_blocks.clear();
             }
@@ -489,7 +636,17 @@ impl Sequence {
         self.xlora_cache.is_some()
     }
 
-    pub fn sampler(&mut self) -> Arc<Sampler> {
+    /// The RNG of this sequence, if it was seeded. Otherwise, the engine's shared RNG is used.
+    pub fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
+    /// The maximum surprise of Mirostat, if it is used.
+    pub fn mirostat_mu(&self) -> Option<Arc<std::sync::Mutex<f32>>> {
+        self.mirostat_mu.clone()
+    }
+
+    pub fn sampler(&self) -> Arc<Sampler> {
         self.sampler.clone()
     }
 
@@ -519,6 +676,163 @@ impl Sequence {
         self.custom_metadata.remove_tokens_from_blocks(n);
     }
 
+    /// γ for the next speculative decoding step of this sequence, if it was adapted.
+    pub(crate) fn speculative_gamma(&self) -> Option<usize> {
+        self.speculative_gamma
+    }
+
+    pub(crate) fn set_speculative_gamma(&mut self, gamma: usize) {
+        self.speculative_gamma = Some(gamma);
+    }
+
+    /// Record a speculative decoding step which proposed `drafted` tokens, of which `accepted`
+    /// were accepted by the target model.
+    pub(crate) fn add_speculative_step(&mut self, drafted: usize, accepted: usize) {
+        self.speculative_stats
+            .get_or_insert_with(SpeculativeStats::default)
+            .add_step(drafted, accepted);
+    }
+
+    /// Acceptance statistics of speculative decoding, if this sequence was run by a speculative
+    /// pipeline.
+    pub fn speculative_stats(&self) -> Option<SpeculativeStats> {
+        self.speculative_stats
+    }
+
+    /// Beam search parameters, if this sequence is a beam of a beam search request.
+    pub(crate) fn beam_search(&self) -> Option<BeamSearchParams> {
+        self.beam_search
+    }
+
+    pub(crate) fn beam_state(&self) -> BeamState {
+        BeamState {
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            last_logprob: self.last_logprob,
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            last_is_done: self.last_is_done,
+            completion_bytes: self.completion_bytes.clone(),
+        }
+    }
+
+    pub(crate) fn set_beam_state(&mut self, state: BeamState) {
+        let BeamState {
+            tokens,
+            logprobs,
+            cumulative_logprob,
+            last_logprob,
+            last_completion_bytes_len,
+            last_is_done,
+            completion_bytes,
+        } = state;
+        self.tokens = tokens;
+        self.logprobs = logprobs;
+        self.cumulative_logprob = cumulative_logprob;
+        self.last_logprob = last_logprob;
+        self.last_completion_bytes_len = last_completion_bytes_len;
+        self.last_is_done = last_is_done;
+        self.completion_bytes = completion_bytes;
+    }
+
+    /// Sum of the log probabilities of the generated tokens.
+    pub(crate) fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
+    pub(crate) fn set_response_index(&mut self, response_index: usize) {
+        self.response_index = response_index;
+    }
+
+    /// Add a beam to be forked from this sequence by the engine after the current step.
+    pub(crate) fn add_beam_fork(&mut self, fork: BeamFork) {
+        self.beam_forks.push(fork);
+    }
+
+    pub(crate) fn take_beam_forks(&mut self) -> Vec<BeamFork> {
+        std::mem::take(&mut self.beam_forks)
+    }
+
+    /// A new running beam of the same request. Beam search does not support grammars or
+    /// PagedAttention, so neither are copied.
+    pub(crate) fn fork_beam(&self, id: usize, fork: BeamFork) -> Self {
+        let BeamFork {
+            response_index,
+            state,
+            token,
+            completion_bytes,
+            cache,
+            xlora_cache,
+        } = fork;
+        let mut seq = Self {
+            tokens: Vec::new(),
+            prompt: self.prompt.clone(),
+            logprobs: Vec::new(),
+            prompt_len: self.prompt_len,
+            id,
+            request_id: self.request_id,
+            timestamp: self.timestamp,
+            state: RwLock::new(SequenceState::RunningCompletion),
+            cache,
+            draft_cache: self.draft_cache.clone(),
+            xlora_cache,
+            responder: self.responder.clone(),
+            sampler: self.sampler.clone(),
+            rng: self.rng.clone(),
+            mirostat_mu: None,
+            beam_search: self.beam_search,
+            beam_forks: Vec::new(),
+            stop_tokens: self.stop_tokens.clone(),
+            stop_strings: self.stop_strings.clone(),
+            max_len: self.max_len,
+            return_logprobs: self.return_logprobs,
+            prompt_tok_per_sec: self.prompt_tok_per_sec,
+            prompt_timestamp: self.prompt_timestamp,
+            group: self.group.clone(),
+            scaling_cache: self.scaling_cache.clone(),
+            response_index,
+            creation_time: self.creation_time,
+            recognizer: SequenceRecognizer::None,
+            prefill_prompt_toks: None,
+            suffix: self.suffix.clone(),
+            prefix: self.prefix.clone(),
+            cumulative_logprob: 0.,
+            completion_bytes: Vec::new(),
+            stream_idx: 0,
+            last_completion_bytes_len: 0,
+            last_logprob: 0.0,
+            last_is_done: None,
+            is_tmp: false,
+            speculative_gamma: None,
+            speculative_stats: None,
+            scheduling_urgency: 0,
+            adapters: self.adapters.clone(),
+            input_images: self.input_images.clone(),
+            custom_metadata: SequenceCustomMetadata::None,
+            tok_trie: self.tok_trie.clone(),
+            tools: self.tools.clone(),
+            tool_call_stream: self.tools.as_ref().and_then(|tools| tools.stream()),
+            image_gen_response_format: self.image_gen_response_format,
+            sequence_stepping_type: self.sequence_stepping_type,
+            diffusion_params: self.diffusion_params.clone(),
+            embedding_params: self.embedding_params,
+            return_prompt_logprobs: self.return_prompt_logprobs,
+            continuation_start: self.continuation_start,
+            priority: self.priority,
+            tenant: self.tenant.clone(),
+            // Only the first beam returns the choices, with its prompt logprobs
+            prompt_logprobs: Vec::new(),
+            n_recorded_toks: 0,
+            last_token_time: self.last_token_time,
+            metrics: self.metrics.clone(),
+        };
+        seq.set_beam_state(state);
+        seq.add_token(token, completion_bytes, &None);
+        // The tokens of the fork were generated, and recorded, for the beam it was forked from
+        seq.n_recorded_toks = seq.tokens.len().saturating_sub(seq.prompt_len);
+        seq
+    }
+
     pub fn add_token(
         &mut self,
         tok: Logprobs,
@@ -552,6 +866,18 @@ impl Sequence {
         self.responder.clone()
     }
 
+    /// Send an error response for this sequence and count it in the metrics.
+    pub(crate) async fn send_error(&self, response: Response) {
+        match &self.metrics {
+            Some(metrics) => metrics.send_error(&self.responder, response).await,
+            None => self
+                .responder
+                .send(response)
+                .await
+                .expect("Expected receiver."),
+        }
+    }
+
     pub fn creation_time(&self) -> u64 {
         self.creation_time
     }
@@ -650,6 +976,14 @@ impl Sequence {
         self.timestamp
     }
 
+    pub fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
     pub fn prompt_timestamp(&self) -> Option<u128> {
         self.prompt_timestamp
     }
@@ -669,6 +1003,13 @@ impl Sequence {
 
         get_mut_group!(self).total_prompt_toks += self.prompt_len;
         get_mut_group!(self).total_toks += self.len();
+
+        if let Some(stats) = self.speculative_stats {
+            get_mut_group!(self)
+                .speculative_stats
+                .get_or_insert_with(SpeculativeStats::default)
+                .merge(&stats);
+        }
     }
 
     pub fn add_image_choice_to_group(&self, choice: ImageChoice) {
@@ -694,6 +1035,102 @@ impl Sequence {
         self.update_time_info();
     }
 
+    /// Finish this sequence of a cancelled request without running it again, adding its choices
+    /// with the text generated so far to its group. Returns the response to send, if this
+    /// completes one: the final chunk of a streaming request, or else the final response.
+    pub(crate) fn finish_cancelled(&self, model: &str) -> Option<Response> {
+        self.set_state(SequenceState::Done(StopReason::Canceled));
+        let (is_streaming, is_chat, n_choices) = {
+            let group = self.get_mut_group();
+            (group.is_streaming, group.is_chat, group.n_choices)
+        };
+        let finish_reason = StopReason::Canceled.to_string();
+        // Beam search requests have one sequence until they are run, which returns all choices
+        let indices = if self.beam_search.is_some() {
+            0..n_choices
+        } else {
+            self.response_index..self.response_index + 1
+        };
+
+        if is_streaming {
+            for index in indices {
+                if is_chat {
+                    self.add_streaming_chunk_choice_to_group(ChunkChoice {
+                        delta: Delta {
+                            content: String::new(),
+                            role: "assistant".to_string(),
+                            tool_calls: None,
+                        },
+                        index,
+                        finish_reason: Some(finish_reason.clone()),
+                        logprobs: None,
+                    });
+                } else {
+                    self.add_streaming_completion_chunk_choice_to_group(CompletionChunkChoice {
+                        text: String::new(),
+                        index,
+                        finish_reason: Some(finish_reason.clone()),
+                        logprobs: None,
+                    });
+                }
+            }
+            return self
+                .get_mut_group()
+                .take_streaming_response(self, model.to_string());
+        }
+
+        let text = String::from_utf8_lossy(&self.completion_bytes)
+            .trim_start()
+            .to_string();
+        for index in indices {
+            if is_chat {
+                self.add_choice_to_group(Choice {
+                    finish_reason: finish_reason.clone(),
+                    index,
+                    message: ResponseMessage {
+                        content: Some(text.clone()),
+                        role: "assistant".to_string(),
+                        tool_calls: Vec::new(),
+                    },
+                    logprobs: None,
+                });
+            } else {
+                self.add_completion_choice_to_group(CompletionChoice {
+                    finish_reason: finish_reason.clone(),
+                    index,
+                    text: text.clone(),
+                    logprobs: None,
+                    prompt_logprobs: None,
+                    score: None,
+                });
+            }
+        }
+        let group = self.get_mut_group();
+        if is_chat && group.choices.len() == group.n_choices {
+            Some(Response::Done(ChatCompletionResponse {
+                id: self.id.to_string(),
+                choices: group.choices.clone(),
+                created: self.creation_time,
+                model: model.to_string(),
+                system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                object: "chat.completion".to_string(),
+                usage: group.get_usage(),
+            }))
+        } else if !is_chat && group.completion_choices.len() == group.n_choices {
+            Some(Response::CompletionDone(CompletionResponse {
+                id: self.id.to_string(),
+                choices: group.get_completion_choices(),
+                created: self.creation_time,
+                model: model.to_string(),
+                system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                object: "text_completion".to_string(),
+                usage: group.get_usage(),
+            }))
+        } else {
+            None
+        }
+    }
+
     pub fn get_response_index(&self) -> usize {
         self.response_index
     }
@@ -733,6 +1170,79 @@ impl Sequence {
     pub fn get_diffusion_diffusion_params(&self) -> Option<DiffusionGenerationParams> {
         self.diffusion_params.clone()
     }
+
+    pub(crate) fn embedding_params(&self) -> Option<EmbeddingParams> {
+        self.embedding_params
+    }
+
+    /// The parser for the tool calls of a streamed message, if tools may be called.
+    pub(crate) fn tool_call_stream(&mut self) -> Option<&mut ToolCallStream> {
+        self.tool_call_stream.as_mut()
+    }
+
+    /// Whether the logprobs of the prompt tokens are computed in the prompt step.
+    pub(crate) fn return_prompt_logprobs(&self) -> bool {
+        self.return_prompt_logprobs
+    }
+
+    /// For scoring requests, the index of the first token of the continuation in the prompt.
+    pub(crate) fn continuation_start(&self) -> Option<usize> {
+        self.continuation_start
+    }
+
+    pub(crate) fn is_scoring(&self) -> bool {
+        self.continuation_start.is_some()
+    }
+
+    /// The logprobs of the prompt tokens after the first one.
+    pub(crate) fn prompt_logprobs(&self) -> &[PromptLogprob] {
+        &self.prompt_logprobs
+    }
+
+    pub(crate) fn set_prompt_logprobs(&mut self, prompt_logprobs: Vec<PromptLogprob>) {
+        self.prompt_logprobs = prompt_logprobs;
+    }
+
+    /// Record the tokens generated since the last call for the metrics. Returns the number of new
+    /// tokens, whether they are the first ones, and the time since the previous tokens, or since
+    /// the request was received for the first ones.
+    pub(crate) fn record_new_toks(&mut self) -> Option<(usize, bool, Duration)> {
+        let n_generated = self.tokens.len().saturating_sub(self.prompt_len);
+        let n_new = n_generated
+            .checked_sub(self.n_recorded_toks)
+            .filter(|n| *n > 0)?;
+        let now = Instant::now();
+        let elapsed = match self.last_token_time {
+            Some(last) => now.duration_since(last),
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time travel has occurred!")
+                    .as_millis();
+                Duration::from_millis(
+                    now.saturating_sub(self.timestamp)
+                        .try_into()
+                        .unwrap_or(u64::MAX),
+                )
+            }
+        };
+        let is_first = self.last_token_time.is_none();
+        self.n_recorded_toks = n_generated;
+        self.last_token_time = Some(now);
+        Some((n_new, is_first, elapsed))
+    }
+
+    /// Whether the prompt steps of this sequence and `other` may run in the same batch. Embeddings
+    /// are computed separately, and the prompt logprobs need the logits of all prompt tokens, so
+    /// such sequences are only batched with sequences of the same length.
+    pub(crate) fn can_batch_prompt_with(&self, other: &Sequence) -> bool {
+        if self.embedding_params.is_some() != other.embedding_params.is_some()
+            || self.return_prompt_logprobs != other.return_prompt_logprobs
+        {
+            return false;
+        }
+        !self.return_prompt_logprobs || self.len() == other.len()
+    }
 }
 
 pub struct SequenceGroup {
@@ -743,6 +1253,9 @@ pub struct SequenceGroup {
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
+    pub speculative_stats: Option<SpeculativeStats>,
+    /// Finished beams of a beam search request.
+    pub(crate) beam_hypotheses: Option<BeamHypotheses<BeamHypothesis>>,
     choices: Vec<Choice>,
     image_choices: Vec<ImageChoice>,
     completion_choices: Vec<(f32, CompletionChoice)>,
@@ -764,6 +1277,8 @@ impl SequenceGroup {
             total_prompt_time: 0,
             total_time: 0,
             total_completion_time: 0,
+            speculative_stats: None,
+            beam_hypotheses: None,
             chat_streaming_chunks: Vec::new(),
             completion_streaming_chunks: Vec::new(),
             is_streaming,
@@ -772,6 +1287,10 @@ impl SequenceGroup {
         }
     }
 
+    pub(crate) fn n_choices(&self) -> usize {
+        self.n_choices
+    }
+
     /// This does not apply best_of.
     pub fn get_choices(&self) -> &[Choice] {
         &self.choices
@@ -808,6 +1327,11 @@ impl SequenceGroup {
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
+            speculative_drafted_tokens: self.speculative_stats.map(|stats| stats.drafted),
+            speculative_accepted_tokens: self.speculative_stats.map(|stats| stats.accepted),
+            speculative_acceptance_rate: self
+                .speculative_stats
+                .map(|stats| stats.acceptance_rate()),
         }
     }
 
@@ -835,26 +1359,21 @@ impl SequenceGroup {
         Ok(())
     }
 
-    pub async fn maybe_send_streaming_response(
-        &mut self,
-        seq: &Sequence,
-        model: String,
-    ) -> Result<(), Box<SendError<Response>>> {
+    /// Take the streaming chunks of the choices as a response, once each choice has one.
+    fn take_streaming_response(&mut self, seq: &Sequence, model: String) -> Option<Response> {
         if self.chat_streaming_chunks.len() == self.n_choices && self.is_streaming {
             let mut swap_streaming_chunks = vec![];
 
             std::mem::swap(&mut swap_streaming_chunks, &mut self.chat_streaming_chunks);
 
-            seq.responder()
-                .send(Response::Chunk(ChatCompletionChunkResponse {
-                    id: seq.id.to_string(),
-                    choices: swap_streaming_chunks,
-                    created: seq.timestamp,
-                    model: model.clone(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
-                    object: "chat.completion.chunk".to_string(),
-                }))
-                .await?;
+            Some(Response::Chunk(ChatCompletionChunkResponse {
+                id: seq.id.to_string(),
+                choices: swap_streaming_chunks,
+                created: seq.timestamp,
+                model,
+                system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                object: "chat.completion.chunk".to_string(),
+            }))
         } else if self.completion_streaming_chunks.len() == self.n_choices && self.is_streaming {
             let mut swap_streaming_chunks = vec![];
 
@@ -863,16 +1382,26 @@ impl SequenceGroup {
                 &mut self.completion_streaming_chunks,
             );
 
-            seq.responder()
-                .send(Response::CompletionChunk(CompletionChunkResponse {
-                    id: seq.id.to_string(),
-                    choices: swap_streaming_chunks,
-                    created: seq.timestamp,
-                    model: model.clone(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
-                    object: "text_completion".to_string(),
-                }))
-                .await?;
+            Some(Response::CompletionChunk(CompletionChunkResponse {
+                id: seq.id.to_string(),
+                choices: swap_streaming_chunks,
+                created: seq.timestamp,
+                model,
+                system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                object: "text_completion".to_string(),
+            }))
+        } else {
+            None
+        }
+    }
+
+    pub async fn maybe_send_streaming_response(
+        &mut self,
+        seq: &Sequence,
+        model: String,
+    ) -> Result<(), Box<SendError<Response>>> {
+        if let Some(response) = self.take_streaming_response(seq, model) {
+            seq.responder().send(response).await?;
         }
         Ok(())
     }
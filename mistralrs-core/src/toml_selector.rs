@@ -3,11 +3,12 @@ use std::{fs::File, num::NonZeroUsize, path::PathBuf};
 use serde::Deserialize;
 
 use crate::{
-    amoe::AnyMoeConfig, pipeline::IsqOrganization, AnyMoeLoader, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, ModelDType,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
-    SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
-    GGUF_MULTI_FILE_DELIMITER,
+    amoe::AnyMoeConfig, kv_quant::KvCacheDtype, pipeline::IsqOrganization, AdaptiveGamma,
+    AnyMoeLoader, DiffusionLoaderBuilder, DiffusionLoaderType, DiffusionSpecificConfig,
+    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader,
+    ModelDType, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
+    SpeculativeLoader, SpeculativeMethod, Topology, VisionLoaderBuilder, VisionLoaderType,
+    VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
 fn default_one() -> usize {
@@ -282,6 +283,19 @@ pub enum TomlModelSelected {
         /// UQFF path to load from. If provided, this takes precedence over applying ISQ.
         from_uqff: Option<PathBuf>,
     },
+
+    /// Select a diffusion plain model, without quantization or adapters
+    DiffusionPlain {
+        /// Model ID to load from. This may be a HF hub repo or a local path.
+        model_id: String,
+
+        /// The architecture of the model.
+        arch: DiffusionLoaderType,
+
+        /// Model data type. Defaults to `auto`.
+        #[serde(default = "default_dtype")]
+        dtype: ModelDType,
+    },
 }
 
 #[derive(Deserialize)]
@@ -289,8 +303,14 @@ pub struct SpeculativeTomlModelSelected {
     /// Gamma value for the model
     gamma: usize,
 
-    /// Base model
-    draft_model: TomlModelSelected,
+    /// Draft model. If this is not specified, the tokens are proposed by prompt lookup.
+    draft_model: Option<TomlModelSelected>,
+
+    /// Maximum size of the n-grams matched by prompt lookup. Defaults to 3.
+    prompt_lookup_max_ngram_size: Option<usize>,
+
+    /// Bounds within which gamma is adapted for each sequence. By default, gamma is fixed.
+    adaptive_gamma: Option<AdaptiveGamma>,
 }
 
 #[derive(Deserialize)]
@@ -316,6 +336,8 @@ pub struct AnyMoeTomlModelSelected {
 }
 
 #[derive(Deserialize)]
+/// Model selector read from a .toml file. It may also be deserialized from other formats, such as
+/// JSON, and is converted into a loader with [`TomlLoaderArgs`].
 pub struct TomlSelector {
     /// Path to local tokenizer.json file. If this is specified it is used over any remote file.
     tokenizer_json: Option<String>,
@@ -328,6 +350,9 @@ pub struct TomlSelector {
 
     /// AnyMoE config
     anymoe: Option<AnyMoeTomlModelSelected>,
+
+    /// KV cache dtype, which may quantize the KV cache
+    kv_cache_dtype: Option<KvCacheDtype>,
 }
 
 #[derive(Clone)]
@@ -351,7 +376,8 @@ pub fn get_toml_selected_model_dtype(model: &TomlSelector) -> ModelDType {
         TomlModelSelected::Plain { dtype, .. }
         | TomlModelSelected::Lora { dtype, .. }
         | TomlModelSelected::XLora { dtype, .. }
-        | TomlModelSelected::VisionPlain { dtype, .. } => dtype,
+        | TomlModelSelected::VisionPlain { dtype, .. }
+        | TomlModelSelected::DiffusionPlain { dtype, .. } => dtype,
         TomlModelSelected::GGUF { .. }
         | TomlModelSelected::LoraGGUF { .. }
         | TomlModelSelected::GGML { .. }
@@ -361,6 +387,10 @@ pub fn get_toml_selected_model_dtype(model: &TomlSelector) -> ModelDType {
     }
 }
 
+pub fn get_toml_selected_kv_cache_dtype(model: &TomlSelector) -> KvCacheDtype {
+    model.kv_cache_dtype.unwrap_or_default()
+}
+
 fn loader_from_selected(
     args: TomlLoaderInnerParams,
     model: TomlModelSelected,
@@ -628,6 +658,14 @@ fn loader_from_selected(
             Some(model_id),
         )
         .build(arch),
+        TomlModelSelected::DiffusionPlain {
+            model_id,
+            arch,
+            dtype: _,
+        } => {
+            DiffusionLoaderBuilder::new(DiffusionSpecificConfig { use_flash_attn }, Some(model_id))
+                .build(arch)
+        }
     };
     Ok(loader)
 }
@@ -645,12 +683,31 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
         };
         let loader = loader_from_selected(args.clone(), selector.model)?;
         let loader = if let Some(speculative) = selector.speculative {
-            let draft_loader = loader_from_selected(args, speculative.draft_model)?;
+            let (draft, method) = match (
+                speculative.draft_model,
+                speculative.prompt_lookup_max_ngram_size,
+            ) {
+                (Some(_), Some(_)) => anyhow::bail!(
+                    "`prompt_lookup_max_ngram_size` may not be specified with a draft model."
+                ),
+                (Some(draft_model), None) => (
+                    Some(loader_from_selected(args, draft_model)?),
+                    SpeculativeMethod::DraftModel,
+                ),
+                (None, max_ngram_size) => (
+                    None,
+                    SpeculativeMethod::PromptLookup {
+                        max_ngram_size: max_ngram_size.unwrap_or(3),
+                    },
+                ),
+            };
             Box::new(SpeculativeLoader {
                 target: loader,
-                draft: draft_loader,
+                draft,
                 config: SpeculativeConfig {
                     gamma: speculative.gamma,
+                    method,
+                    adaptive_gamma: speculative.adaptive_gamma,
                 },
             })
         } else {
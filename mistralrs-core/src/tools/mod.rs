@@ -1,14 +1,22 @@
+mod parser;
 mod request;
 mod response;
 
+pub use parser::ToolCallFormat;
+pub(crate) use parser::ToolCallStream;
+use parser::{new_call_id, parse_message};
 pub use request::*;
 pub use response::*;
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::collections::HashMap;
-use uuid::Uuid;
+
+use crate::aici::{json_schema::remove_unsupported_keywords, toktree::TokTrie};
 
 pub struct ToolCallingMatcher {
     tool_choice: ToolChoice,
+    format: ToolCallFormat,
+    /// ID of the token of the marker which starts the tool calls, if the marker is a token.
+    marker_token: Option<u32>,
 }
 
 // Same as CalledFunction, but uses `parameters`
@@ -26,70 +34,306 @@ pub struct CalledFunctionArguments {
 }
 
 impl ToolCallingMatcher {
-    pub fn new(tool_choice: ToolChoice) -> anyhow::Result<Self> {
-        Ok(Self { tool_choice })
+    pub fn new(
+        tool_choice: ToolChoice,
+        format: ToolCallFormat,
+        marker_token: Option<u32>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            tool_choice,
+            format,
+            marker_token,
+        })
     }
 
     pub fn get_call(&self, message: &str) -> anyhow::Result<Vec<ToolCallResponse>> {
+        Ok(self.get_content_and_calls(message)?.1)
+    }
+
+    /// Split a complete message into its content before the tool calls, if any, and the calls.
+    pub fn get_content_and_calls(
+        &self,
+        message: &str,
+    ) -> anyhow::Result<(Option<String>, Vec<ToolCallResponse>)> {
         if matches!(self.tool_choice, ToolChoice::None) {
-            return Ok(Vec::new());
+            return Ok((Some(message.to_string()), Vec::new()));
         }
 
-        if let Ok(deser) = serde_json::from_str::<CalledFunctionParameters>(message) {
-            let id = format!("call-{}", Uuid::new_v4());
-            Ok(vec![ToolCallResponse {
-                id,
-                tp: ToolCallType::Function,
-                function: CalledFunction {
-                    name: deser.name,
-                    arguments: serde_json::to_string(&deser.parameters)?,
-                },
-            }])
-        } else if let Ok(deser) = serde_json::from_str::<Vec<CalledFunctionParameters>>(message) {
-            Ok(deser
-                .into_iter()
-                .map(|deser| {
-                    let id = format!("call-{}", Uuid::new_v4());
-                    Ok(ToolCallResponse {
-                        id,
-                        tp: ToolCallType::Function,
-                        function: CalledFunction {
-                            name: deser.name,
-                            arguments: serde_json::to_string(&deser.parameters)?,
-                        },
-                    })
-                })
-                .collect::<anyhow::Result<Vec<_>>>()?)
-        } else if let Ok(deser) = serde_json::from_str::<CalledFunctionArguments>(message) {
-            let id = format!("call-{}", Uuid::new_v4());
-            Ok(vec![ToolCallResponse {
-                id,
+        let (content, calls) = parse_message(self.format, message);
+        if calls.is_empty()
+            && matches!(self.tool_choice, ToolChoice::Required | ToolChoice::Tool(_))
+        {
+            anyhow::bail!("Tool choice was required but no tools were called.")
+        }
+        let calls = calls
+            .into_iter()
+            .map(|function| ToolCallResponse {
+                id: new_call_id(),
                 tp: ToolCallType::Function,
-                function: CalledFunction {
-                    name: deser.name,
-                    arguments: serde_json::to_string(&deser.arguments)?,
-                },
-            }])
-        } else if let Ok(deser) = serde_json::from_str::<Vec<CalledFunctionArguments>>(message) {
-            Ok(deser
-                .into_iter()
-                .map(|deser| {
-                    let id = format!("call-{}", Uuid::new_v4());
-                    Ok(ToolCallResponse {
-                        id,
-                        tp: ToolCallType::Function,
-                        function: CalledFunction {
-                            name: deser.name,
-                            arguments: serde_json::to_string(&deser.arguments)?,
-                        },
-                    })
-                })
-                .collect::<anyhow::Result<Vec<_>>>()?)
-        } else {
-            if matches!(self.tool_choice, ToolChoice::Tool(_)) {
-                anyhow::bail!("Tool choice was required but no tools were called.")
+                function,
+            })
+            .collect();
+        Ok((content, calls))
+    }
+
+    /// The text of `token` if it is the marker token which starts the tool calls, and tools may
+    /// be called.
+    fn marker_text(&self, token: u32) -> Option<&'static str> {
+        if matches!(self.tool_choice, ToolChoice::None) || self.marker_token != Some(token) {
+            return None;
+        }
+        self.format.start_marker()
+    }
+
+    /// An incremental parser for the tool calls of a streamed message, if tools may be called.
+    pub(crate) fn stream(&self) -> Option<ToolCallStream> {
+        (!matches!(self.tool_choice, ToolChoice::None)).then(|| ToolCallStream::new(self.format))
+    }
+}
+
+/// The bytes of a sampled token in the completion. Special tokens, such as the `[TOOL_CALLS]` or
+/// `<|python_tag|>` markers, have no bytes in the token trie, so the marker of the tool calls is
+/// decoded to its text for the tool call parser to find it.
+pub(crate) fn completion_bytes(
+    tok_trie: &TokTrie,
+    tools: Option<&ToolCallingMatcher>,
+    token: u32,
+) -> Vec<u8> {
+    match tools.and_then(|tools| tools.marker_text(token)) {
+        Some(marker) => marker.as_bytes().to_vec(),
+        None => tok_trie.decode(&[token]),
+    }
+}
+
+/// The JSON Schema of the tool calls allowed by a tool choice which forces a call, if it does.
+/// Decoding is constrained to this schema so that the call has valid arguments. Keywords of the
+/// parameters which cannot be enforced by a grammar, such as `pattern` or `minimum`, are dropped,
+/// as tool parameters commonly use them.
+pub(crate) fn forced_tool_call_schema(
+    tool_choice: &ToolChoice,
+    tools: &[Tool],
+) -> anyhow::Result<Option<Value>> {
+    let call_schema = |function: &Function| {
+        let mut arguments = function
+            .parameters
+            .as_ref()
+            .map_or_else(|| json!({"type": "object"}), |parameters| json!(parameters));
+        remove_unsupported_keywords(&mut arguments);
+        json!({
+            "type": "object",
+            "properties": {
+                "name": {"const": function.name},
+                "arguments": arguments,
+            },
+            "required": ["name", "arguments"],
+        })
+    };
+    match tool_choice {
+        ToolChoice::None | ToolChoice::Auto => Ok(None),
+        ToolChoice::Required => {
+            if tools.is_empty() {
+                anyhow::bail!("Tool choice `required` needs at least one tool.");
+            }
+            // The calls are told apart by the name of the tool
+            for (i, tool) in tools.iter().enumerate() {
+                if tools[..i]
+                    .iter()
+                    .any(|other| other.function.name == tool.function.name)
+                {
+                    anyhow::bail!("Tool `{}` is given more than once.", tool.function.name);
+                }
             }
-            Ok(Vec::new())
+            let schemas = tools
+                .iter()
+                .map(|tool| call_schema(&tool.function))
+                .collect::<Vec<_>>();
+            Ok(Some(json!({"anyOf": schemas})))
         }
+        ToolChoice::Tool(forced) => {
+            // The tool choice may only name the function, its parameters are in the tools
+            let Some(tool) = tools
+                .iter()
+                .find(|tool| tool.function.name == forced.function.name)
+            else {
+                anyhow::bail!(
+                    "Tool choice `{}` is not one of the tools.",
+                    forced.function.name
+                );
+            };
+            Ok(Some(call_schema(&tool.function)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+    use tokenizers::{
+        models::bpe::BPE, pre_tokenizers::byte_level::ByteLevel, AddedToken, DecoderWrapper,
+        PreTokenizerWrapper, Tokenizer,
+    };
+
+    use super::{
+        completion_bytes, forced_tool_call_schema, Function, Tool, ToolCallFormat,
+        ToolCallingMatcher, ToolChoice, ToolType,
+    };
+    use crate::aici::{
+        bintokens::build_tok_trie,
+        cfg::CfgParser,
+        json_schema::json_schema_to_yacc,
+        toktree::{Recognizer, SpecialToken},
+    };
+
+    #[test]
+    fn test_forced_tool_call_schema() {
+        let tool = |name: &str| Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: None,
+                name: name.to_string(),
+                parameters: None,
+            },
+        };
+        let tools = vec![tool("f"), tool("g")];
+
+        assert!(forced_tool_call_schema(&ToolChoice::Auto, &tools)
+            .unwrap()
+            .is_none());
+        let schema = forced_tool_call_schema(&ToolChoice::Tool(tool("g")), &tools)
+            .unwrap()
+            .unwrap();
+        assert_eq!(schema["properties"]["name"], json!({"const": "g"}));
+        let schema = forced_tool_call_schema(&ToolChoice::Required, &tools)
+            .unwrap()
+            .unwrap();
+        assert_eq!(schema["anyOf"].as_array().unwrap().len(), 2);
+
+        assert!(forced_tool_call_schema(&ToolChoice::Tool(tool("h")), &tools).is_err());
+        assert!(forced_tool_call_schema(&ToolChoice::Required, &[]).is_err());
+        assert!(forced_tool_call_schema(&ToolChoice::Required, &[tool("f"), tool("f")]).is_err());
+    }
+
+    #[test]
+    fn test_required_tool_call_grammar() {
+        let tool = |name: &str| Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: None,
+                name: name.to_string(),
+                parameters: None,
+            },
+        };
+        let schema = forced_tool_call_schema(&ToolChoice::Required, &[tool("f"), tool("g")])
+            .unwrap()
+            .unwrap();
+        // The grammar must be unambiguous, otherwise the parser is not built
+        let yacc = json_schema_to_yacc(&schema).unwrap();
+        let accepts = |document: &str| {
+            let mut parser = CfgParser::from_yacc_unambiguous(&yacc).unwrap();
+            document.bytes().all(|b| parser.try_push_byte(b))
+                && parser.special_allowed(SpecialToken::EndOfSentence)
+        };
+        assert!(accepts(r#"{"name": "g", "arguments": {"x": 1}}"#));
+        assert!(accepts(r#"{"name": "f", "arguments": {}}"#));
+        assert!(!accepts(r#"{"name": "h", "arguments": {}}"#));
+    }
+
+    #[test]
+    fn test_marker_token_completion_bytes() {
+        // A byte-level tokenizer in which `[TOOL_CALLS]` is a special token, as for Mistral
+        let vocab = ByteLevel::alphabet()
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| (c.to_string(), i as u32))
+            .collect::<HashMap<_, _>>();
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, Vec::new())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(bpe);
+        tokenizer
+            .with_pre_tokenizer(PreTokenizerWrapper::ByteLevel(ByteLevel::default()))
+            .with_decoder(DecoderWrapper::ByteLevel(ByteLevel::default()));
+        tokenizer.add_special_tokens(&[AddedToken::from("[TOOL_CALLS]", true)]);
+        let format = ToolCallFormat::Mistral;
+        let marker_token = format.marker_token(&tokenizer);
+        assert!(marker_token.is_some());
+
+        let tok_trie = build_tok_trie(tokenizer.clone());
+        let tokens = tokenizer
+            .encode(
+                r#"Calling f.[TOOL_CALLS][{"name": "f", "arguments": {"x": 1}}]"#,
+                false,
+            )
+            .unwrap()
+            .get_ids()
+            .to_vec();
+        let decode = |tools: Option<&ToolCallingMatcher>| {
+            let bytes = tokens
+                .iter()
+                .flat_map(|token| completion_bytes(&tok_trie, tools, *token))
+                .collect::<Vec<_>>();
+            String::from_utf8(bytes).unwrap()
+        };
+
+        // The token trie has no bytes for special tokens
+        assert!(!decode(None).contains("[TOOL_CALLS]"));
+
+        let matcher = ToolCallingMatcher::new(ToolChoice::Auto, format, marker_token).unwrap();
+        let (content, calls) = matcher
+            .get_content_and_calls(&decode(Some(&matcher)))
+            .unwrap();
+        assert_eq!(content.as_deref(), Some("Calling f."));
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "f");
+        assert_eq!(calls[0].function.arguments, r#"{"x":1}"#);
+
+        // Without tools, the marker stays out of the text
+        let matcher = ToolCallingMatcher::new(ToolChoice::None, format, marker_token).unwrap();
+        assert!(!decode(Some(&matcher)).contains("[TOOL_CALLS]"));
+    }
+
+    #[test]
+    fn test_tool_call_grammar_with_validation_keywords() {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "city": {"type": "string", "pattern": "^[A-Z]", "minLength": 1},
+                "days": {"type": "integer", "minimum": 1, "maximum": 14},
+                "tags": {"type": "array", "items": {"type": "string", "maxLength": 8}},
+            },
+            "required": ["city", "days"],
+        });
+        let tool = Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: None,
+                name: "forecast".to_string(),
+                parameters: Some(serde_json::from_value(parameters.clone()).unwrap()),
+            },
+        };
+        assert!(json_schema_to_yacc(&parameters).is_err());
+
+        let schema = forced_tool_call_schema(&ToolChoice::Tool(tool.clone()), &[tool])
+            .unwrap()
+            .unwrap();
+        let yacc = json_schema_to_yacc(&schema).unwrap();
+        let accepts = |document: &str| {
+            let mut parser = CfgParser::from_yacc_unambiguous(&yacc).unwrap();
+            document.bytes().all(|b| parser.try_push_byte(b))
+                && parser.special_allowed(SpecialToken::EndOfSentence)
+        };
+        // The structure of the arguments is still enforced
+        assert!(accepts(
+            r#"{"name": "forecast", "arguments": {"city": "Paris", "days": 3}}"#
+        ));
+        assert!(!accepts(
+            r#"{"name": "forecast", "arguments": {"city": "Paris", "days": "3"}}"#
+        ));
+        assert!(!accepts(
+            r#"{"name": "forecast", "arguments": {"days": 3}}"#
+        ));
     }
 }
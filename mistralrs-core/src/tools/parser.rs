@@ -0,0 +1,422 @@
+//! Parsers for the formats in which models emit tool calls. Each call is parsed as soon as it is
+//! complete, so that streaming responses send tool call deltas instead of the raw text.
+
+use std::{collections::HashMap, str::FromStr};
+
+use either::Either;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use strum::{EnumString, VariantNames};
+use tokenizers::Tokenizer;
+use uuid::Uuid;
+
+use crate::pipeline::chat_template::{ChatTemplate, ChatTemplateValue};
+
+use super::{
+    CalledFunction, CalledFunctionArguments, CalledFunctionParameters, ToolCallDelta, ToolCallType,
+};
+
+/// The format in which a model emits tool calls. All formats also accept a message which is only a
+/// JSON call, or an array of calls, as models often omit their marker.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    EnumString,
+    VariantNames,
+    strum::Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ToolCallFormat {
+    /// A JSON object, or an array of objects, with a `name` and `parameters` or `arguments`.
+    #[default]
+    Json,
+    /// Mistral: `[TOOL_CALLS]` followed by a JSON array of calls.
+    Mistral,
+    /// Llama 3.1: `<|python_tag|>` followed by JSON calls separated by `;`.
+    Llama3,
+    /// Hermes: each call is a JSON object between `<tool_call>` and `</tool_call>`.
+    Hermes,
+    /// Qwen: `✿FUNCTION✿: <name>` followed by `✿ARGS✿: <JSON arguments>` for each call.
+    Qwen,
+}
+
+const QWEN_FUNCTION: &str = "✿FUNCTION✿";
+const QWEN_ARGS: &str = "✿ARGS✿";
+
+impl ToolCallFormat {
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        Self::from_str(name).map_err(|_| {
+            anyhow::anyhow!(
+                "Unknown tool call format `{name}`, expected one of {}.",
+                Self::VARIANTS.join(", ")
+            )
+        })
+    }
+
+    /// Detect the format of a model from the markers in its chat template, or else from the
+    /// special tokens of its tokenizer.
+    pub(crate) fn detect(
+        chat_template: Option<&ChatTemplate>,
+        tokenizer: Option<&Tokenizer>,
+    ) -> Self {
+        const FORMATS: [ToolCallFormat; 4] = [
+            ToolCallFormat::Mistral,
+            ToolCallFormat::Hermes,
+            ToolCallFormat::Llama3,
+            ToolCallFormat::Qwen,
+        ];
+
+        let templates = match chat_template.and_then(|template| template.chat_template.as_ref()) {
+            Some(ChatTemplateValue(Either::Left(template))) => vec![template.as_str()],
+            Some(ChatTemplateValue(Either::Right(templates))) => templates
+                .iter()
+                .flat_map(|template| template.values())
+                .map(String::as_str)
+                .collect(),
+            None => Vec::new(),
+        };
+        let marker = |format: &ToolCallFormat| format.start_marker().unwrap_or_default();
+        FORMATS
+            .into_iter()
+            .find(|format| templates.iter().any(|t| t.contains(marker(format))))
+            .or_else(|| {
+                let tokenizer = tokenizer?;
+                FORMATS
+                    .into_iter()
+                    .find(|format| tokenizer.token_to_id(marker(format)).is_some())
+            })
+            .unwrap_or_default()
+    }
+
+    /// The ID of the token of the marker which starts the tool calls, if the marker is a token.
+    pub(crate) fn marker_token(&self, tokenizer: &Tokenizer) -> Option<u32> {
+        tokenizer.token_to_id(self.start_marker()?)
+    }
+
+    /// The text which starts the tool calls.
+    pub(super) fn start_marker(&self) -> Option<&'static str> {
+        match self {
+            Self::Json => None,
+            Self::Mistral => Some("[TOOL_CALLS]"),
+            Self::Llama3 => Some("<|python_tag|>"),
+            Self::Hermes => Some("<tool_call>"),
+            Self::Qwen => Some(QWEN_FUNCTION),
+        }
+    }
+
+    /// The position at which the tool calls start in `text`. If `is_message_start`, `text` is the
+    /// start of the message, which may be only JSON calls.
+    fn find_start(&self, text: &str, is_message_start: bool) -> Option<usize> {
+        if let Some(pos) = self.start_marker().and_then(|marker| text.find(marker)) {
+            return Some(pos);
+        }
+        let trimmed = text.trim_start();
+        (is_message_start && (trimmed.starts_with('{') || trimmed.starts_with('[')))
+            .then_some(text.len() - trimmed.len())
+    }
+
+    /// Parse the complete calls in `text`, which starts where the calls start. Returns `None` if a
+    /// complete part of `text` is not a call.
+    fn parse_calls(&self, text: &str) -> Option<Vec<CalledFunction>> {
+        if *self == Self::Qwen && text.starts_with(QWEN_FUNCTION) {
+            return parse_qwen_calls(text);
+        }
+        complete_json_objects(text)
+            .into_iter()
+            .map(parse_json_call)
+            .collect()
+    }
+}
+
+/// Parse a JSON call with a `name` and `parameters` or `arguments`.
+fn parse_json_call(object: &str) -> Option<CalledFunction> {
+    if let Ok(call) = serde_json::from_str::<CalledFunctionParameters>(object) {
+        Some(CalledFunction {
+            name: call.name,
+            arguments: serde_json::to_string(&call.parameters).ok()?,
+        })
+    } else if let Ok(call) = serde_json::from_str::<CalledFunctionArguments>(object) {
+        Some(CalledFunction {
+            name: call.name,
+            arguments: serde_json::to_string(&call.arguments).ok()?,
+        })
+    } else {
+        None
+    }
+}
+
+fn parse_qwen_calls(text: &str) -> Option<Vec<CalledFunction>> {
+    let mut calls = Vec::new();
+    for call in text.split(QWEN_FUNCTION).skip(1) {
+        let Some((name, arguments)) = call.split_once(QWEN_ARGS) else {
+            break;
+        };
+        let Some(arguments) = complete_json_objects(arguments).into_iter().next() else {
+            break;
+        };
+        let arguments = serde_json::from_str::<HashMap<String, Value>>(arguments).ok()?;
+        calls.push(CalledFunction {
+            name: name.trim_start_matches(':').trim().to_string(),
+            arguments: serde_json::to_string(&arguments).ok()?,
+        });
+    }
+    Some(calls)
+}
+
+/// The complete top-level JSON objects in `text`. Text outside of the objects, such as the
+/// brackets of an array or the tags around a call, is skipped.
+fn complete_json_objects(text: &str) -> Vec<&str> {
+    let mut objects = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match c {
+            '"' if depth > 0 => in_string = true,
+            '{' => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    objects.push(&text[start..=i]);
+                }
+            }
+            _ => (),
+        }
+    }
+    objects
+}
+
+pub(super) fn new_call_id() -> String {
+    format!("call-{}", Uuid::new_v4())
+}
+
+/// Split a complete message into its content before the tool calls and the calls. If the message
+/// has no calls, it is all content.
+pub(crate) fn parse_message(
+    format: ToolCallFormat,
+    message: &str,
+) -> (Option<String>, Vec<CalledFunction>) {
+    let calls = format.find_start(message, true).and_then(|start| {
+        let calls = format.parse_calls(&message[start..])?;
+        (!calls.is_empty()).then_some((start, calls))
+    });
+    match calls {
+        Some((start, calls)) => {
+            let content = message[..start].trim();
+            ((!content.is_empty()).then(|| content.to_string()), calls)
+        }
+        None => (Some(message.to_string()), Vec::new()),
+    }
+}
+
+/// The new content and tool calls of a streamed message after a delta.
+#[derive(Debug, Default)]
+pub(crate) struct ToolCallStreamOutput {
+    pub content: String,
+    pub tool_calls: Vec<ToolCallDelta>,
+}
+
+/// Incremental parser for the tool calls of a streamed message. Text which may be the start of
+/// the calls is held back until it is known whether it is.
+#[derive(Debug, Clone)]
+pub(crate) struct ToolCallStream {
+    format: ToolCallFormat,
+    /// Text which was not returned yet.
+    buffer: String,
+    /// Once there is content, the calls can only start with the marker of the format.
+    has_content: bool,
+    in_calls: bool,
+    n_calls: usize,
+}
+
+impl ToolCallStream {
+    pub fn new(format: ToolCallFormat) -> Self {
+        Self {
+            format,
+            buffer: String::new(),
+            has_content: false,
+            in_calls: false,
+            n_calls: 0,
+        }
+    }
+
+    pub fn push(&mut self, delta: &str) -> ToolCallStreamOutput {
+        self.buffer.push_str(delta);
+        let mut content = String::new();
+        if !self.in_calls {
+            let end = match self.format.find_start(&self.buffer, !self.has_content) {
+                Some(start) => {
+                    self.in_calls = true;
+                    start
+                }
+                None => self.buffer.len() - self.held_back_len(),
+            };
+            content = self.buffer.drain(..end).collect();
+            self.has_content |= !content.trim().is_empty();
+        }
+        ToolCallStreamOutput {
+            content,
+            tool_calls: self.new_calls(),
+        }
+    }
+
+    /// The rest of the message, once it is done. If the calls could not be parsed, their text is
+    /// returned as content.
+    pub fn finish(&mut self) -> ToolCallStreamOutput {
+        let mut output = self.push("");
+        if !self.in_calls || self.n_calls == 0 {
+            output.content.push_str(&self.buffer);
+        }
+        self.buffer.clear();
+        output
+    }
+
+    /// Length of the end of the buffer which may be the start of the calls.
+    fn held_back_len(&self) -> usize {
+        if !self.has_content && self.buffer.trim().is_empty() {
+            return self.buffer.len();
+        }
+        let Some(marker) = self.format.start_marker() else {
+            return 0;
+        };
+        (1..marker.len())
+            .rev()
+            .filter(|len| marker.is_char_boundary(*len))
+            .find(|len| self.buffer.ends_with(&marker[..*len]))
+            .unwrap_or(0)
+    }
+
+    fn new_calls(&mut self) -> Vec<ToolCallDelta> {
+        if !self.in_calls {
+            return Vec::new();
+        }
+        let calls = self.format.parse_calls(&self.buffer).unwrap_or_default();
+        let new = calls
+            .into_iter()
+            .enumerate()
+            .skip(self.n_calls)
+            .map(|(index, function)| ToolCallDelta {
+                index,
+                id: new_call_id(),
+                tp: ToolCallType::Function,
+                function,
+            })
+            .collect::<Vec<_>>();
+        self.n_calls += new.len();
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{complete_json_objects, parse_message, ToolCallFormat, ToolCallStream};
+
+    #[test]
+    fn test_complete_json_objects() {
+        let text = r#"[{"a": {"b": "}"}}, {"c": 1}, {"d": "#;
+        assert_eq!(
+            complete_json_objects(text),
+            vec![r#"{"a": {"b": "}"}}"#, r#"{"c": 1}"#]
+        );
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let (content, calls) = parse_message(
+            ToolCallFormat::Mistral,
+            r#"[TOOL_CALLS][{"name": "f", "arguments": {"x": 1}}, {"name": "g", "arguments": {}}]"#,
+        );
+        assert_eq!(content, None);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "f");
+        assert_eq!(calls[0].arguments, r#"{"x":1}"#);
+
+        let (content, calls) = parse_message(
+            ToolCallFormat::Hermes,
+            "Let me check.\n<tool_call>\n{\"name\": \"f\", \"arguments\": {}}\n</tool_call>",
+        );
+        assert_eq!(content.as_deref(), Some("Let me check."));
+        assert_eq!(calls.len(), 1);
+
+        let (_, calls) = parse_message(
+            ToolCallFormat::Llama3,
+            r#"<|python_tag|>{"name": "f", "parameters": {"x": "a"}}; {"name": "g", "parameters": {}}"#,
+        );
+        assert_eq!(calls.len(), 2);
+
+        let (_, calls) = parse_message(
+            ToolCallFormat::Qwen,
+            "✿FUNCTION✿: f\n✿ARGS✿: {\"x\": 1}\n✿FUNCTION✿: g\n✿ARGS✿: {}",
+        );
+        assert_eq!(
+            calls.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["f", "g"]
+        );
+
+        // Bare JSON is accepted by all formats, but JSON which is not a call is content
+        let (_, calls) =
+            parse_message(ToolCallFormat::Mistral, r#"{"name": "f", "arguments": {}}"#);
+        assert_eq!(calls.len(), 1);
+        let (content, calls) = parse_message(ToolCallFormat::Json, r#"{"answer": 42}"#);
+        assert_eq!(content.as_deref(), Some(r#"{"answer": 42}"#));
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut stream = ToolCallStream::new(ToolCallFormat::Hermes);
+        let mut content = String::new();
+        let mut calls = Vec::new();
+        for delta in [
+            "Sure",
+            ". <tool",
+            "_call>{\"name\": \"f\", ",
+            "\"arguments\": {}}</tool_call>",
+            "<tool_call>{\"name\": \"g\", \"arguments\": {}}",
+        ] {
+            let output = stream.push(delta);
+            content.push_str(&output.content);
+            calls.extend(output.tool_calls);
+        }
+        let output = stream.finish();
+        content.push_str(&output.content);
+        calls.extend(output.tool_calls);
+        assert_eq!(content, "Sure. ");
+        assert_eq!(
+            calls
+                .iter()
+                .map(|c| (c.index, c.function.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(0, "f"), (1, "g")]
+        );
+
+        // Text which only looks like the start of calls is returned once the message is done
+        let mut stream = ToolCallStream::new(ToolCallFormat::Json);
+        assert_eq!(stream.push("[1] See").content, "");
+        let output = stream.finish();
+        assert_eq!(output.content, "[1] See");
+        assert!(output.tool_calls.is_empty());
+    }
+}
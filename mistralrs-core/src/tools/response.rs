@@ -23,3 +23,16 @@ pub struct ToolCallResponse {
     pub tp: ToolCallType,
     pub function: CalledFunction,
 }
+
+/// A tool call in a streaming response. Each call is sent in one delta once it is complete.
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ToolCallDelta {
+    /// Index of the call in the message.
+    pub index: usize,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub tp: ToolCallType,
+    pub function: CalledFunction,
+}
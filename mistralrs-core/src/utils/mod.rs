@@ -24,15 +24,14 @@ macro_rules! get_mut_arcmutex {
 #[doc(hidden)]
 #[macro_export]
 macro_rules! handle_seq_error {
-    ($fallible:expr, $response:expr) => {
+    ($fallible:expr, $response:expr, $metrics:expr) => {
         match $fallible {
             Ok(v) => v,
             Err(e) => {
                 use $crate::response::Response;
-                $response
-                    .send(Response::InternalError(e.into()))
-                    .await
-                    .expect("Expected receiver.");
+                $metrics
+                    .send_error(&$response, Response::InternalError(e.into()))
+                    .await;
                 return;
             }
         }
@@ -42,15 +41,12 @@ macro_rules! handle_seq_error {
 #[doc(hidden)]
 #[macro_export]
 macro_rules! handle_seq_error_ok {
-    ($fallible:expr, $response:expr) => {
+    ($fallible:expr, $seq:expr) => {
         match $fallible {
             Ok(v) => v,
             Err(e) => {
                 use $crate::response::Response;
-                $response
-                    .send(Response::InternalError(e.into()))
-                    .await
-                    .expect("Expected receiver.");
+                $seq.send_error(Response::InternalError(e.into())).await;
                 return Ok(());
             }
         }
@@ -66,10 +62,7 @@ macro_rules! handle_seq_error_stateaware_ok {
             Err(e) => {
                 use $crate::response::Response;
                 use $crate::sequence::SequenceState;
-                $seq.responder()
-                    .send(Response::InternalError(e.into()))
-                    .await
-                    .expect("Expected receiver.");
+                $seq.send_error(Response::InternalError(e.into())).await;
                 $seq.set_state(SequenceState::Error);
                 return Ok(());
             }
@@ -143,13 +136,11 @@ macro_rules! handle_pipeline_forward_error {
                             usage: group.get_usage(),
                         };
 
-                        seq.responder()
-                            .send(Response::ModelError(
-                                e.to_string(),
-                                partial_completion_response
-                            ))
-                            .await
-                            .unwrap();
+                        seq.send_error(Response::ModelError(
+                            e.to_string(),
+                            partial_completion_response
+                        ))
+                        .await;
                     } else {
                         let partial_completion_response = CompletionResponse {
                             id: seq.id().to_string(),
@@ -161,13 +152,11 @@ macro_rules! handle_pipeline_forward_error {
                             usage: group.get_usage(),
                         };
 
-                        seq.responder()
-                            .send(Response::CompletionModelError(
-                                e.to_string(),
-                                partial_completion_response
-                            ))
-                            .await
-                            .unwrap();
+                        seq.send_error(Response::CompletionModelError(
+                            e.to_string(),
+                            partial_completion_response
+                        ))
+                        .await;
                     }
                 }
                 for seq in $seq_slice.iter_mut() {
@@ -228,12 +217,8 @@ macro_rules! serde_default_fn {
     };
 }
 
-#[cfg(all(feature = "cuda", target_family = "unix"))]
+/// PagedAttention is supported on all devices: CUDA uses the fused kernels and other devices a
+/// portable implementation.
 pub const fn paged_attn_supported() -> bool {
     true
 }
-
-#[cfg(not(all(feature = "cuda", target_family = "unix")))]
-pub const fn paged_attn_supported() -> bool {
-    false
-}
@@ -291,9 +291,13 @@ impl<R: std::io::Seek + std::io::Read> Config<ParamsGGUF<'_, R>, Adapter<'_>> {
 }
 
 use crate::{
+    models::quantized_gemma::ModelWeights as QGemma,
+    models::quantized_gemma2::ModelWeights as QGemma2,
     models::quantized_llama::ModelWeights as QLlama,
+    models::quantized_mamba::ModelWeights as QMamba,
     models::quantized_phi2::ModelWeights as QPhi,
     models::quantized_phi3::ModelWeights as QPhi3,
+    models::quantized_qwen2::ModelWeights as QQwen2,
     models::quantized_starcoder2::ModelWeights as QStarcoder2,
     xlora_models::{XLoraQLlama, XLoraQPhi3},
 };
@@ -318,7 +322,16 @@ impl TryFrom<ModelParams<'_, ParamsGGML>> for XLoraQLlama {
 }
 
 akin! {
-    let &models_gguf = [QLlama, QPhi, QPhi3, QStarcoder2];
+    let &models_gguf = [
+        QLlama,
+        QPhi,
+        QPhi3,
+        QStarcoder2,
+        QMamba,
+        QQwen2,
+        QGemma,
+        QGemma2,
+    ];
 
     impl<R: std::io::Seek + std::io::Read> TryFrom<ModelParams<'_, ParamsGGUF<'_, R>>> for *models_gguf {
         type Error = candle_core::Error;
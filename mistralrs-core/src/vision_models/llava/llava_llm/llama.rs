@@ -16,6 +16,7 @@ use crate::{
     attention::SdpaParams,
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
+    kv_quant::KvCacheDtype,
     layers::{CausalMasker, MatMul, RmsNorm, Sdpa},
     layers_masker::PastKvLenCache,
     models::llama::Config,
@@ -53,6 +54,7 @@ impl CausalSelfAttention {
         _start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         rope_parameter: (&Tensor, &Tensor),
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
@@ -102,8 +104,13 @@ impl CausalSelfAttention {
                 )?
             }
             None => {
-                let (k, v) =
-                    crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+                let (k, v) = crate::pipeline::Cache::update_kv_cache(
+                    &mut kv_cache[block_idx],
+                    k,
+                    v,
+                    kv_cache_dtype,
+                    false,
+                )?;
 
                 Sdpa.run_attention(
                     &q,
@@ -291,6 +298,7 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         rope_parameters: (&Tensor, &Tensor),
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
@@ -304,6 +312,7 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            kv_cache_dtype,
             rope_parameters,
             metadata,
             flash_params,
@@ -506,6 +515,7 @@ impl LLaVALLM for Llama {
     ) -> Result<Tensor> {
         let mut x = input_embed;
         let mut cache = self.kv_cache.lock();
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             metadata
@@ -524,6 +534,7 @@ impl LLaVALLM for Llama {
                 start_offsets_kernel.clone(),
                 block_idx,
                 &mut cache,
+                kv_cache_dtype,
                 (&self.rope_parameters.0, &self.rope_parameters.1),
                 metadata
                     .as_mut()
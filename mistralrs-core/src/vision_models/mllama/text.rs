@@ -9,6 +9,7 @@ use mistralrs_quant::{linear_no_bias, QuantMethod, QuantMethodConfig, UnquantLin
 use crate::{
     attention::SdpaParams,
     device_map::DeviceMapper,
+    kv_quant::KvCacheDtype,
     layers::{repeat_kv, CausalMasker, Llama3RotaryEmbedding, MatMul, RmsNorm, Sdpa},
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata},
@@ -134,6 +135,7 @@ impl MLlamaTextSelfAttention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
     ) -> Result<Tensor> {
         let (bs, q_len, _) = hidden_states.dims3()?;
 
@@ -171,7 +173,7 @@ impl MLlamaTextSelfAttention {
                 .contiguous()?;
         }
 
-        (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+        (k, v) = Cache::update_kv_cache(kv_cache, k, v, kv_cache_dtype, false)?;
 
         let mut attn_output = Sdpa
             .run_attention(&q, &k, &v, attention_mask, None, &self.sdpa_params)?
@@ -237,6 +239,7 @@ impl MLlamaSelfAttentionDecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
     ) -> Result<Tensor> {
         let residual = hidden_states;
 
@@ -248,6 +251,7 @@ impl MLlamaSelfAttentionDecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
         )?;
         hidden_states = (residual + hidden_states)?;
 
@@ -325,6 +329,7 @@ impl MLlamaTextCrossAttention {
         cross_attn_states: Option<&Tensor>,
         attention_mask: Option<&Tensor>,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
     ) -> Result<Tensor> {
         let (bs, q_len, _) = hidden_states.dims3()?;
 
@@ -368,10 +373,13 @@ impl MLlamaTextCrossAttention {
             k = repeat_kv(k.clone(), self.num_heads / self.num_kv_heads)?.contiguous()?;
             v = repeat_kv(v.clone(), self.num_heads / self.num_kv_heads)?.contiguous()?;
 
-            (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+            (k, v) = Cache::update_kv_cache(kv_cache, k, v, kv_cache_dtype, false)?;
             (k, v)
         } else if let Some((k_cache, v_cache)) = kv_cache {
-            (k_cache.clone(), v_cache.clone())
+            (
+                kv_cache_dtype.dequantize(k_cache, q.dtype())?,
+                kv_cache_dtype.dequantize(v_cache, q.dtype())?,
+            )
         } else {
             candle_core::bail!("Cross attn cannot find k,v cache or cross attn hidden states!")
         };
@@ -466,14 +474,19 @@ impl MLlamaCrossAttentionDecoderLayer {
         attention_mask: Option<&Tensor>,
         full_text_row_masked_out_mask: Option<&Tensor>,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
     ) -> Result<Tensor> {
         let residual = hidden_states;
 
         let mut hidden_states = self.input_layernorm.forward(hidden_states)?;
 
-        hidden_states =
-            self.attn
-                .forward(&hidden_states, cross_attn_states, attention_mask, kv_cache)?;
+        hidden_states = self.attn.forward(
+            &hidden_states,
+            cross_attn_states,
+            attention_mask,
+            kv_cache,
+            kv_cache_dtype,
+        )?;
         hidden_states = (residual + hidden_states.broadcast_mul(&self.attn_gate)?)?;
 
         let residual = &hidden_states;
@@ -632,6 +645,7 @@ impl MLlamaTextModel {
         let mut hidden_states = self.embed_tokens.forward(input_ids)?;
 
         let mut self_cache = self.self_attn_cache.lock();
+        let kv_cache_dtype = self.self_attn_cache.kv_cache_dtype();
         let self_mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             &seqlen_offsets as &dyn PastKvLenCache,
@@ -649,6 +663,7 @@ impl MLlamaTextModel {
                         seqlen_offsets,
                         start_offsets_kernel.clone(),
                         &mut self_cache[i],
+                        kv_cache_dtype,
                     )?;
                 }
                 MLlamaDecoderLayer::CrossAttn(attn) => {
@@ -664,6 +679,7 @@ impl MLlamaTextModel {
                         cross_attention_mask,
                         full_text_row_masked_out_mask,
                         &mut self_cache[i],
+                        kv_cache_dtype,
                     )?;
                 }
             }
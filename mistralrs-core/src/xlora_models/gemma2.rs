@@ -12,6 +12,7 @@ use crate::{
     amoe::AnyMoeBaseModelMixin,
     attention::SdpaParams,
     device_map::DeviceMapper,
+    kv_quant::KvCacheDtype,
     layers::{CausalMasker, RmsNorm, Sdpa},
     lora::{linear_b, linear_no_bias, LinearLayerLike, LoraConfig},
     models::gemma2::Config,
@@ -245,6 +246,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -326,6 +328,7 @@ impl Attention {
             v,
             mask,
             self.sliding_window,
+            kv_cache_dtype,
             false,
         )?;
 
@@ -439,6 +442,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -455,6 +459,7 @@ impl DecoderLayer {
                 seqlen_offsets,
                 start_offsets_kernel,
                 kv_cache,
+                kv_cache_dtype,
                 scalings.clone(),
                 global_scaling_weight,
                 is_scaling_pass,
@@ -667,6 +672,7 @@ impl Model {
         } else {
             self.cache.lock()
         };
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let attention_mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             &*cache,
@@ -696,6 +702,7 @@ impl Model {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
@@ -3,6 +3,7 @@
 use crate::{
     amoe::AnyMoeBaseModelMixin,
     attention::SdpaParams,
+    kv_quant::KvCacheDtype,
     layers::{Llama3RotaryEmbedding, Sdpa},
     lora::{linear_no_bias as linear, LinearLayerLike, LoraConfig, Ordering},
     paged_attention::ModelConfigMetadata,
@@ -51,6 +52,7 @@ impl CausalSelfAttention {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -119,8 +121,13 @@ impl CausalSelfAttention {
                 .contiguous()?;
         }
 
-        let (k, v) =
-            crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+        let (k, v) = crate::pipeline::Cache::update_kv_cache(
+            &mut kv_cache[block_idx],
+            k,
+            v,
+            kv_cache_dtype,
+            false,
+        )?;
 
         let y = Sdpa.run_attention(
             &q,
@@ -336,6 +343,7 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -350,6 +358,7 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            kv_cache_dtype,
             scalings.clone(),
             global_scaling_weight,
             is_scaling_pass,
@@ -460,6 +469,7 @@ impl XLoraLlama {
         } else {
             self.kv_cache.lock()
         };
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             &*cache,
@@ -475,6 +485,7 @@ impl XLoraLlama {
                 start_offsets_kernel.clone(),
                 block_idx,
                 &mut cache,
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
@@ -3,6 +3,7 @@
 use crate::{
     amoe::AnyMoeBaseModelMixin,
     attention::SdpaParams,
+    kv_quant::KvCacheDtype,
     layers::Sdpa,
     lora::{linear_no_bias, LinearLayerLike, LoraConfig, Ordering},
     paged_attention::ModelConfigMetadata,
@@ -130,6 +131,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -204,6 +206,7 @@ impl Attention {
             v,
             attention_mask,
             self.sliding_window,
+            kv_cache_dtype,
             false,
         )?;
 
@@ -540,6 +543,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -553,6 +557,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             scalings.clone(),
             global_scaling_weight,
             is_scaling_pass,
@@ -757,6 +762,7 @@ impl XLoraModel {
         } else {
             self.cache.lock()
         };
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
@@ -776,6 +782,7 @@ impl XLoraModel {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
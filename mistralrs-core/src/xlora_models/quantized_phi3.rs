@@ -5,6 +5,7 @@ use std::collections::HashMap;
 use crate::attention::SdpaParams;
 use crate::device_map::DeviceMapper;
 use crate::gguf::Content;
+use crate::kv_quant::KvCacheDtype;
 use crate::layers::CausalMasker;
 use crate::layers::RmsNorm;
 use crate::layers::Sdpa;
@@ -119,6 +120,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -168,6 +170,7 @@ impl LayerWeights {
             v,
             mask,
             Some(self.sliding_window),
+            kv_cache_dtype,
             true,
         )?;
 
@@ -426,6 +429,7 @@ impl ModelWeights {
         } else {
             self.cache.lock()
         };
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
             &*cache,
@@ -446,6 +450,7 @@ impl ModelWeights {
                     .as_ref(),
                 seqlen_offsets,
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
@@ -6,6 +6,7 @@ from typing import Iterator
 class ToolChoice(Enum):
     NoTools = "None"
     Auto = "Auto"
+    Required = "Required"
 
 @dataclass
 class ChatCompletionRequest:
@@ -39,6 +40,19 @@ class ChatCompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    seed: int | None = None
+    beam_width: int | None = None
+    length_penalty: float | None = None
+    early_stopping: bool | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    repetition_penalty: float | None = None
+    repetition_penalty_last_n: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    xtc_threshold: float | None = None
+    xtc_probability: float | None = None
+    sampler_order: list[str] | None = None
 
 @dataclass
 class CompletionRequest:
@@ -67,6 +81,21 @@ class CompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    seed: int | None = None
+    beam_width: int | None = None
+    length_penalty: float | None = None
+    early_stopping: bool | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    repetition_penalty: float | None = None
+    repetition_penalty_last_n: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    xtc_threshold: float | None = None
+    xtc_probability: float | None = None
+    sampler_order: list[str] | None = None
+    prompt_logprobs: int | None = None
+    continuation: str | None = None
 
 @dataclass
 class Architecture(Enum):
@@ -79,6 +108,7 @@ class Architecture(Enum):
     Gemma2 = "gemma2"
     Starcoder2 = "starcoder2"
     Phi3_5MoE = "phi3.5moe"
+    Mamba = "mamba"
 
 @dataclass
 class VisionArchitecture(Enum):
@@ -230,6 +260,13 @@ class Which(Enum):
         arch: DiffusionArchitecture
         dtype: ModelDType = ModelDType.Auto
 
+class ChatCompletionStreamer(Iterator[ChatCompletionChunkResponse]):
+    def cancel(self) -> None:
+        """
+        Stop generating the response. The last chunk, with a `canceled` finish reason, is still
+        returned by the iterator.
+        """
+
 class Runner:
     def __init__(
         self,
@@ -272,18 +309,19 @@ class Runner:
         - `in_situ_quant` sets the optional in-situ quantization for models that are not quantized (not GGUF or GGML).
         - `anymoe_config` specifies the AnyMoE config. If this is set, then the model will be loaded as an AnyMoE model.
         - `pa_gpu_mem`: GPU memory to allocate for KV cache with PagedAttention in MBs.
-            PagedAttention is only supported on CUDA and is always automatically activated.
+            PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa_gpu_mem`, `pa_gpu_mem_usage` or `pa_ctxt_len`.
             The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
         - `pa_gpu_mem_usage`: Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
             If this is not set and the device is CUDA, it will default to `0.9`.
-            PagedAttention is only supported on CUDA and is always automatically activated.
+            PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa_gpu_mem`, `pa_gpu_mem_usage` or `pa_ctxt_len`.
             The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
         - `pa_ctxt_len`: Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold)
-            when using PagedAttention, which is only supported on CUDA and is always automatically activated.
+            when using PagedAttention.
+            PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa_gpu_mem`, `pa_gpu_mem_usage` or `pa_ctxt_len`.
             The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
         - `pa_blk_size` sets the block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA,
-            it will default to 32. PagedAttention is only supported on CUDA and is always automatically activated.
-        - `no_paged_attn` disables PagedAttention on CUDA
+            it will default to 32. PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa_gpu_mem`, `pa_gpu_mem_usage` or `pa_ctxt_len`.
+        - `no_paged_attn` disables PagedAttention
         - `prompt_batchsize` Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
         - `seed`, used to ensure reproducible random number generation.
         """
@@ -291,7 +329,7 @@ class Runner:
 
     def send_chat_completion_request(
         self, request: ChatCompletionRequest
-    ) -> ChatCompletionResponse | Iterator[ChatCompletionChunkResponse]:
+    ) -> ChatCompletionResponse | ChatCompletionStreamer:
         """
         Send a chat completion request to the mistral.rs engine, returning the response object or a generator
         over chunk objects.
@@ -323,6 +361,37 @@ class Runner:
         Send a request to make the specified adapters the active adapters for the model.
         """
 
+    def get_metrics(self) -> EngineMetrics:
+        """
+        Get the current metrics of the engine, such as queue depth, token counts and latencies.
+        """
+
+    def tokenize_text(self, text: str, add_special_tokens: bool = True) -> list[int]:
+        """
+        Tokenize a text with the tokenizer of the model, without applying the chat template.
+        """
+
+    def tokenize_messages(
+        self, request: ChatCompletionRequest, add_generation_prompt: bool = True
+    ) -> list[int]:
+        """
+        Render the messages and tools of a chat completion request with the chat template of the model
+        and tokenize them, as the prompt of the request would be.
+        """
+
+    def apply_chat_template(
+        self, request: ChatCompletionRequest, add_generation_prompt: bool = True
+    ) -> str:
+        """
+        Render the messages and tools of a chat completion request with the chat template of the model.
+        Messages with images are rejected.
+        """
+
+    def detokenize_text(self, tokens: list[int], skip_special_tokens: bool = False) -> str:
+        """
+        Decode tokens into text with the tokenizer of the model.
+        """
+
 class AnyMoeExpertType(Enum):
     """
     Expert type for an AnyMoE model. May be:
@@ -385,6 +454,9 @@ class Usage:
     total_time_sec: float
     total_prompt_time_sec: float
     total_completion_time_sec: float
+    speculative_drafted_tokens: int | None
+    speculative_accepted_tokens: int | None
+    speculative_acceptance_rate: float | None
 
 @dataclass
 class ToolCallType(Enum):
@@ -401,6 +473,13 @@ class ToolCallResponse:
     type: ToolCallType
     function: CalledFunction
 
+@dataclass
+class ToolCallDelta:
+    index: int
+    id: str
+    type: ToolCallType
+    function: CalledFunction
+
 @dataclass
 class ResponseMessage:
     content: str
@@ -445,6 +524,7 @@ class ChatCompletionResponse:
 class Delta:
     content: str
     role: str
+    tool_calls: list[ToolCallDelta] | None
 
 @dataclass
 class ChunkChoice:
@@ -462,12 +542,20 @@ class ChatCompletionChunkResponse:
     system_fingerprint: str
     object: str
 
+@dataclass
+class ContinuationScore:
+    logprob: float
+    is_greedy: bool
+    tokens: list[ResponseLogprob]
+
 @dataclass
 class CompletionChoice:
     finish_reason: str
     index: int
     text: str
     # NOTE(EricLBuehler): `logprobs` in undocumented
+    prompt_logprobs: list[ResponseLogprob] | None
+    score: ContinuationScore | None
 
 @dataclass
 class CompletionResponse:
@@ -488,3 +576,31 @@ class ImageChoice:
 class ImageGenerationResponse:
     choices: list[ImageChoice]
     created: int
+
+@dataclass
+class Histogram:
+    bounds: list[float]
+    counts: list[int]
+    sum: float
+    count: int
+
+@dataclass
+class ErrorCounts:
+    internal: int
+    validation: int
+    model: int
+    completion_model: int
+
+@dataclass
+class EngineMetrics:
+    waiting_sequences: int
+    running_sequences: int
+    prompt_tokens: int
+    completion_tokens: int
+    time_to_first_token: Histogram
+    inter_token_latency: Histogram
+    prefix_cache_hits: int
+    prefix_cache_misses: int
+    kv_cache_blocks_used: int | None
+    kv_cache_blocks_total: int | None
+    errors: ErrorCounts
@@ -18,15 +18,17 @@ use util::{PyApiErr, PyApiResult};
 
 use candle_core::{Device, Result};
 use mistralrs_core::{
-    initialize_logging, paged_attn_supported, parse_isq_value, AnyMoeLoader,
+    initialize_logging, paged_attn_supported, parse_isq_value, AnyMoeLoader, BeamSearchParams,
     ChatCompletionResponse, CompletionResponse, Constraint, DefaultSchedulerMethod,
-    DeviceLayerMapMetadata, DeviceMapMetadata, DiffusionGenerationParams, DiffusionLoaderBuilder,
-    DiffusionSpecificConfig, DrySamplingParams, GGMLLoaderBuilder, GGMLSpecificConfig,
-    GGUFLoaderBuilder, GGUFSpecificConfig, ImageGenerationResponse, ImageGenerationResponseFormat,
-    Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalRequest,
-    NormalSpecificConfig, PagedAttentionConfig, Request as _Request, RequestMessage, Response,
-    ResponseOk, SamplingParams, SchedulerConfig, SpeculativeConfig, SpeculativeLoader, StopTokens,
-    TokenSource, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
+    DetokenizationRequest, DeviceLayerMapMetadata, DeviceMapMetadata, DiffusionGenerationParams,
+    DiffusionLoaderBuilder, DiffusionSpecificConfig, DrySamplingParams, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, ImageGenerationResponse,
+    ImageGenerationResponseFormat, Loader, MemoryGpuConfig, MessageContent, MirostatParams,
+    MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig,
+    PagedAttentionConfig, Request as _Request, RequestMessage, RequestPriority, Response,
+    ResponseOk, SamplerStage, SamplingParams, SchedulerConfig, SpeculativeConfig,
+    SpeculativeLoader, SpeculativeMethod, StopTokens, TokenSource, Tokenization,
+    TokenizationRequest, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig, XtcParams,
 };
 use pyo3::prelude::*;
 use std::fs::File;
@@ -61,6 +63,155 @@ fn get_device(seed: Option<u64>) -> &'static Result<Device> {
     })
 }
 
+/// Convert the messages of a chat completion request into the messages of the chat template,
+/// returning them with the URLs of their images in order. A prompt is a single user message.
+#[allow(clippy::type_complexity)]
+fn parse_messages(
+    messages: &Either<
+        Vec<
+            HashMap<
+                String,
+                Either<String, Vec<HashMap<String, Either<String, HashMap<String, String>>>>>,
+            >,
+        >,
+        String,
+    >,
+) -> PyApiResult<(Vec<IndexMap<String, MessageContent>>, Vec<String>)> {
+    match messages {
+        Either::Left(messages) => {
+            let mut messages_vec = Vec::new();
+            let mut image_urls = Vec::new();
+            for message in messages {
+                match &message["content"] {
+                    Either::Left(content) => {
+                        let mut message_map: IndexMap<
+                            String,
+                            Either<String, Vec<IndexMap<String, String>>>,
+                        > = IndexMap::new();
+                        message_map.insert(
+                            "role".to_string(),
+                            Either::Left(message["role"].as_ref().left().unwrap().clone()),
+                        );
+                        message_map
+                            .insert("content".to_string(), Either::Left(content.to_string()));
+                        messages_vec.push(message_map);
+                    }
+                    Either::Right(image_messages) => {
+                        if image_messages.len() != 2 {
+                            return Err(PyApiErr::from(
+                                "Expected 2 items for the content of a message with an image.",
+                            ));
+                        }
+                        if message["role"].as_ref().left().unwrap() != "user" {
+                            return Err(PyApiErr::from(format!(
+                                "Role for an image message must be `user`, but it is {}",
+                                &message["role"].as_ref().left().unwrap()
+                            )));
+                        }
+
+                        let mut items = Vec::new();
+                        for image_message in image_messages {
+                            if image_message.len() != 2 {
+                                return Err(PyApiErr::from("Expected 2 items for the sub-content of a message with an image.".to_string()));
+                            }
+                            if !image_message.contains_key("type") {
+                                return Err(PyApiErr::from(
+                                    "Expected `type` key in input message.".to_string(),
+                                ));
+                            }
+                            if image_message["type"].is_right() {
+                                return Err(PyApiErr::from(
+                                    "Expected string value in `type`.".to_string(),
+                                ));
+                            }
+                            items.push(image_message["type"].as_ref().unwrap_left().clone())
+                        }
+
+                        #[allow(clippy::type_complexity)]
+                        fn get_content_and_url(
+                            text_idx: usize,
+                            url_idx: usize,
+                            image_messages: &[HashMap<
+                                String,
+                                Either<String, HashMap<String, String>>,
+                            >],
+                        ) -> PyApiResult<(String, String)> {
+                            if image_messages[text_idx]["text"].is_right() {
+                                return Err(PyApiErr::from(
+                                    "Expected string value in `text`.".to_string(),
+                                ));
+                            }
+                            let content = image_messages[text_idx]["text"]
+                                .as_ref()
+                                .unwrap_left()
+                                .clone();
+                            if image_messages[url_idx]["image_url"].is_left()
+                                || !image_messages[url_idx]["image_url"]
+                                    .as_ref()
+                                    .unwrap_right()
+                                    .contains_key("url")
+                            {
+                                return Err(PyApiErr::from("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}".to_string()));
+                            }
+                            let url = image_messages[url_idx]["image_url"].as_ref().unwrap_right()
+                                ["url"]
+                                .clone();
+                            Ok((content, url))
+                        }
+                        let mut message_map: IndexMap<
+                            String,
+                            Either<String, Vec<IndexMap<String, String>>>,
+                        > = IndexMap::new();
+                        message_map.insert(
+                            "role".to_string(),
+                            Either::Left(message["role"].as_ref().left().unwrap().clone()),
+                        );
+                        let (content, url) = if items[0] == "text" {
+                            get_content_and_url(0, 1, image_messages)?
+                        } else {
+                            get_content_and_url(1, 0, image_messages)?
+                        };
+
+                        let mut content_map = Vec::new();
+                        let mut content_image_map = IndexMap::new();
+                        content_image_map.insert("type".to_string(), "image".to_string());
+                        content_map.push(content_image_map);
+                        let mut content_text_map = IndexMap::new();
+                        content_text_map.insert("type".to_string(), "text".to_string());
+                        content_text_map.insert("text".to_string(), content);
+                        content_map.push(content_text_map);
+
+                        message_map.insert("content".to_string(), Either::Right(content_map));
+                        messages_vec.push(message_map);
+                        image_urls.push(url);
+                    }
+                }
+            }
+            Ok((messages_vec, image_urls))
+        }
+        Either::Right(prompt) => {
+            let mut messages = Vec::new();
+            let mut message_map: IndexMap<String, Either<String, Vec<IndexMap<String, String>>>> =
+                IndexMap::new();
+            message_map.insert("role".to_string(), Either::Left("user".to_string()));
+            message_map.insert("content".to_string(), Either::Left(prompt.to_string()));
+            messages.push(message_map);
+            Ok((messages, Vec::new()))
+        }
+    }
+}
+
+fn parse_tools(tool_schemas: &Option<Vec<String>>) -> PyApiResult<Option<Vec<Tool>>> {
+    let Some(tool_schemas) = tool_schemas else {
+        return Ok(None);
+    };
+    let mut tools = Vec::new();
+    for schema in tool_schemas {
+        tools.push(serde_json::from_str::<Tool>(schema)?);
+    }
+    Ok(Some(tools))
+}
+
 #[pyclass]
 /// An object wrapping the underlying Rust system to handle requests and process conversations.
 struct Runner {
@@ -465,9 +616,11 @@ impl Runner {
             let draft = parse_which(draft_which, no_kv_cache, chat_template, prompt_batchsize)?;
             Box::new(SpeculativeLoader {
                 target: loader,
-                draft,
+                draft: Some(draft),
                 config: SpeculativeConfig {
                     gamma: speculative_gamma,
+                    method: SpeculativeMethod::DraftModel,
+                    adaptive_gamma: None,
                 },
             })
         } else {
@@ -551,11 +704,13 @@ impl Runner {
                 paged_attn_supported(),
                 no_paged_attn,
             ) {
-                (block_size, None, None, None, true, false) => Some(PagedAttentionConfig::new(
-                    block_size,
-                    512,
-                    MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
-                )?),
+                (block_size, None, None, None, true, false) if device.is_cuda() => {
+                    Some(PagedAttentionConfig::new(
+                        block_size,
+                        512,
+                        MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
+                    )?)
+                }
                 (block_size, None, None, Some(ctxt), true, false) => Some(
                     PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::ContextSize(ctxt))?,
                 ),
@@ -653,9 +808,18 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                let schema = serde_json::from_str(request.grammar.as_ref().unwrap())
+                    .map_err(|e| PyApiErr::from(e.to_string()))?;
+                Constraint::JsonSchema(schema)
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc` or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -672,172 +836,48 @@ impl Runner {
                 None
             };
 
-            let messages = match request.messages {
-                Either::Left(ref messages) => {
-                    let mut messages_vec = Vec::new();
-                    let mut image_urls = Vec::new();
-                    for message in messages {
-                        match &message["content"] {
-                            Either::Left(content) => {
-                                let mut message_map: IndexMap<
-                                    String,
-                                    Either<String, Vec<IndexMap<String, String>>>,
-                                > = IndexMap::new();
-                                message_map.insert(
-                                    "role".to_string(),
-                                    Either::Left(message["role"].as_ref().left().unwrap().clone()),
-                                );
-                                message_map.insert(
-                                    "content".to_string(),
-                                    Either::Left(content.to_string()),
-                                );
-                                messages_vec.push(message_map);
-                            }
-                            Either::Right(image_messages) => {
-                                if image_messages.len() != 2 {
-                                    return Err(PyApiErr::from(
-                                        "Expected 2 items for the content of a message with an image."
-                                    ));
-                                }
-                                if message["role"].as_ref().left().unwrap() != "user" {
-                                    return Err(PyApiErr::from(format!(
-                                        "Role for an image message must be `user`, but it is {}",
-                                        &message["role"].as_ref().left().unwrap()
-                                    )));
-                                }
-
-                                let mut items = Vec::new();
-                                for image_message in image_messages {
-                                    if image_message.len() != 2 {
-                                        return Err(PyApiErr::from("Expected 2 items for the sub-content of a message with an image.".to_string()));
-                                    }
-                                    if !image_message.contains_key("type") {
-                                        return Err(PyApiErr::from(
-                                            "Expected `type` key in input message.".to_string(),
-                                        ));
-                                    }
-                                    if image_message["type"].is_right() {
-                                        return Err(PyApiErr::from(
-                                            "Expected string value in `type`.".to_string(),
-                                        ));
-                                    }
-                                    items.push(image_message["type"].as_ref().unwrap_left().clone())
-                                }
-
-                                #[allow(clippy::type_complexity)]
-                                fn get_content_and_url(
-                                    text_idx: usize,
-                                    url_idx: usize,
-                                    image_messages: &[HashMap<
-                                        String,
-                                        Either<String, HashMap<String, String>>,
-                                    >],
-                                ) -> PyApiResult<(String, String)> {
-                                    if image_messages[text_idx]["text"].is_right() {
-                                        return Err(PyApiErr::from(
-                                            "Expected string value in `text`.".to_string(),
-                                        ));
-                                    }
-                                    let content = image_messages[text_idx]["text"]
-                                        .as_ref()
-                                        .unwrap_left()
-                                        .clone();
-                                    if image_messages[url_idx]["image_url"].is_left()
-                                        || !image_messages[url_idx]["image_url"]
-                                            .as_ref()
-                                            .unwrap_right()
-                                            .contains_key("url")
-                                    {
-                                        return Err(PyApiErr::from("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}".to_string()));
-                                    }
-                                    let url = image_messages[url_idx]["image_url"]
-                                        .as_ref()
-                                        .unwrap_right()["url"]
-                                        .clone();
-                                    Ok((content, url))
-                                }
-                                let mut message_map: IndexMap<
-                                    String,
-                                    Either<String, Vec<IndexMap<String, String>>>,
-                                > = IndexMap::new();
-                                message_map.insert(
-                                    "role".to_string(),
-                                    Either::Left(message["role"].as_ref().left().unwrap().clone()),
-                                );
-                                let (content, url) = if items[0] == "text" {
-                                    get_content_and_url(0, 1, image_messages)?
-                                } else {
-                                    get_content_and_url(1, 0, image_messages)?
-                                };
-
-                                let mut content_map = Vec::new();
-                                let mut content_image_map = IndexMap::new();
-                                content_image_map.insert("type".to_string(), "image".to_string());
-                                content_map.push(content_image_map);
-                                let mut content_text_map = IndexMap::new();
-                                content_text_map.insert("type".to_string(), "text".to_string());
-                                content_text_map.insert("text".to_string(), content);
-                                content_map.push(content_text_map);
-
-                                message_map
-                                    .insert("content".to_string(), Either::Right(content_map));
-                                messages_vec.push(message_map);
-                                image_urls.push(url);
-                            }
-                        }
-                    }
-                    if !image_urls.is_empty() {
-                        let mut images = Vec::new();
-                        for url in image_urls {
-                            let url_unparsed = url.trim();
+            let sampler_order = request
+                .sampler_order
+                .as_ref()
+                .map(|order| {
+                    order
+                        .iter()
+                        .map(|name| SamplerStage::from_name(name))
+                        .collect::<anyhow::Result<Vec<_>>>()
+                })
+                .transpose()?;
 
-                            let image = util::parse_image_url(url_unparsed)?;
-                            images.push(image);
-                        }
-                        RequestMessage::VisionChat {
-                            messages: messages_vec,
-                            images,
-                        }
-                    } else {
-                        RequestMessage::Chat(messages_vec)
-                    }
-                }
-                Either::Right(ref prompt) => {
-                    let mut messages = Vec::new();
-                    let mut message_map: IndexMap<
-                        String,
-                        Either<String, Vec<IndexMap<String, String>>>,
-                    > = IndexMap::new();
-                    message_map.insert("role".to_string(), Either::Left("user".to_string()));
-                    message_map.insert("content".to_string(), Either::Left(prompt.to_string()));
-                    messages.push(message_map);
-                    RequestMessage::Chat(messages)
+            let (messages, image_urls) = parse_messages(&request.messages)?;
+            let messages = if !image_urls.is_empty() {
+                let mut images = Vec::new();
+                for url in image_urls {
+                    let url_unparsed = url.trim();
+
+                    let image = util::parse_image_url(url_unparsed)?;
+                    images.push(image);
                 }
+                RequestMessage::VisionChat { messages, images }
+            } else {
+                RequestMessage::Chat(messages)
             };
 
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
-            let tools = if let Some(tools) = &request.tool_schemas {
-                let mut new_tools = Vec::new();
-                for schema in tools {
-                    new_tools.push(serde_json::from_str::<Tool>(schema)?);
-                }
-                Some(new_tools)
-            } else {
-                None
-            };
+            let tools = parse_tools(&request.tool_schemas)?;
 
+            let request_id = {
+                let l = NEXT_REQUEST_ID.lock().unwrap();
+                let last = &mut *l.borrow_mut();
+                let last_v = *last;
+                *last += 1;
+                last_v
+            };
             let model_request = _Request::Normal(NormalRequest {
-                id: {
-                    let l = NEXT_REQUEST_ID.lock().unwrap();
-                    let last = &mut *l.borrow_mut();
-                    let last_v = *last;
-                    *last += 1;
-                    last_v
-                },
+                id: request_id,
                 messages,
                 sampling_params: SamplingParams {
                     temperature: request.temperature,
@@ -851,7 +891,37 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    repetition_penalty: request.repetition_penalty,
+                    repetition_penalty_last_n: request.repetition_penalty_last_n,
                     dry_params,
+                    mirostat: request.mirostat_tau.map(|tau| MirostatParams {
+                        tau,
+                        eta: request
+                            .mirostat_eta
+                            .unwrap_or(MirostatParams::default().eta),
+                    }),
+                    xtc: request.xtc_probability.map(|probability| XtcParams {
+                        threshold: request
+                            .xtc_threshold
+                            .unwrap_or(XtcParams::default().threshold),
+                        probability,
+                    }),
+                    sampler_order,
+                    seed: request.seed,
+                    beam_search: request.beam_width.map(|beam_width| {
+                        let defaults = BeamSearchParams::new(beam_width);
+                        BeamSearchParams {
+                            beam_width,
+                            length_penalty: request
+                                .length_penalty
+                                .unwrap_or(defaults.length_penalty),
+                            early_stopping: request
+                                .early_stopping
+                                .unwrap_or(defaults.early_stopping),
+                        }
+                    }),
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -862,6 +932,8 @@ impl Runner {
                 tool_choice,
                 tools,
                 logits_processors: None,
+                priority: RequestPriority::Normal,
+                tenant: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -869,7 +941,11 @@ impl Runner {
             sender.blocking_send(model_request).unwrap();
 
             if request.stream {
-                Ok(Either::Right(ChatCompletionStreamer::from_rx(rx)))
+                Ok(Either::Right(ChatCompletionStreamer::from_rx(
+                    rx,
+                    self.runner.clone(),
+                    request_id,
+                )))
             } else {
                 let response = rx.blocking_recv().unwrap();
 
@@ -884,6 +960,7 @@ impl Runner {
                     Response::CompletionModelError(_, _) => unreachable!(),
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::Embedding(_) => unreachable!(),
                 }
             }
         })
@@ -915,9 +992,18 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                let schema = serde_json::from_str(request.grammar.as_ref().unwrap())
+                    .map_err(|e| PyApiErr::from(e.to_string()))?;
+                Constraint::JsonSchema(schema)
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc` or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -926,17 +1012,10 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
-            let tools = if let Some(tools) = &request.tool_schemas {
-                let mut new_tools = Vec::new();
-                for schema in tools {
-                    new_tools.push(serde_json::from_str::<Tool>(schema)?);
-                }
-                Some(new_tools)
-            } else {
-                None
-            };
+            let tools = parse_tools(&request.tool_schemas)?;
 
             let dry_params = if let Some(dry_multiplier) = request.dry_multiplier {
                 Some(DrySamplingParams::new_with_defaults(
@@ -949,6 +1028,35 @@ impl Runner {
                 None
             };
 
+            let sampler_order = request
+                .sampler_order
+                .as_ref()
+                .map(|order| {
+                    order
+                        .iter()
+                        .map(|name| SamplerStage::from_name(name))
+                        .collect::<anyhow::Result<Vec<_>>>()
+                })
+                .transpose()?;
+
+            if request.continuation.is_some() && request.prompt_logprobs.is_some() {
+                return Err(PyApiErr::from(
+                    "`prompt_logprobs` cannot be used together with `continuation`",
+                ));
+            }
+            let messages = match &request.continuation {
+                Some(continuation) => RequestMessage::Score {
+                    prompt: request.prompt.clone(),
+                    continuation: continuation.clone(),
+                },
+                None => RequestMessage::Completion {
+                    text: request.prompt.clone(),
+                    echo_prompt: request.echo_prompt,
+                    best_of: request.best_of,
+                    prompt_logprobs: request.prompt_logprobs.is_some(),
+                },
+            };
+
             let model_request = _Request::Normal(NormalRequest {
                 id: {
                     let l = NEXT_REQUEST_ID.lock().unwrap();
@@ -957,16 +1065,12 @@ impl Runner {
                     *last += 1;
                     last_v
                 },
-                messages: RequestMessage::Completion {
-                    text: request.prompt.clone(),
-                    echo_prompt: request.echo_prompt,
-                    best_of: request.best_of,
-                },
+                messages,
                 sampling_params: SamplingParams {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
-                    top_n_logprobs: 1,
+                    top_n_logprobs: request.prompt_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
                     max_len: request.max_tokens,
@@ -974,7 +1078,37 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    repetition_penalty: request.repetition_penalty,
+                    repetition_penalty_last_n: request.repetition_penalty_last_n,
                     dry_params,
+                    mirostat: request.mirostat_tau.map(|tau| MirostatParams {
+                        tau,
+                        eta: request
+                            .mirostat_eta
+                            .unwrap_or(MirostatParams::default().eta),
+                    }),
+                    xtc: request.xtc_probability.map(|probability| XtcParams {
+                        threshold: request
+                            .xtc_threshold
+                            .unwrap_or(XtcParams::default().threshold),
+                        probability,
+                    }),
+                    sampler_order,
+                    seed: request.seed,
+                    beam_search: request.beam_width.map(|beam_width| {
+                        let defaults = BeamSearchParams::new(beam_width);
+                        BeamSearchParams {
+                            beam_width,
+                            length_penalty: request
+                                .length_penalty
+                                .unwrap_or(defaults.length_penalty),
+                            early_stopping: request
+                                .early_stopping
+                                .unwrap_or(defaults.early_stopping),
+                        }
+                    }),
                 },
                 response: tx,
                 return_logprobs: false,
@@ -985,6 +1119,8 @@ impl Runner {
                 tool_choice,
                 tools,
                 logits_processors: None,
+                priority: RequestPriority::Normal,
+                tenant: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1003,6 +1139,7 @@ impl Runner {
                 Response::ModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
             }
         })
     }
@@ -1040,6 +1177,8 @@ impl Runner {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
         });
 
         let sender = self.runner.get_sender()?;
@@ -1073,6 +1212,101 @@ impl Runner {
             .blocking_send(request)
             .unwrap();
     }
+
+    /// Tokenize a text with the tokenizer of the model, without applying the chat template.
+    #[pyo3(signature = (text, add_special_tokens = true))]
+    fn tokenize_text(&self, text: String, add_special_tokens: bool) -> PyApiResult<Vec<u32>> {
+        Ok(self
+            .send_tokenize(Either::Right(text), None, false, add_special_tokens)?
+            .tokens)
+    }
+
+    /// Render the messages and tools of a chat completion request with the chat template of the
+    /// model and tokenize them, as the prompt of the request would be.
+    #[pyo3(signature = (request, add_generation_prompt = true))]
+    fn tokenize_messages(
+        &self,
+        request: Py<ChatCompletionRequest>,
+        add_generation_prompt: bool,
+    ) -> PyApiResult<Vec<u32>> {
+        Ok(self
+            .tokenize_request(request, add_generation_prompt)?
+            .tokens)
+    }
+
+    /// Render the messages and tools of a chat completion request with the chat template of the
+    /// model. Messages with images are rejected.
+    #[pyo3(signature = (request, add_generation_prompt = true))]
+    fn apply_chat_template(
+        &self,
+        request: Py<ChatCompletionRequest>,
+        add_generation_prompt: bool,
+    ) -> PyApiResult<String> {
+        Ok(self
+            .tokenize_request(request, add_generation_prompt)?
+            .prompt)
+    }
+
+    /// Decode tokens into text with the tokenizer of the model.
+    #[pyo3(signature = (tokens, skip_special_tokens = false))]
+    fn detokenize_text(&self, tokens: Vec<u32>, skip_special_tokens: bool) -> PyApiResult<String> {
+        let (tx, mut rx) = channel(1);
+        let request = _Request::Detokenize(DetokenizationRequest {
+            tokens,
+            skip_special_tokens,
+            response: tx,
+        });
+
+        self.runner.get_sender()?.blocking_send(request).unwrap();
+
+        Ok(rx
+            .blocking_recv()
+            .context("Channel was erroneously closed!")??)
+    }
+
+    /// Get the current metrics of the engine, such as queue depth, token counts and latencies.
+    fn get_metrics(&self) -> mistralrs_core::EngineMetrics {
+        self.runner.metrics()
+    }
+}
+
+impl Runner {
+    fn tokenize_request(
+        &self,
+        request: Py<ChatCompletionRequest>,
+        add_generation_prompt: bool,
+    ) -> PyApiResult<Tokenization> {
+        let (messages, tools) = Python::with_gil(|py| {
+            let request = request.bind(py).borrow();
+            let (messages, _) = parse_messages(&request.messages)?;
+            let tools = parse_tools(&request.tool_schemas)?;
+            PyApiResult::Ok((messages, tools))
+        })?;
+        self.send_tokenize(Either::Left(messages), tools, add_generation_prompt, false)
+    }
+
+    fn send_tokenize(
+        &self,
+        text: Either<Vec<IndexMap<String, MessageContent>>, String>,
+        tools: Option<Vec<Tool>>,
+        add_generation_prompt: bool,
+        add_special_tokens: bool,
+    ) -> PyApiResult<Tokenization> {
+        let (tx, mut rx) = channel(1);
+        let request = _Request::Tokenize(TokenizationRequest {
+            text,
+            tools,
+            add_generation_prompt,
+            add_special_tokens,
+            response: tx,
+        });
+
+        self.runner.get_sender()?.blocking_send(request).unwrap();
+
+        Ok(rx
+            .blocking_recv()
+            .context("Channel was erroneously closed!")??)
+    }
 }
 
 #[pymodule]
@@ -1099,9 +1333,13 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::ChatCompletionResponse>()?;
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
+    m.add_class::<mistralrs_core::ContinuationScore>()?;
     m.add_class::<mistralrs_core::CompletionResponse>()?;
     m.add_class::<mistralrs_core::TopLogprob>()?;
     m.add_class::<mistralrs_core::ModelDType>()?;
     m.add_class::<mistralrs_core::ImageGenerationResponseFormat>()?;
+    m.add_class::<mistralrs_core::EngineMetrics>()?;
+    m.add_class::<mistralrs_core::Histogram>()?;
+    m.add_class::<mistralrs_core::ErrorCounts>()?;
     Ok(())
 }
@@ -13,6 +13,7 @@ use pyo3::{
 pub enum ToolChoice {
     NoTools,
     Auto,
+    Required,
 }
 
 #[pyclass]
@@ -43,6 +44,21 @@ pub struct CompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) beam_width: Option<usize>,
+    pub(crate) length_penalty: Option<f32>,
+    pub(crate) early_stopping: Option<bool>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) repetition_penalty_last_n: Option<usize>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) xtc_threshold: Option<f32>,
+    pub(crate) xtc_probability: Option<f32>,
+    pub(crate) sampler_order: Option<Vec<String>>,
+    pub(crate) prompt_logprobs: Option<usize>,
+    pub(crate) continuation: Option<String>,
 }
 
 #[pymethods]
@@ -73,6 +89,21 @@ impl CompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        seed=None,
+        beam_width=None,
+        length_penalty=None,
+        early_stopping=None,
+        typical_p=None,
+        top_a=None,
+        repetition_penalty=None,
+        repetition_penalty_last_n=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        xtc_threshold=None,
+        xtc_probability=None,
+        sampler_order=None,
+        prompt_logprobs=None,
+        continuation=None,
     ))]
     fn new(
         prompt: String,
@@ -99,6 +130,21 @@ impl CompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        seed: Option<u64>,
+        beam_width: Option<usize>,
+        length_penalty: Option<f32>,
+        early_stopping: Option<bool>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        repetition_penalty: Option<f32>,
+        repetition_penalty_last_n: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        xtc_threshold: Option<f32>,
+        xtc_probability: Option<f32>,
+        sampler_order: Option<Vec<String>>,
+        prompt_logprobs: Option<usize>,
+        continuation: Option<String>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -125,6 +171,21 @@ impl CompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            seed,
+            beam_width,
+            length_penalty,
+            early_stopping,
+            typical_p,
+            top_a,
+            repetition_penalty,
+            repetition_penalty_last_n,
+            mirostat_tau,
+            mirostat_eta,
+            xtc_threshold,
+            xtc_probability,
+            sampler_order,
+            prompt_logprobs,
+            continuation,
         })
     }
 }
@@ -166,6 +227,19 @@ pub struct ChatCompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) beam_width: Option<usize>,
+    pub(crate) length_penalty: Option<f32>,
+    pub(crate) early_stopping: Option<bool>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) repetition_penalty_last_n: Option<usize>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) xtc_threshold: Option<f32>,
+    pub(crate) xtc_probability: Option<f32>,
+    pub(crate) sampler_order: Option<Vec<String>>,
 }
 
 #[pymethods]
@@ -196,6 +270,19 @@ impl ChatCompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        seed=None,
+        beam_width=None,
+        length_penalty=None,
+        early_stopping=None,
+        typical_p=None,
+        top_a=None,
+        repetition_penalty=None,
+        repetition_penalty_last_n=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        xtc_threshold=None,
+        xtc_probability=None,
+        sampler_order=None,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -222,6 +309,19 @@ impl ChatCompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        seed: Option<u64>,
+        beam_width: Option<usize>,
+        length_penalty: Option<f32>,
+        early_stopping: Option<bool>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        repetition_penalty: Option<f32>,
+        repetition_penalty_last_n: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        xtc_threshold: Option<f32>,
+        xtc_probability: Option<f32>,
+        sampler_order: Option<Vec<String>>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -296,6 +396,19 @@ impl ChatCompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            seed,
+            beam_width,
+            length_penalty,
+            early_stopping,
+            typical_p,
+            top_a,
+            repetition_penalty,
+            repetition_penalty_last_n,
+            mirostat_tau,
+            mirostat_eta,
+            xtc_threshold,
+            xtc_probability,
+            sampler_order,
         })
     }
 }
@@ -1,22 +1,40 @@
+use std::sync::Arc;
+
 use tokio::sync::mpsc::Receiver;
 
-use mistralrs_core::{ChatCompletionChunkResponse, Response};
+use mistralrs_core::{ChatCompletionChunkResponse, MistralRs, Response};
 use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyRef, PyRefMut, PyResult};
 
 #[pyclass]
 pub struct ChatCompletionStreamer {
     rx: Receiver<Response>,
     is_done: bool,
+    runner: Arc<MistralRs>,
+    request_id: usize,
 }
 
 impl ChatCompletionStreamer {
-    pub fn from_rx(rx: Receiver<Response>) -> Self {
-        Self { rx, is_done: false }
+    pub fn from_rx(rx: Receiver<Response>, runner: Arc<MistralRs>, request_id: usize) -> Self {
+        Self {
+            rx,
+            is_done: false,
+            runner,
+            request_id,
+        }
     }
 }
 
 #[pymethods]
 impl ChatCompletionStreamer {
+    /// Stop generating the response. The last chunk, with a `canceled` finish reason, is still
+    /// returned by the iterator.
+    fn cancel(&self) -> PyResult<()> {
+        if !self.is_done {
+            self.runner.cancel_request(self.request_id)?;
+        }
+        Ok(())
+    }
+
     fn __iter__(this: PyRef<'_, Self>) -> PyRef<'_, Self> {
         this
     }
@@ -40,6 +58,7 @@ impl ChatCompletionStreamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
             },
             None => Some(Err(PyValueError::new_err(
                 "Received none in ChatCompletionStreamer".to_string(),
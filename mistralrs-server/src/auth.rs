@@ -0,0 +1,647 @@
+//! API key authentication and per-key rate limiting. Keys are loaded from a TOML file given with
+//! `--api-keys`, or from the `MISTRALRS_API_KEY` environment variable. Without keys, all routes are
+//! open.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use mistralrs_core::RequestPriority;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{MB_TO_B, N_INPUT_SIZE};
+
+/// Environment variable holding a key with all permissions and no limits.
+const API_KEY_ENV: &str = "MISTRALRS_API_KEY";
+
+/// Window of the rate limits.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Tokens per character of the text of a request, to estimate its prompt tokens before it is
+/// tokenized.
+const TOKENS_PER_CHAR: f64 = 0.25;
+
+/// The routes which a key may access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Generation, embedding and model listing routes.
+    Inference,
+    /// Routes which change or inspect the server, such as `/re_isq`, `/load_model` and `/metrics`.
+    Admin,
+}
+
+fn default_permissions() -> HashSet<Permission> {
+    HashSet::from([Permission::Inference])
+}
+
+fn default_max_priority() -> RequestPriority {
+    RequestPriority::Normal
+}
+
+/// An API key with its permissions and limits.
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyConfig {
+    pub key: String,
+    /// Name of the key in error messages. Defaults to the start of the key.
+    pub name: Option<String>,
+    /// Defaults to `["inference"]`.
+    #[serde(default = "default_permissions")]
+    pub permissions: HashSet<Permission>,
+    /// Highest priority which the requests of the key may use. Defaults to `normal`, so only keys
+    /// which are allowed to may jump ahead of the requests of other keys.
+    #[serde(default = "default_max_priority")]
+    pub max_priority: RequestPriority,
+    pub requests_per_minute: Option<u64>,
+    /// Limit on the estimated prompt tokens plus `max_tokens` of the requests.
+    pub tokens_per_minute: Option<u64>,
+}
+
+/// The API keys of the server, loaded with `--api-keys`.
+#[derive(Debug, Deserialize)]
+pub struct ApiKeysConfig {
+    keys: Vec<ApiKeyConfig>,
+}
+
+impl ApiKeysConfig {
+    pub fn from_file(file: &str) -> Result<Self> {
+        let config = fs::read_to_string(file)
+            .map_err(|e| anyhow::anyhow!("Could not load API keys at {file}: {e}"))?;
+        Self::from_toml(&config)
+    }
+
+    fn from_toml(config: &str) -> Result<Self> {
+        let config: Self = toml::from_str(config)?;
+        let mut keys = HashSet::new();
+        for ApiKeyConfig { key, .. } in &config.keys {
+            if key.is_empty() {
+                anyhow::bail!("API keys must not be empty.");
+            }
+            if !keys.insert(key) {
+                anyhow::bail!("An API key is listed several times.");
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The requests and tokens of a key in the current window.
+#[derive(Default)]
+struct Usage {
+    requests: VecDeque<(Instant, u64)>,
+}
+
+impl Usage {
+    /// Record a request with `tokens` at `now` if it is within the limits, otherwise return the
+    /// limit which is exceeded and how long until the request would be allowed.
+    fn try_add(
+        &mut self,
+        config: &ApiKeyConfig,
+        tokens: u64,
+        now: Instant,
+    ) -> Result<(), (&'static str, Duration)> {
+        while self
+            .requests
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) >= RATE_LIMIT_WINDOW)
+        {
+            self.requests.pop_front();
+        }
+        // Time until the request at `index` leaves the window
+        let wait_for = |index: usize| {
+            self.requests
+                .get(index)
+                .map_or(RATE_LIMIT_WINDOW, |(time, _)| {
+                    RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(*time))
+                })
+        };
+
+        if let Some(limit) = config.requests_per_minute {
+            if self.requests.len() as u64 >= limit {
+                return Err(("requests", wait_for(0)));
+            }
+        }
+        if let Some(limit) = config.tokens_per_minute {
+            if tokens > limit {
+                return Err(("tokens", RATE_LIMIT_WINDOW));
+            }
+            let mut used = self.requests.iter().map(|(_, tokens)| tokens).sum::<u64>();
+            let mut index = 0;
+            while used + tokens > limit {
+                used -= self.requests[index].1;
+                index += 1;
+            }
+            if index > 0 {
+                return Err(("tokens", wait_for(index - 1)));
+            }
+        }
+        self.requests.push_back((now, tokens));
+        Ok(())
+    }
+}
+
+struct ApiKey {
+    config: ApiKeyConfig,
+    usage: Mutex<Usage>,
+}
+
+impl ApiKey {
+    /// The name of the key in errors, which is the start of the key if it has no name.
+    fn name(&self) -> String {
+        self.config
+            .name
+            .clone()
+            .unwrap_or_else(|| self.config.key.chars().take(8).collect::<String>() + "...")
+    }
+}
+
+/// The API keys accepted by the server, with their usage.
+pub struct ApiKeys {
+    keys: HashMap<String, ApiKey>,
+}
+
+/// An ID of an API key which does not reveal the key, to record the owner of stored objects. It
+/// is the same across restarts of the server.
+fn owner_id(key: &str) -> String {
+    // 64-bit FNV-1a
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("key-{hash:016x}")
+}
+
+/// The API key which authenticated a request. It is added to the extensions of the request, so
+/// that work done later for the key, such as the requests of a batch, is owned by the key and
+/// counted in its rate limits.
+#[derive(Clone)]
+pub struct Caller {
+    keys: Arc<ApiKeys>,
+    key: String,
+}
+
+impl Caller {
+    /// The ID of the key, which owns the files and batches it creates.
+    pub fn owner(&self) -> String {
+        owner_id(&self.key)
+    }
+
+    /// Check that the key may send a request with `priority`.
+    pub fn check_priority(&self, priority: RequestPriority) -> Result<(), String> {
+        let key = &self.keys.keys[&self.key];
+        if priority > key.config.max_priority {
+            return Err(format!(
+                "The API key `{}` may not use the `{priority:?}` priority, its highest priority is `{:?}`.",
+                key.name(),
+                key.config.max_priority
+            ));
+        }
+        Ok(())
+    }
+
+    /// Count a request with the body `body` in the rate limits of the key, waiting until the
+    /// limits allow it. Fails if the request exceeds the limits on its own.
+    pub async fn wait_for_limits(&self, body: &Value) -> Result<(), String> {
+        let key = &self.keys.keys[&self.key];
+        let tokens = estimate_tokens(body);
+        if let Some(limit) = key.config.tokens_per_minute.filter(|limit| tokens > *limit) {
+            return Err(format!(
+                "The request exceeds the rate limit of the API key `{}` on tokens per minute: limit {limit}, requested {tokens}.",
+                key.name()
+            ));
+        }
+        loop {
+            let result = key
+                .usage
+                .lock()
+                .expect("API key usage was poisoned")
+                .try_add(&key.config, tokens, Instant::now());
+            match result {
+                Ok(()) => return Ok(()),
+                Err((_, retry_after)) => tokio::time::sleep(retry_after).await,
+            }
+        }
+    }
+}
+
+impl ApiKeys {
+    /// Load the keys of the file, if any, and of the `MISTRALRS_API_KEY` environment variable.
+    /// Returns `None` if there are no keys, in which case authentication is disabled.
+    pub fn load(file: Option<&str>) -> Result<Option<Arc<Self>>> {
+        let mut keys = match file {
+            Some(file) => ApiKeysConfig::from_file(file)?.keys,
+            None => Vec::new(),
+        };
+        if let Some(key) = std::env::var(API_KEY_ENV)
+            .ok()
+            .filter(|key| !key.is_empty())
+        {
+            keys.push(ApiKeyConfig {
+                key,
+                name: Some(API_KEY_ENV.to_string()),
+                permissions: HashSet::from([Permission::Inference, Permission::Admin]),
+                max_priority: RequestPriority::High,
+                requests_per_minute: None,
+                tokens_per_minute: None,
+            });
+        }
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(Self::new(keys))))
+    }
+
+    fn new(keys: Vec<ApiKeyConfig>) -> Self {
+        Self {
+            keys: keys
+                .into_iter()
+                .map(|config| {
+                    let key = ApiKey {
+                        config,
+                        usage: Mutex::new(Usage::default()),
+                    };
+                    (key.config.key.clone(), key)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// An error body in the format of the OpenAI API.
+#[derive(Serialize)]
+struct ApiError {
+    error: ApiErrorBody,
+}
+
+#[derive(Serialize)]
+struct ApiErrorBody {
+    message: String,
+    #[serde(rename = "type")]
+    tp: &'static str,
+    param: Option<String>,
+    code: &'static str,
+}
+
+fn error_response(
+    status: StatusCode,
+    message: String,
+    tp: &'static str,
+    code: &'static str,
+) -> Response {
+    let body = ApiError {
+        error: ApiErrorBody {
+            message,
+            tp,
+            param: None,
+            code,
+        },
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Estimate the tokens of a request: the characters of its prompt, messages or input, times
+/// `TOKENS_PER_CHAR`, plus `max_tokens` for each of the `n` choices.
+fn estimate_tokens(body: &Value) -> u64 {
+    fn text_len(value: &Value) -> usize {
+        match value {
+            Value::String(text) => text.chars().count(),
+            Value::Array(values) => values.iter().map(text_len).sum(),
+            Value::Object(map) => map.values().map(text_len).sum(),
+            _ => 0,
+        }
+    }
+    let prompt_chars = ["messages", "prompt", "input"]
+        .iter()
+        .filter_map(|key| body.get(key))
+        .map(text_len)
+        .sum::<usize>();
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    let prompt_tokens = (prompt_chars as f64 * TOKENS_PER_CHAR).ceil() as u64;
+    let max_tokens = body.get("max_tokens").and_then(Value::as_u64).unwrap_or(0);
+    let n = body.get("n").and_then(Value::as_u64).unwrap_or(1);
+    prompt_tokens + max_tokens * n
+}
+
+/// Find the key of a request to a route which needs `permission`. Otherwise, returns the status,
+/// message and code of the error: 401 for a missing or invalid key, and 403 for a key which does
+/// not have `permission`.
+fn authenticate<'a>(
+    keys: &'a ApiKeys,
+    headers: &HeaderMap,
+    permission: Permission,
+) -> Result<&'a ApiKey, (StatusCode, String, &'static str)> {
+    let key = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let Some(key) = key else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "You didn't provide an API key. Provide it in the Authorization header as `Bearer <key>`.".to_string(),
+            "missing_api_key",
+        ));
+    };
+    let Some(key) = keys.keys.get(key) else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Incorrect API key provided.".to_string(),
+            "invalid_api_key",
+        ));
+    };
+    if !key.config.permissions.contains(&permission) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!(
+                "The API key `{}` does not have the `{permission:?}` permission for this route.",
+                key.name()
+            ),
+            "insufficient_permissions",
+        ));
+    }
+    Ok(key)
+}
+
+/// Authenticate a request to a route which needs `permission`, and apply the rate limits of its
+/// key.
+async fn authorize(
+    keys: &Arc<ApiKeys>,
+    permission: Permission,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let key = match authenticate(keys, request.headers(), permission) {
+        Ok(key) => key,
+        Err((status, message, code)) => {
+            return error_response(status, message, "invalid_request_error", code)
+        }
+    };
+    let name = key.name();
+
+    request.extensions_mut().insert(Caller {
+        keys: keys.clone(),
+        key: key.config.key.clone(),
+    });
+
+    // The body is only read to estimate the tokens of the request if they are limited
+    let (request, tokens) =
+        if permission == Permission::Inference && key.config.tokens_per_minute.is_some() {
+            let (parts, body) = request.into_parts();
+            let bytes = match axum::body::to_bytes(body, N_INPUT_SIZE * MB_TO_B).await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("Could not read the request body: {e}"),
+                        "invalid_request_error",
+                        "invalid_body",
+                    )
+                }
+            };
+            let tokens = serde_json::from_slice::<Value>(&bytes)
+                .map(|body| estimate_tokens(&body))
+                .unwrap_or(0);
+            (Request::from_parts(parts, Body::from(bytes)), tokens)
+        } else {
+            (request, 0)
+        };
+
+    let result = key
+        .usage
+        .lock()
+        .expect("API key usage was poisoned")
+        .try_add(&key.config, tokens, Instant::now());
+    if let Err((limit, retry_after)) = result {
+        let message = match limit {
+            "requests" => format!(
+                "Rate limit reached for the API key `{name}` on requests per minute: limit {}.",
+                key.config.requests_per_minute.unwrap_or_default()
+            ),
+            _ => format!(
+                "Rate limit reached for the API key `{name}` on tokens per minute: limit {}, requested {tokens}.",
+                key.config.tokens_per_minute.unwrap_or_default()
+            ),
+        };
+        let mut response = error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            message,
+            limit,
+            "rate_limit_exceeded",
+        );
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+        );
+        return response;
+    }
+
+    next.run(request).await
+}
+
+pub async fn require_inference(
+    State(keys): State<Arc<ApiKeys>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    authorize(&keys, Permission::Inference, request, next).await
+}
+
+pub async fn require_admin(
+    State(keys): State<Arc<ApiKeys>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    authorize(&keys, Permission::Admin, request, next).await
+}
+
+#[cfg(test)]
+impl ApiKeys {
+    /// The keys of a keys file, for the tests of other modules.
+    pub(crate) fn from_toml(config: &str) -> Arc<Self> {
+        Arc::new(Self::new(ApiKeysConfig::from_toml(config).unwrap().keys))
+    }
+
+    /// The caller authenticated with `key`, for the tests of other modules.
+    pub(crate) fn caller(self: &Arc<Self>, key: &str) -> Caller {
+        Caller {
+            keys: self.clone(),
+            key: key.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use axum::http::{header, HeaderMap, StatusCode};
+    use mistralrs_core::RequestPriority;
+    use serde_json::json;
+
+    use super::{
+        authenticate, estimate_tokens, ApiKeyConfig, ApiKeys, ApiKeysConfig, Caller, Permission,
+        Usage,
+    };
+
+    #[test]
+    fn test_config() {
+        let config = ApiKeysConfig::from_toml(
+            r#"
+            [[keys]]
+            key = "sk-admin"
+            permissions = ["admin", "inference"]
+
+            [[keys]]
+            key = "sk-user"
+            requests_per_minute = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.keys.len(), 2);
+        assert_eq!(
+            config.keys[1].permissions,
+            HashSet::from([Permission::Inference])
+        );
+
+        assert!(ApiKeysConfig::from_toml(
+            r#"
+            [[keys]]
+            key = "sk-user"
+
+            [[keys]]
+            key = "sk-user"
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let config = ApiKeysConfig::from_toml(
+            r#"
+            [[keys]]
+            key = "sk-user"
+            "#,
+        )
+        .unwrap();
+        let keys = ApiKeys::new(config.keys);
+        let status = |key: Option<&str>, permission| {
+            let mut headers = HeaderMap::new();
+            if let Some(key) = key {
+                headers.insert(
+                    header::AUTHORIZATION,
+                    format!("Bearer {key}").parse().unwrap(),
+                );
+            }
+            authenticate(&keys, &headers, permission)
+                .err()
+                .map(|(status, _, _)| status)
+        };
+        assert_eq!(status(Some("sk-user"), Permission::Inference), None);
+        assert_eq!(
+            status(None, Permission::Inference),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(Some("sk-other"), Permission::Inference),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        // The key is valid, but may not use the route
+        assert_eq!(
+            status(Some("sk-user"), Permission::Admin),
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn test_max_priority() {
+        let config = ApiKeysConfig::from_toml(
+            r#"
+            [[keys]]
+            key = "sk-user"
+
+            [[keys]]
+            key = "sk-interactive"
+            max_priority = "high"
+            "#,
+        )
+        .unwrap();
+        let keys = Arc::new(ApiKeys::new(config.keys));
+        let caller = |key: &str| Caller {
+            keys: keys.clone(),
+            key: key.to_string(),
+        };
+        assert!(caller("sk-user")
+            .check_priority(RequestPriority::Low)
+            .is_ok());
+        assert!(caller("sk-user")
+            .check_priority(RequestPriority::Normal)
+            .is_ok());
+        assert!(caller("sk-user")
+            .check_priority(RequestPriority::High)
+            .is_err());
+        assert!(caller("sk-interactive")
+            .check_priority(RequestPriority::High)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let config = ApiKeyConfig {
+            key: "sk".to_string(),
+            name: None,
+            permissions: HashSet::from([Permission::Inference]),
+            max_priority: RequestPriority::Normal,
+            requests_per_minute: Some(2),
+            tokens_per_minute: Some(100),
+        };
+        let start = Instant::now();
+        let mut usage = Usage::default();
+        assert!(usage.try_add(&config, 30, start).is_ok());
+        assert!(usage.try_add(&config, 30, start).is_ok());
+        let (limit, retry_after) = usage.try_add(&config, 30, start).unwrap_err();
+        assert_eq!(limit, "requests");
+        assert_eq!(retry_after, Duration::from_secs(60));
+
+        // The first requests leave the window
+        let later = start + Duration::from_secs(61);
+        assert!(usage.try_add(&config, 80, later).is_ok());
+        let (limit, _) = usage
+            .try_add(&config, 30, later + Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(limit, "tokens");
+        assert_eq!(usage.try_add(&config, 101, later).unwrap_err().0, "tokens");
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        let body = json!({
+            "messages": [{"role": "user", "content": "abcdefgh"}],
+            "max_tokens": 10,
+            "n": 2
+        });
+        // 12 characters of messages, including the role
+        assert_eq!(estimate_tokens(&body), 3 + 20);
+        assert_eq!(estimate_tokens(&json!({"prompt": "abc"})), 1);
+    }
+}
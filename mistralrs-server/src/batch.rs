@@ -0,0 +1,956 @@
+//! The OpenAI Batch API: JSONL files of chat completion or completion requests, stored in a local
+//! directory and run in the background behind interactive requests.
+
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Json, Multipart, Path as UrlPath, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures::StreamExt;
+use indexmap::IndexMap;
+use mistralrs_core::RequestPriority;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    auth::Caller,
+    chat_completion::chatcompletions,
+    completions::completions,
+    multi_model::ModelRouter,
+    openai::{ChatCompletionRequest, CompletionRequest},
+};
+
+/// Number of requests of a batch which are run at the same time. They are sent with the low
+/// priority, so the scheduler runs interactive requests first and preempts batch requests for them.
+const BATCH_CONCURRENCY: usize = 8;
+
+const CHAT_COMPLETIONS_ENDPOINT: &str = "/v1/chat/completions";
+const COMPLETIONS_ENDPOINT: &str = "/v1/completions";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel has occurred!")
+        .as_secs()
+}
+
+fn new_id(prefix: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel has occurred!")
+        .as_nanos();
+    format!(
+        "{prefix}-{nanos:x}{:x}",
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileObject {
+    pub id: String,
+    pub object: String,
+    pub bytes: usize,
+    pub created_at: u64,
+    pub filename: String,
+    pub purpose: String,
+    /// ID of the API key which created the file. Only this key may use the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RequestCounts {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Validating,
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Cancelling,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchObject {
+    pub id: String,
+    pub object: &'static str,
+    pub endpoint: String,
+    pub input_file_id: String,
+    pub completion_window: String,
+    pub status: BatchStatus,
+    pub output_file_id: Option<String>,
+    pub errors: Option<String>,
+    pub created_at: u64,
+    pub in_progress_at: Option<u64>,
+    pub finalizing_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub failed_at: Option<u64>,
+    pub cancelling_at: Option<u64>,
+    pub cancelled_at: Option<u64>,
+    pub request_counts: RequestCounts,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateBatchRequest {
+    pub input_file_id: String,
+    pub endpoint: String,
+    #[serde(default = "default_completion_window")]
+    pub completion_window: String,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+fn default_completion_window() -> String {
+    "24h".to_string()
+}
+
+/// Progress of a running batch, shared with the task which runs it.
+#[derive(Default)]
+pub struct BatchProgress {
+    completed: AtomicUsize,
+    failed: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl BatchProgress {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+struct Batch {
+    object: Mutex<BatchObject>,
+    progress: BatchProgress,
+    /// ID of the API key which created the batch. Only this key may use the batch.
+    owner: Option<String>,
+}
+
+impl Batch {
+    fn object(&self) -> MutexGuard<'_, BatchObject> {
+        self.object.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The batch with the counts of the requests which have finished so far.
+    fn snapshot(&self) -> BatchObject {
+        let mut object = self.object().clone();
+        object.request_counts.completed = self.progress.completed.load(Ordering::Relaxed);
+        object.request_counts.failed = self.progress.failed.load(Ordering::Relaxed);
+        object
+    }
+}
+
+/// The files and batches of the server. Files are kept in a local directory, with their metadata
+/// next to them, so they outlive the server. Batches are only kept in memory.
+///
+/// With API keys, files and batches are owned by the key which created them. The other keys see
+/// them as if they did not exist.
+pub struct Batches {
+    dir: PathBuf,
+    files: Mutex<IndexMap<String, FileObject>>,
+    batches: Mutex<IndexMap<String, Arc<Batch>>>,
+}
+
+impl Batches {
+    /// Use the files in `dir`, which is created when the first file is stored.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let mut files = Vec::new();
+        if dir.is_dir() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    let file: FileObject = serde_json::from_str(&fs::read_to_string(&path)?)
+                        .with_context(|| format!("Invalid file metadata `{}`", path.display()))?;
+                    files.push(file);
+                }
+            }
+        }
+        files.sort_by_key(|file| file.created_at);
+        Ok(Self {
+            dir,
+            files: Mutex::new(files.into_iter().map(|f| (f.id.clone(), f)).collect()),
+            batches: Mutex::new(IndexMap::new()),
+        })
+    }
+
+    fn files(&self) -> MutexGuard<'_, IndexMap<String, FileObject>> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn batches(&self) -> MutexGuard<'_, IndexMap<String, Arc<Batch>>> {
+        self.batches.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn content_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.jsonl"))
+    }
+
+    fn store_file(
+        &self,
+        filename: String,
+        purpose: String,
+        content: &[u8],
+        owner: Option<String>,
+    ) -> Result<FileObject> {
+        fs::create_dir_all(&self.dir)?;
+        let file = FileObject {
+            id: new_id("file"),
+            object: "file".to_string(),
+            bytes: content.len(),
+            created_at: now(),
+            filename,
+            purpose,
+            owner,
+        };
+        fs::write(self.content_path(&file.id), content)?;
+        fs::write(
+            self.dir.join(format!("{}.json", file.id)),
+            serde_json::to_string(&file)?,
+        )?;
+        self.files().insert(file.id.clone(), file.clone());
+        Ok(file)
+    }
+
+    /// The file `id`, if it is owned by `owner`.
+    fn get_file(&self, id: &str, owner: Option<&str>) -> Result<FileObject> {
+        match self.files().get(id) {
+            Some(file) if file.owner.as_deref() == owner => Ok(file.clone()),
+            _ => anyhow::bail!("No file with the ID `{id}`."),
+        }
+    }
+
+    fn list_files(&self, owner: Option<&str>) -> Vec<FileObject> {
+        self.files()
+            .values()
+            .filter(|file| file.owner.as_deref() == owner)
+            .cloned()
+            .collect()
+    }
+
+    fn read_file(&self, id: &str, owner: Option<&str>) -> Result<Vec<u8>> {
+        self.get_file(id, owner)?;
+        Ok(fs::read(self.content_path(id))?)
+    }
+
+    fn delete_file(&self, id: &str, owner: Option<&str>) -> Result<()> {
+        self.get_file(id, owner)?;
+        self.files().shift_remove(id);
+        fs::remove_file(self.content_path(id))?;
+        fs::remove_file(self.dir.join(format!("{id}.json")))?;
+        Ok(())
+    }
+
+    /// The batch `id`, if it is owned by `owner`.
+    fn get_batch(&self, id: &str, owner: Option<&str>) -> Option<Arc<Batch>> {
+        self.batches()
+            .get(id)
+            .filter(|batch| batch.owner.as_deref() == owner)
+            .cloned()
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchRequestLine {
+    custom_id: String,
+    #[serde(default)]
+    method: Option<String>,
+    url: String,
+    body: Value,
+}
+
+#[derive(Serialize)]
+struct BatchResultLine {
+    id: String,
+    custom_id: Option<String>,
+    response: Option<BatchResultResponse>,
+    error: Option<BatchResultError>,
+}
+
+#[derive(Serialize)]
+struct BatchResultResponse {
+    status_code: u16,
+    request_id: String,
+    body: Value,
+}
+
+#[derive(Serialize)]
+struct BatchResultError {
+    code: &'static str,
+    message: String,
+}
+
+impl BatchResultLine {
+    fn error(custom_id: Option<String>, code: &'static str, message: impl ToString) -> Self {
+        Self {
+            id: new_id("batch_req"),
+            custom_id,
+            response: None,
+            error: Some(BatchResultError {
+                code,
+                message: message.to_string(),
+            }),
+        }
+    }
+
+    fn succeeded(&self) -> bool {
+        self.response
+            .as_ref()
+            .is_some_and(|response| response.status_code == StatusCode::OK.as_u16())
+    }
+}
+
+/// Parse one line of a batch and check it: its `url` must be `endpoint` if it is given. With an
+/// API key, the line waits for the rate limits of the key.
+async fn check_line(
+    line: &str,
+    endpoint: Option<&str>,
+    caller: Option<&Caller>,
+) -> Result<BatchRequestLine, BatchResultLine> {
+    let line: BatchRequestLine =
+        serde_json::from_str(line).map_err(|e| BatchResultLine::error(None, "invalid_json", e))?;
+    let custom_id = Some(line.custom_id.clone());
+    if line.method.as_ref().is_some_and(|method| method != "POST") {
+        return Err(BatchResultLine::error(
+            custom_id,
+            "invalid_method",
+            "Only `POST` is supported.",
+        ));
+    }
+    if let Some(endpoint) = endpoint.filter(|endpoint| *endpoint != line.url) {
+        return Err(BatchResultLine::error(
+            custom_id,
+            "invalid_url",
+            format!("The URL must be the endpoint of the batch, `{endpoint}`."),
+        ));
+    }
+    if let Some(caller) = caller {
+        if let Err(e) = caller.wait_for_limits(&line.body).await {
+            return Err(BatchResultLine::error(custom_id, "rate_limit_exceeded", e));
+        }
+    }
+    Ok(line)
+}
+
+/// Run one line of a batch with the route of its `url`, after checking it with `check_line`.
+async fn run_line(
+    router: &Arc<ModelRouter>,
+    line: &str,
+    endpoint: Option<&str>,
+    caller: Option<&Caller>,
+) -> BatchResultLine {
+    let line = match check_line(line, endpoint, caller).await {
+        Ok(line) => line,
+        Err(result) => return result,
+    };
+    let custom_id = Some(line.custom_id);
+
+    let response = match line.url.as_str() {
+        CHAT_COMPLETIONS_ENDPOINT => {
+            let mut request: ChatCompletionRequest = match serde_json::from_value(line.body) {
+                Ok(request) => request,
+                Err(e) => return BatchResultLine::error(custom_id, "invalid_body", e),
+            };
+            request.stream = Some(false);
+            request.priority = RequestPriority::Low;
+            chatcompletions(
+                State(router.clone()),
+                caller.cloned().map(Extension),
+                Json(request),
+            )
+            .await
+            .into_response()
+        }
+        COMPLETIONS_ENDPOINT => {
+            let mut request: CompletionRequest = match serde_json::from_value(line.body) {
+                Ok(request) => request,
+                Err(e) => return BatchResultLine::error(custom_id, "invalid_body", e),
+            };
+            request.stream = Some(false);
+            request.priority = RequestPriority::Low;
+            completions(
+                State(router.clone()),
+                caller.cloned().map(Extension),
+                Json(request),
+            )
+            .await
+            .into_response()
+        }
+        url => {
+            return BatchResultLine::error(
+                custom_id,
+                "invalid_url",
+                format!(
+                "Expected `{CHAT_COMPLETIONS_ENDPOINT}` or `{COMPLETIONS_ENDPOINT}`, got `{url}`."
+            ),
+            )
+        }
+    };
+
+    let status_code = response.status().as_u16();
+    let body = match axum::body::to_bytes(response.into_body(), usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => return BatchResultLine::error(custom_id, "internal_error", e),
+    };
+    let body = serde_json::from_slice(&body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+    BatchResultLine {
+        id: new_id("batch_req"),
+        custom_id,
+        response: Some(BatchResultResponse {
+            status_code,
+            request_id: new_id("req"),
+            body,
+        }),
+        error: None,
+    }
+}
+
+/// Run the requests of a JSONL batch with `run_line`, returning the JSONL results in the order of
+/// the requests. Requests which were not started when the batch is cancelled have no result.
+async fn run_batch<F, Fut>(input: &str, progress: &BatchProgress, run_line: F) -> String
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = BatchResultLine>,
+{
+    let run_line = &run_line;
+    let results = futures::stream::iter(input.lines().filter(|line| !line.trim().is_empty()))
+        .map(|line| async move {
+            if progress.is_cancelled() {
+                return None;
+            }
+            let result = run_line(line.to_string()).await;
+            if result.succeeded() {
+                progress.completed.fetch_add(1, Ordering::Relaxed);
+            } else {
+                progress.failed.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(result)
+        })
+        .buffered(BATCH_CONCURRENCY)
+        .filter_map(|result| async move { result })
+        .collect::<Vec<_>>()
+        .await;
+
+    results
+        .into_iter()
+        .map(|result| {
+            serde_json::to_string(&result).expect("Serialization of batch result failed.") + "\n"
+        })
+        .collect()
+}
+
+/// Run a JSONL file of requests without the HTTP server, writing the results to `output`.
+pub async fn run_batch_file(router: Arc<ModelRouter>, input: &Path, output: &Path) -> Result<()> {
+    let input = fs::read_to_string(input)
+        .with_context(|| format!("Could not read the batch file `{}`", input.display()))?;
+    let progress = BatchProgress::default();
+    let router = &router;
+    let results = run_batch(&input, &progress, |line| async move {
+        run_line(router, &line, None, None).await
+    })
+    .await;
+    fs::write(output, results)
+        .with_context(|| format!("Could not write the batch output `{}`", output.display()))?;
+    info!(
+        "Batch finished: {} requests completed, {} failed.",
+        progress.completed.load(Ordering::Relaxed),
+        progress.failed.load(Ordering::Relaxed)
+    );
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ListObject<T> {
+    object: &'static str,
+    data: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct DeletedObject {
+    id: String,
+    object: &'static str,
+    deleted: bool,
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+fn error_response(code: StatusCode, e: impl ToString) -> Response {
+    let mut r = Json(JsonError {
+        message: e.to_string(),
+    })
+    .into_response();
+    *r.status_mut() = code;
+    r
+}
+
+/// The owner of the files and batches created by a request, which is its API key if there are
+/// keys.
+fn owner(caller: &Option<Extension<Caller>>) -> Option<String> {
+    caller.as_ref().map(|Extension(caller)| caller.owner())
+}
+
+pub async fn upload_file(
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+    mut multipart: Multipart,
+) -> Response {
+    let mut purpose = None;
+    let mut file = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+        };
+        let name = field.name().map(str::to_string);
+        match name.as_deref() {
+            Some("purpose") => match field.text().await {
+                Ok(text) => purpose = Some(text),
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+            },
+            Some("file") => {
+                let filename = field.file_name().unwrap_or("file.jsonl").to_string();
+                match field.bytes().await {
+                    Ok(bytes) => file = Some((filename, bytes)),
+                    Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+                }
+            }
+            _ => {}
+        }
+    }
+    let (Some(purpose), Some((filename, content))) = (purpose, file) else {
+        return error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Expected the form fields `purpose` and `file`.",
+        );
+    };
+
+    match batches.store_file(filename, purpose, &content, owner(&caller)) {
+        Ok(file) => Json(file).into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+pub async fn list_files(
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+) -> Response {
+    Json(ListObject {
+        object: "list",
+        data: batches.list_files(owner(&caller).as_deref()),
+    })
+    .into_response()
+}
+
+pub async fn retrieve_file(
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+    UrlPath(file_id): UrlPath<String>,
+) -> Response {
+    match batches.get_file(&file_id, owner(&caller).as_deref()) {
+        Ok(file) => Json(file).into_response(),
+        Err(e) => error_response(StatusCode::NOT_FOUND, e),
+    }
+}
+
+pub async fn file_content(
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+    UrlPath(file_id): UrlPath<String>,
+) -> Response {
+    match batches.read_file(&file_id, owner(&caller).as_deref()) {
+        Ok(content) => ([(header::CONTENT_TYPE, "application/jsonl")], content).into_response(),
+        Err(e) => error_response(StatusCode::NOT_FOUND, e),
+    }
+}
+
+pub async fn delete_file(
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+    UrlPath(file_id): UrlPath<String>,
+) -> Response {
+    match batches.delete_file(&file_id, owner(&caller).as_deref()) {
+        Ok(()) => Json(DeletedObject {
+            id: file_id,
+            object: "file",
+            deleted: true,
+        })
+        .into_response(),
+        Err(e) => error_response(StatusCode::NOT_FOUND, e),
+    }
+}
+
+pub async fn create_batch(
+    State(router): State<Arc<ModelRouter>>,
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+    Json(request): Json<CreateBatchRequest>,
+) -> Response {
+    if ![CHAT_COMPLETIONS_ENDPOINT, COMPLETIONS_ENDPOINT].contains(&request.endpoint.as_str()) {
+        return error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "Expected the endpoint `{CHAT_COMPLETIONS_ENDPOINT}` or `{COMPLETIONS_ENDPOINT}`, got `{}`.",
+                request.endpoint
+            ),
+        );
+    }
+    let owner = owner(&caller);
+    let input = match batches.read_file(&request.input_file_id, owner.as_deref()) {
+        Ok(input) => input,
+        Err(e) => return error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    };
+    let input = match String::from_utf8(input) {
+        Ok(input) => input,
+        Err(e) => return error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    };
+
+    let batch = Arc::new(Batch {
+        object: Mutex::new(BatchObject {
+            id: new_id("batch"),
+            object: "batch",
+            endpoint: request.endpoint,
+            input_file_id: request.input_file_id,
+            completion_window: request.completion_window,
+            status: BatchStatus::Validating,
+            output_file_id: None,
+            errors: None,
+            created_at: now(),
+            in_progress_at: None,
+            finalizing_at: None,
+            completed_at: None,
+            failed_at: None,
+            cancelling_at: None,
+            cancelled_at: None,
+            request_counts: RequestCounts {
+                total: input.lines().filter(|line| !line.trim().is_empty()).count(),
+                ..Default::default()
+            },
+            metadata: request.metadata,
+        }),
+        progress: BatchProgress::default(),
+        owner,
+    });
+    let object = batch.snapshot();
+    batches.batches().insert(object.id.clone(), batch.clone());
+
+    tokio::spawn(async move {
+        let endpoint = {
+            let mut object = batch.object();
+            if object.status == BatchStatus::Validating {
+                object.status = BatchStatus::InProgress;
+                object.in_progress_at = Some(now());
+            }
+            object.endpoint.clone()
+        };
+        let caller = caller.map(|Extension(caller)| caller);
+        let (router, endpoint, caller) = (&router, endpoint.as_str(), caller.as_ref());
+        let results = run_batch(&input, &batch.progress, |line| async move {
+            run_line(router, &line, Some(endpoint), caller).await
+        })
+        .await;
+        if !batch.progress.is_cancelled() {
+            let mut object = batch.object();
+            object.status = BatchStatus::Finalizing;
+            object.finalizing_at = Some(now());
+        }
+
+        let filename = format!("{}_output.jsonl", batch.object().id);
+        let output = batches.store_file(
+            filename,
+            "batch_output".to_string(),
+            results.as_bytes(),
+            batch.owner.clone(),
+        );
+        let mut object = batch.object();
+        match output {
+            Ok(file) => object.output_file_id = Some(file.id),
+            Err(e) => {
+                warn!("Could not store the output of batch `{}`: {e}", object.id);
+                object.status = BatchStatus::Failed;
+                object.failed_at = Some(now());
+                object.errors = Some(e.to_string());
+                return;
+            }
+        }
+        if batch.progress.is_cancelled() {
+            object.status = BatchStatus::Cancelled;
+            object.cancelled_at = Some(now());
+        } else {
+            object.status = BatchStatus::Completed;
+            object.completed_at = Some(now());
+        }
+    });
+
+    Json(object).into_response()
+}
+
+pub async fn list_batches(
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+) -> Response {
+    let owner = owner(&caller);
+    Json(ListObject {
+        object: "list",
+        data: batches
+            .batches()
+            .values()
+            .filter(|batch| batch.owner == owner)
+            .map(|batch| batch.snapshot())
+            .collect(),
+    })
+    .into_response()
+}
+
+pub async fn retrieve_batch(
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+    UrlPath(batch_id): UrlPath<String>,
+) -> Response {
+    match batches.get_batch(&batch_id, owner(&caller).as_deref()) {
+        Some(batch) => Json(batch.snapshot()).into_response(),
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            format!("No batch with the ID `{batch_id}`."),
+        ),
+    }
+}
+
+/// Cancel a batch: its running requests finish, and the requests which were not started are
+/// skipped.
+pub async fn cancel_batch(
+    Extension(batches): Extension<Arc<Batches>>,
+    caller: Option<Extension<Caller>>,
+    UrlPath(batch_id): UrlPath<String>,
+) -> Response {
+    let Some(batch) = batches.get_batch(&batch_id, owner(&caller).as_deref()) else {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            format!("No batch with the ID `{batch_id}`."),
+        );
+    };
+    {
+        let mut object = batch.object();
+        if matches!(
+            object.status,
+            BatchStatus::Validating | BatchStatus::InProgress
+        ) {
+            batch.progress.cancel();
+            object.status = BatchStatus::Cancelling;
+            object.cancelling_at = Some(now());
+        }
+    }
+    Json(batch.snapshot()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::{
+        check_line, run_batch, BatchProgress, BatchResultLine, BatchResultResponse, Batches,
+        CHAT_COMPLETIONS_ENDPOINT, COMPLETIONS_ENDPOINT,
+    };
+    use crate::auth::{ApiKeys, Caller};
+
+    fn line(custom_id: &str, max_tokens: u64) -> String {
+        json!({
+            "custom_id": custom_id,
+            "method": "POST",
+            "url": COMPLETIONS_ENDPOINT,
+            "body": {"model": "default", "prompt": "", "max_tokens": max_tokens},
+        })
+        .to_string()
+    }
+
+    /// Check a line like `run_line`, answering the lines which pass with their body.
+    async fn echo_line(line: &str, caller: Option<&Caller>) -> BatchResultLine {
+        match check_line(line, Some(COMPLETIONS_ENDPOINT), caller).await {
+            Ok(line) => BatchResultLine {
+                id: "batch_req".to_string(),
+                custom_id: Some(line.custom_id),
+                response: Some(BatchResultResponse {
+                    status_code: StatusCode::OK.as_u16(),
+                    request_id: "req".to_string(),
+                    body: line.body,
+                }),
+                error: None,
+            },
+            Err(result) => result,
+        }
+    }
+
+    fn error_code(result: &BatchResultLine) -> Option<&str> {
+        result.error.as_ref().map(|error| error.code)
+    }
+
+    #[test]
+    fn files_are_persisted() {
+        let dir = std::env::temp_dir().join(format!("mistralrs-batches-{}", std::process::id()));
+        let batches = Batches::new(&dir).unwrap();
+        let file = batches
+            .store_file("in.jsonl".to_string(), "batch".to_string(), b"{}\n", None)
+            .unwrap();
+        assert_eq!(file.bytes, 3);
+
+        let reloaded = Batches::new(&dir).unwrap();
+        assert_eq!(reloaded.read_file(&file.id, None).unwrap(), b"{}\n");
+        reloaded.delete_file(&file.id, None).unwrap();
+        assert!(reloaded.read_file(&file.id, None).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_owned() {
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs-owned-batches-{}", std::process::id()));
+        let batches = Batches::new(&dir).unwrap();
+        let file = batches
+            .store_file(
+                "in.jsonl".to_string(),
+                "batch".to_string(),
+                b"{}\n",
+                Some("key-a".to_string()),
+            )
+            .unwrap();
+        assert_eq!(batches.list_files(Some("key-a")).len(), 1);
+        assert!(batches.list_files(Some("key-b")).is_empty());
+        assert!(batches.list_files(None).is_empty());
+        assert!(batches.read_file(&file.id, Some("key-b")).is_err());
+        assert!(batches.delete_file(&file.id, Some("key-b")).is_err());
+
+        // The owner is persisted
+        let reloaded = Batches::new(&dir).unwrap();
+        assert!(reloaded.get_file(&file.id, None).is_err());
+        reloaded.delete_file(&file.id, Some("key-a")).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn error_lines_do_not_succeed() {
+        let line = BatchResultLine::error(Some("a".to_string()), "invalid_json", "bad");
+        assert!(!line.succeeded());
+        let json = serde_json::to_value(&line).unwrap();
+        assert_eq!(json["custom_id"], "a");
+        assert_eq!(json["error"]["code"], "invalid_json");
+        assert!(json["response"].is_null());
+    }
+
+    #[tokio::test]
+    async fn invalid_lines_are_rejected() {
+        let result = check_line("not json", None, None).await.unwrap_err();
+        assert_eq!(error_code(&result), Some("invalid_json"));
+
+        let get =
+            json!({"custom_id": "a", "method": "GET", "url": COMPLETIONS_ENDPOINT, "body": {}});
+        let result = check_line(&get.to_string(), None, None).await.unwrap_err();
+        assert_eq!(error_code(&result), Some("invalid_method"));
+        assert_eq!(result.custom_id.as_deref(), Some("a"));
+
+        let result = check_line(&line("a", 1), Some(CHAT_COMPLETIONS_ENDPOINT), None)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&result), Some("invalid_url"));
+
+        assert!(check_line(&line("a", 1), None, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn lines_use_the_limits_of_their_key() {
+        let keys = ApiKeys::from_toml(
+            r#"
+            [[keys]]
+            key = "sk-a"
+            tokens_per_minute = 20
+
+            [[keys]]
+            key = "sk-b"
+            tokens_per_minute = 20
+            "#,
+        );
+        let (a, b) = (keys.caller("sk-a"), keys.caller("sk-b"));
+
+        // A line which exceeds the limit on its own fails instead of waiting
+        let result = check_line(&line("big", 21), None, Some(&a))
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&result), Some("rate_limit_exceeded"));
+
+        let progress = BatchProgress::default();
+        let input = format!("{}\n{}\n", line("1", 10), line("2", 10));
+        let results = run_batch(&input, &progress, |line| {
+            let a = a.clone();
+            async move { echo_line(&line, Some(&a)).await }
+        })
+        .await;
+        assert_eq!(results.lines().count(), 2);
+        assert_eq!(progress.completed.load(Ordering::Relaxed), 2);
+
+        // The batch used up the limit of `sk-a`, but not that of `sk-b`
+        let body = json!({"max_tokens": 10});
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), a.wait_for_limits(&body))
+                .await
+                .is_err()
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), b.wait_for_limits(&body))
+                .await
+                .unwrap()
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_results_are_in_order() {
+        let progress = BatchProgress::default();
+        let input = format!("{}\n\nnot json\n{}\n", line("1", 1), line("2", 1));
+        let results = run_batch(&input, &progress, |line| async move {
+            echo_line(&line, None).await
+        })
+        .await;
+        let results = results
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["custom_id"], "1");
+        assert_eq!(results[1]["error"]["code"], "invalid_json");
+        assert_eq!(results[2]["custom_id"], "2");
+        assert_eq!(progress.completed.load(Ordering::Relaxed), 2);
+        assert_eq!(progress.failed.load(Ordering::Relaxed), 1);
+    }
+}
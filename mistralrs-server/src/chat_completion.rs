@@ -11,12 +11,14 @@ use std::{
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{
-    openai::{ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens},
+    auth::Caller,
+    multi_model::{validation_status, ModelRouter},
+    openai::{output_constraint, ChatCompletionRequest, Message, MessageInnerContent, StopTokens},
     util,
 };
 use anyhow::{Context as _, Result};
 use axum::{
-    extract::{Json, State},
+    extract::{Extension, Json, State},
     http::{self, StatusCode},
     response::{
         sse::{Event, KeepAlive},
@@ -26,8 +28,9 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    BeamSearchParams, ChatCompletionResponse, Constraint, DrySamplingParams, MessageContent,
+    MirostatParams, MistralRs, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens, XtcParams,
 };
 use serde::Serialize;
 
@@ -44,6 +47,16 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The client disconnected before the response was finished, so stop generating it.
+        if !self.is_done {
+            let _ = self.state.cancel_request(self.request_id);
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -81,6 +94,7 @@ impl futures::Stream for Streamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -141,7 +155,7 @@ impl IntoResponse for ChatCompletionResponder {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
             }
             ChatCompletionResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+                JsonError::new(e.to_string()).to_response(validation_status(&*e))
             }
             ChatCompletionResponder::ModelError(msg, response) => {
                 JsonModelError::new(msg, response)
@@ -151,8 +165,107 @@ impl IntoResponse for ChatCompletionResponder {
     }
 }
 
+/// Convert the messages of a request into the messages of the chat template, returning them with
+/// the URLs of their images in order.
+pub(crate) fn parse_messages(
+    req_messages: Vec<Message>,
+) -> Result<(Vec<IndexMap<String, MessageContent>>, Vec<String>)> {
+    let mut messages = Vec::new();
+    let mut image_urls = Vec::new();
+    for message in req_messages {
+        match message.content.deref() {
+            Either::Left(content) => {
+                let mut message_map: IndexMap<
+                    String,
+                    Either<String, Vec<IndexMap<String, String>>>,
+                > = IndexMap::new();
+                message_map.insert("role".to_string(), Either::Left(message.role));
+                message_map.insert("content".to_string(), Either::Left(content.to_string()));
+                messages.push(message_map);
+            }
+            Either::Right(image_messages) => {
+                if image_messages.len() != 2 {
+                    anyhow::bail!("Expected 2 items for the content of a message with an image.");
+                }
+                if message.role != "user" {
+                    anyhow::bail!(
+                        "Role for an image message must be `user`, but it is {}",
+                        message.role
+                    );
+                }
+
+                let mut items = Vec::new();
+                for image_message in image_messages {
+                    if image_message.len() != 2 {
+                        anyhow::bail!(
+                            "Expected 2 items for the sub-content of a message with an image."
+                        );
+                    }
+                    if !image_message.contains_key("type") {
+                        anyhow::bail!("Expected `type` key in input message.");
+                    }
+                    if image_message["type"].is_right() {
+                        anyhow::bail!("Expected string value in `type`.");
+                    }
+                    items.push(image_message["type"].as_ref().unwrap_left().clone())
+                }
+
+                fn get_content_and_url(
+                    text_idx: usize,
+                    url_idx: usize,
+                    image_messages: &[HashMap<String, MessageInnerContent>],
+                ) -> Result<(String, String)> {
+                    if image_messages[text_idx]["text"].is_right() {
+                        anyhow::bail!("Expected string value in `text`.");
+                    }
+                    let content = image_messages[text_idx]["text"]
+                        .as_ref()
+                        .unwrap_left()
+                        .clone();
+                    if image_messages[url_idx]["image_url"].is_left()
+                        || !image_messages[url_idx]["image_url"]
+                            .as_ref()
+                            .unwrap_right()
+                            .contains_key("url")
+                    {
+                        anyhow::bail!("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}")
+                    }
+                    let url =
+                        image_messages[url_idx]["image_url"].as_ref().unwrap_right()["url"].clone();
+                    Ok((content, url))
+                }
+                let mut message_map: IndexMap<
+                    String,
+                    Either<String, Vec<IndexMap<String, String>>>,
+                > = IndexMap::new();
+                message_map.insert("role".to_string(), Either::Left(message.role));
+                let (content, url) = if items[0] == "text" {
+                    get_content_and_url(0, 1, image_messages)?
+                } else {
+                    get_content_and_url(1, 0, image_messages)?
+                };
+
+                let mut content_map = Vec::new();
+                let mut content_image_map = IndexMap::new();
+                content_image_map.insert("type".to_string(), "image".to_string());
+                content_map.push(content_image_map);
+                let mut content_text_map = IndexMap::new();
+                content_text_map.insert("type".to_string(), "text".to_string());
+                content_text_map.insert("text".to_string(), content);
+                content_map.push(content_text_map);
+
+                message_map.insert("content".to_string(), Either::Right(content_map));
+                messages.push(message_map);
+                image_urls.push(url);
+            }
+        }
+    }
+    Ok((messages, image_urls))
+}
+
 async fn parse_request(
     oairequest: ChatCompletionRequest,
+    constraint: Constraint,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
 ) -> Result<(Request, bool)> {
@@ -166,98 +279,7 @@ async fn parse_request(
     };
     let messages = match oairequest.messages {
         Either::Left(req_messages) => {
-            let mut messages = Vec::new();
-            let mut image_urls = Vec::new();
-            for message in req_messages {
-                match message.content.deref() {
-                    Either::Left(content) => {
-                        let mut message_map: IndexMap<
-                            String,
-                            Either<String, Vec<IndexMap<String, String>>>,
-                        > = IndexMap::new();
-                        message_map.insert("role".to_string(), Either::Left(message.role));
-                        message_map
-                            .insert("content".to_string(), Either::Left(content.to_string()));
-                        messages.push(message_map);
-                    }
-                    Either::Right(image_messages) => {
-                        if image_messages.len() != 2 {
-                            anyhow::bail!(
-                                "Expected 2 items for the content of a message with an image."
-                            );
-                        }
-                        if message.role != "user" {
-                            anyhow::bail!(
-                                "Role for an image message must be `user`, but it is {}",
-                                message.role
-                            );
-                        }
-
-                        let mut items = Vec::new();
-                        for image_message in image_messages {
-                            if image_message.len() != 2 {
-                                anyhow::bail!("Expected 2 items for the sub-content of a message with an image.");
-                            }
-                            if !image_message.contains_key("type") {
-                                anyhow::bail!("Expected `type` key in input message.");
-                            }
-                            if image_message["type"].is_right() {
-                                anyhow::bail!("Expected string value in `type`.");
-                            }
-                            items.push(image_message["type"].as_ref().unwrap_left().clone())
-                        }
-
-                        fn get_content_and_url(
-                            text_idx: usize,
-                            url_idx: usize,
-                            image_messages: &[HashMap<String, MessageInnerContent>],
-                        ) -> Result<(String, String)> {
-                            if image_messages[text_idx]["text"].is_right() {
-                                anyhow::bail!("Expected string value in `text`.");
-                            }
-                            let content = image_messages[text_idx]["text"]
-                                .as_ref()
-                                .unwrap_left()
-                                .clone();
-                            if image_messages[url_idx]["image_url"].is_left()
-                                || !image_messages[url_idx]["image_url"]
-                                    .as_ref()
-                                    .unwrap_right()
-                                    .contains_key("url")
-                            {
-                                anyhow::bail!("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}")
-                            }
-                            let url = image_messages[url_idx]["image_url"].as_ref().unwrap_right()
-                                ["url"]
-                                .clone();
-                            Ok((content, url))
-                        }
-                        let mut message_map: IndexMap<
-                            String,
-                            Either<String, Vec<IndexMap<String, String>>>,
-                        > = IndexMap::new();
-                        message_map.insert("role".to_string(), Either::Left(message.role));
-                        let (content, url) = if items[0] == "text" {
-                            get_content_and_url(0, 1, image_messages)?
-                        } else {
-                            get_content_and_url(1, 0, image_messages)?
-                        };
-
-                        let mut content_map = Vec::new();
-                        let mut content_image_map = IndexMap::new();
-                        content_image_map.insert("type".to_string(), "image".to_string());
-                        content_map.push(content_image_map);
-                        let mut content_text_map = IndexMap::new();
-                        content_text_map.insert("type".to_string(), "text".to_string());
-                        content_text_map.insert("text".to_string(), content);
-                        content_map.push(content_text_map);
-
-                        message_map.insert("content".to_string(), Either::Right(content_map));
-                        messages.push(message_map);
-                        image_urls.push(url);
-                    }
-                }
-            }
+            let (messages, image_urls) = parse_messages(req_messages)?;
             if !image_urls.is_empty() {
                 let mut images = Vec::new();
                 for url_unparsed in image_urls {
@@ -296,6 +318,29 @@ async fn parse_request(
         None
     };
 
+    let mirostat = oairequest.mirostat_tau.map(|tau| MirostatParams {
+        tau,
+        eta: oairequest
+            .mirostat_eta
+            .unwrap_or(MirostatParams::default().eta),
+    });
+
+    let xtc = oairequest.xtc_probability.map(|probability| XtcParams {
+        threshold: oairequest
+            .xtc_threshold
+            .unwrap_or(XtcParams::default().threshold),
+        probability,
+    });
+
+    let beam_search = oairequest.beam_width.map(|beam_width| {
+        let defaults = BeamSearchParams::new(beam_width);
+        BeamSearchParams {
+            beam_width,
+            length_penalty: oairequest.length_penalty.unwrap_or(defaults.length_penalty),
+            early_stopping: oairequest.early_stopping.unwrap_or(defaults.early_stopping),
+        }
+    });
+
     let is_streaming = oairequest.stream.unwrap_or(false);
     Ok((
         Request::Normal(NormalRequest {
@@ -306,28 +351,35 @@ async fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                repetition_penalty: oairequest.repetition_penalty,
+                repetition_penalty_last_n: oairequest.repetition_penalty_last_n,
                 max_len: oairequest.max_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                mirostat,
+                xtc,
+                sampler_order: oairequest.sampler_order,
+                seed: oairequest.seed,
+                beam_search,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
             is_streaming,
             suffix: None,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
-            },
+            constraint,
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
+            priority: oairequest.priority,
+            tenant: oairequest.user,
         }),
         is_streaming,
     ))
@@ -341,17 +393,41 @@ async fn parse_request(
     responses((status = 200, description = "Chat completions"))
 )]
 pub async fn chatcompletions(
-    State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<ChatCompletionRequest>,
+    State(router): State<Arc<ModelRouter>>,
+    caller: Option<Extension<Caller>>,
+    Json(mut oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
-    let (tx, mut rx) = channel(10_000);
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
-        Ok(x) => x,
-        Err(e) => {
-            let e = anyhow::Error::msg(e.to_string());
-            MistralRs::maybe_log_error(state, &*e);
-            return ChatCompletionResponder::InternalError(e.into());
+    if let Some(Extension(caller)) = &caller {
+        if let Err(e) = caller.check_priority(oairequest.priority) {
+            return ChatCompletionResponder::ValidationError(e.into());
         }
+        // The requests of a key are one tenant, unless they name their users
+        oairequest.user.get_or_insert_with(|| caller.owner());
+    }
+    let state = match router.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return ChatCompletionResponder::ValidationError(e.into()),
+    };
+    let constraint = match output_constraint(
+        oairequest.grammar.clone(),
+        oairequest.response_format.clone(),
+    ) {
+        Ok(constraint) => constraint,
+        Err(e) => return ChatCompletionResponder::ValidationError(e.into()),
+    };
+    let (tx, mut rx) = channel(10_000);
+    let (request, is_streaming) =
+        match parse_request(oairequest, constraint, state.clone(), tx).await {
+            Ok(x) => x,
+            Err(e) => {
+                let e = anyhow::Error::msg(e.to_string());
+                MistralRs::maybe_log_error(state, &*e);
+                return ChatCompletionResponder::InternalError(e.into());
+            }
+        };
+    let request_id = match &request {
+        Request::Normal(NormalRequest { id, .. }) => *id,
+        _ => unreachable!("`parse_request` only creates normal requests"),
     };
     let sender = state.get_sender().unwrap();
 
@@ -366,6 +442,7 @@ pub async fn chatcompletions(
             rx,
             is_done: false,
             state,
+            request_id,
         };
 
         ChatCompletionResponder::Sse(
@@ -409,6 +486,7 @@ pub async fn chatcompletions(
             Response::CompletionModelError(_, _) => unreachable!(),
             Response::CompletionChunk(_) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::Embedding(_) => unreachable!(),
         }
     }
 }
@@ -9,9 +9,13 @@ use std::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::openai::{output_constraint, CompletionRequest, StopTokens};
+use crate::{
+    auth::Caller,
+    multi_model::{validation_status, ModelRouter},
+};
 use axum::{
-    extract::{Json, State},
+    extract::{Extension, Json, State},
     http::{self, StatusCode},
     response::{
         sse::{Event, KeepAlive},
@@ -19,8 +23,9 @@ use axum::{
     },
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    BeamSearchParams, CompletionResponse, Constraint, DrySamplingParams, MirostatParams, MistralRs,
+    NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens, XtcParams,
 };
 use serde::Serialize;
 use tracing::warn;
@@ -38,6 +43,16 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The client disconnected before the response was finished, so stop generating it.
+        if !self.is_done {
+            let _ = self.state.cancel_request(self.request_id);
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -75,6 +90,7 @@ impl futures::Stream for Streamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::Chunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -135,7 +151,7 @@ impl IntoResponse for CompletionResponder {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
             }
             CompletionResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+                JsonError::new(e.to_string()).to_response(validation_status(&*e))
             }
             CompletionResponder::ModelError(msg, response) => JsonModelError::new(msg, response)
                 .to_response(http::StatusCode::INTERNAL_SERVER_ERROR),
@@ -145,6 +161,7 @@ impl IntoResponse for CompletionResponder {
 
 fn parse_request(
     oairequest: CompletionRequest,
+    constraint: Constraint,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
 ) -> Result<(Request, bool)> {
@@ -173,41 +190,79 @@ fn parse_request(
     } else {
         None
     };
+
+    let mirostat = oairequest.mirostat_tau.map(|tau| MirostatParams {
+        tau,
+        eta: oairequest
+            .mirostat_eta
+            .unwrap_or(MirostatParams::default().eta),
+    });
+
+    let xtc = oairequest.xtc_probability.map(|probability| XtcParams {
+        threshold: oairequest
+            .xtc_threshold
+            .unwrap_or(XtcParams::default().threshold),
+        probability,
+    });
+
+    let beam_search = oairequest.beam_width.map(|beam_width| {
+        let defaults = BeamSearchParams::new(beam_width);
+        BeamSearchParams {
+            beam_width,
+            length_penalty: oairequest.length_penalty.unwrap_or(defaults.length_penalty),
+            early_stopping: oairequest.early_stopping.unwrap_or(defaults.early_stopping),
+        }
+    });
+
     Ok((
         Request::Normal(NormalRequest {
             id: state.next_request_id(),
-            messages: RequestMessage::Completion {
-                text: oairequest.prompt,
-                echo_prompt: oairequest.echo_prompt,
-                best_of: oairequest.best_of,
+            messages: match oairequest.continuation {
+                Some(continuation) => RequestMessage::Score {
+                    prompt: oairequest.prompt,
+                    continuation,
+                },
+                None => RequestMessage::Completion {
+                    text: oairequest.prompt,
+                    echo_prompt: oairequest.echo_prompt,
+                    best_of: oairequest.best_of,
+                    prompt_logprobs: oairequest.prompt_logprobs.is_some(),
+                },
             },
             sampling_params: SamplingParams {
                 temperature: oairequest.temperature,
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
-                top_n_logprobs: 1,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                top_n_logprobs: oairequest.prompt_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                repetition_penalty: oairequest.repetition_penalty,
+                repetition_penalty_last_n: oairequest.repetition_penalty_last_n,
                 max_len: oairequest.max_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                mirostat,
+                xtc,
+                sampler_order: oairequest.sampler_order,
+                seed: oairequest.seed,
+                beam_search,
             },
             response: tx,
             return_logprobs: false,
             is_streaming,
             suffix: oairequest.suffix,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
-            },
+            constraint,
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
+            priority: oairequest.priority,
+            tenant: oairequest.user,
         }),
         is_streaming,
     ))
@@ -222,17 +277,41 @@ fn parse_request(
 )]
 
 pub async fn completions(
-    State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<CompletionRequest>,
+    State(router): State<Arc<ModelRouter>>,
+    caller: Option<Extension<Caller>>,
+    Json(mut oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
+    if let Some(Extension(caller)) = &caller {
+        if let Err(e) = caller.check_priority(oairequest.priority) {
+            return CompletionResponder::ValidationError(e.into());
+        }
+        // The requests of a key are one tenant, unless they name their users
+        oairequest.user.get_or_insert_with(|| caller.owner());
+    }
+    let state = match router.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return CompletionResponder::ValidationError(e.into()),
+    };
     let (tx, mut rx) = channel(10_000);
     if oairequest.logprobs.is_some() {
         return CompletionResponder::ValidationError(
             "Completion requests do not support logprobs.".into(),
         );
     }
+    if oairequest.continuation.is_some() && oairequest.prompt_logprobs.is_some() {
+        return CompletionResponder::ValidationError(
+            "`prompt_logprobs` cannot be used together with `continuation`.".into(),
+        );
+    }
+    let constraint = match output_constraint(
+        oairequest.grammar.clone(),
+        oairequest.response_format.clone(),
+    ) {
+        Ok(constraint) => constraint,
+        Err(e) => return CompletionResponder::ValidationError(e.into()),
+    };
 
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
+    let (request, is_streaming) = match parse_request(oairequest, constraint, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => {
             let e = anyhow::Error::msg(e.to_string());
@@ -240,6 +319,10 @@ pub async fn completions(
             return CompletionResponder::InternalError(e.into());
         }
     };
+    let request_id = match &request {
+        Request::Normal(NormalRequest { id, .. }) => *id,
+        _ => unreachable!("`parse_request` only creates normal requests"),
+    };
     let sender = state.get_sender().unwrap();
 
     if let Err(e) = sender.send(request).await {
@@ -253,6 +336,7 @@ pub async fn completions(
             rx,
             is_done: false,
             state,
+            request_id,
         };
 
         CompletionResponder::Sse(
@@ -296,6 +380,7 @@ pub async fn completions(
             Response::Done(_) => unreachable!(),
             Response::ModelError(_, _) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::Embedding(_) => unreachable!(),
         }
     }
 }
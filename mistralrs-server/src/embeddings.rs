@@ -0,0 +1,213 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use either::Either;
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::{channel, Receiver};
+
+use crate::{
+    multi_model::{validation_status, ModelRouter},
+    openai::{
+        EmbeddingData, EmbeddingEncodingFormat, EmbeddingInput, EmbeddingRequest,
+        EmbeddingResponse, EmbeddingUsage, EmbeddingVector,
+    },
+};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    Constraint, MistralRs, NormalRequest, Request, RequestMessage, RequestPriority, Response,
+    SamplingParams,
+};
+use serde::Serialize;
+
+pub enum EmbeddingResponder {
+    Json(EmbeddingResponse),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl IntoResponse for EmbeddingResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EmbeddingResponder::Json(s) => Json(s).into_response(),
+            EmbeddingResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            EmbeddingResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(validation_status(&*e))
+            }
+        }
+    }
+}
+
+/// Create one request per input, returning the receivers of the responses in the order of the
+/// inputs.
+fn parse_requests(
+    oairequest: &EmbeddingRequest,
+    state: Arc<MistralRs>,
+) -> Result<Vec<(Request, Receiver<Response>)>> {
+    let repr = serde_json::to_string(oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let inputs = match oairequest.input.clone() {
+        EmbeddingInput::Single(text) => vec![Either::Left(text)],
+        EmbeddingInput::Multi(texts) => texts.into_iter().map(Either::Left).collect(),
+        EmbeddingInput::Tokens(tokens) => vec![Either::Right(tokens)],
+        EmbeddingInput::MultiTokens(tokens) => tokens.into_iter().map(Either::Right).collect(),
+    };
+    if inputs.is_empty() {
+        anyhow::bail!("The input must not be empty.");
+    }
+
+    Ok(inputs
+        .into_iter()
+        .map(|input| {
+            let (tx, rx) = channel(1);
+            let request = Request::Normal(NormalRequest {
+                id: state.next_request_id(),
+                messages: RequestMessage::Embedding {
+                    input,
+                    pooling: oairequest.pooling,
+                    normalize: oairequest.normalize,
+                },
+                sampling_params: SamplingParams::deterministic(),
+                response: tx,
+                return_logprobs: false,
+                is_streaming: false,
+                suffix: None,
+                constraint: Constraint::None,
+                adapters: None,
+                tool_choice: None,
+                tools: None,
+                logits_processors: None,
+                priority: RequestPriority::Normal,
+                tenant: None,
+            });
+            (request, rx)
+        })
+        .collect())
+}
+
+fn encode(embedding: Vec<f32>, format: EmbeddingEncodingFormat) -> EmbeddingVector {
+    match format {
+        EmbeddingEncodingFormat::Float => EmbeddingVector::Float(embedding),
+        EmbeddingEncodingFormat::Base64 => EmbeddingVector::Base64(
+            STANDARD.encode(
+                embedding
+                    .into_iter()
+                    .flat_map(f32::to_le_bytes)
+                    .collect::<Vec<_>>(),
+            ),
+        ),
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings"))
+)]
+
+pub async fn embeddings(
+    State(router): State<Arc<ModelRouter>>,
+    Json(oairequest): Json<EmbeddingRequest>,
+) -> EmbeddingResponder {
+    let state = match router.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return EmbeddingResponder::ValidationError(e.into()),
+    };
+    let requests = match parse_requests(&oairequest, state.clone()) {
+        Ok(x) => x,
+        Err(e) => return EmbeddingResponder::ValidationError(e.into()),
+    };
+    let sender = state.get_sender().unwrap();
+
+    let mut receivers = Vec::new();
+    for (request, rx) in requests {
+        if let Err(e) = sender.send(request).await {
+            let e = anyhow::Error::msg(e.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            return EmbeddingResponder::InternalError(e.into());
+        }
+        receivers.push(rx);
+    }
+
+    let mut data = Vec::new();
+    let mut prompt_tokens = 0;
+    for (index, mut rx) in receivers.into_iter().enumerate() {
+        let response = match rx.recv().await {
+            Some(response) => response,
+            None => {
+                let e = anyhow::Error::msg("No response received from the model.");
+                MistralRs::maybe_log_error(state, &*e);
+                return EmbeddingResponder::InternalError(e.into());
+            }
+        };
+
+        match response {
+            Response::InternalError(e) => {
+                MistralRs::maybe_log_error(state, &*e);
+                return EmbeddingResponder::InternalError(e);
+            }
+            Response::ValidationError(e) => return EmbeddingResponder::ValidationError(e),
+            Response::Embedding(response) => {
+                prompt_tokens += response.prompt_tokens;
+                data.push(EmbeddingData {
+                    object: "embedding",
+                    embedding: encode(response.embedding, oairequest.encoding_format),
+                    index,
+                });
+            }
+            Response::CompletionModelError(m, _) | Response::ModelError(m, _) => {
+                let e = anyhow::Error::msg(m.to_string());
+                MistralRs::maybe_log_error(state, &*e);
+                return EmbeddingResponder::InternalError(e.into());
+            }
+            Response::CompletionDone(_)
+            | Response::CompletionChunk(_)
+            | Response::Chunk(_)
+            | Response::Done(_)
+            | Response::ImageGeneration(_) => {
+                let e = anyhow::Error::msg("The model did not respond with an embedding.");
+                MistralRs::maybe_log_error(state, &*e);
+                return EmbeddingResponder::InternalError(e.into());
+            }
+        }
+    }
+
+    let response = EmbeddingResponse {
+        object: "list",
+        data,
+        model: oairequest.model,
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    };
+    MistralRs::maybe_log_response(state, &response);
+    EmbeddingResponder::Json(response)
+}
@@ -2,6 +2,7 @@ use anyhow::Result;
 use std::{error::Error, sync::Arc};
 use tokio::sync::mpsc::{channel, Sender};
 
+use crate::multi_model::{validation_status, ModelRouter};
 use crate::openai::ImageGenerationRequest;
 use axum::{
     extract::{Json, State},
@@ -10,7 +11,7 @@ use axum::{
 };
 use mistralrs_core::{
     Constraint, DiffusionGenerationParams, ImageGenerationResponse, MistralRs, NormalRequest,
-    Request, RequestMessage, Response, SamplingParams,
+    Request, RequestMessage, RequestPriority, Response, SamplingParams,
 };
 use serde::Serialize;
 
@@ -48,7 +49,7 @@ impl IntoResponse for ImageGenerationResponder {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
             }
             ImageGenerationResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+                JsonError::new(e.to_string()).to_response(validation_status(&*e))
             }
         }
     }
@@ -82,6 +83,8 @@ fn parse_request(
         tool_choice: None,
         tools: None,
         logits_processors: None,
+        priority: RequestPriority::Normal,
+        tenant: None,
     }))
 }
 
@@ -94,9 +97,13 @@ fn parse_request(
 )]
 
 pub async fn image_generation(
-    State(state): State<Arc<MistralRs>>,
+    State(router): State<Arc<ModelRouter>>,
     Json(oairequest): Json<ImageGenerationRequest>,
 ) -> ImageGenerationResponder {
+    let state = match router.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return ImageGenerationResponder::ValidationError(e.into()),
+    };
     let (tx, mut rx) = channel(10_000);
 
     let request = match parse_request(oairequest, state.clone(), tx) {
@@ -144,5 +151,6 @@ pub async fn image_generation(
         Response::Chunk(_) => unreachable!(),
         Response::Done(_) => unreachable!(),
         Response::ModelError(_, _) => unreachable!(),
+        Response::Embedding(_) => unreachable!(),
     }
 }
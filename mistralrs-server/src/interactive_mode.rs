@@ -2,8 +2,8 @@ use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
     Constraint, DiffusionGenerationParams, DrySamplingParams, ImageGenerationResponseFormat,
-    MessageContent, MistralRs, ModelCategory, NormalRequest, Request, RequestMessage, Response,
-    ResponseOk, SamplingParams, TERMINATE_ALL_NEXT_STEP,
+    MessageContent, MistralRs, ModelCategory, NormalRequest, Request, RequestMessage,
+    RequestPriority, Response, ResponseOk, SamplingParams, TERMINATE_ALL_NEXT_STEP,
 };
 use once_cell::sync::Lazy;
 use std::{
@@ -85,14 +85,23 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
+        top_a: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_penalty_last_n: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        mirostat: None,
+        xtc: None,
+        sampler_order: None,
+        seed: None,
+        beam_search: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -174,6 +183,8 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
         });
         sender.send(req).await.unwrap();
 
@@ -213,6 +224,7 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
             }
         }
         if throughput {
@@ -239,14 +251,23 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
+        top_a: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_penalty_last_n: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        mirostat: None,
+        xtc: None,
+        sampler_order: None,
+        seed: None,
+        beam_search: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -356,6 +377,8 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
         });
         sender.send(req).await.unwrap();
 
@@ -395,6 +418,7 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
             }
         }
         if throughput {
@@ -477,6 +501,8 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
         });
         sender.send(req).await.unwrap();
 
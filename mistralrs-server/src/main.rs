@@ -1,40 +1,56 @@
 use anyhow::Result;
 use axum::{
     extract::{DefaultBodyLimit, Json, State},
-    http::{self, Method},
+    http::{self, Method, StatusCode},
+    middleware,
+    response::IntoResponse,
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
 use candle_core::Device;
 use clap::Parser;
+use indexmap::IndexMap;
 use mistralrs_core::{
-    get_model_dtype, get_tgt_non_granular_index, initialize_logging, paged_attn_supported,
-    parse_isq_value, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata, IsqType,
-    Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected,
-    PagedAttentionConfig, Request, SchedulerConfig, TokenSource,
+    get_kv_cache_dtype, get_model_dtype, get_tgt_non_granular_index,
+    get_toml_selected_kv_cache_dtype, get_toml_selected_model_dtype, initialize_logging,
+    paged_attn_supported, parse_isq_value, render_prometheus, DefaultSchedulerMethod,
+    DeviceLayerMapMetadata, DeviceMapMetadata, IsqType, KvCacheDtype, Loader, LoaderBuilder,
+    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, ModelSelected, PagedAttentionConfig,
+    Request, SchedulerConfig, SpeculativeConfig, SpeculativeLoader, SpeculativeMethod, TokenSource,
+    TomlLoaderArgs, TomlSelector, ToolCallFormat,
 };
 use openai::{
-    ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, Message, ModelObjects,
-    StopTokens,
+    ApplyChatTemplateRequest, ChatCompletionRequest, CompletionRequest, DetokenizeRequest,
+    EmbeddingRequest, ImageGenerationRequest, Message, ModelObjects, StopTokens, TokenizeRequest,
 };
 use serde::{Deserialize, Serialize};
 use std::{num::NonZeroUsize, sync::Arc};
 
+mod auth;
+mod batch;
 mod chat_completion;
 mod completions;
+mod embeddings;
 mod image_generation;
 mod interactive_mode;
+mod multi_model;
 mod openai;
+mod tokenization;
 mod util;
 
 use crate::openai::ModelObject;
 use crate::{
     chat_completion::{__path_chatcompletions, chatcompletions},
     completions::completions,
+    embeddings::embeddings,
     image_generation::image_generation,
+    tokenization::{apply_chat_template, detokenize, tokenize},
 };
 
+use auth::ApiKeys;
+use batch::Batches;
 use interactive_mode::interactive_mode;
+use multi_model::{ModelConfig, ModelRouter, ModelStatus, MultiModelConfig};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{info, warn};
 use utoipa::{OpenApi, ToSchema};
@@ -48,6 +64,14 @@ fn parse_token_source(s: &str) -> Result<TokenSource, String> {
     s.parse()
 }
 
+fn parse_kv_cache_dtype(s: &str) -> Result<KvCacheDtype, String> {
+    s.parse()
+}
+
+fn parse_tool_call_format(s: &str) -> Result<ToolCallFormat, String> {
+    ToolCallFormat::from_name(s).map_err(|e| e.to_string())
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -75,7 +99,18 @@ struct Args {
 
     /// Model selector
     #[clap(subcommand)]
-    model: ModelSelected,
+    model: Option<ModelSelected>,
+
+    /// Serve several models described by this .toml file instead of one model. Requests are routed to a model by their `model` field.
+    /// The other options apply to each of the models.
+    #[arg(long = "multi-model")]
+    multi_model: Option<String>,
+
+    /// Require API keys, with their permissions and rate limits, described by this .toml file.
+    /// A key with all permissions and no limits may also be set with the `MISTRALRS_API_KEY` environment variable.
+    /// Without keys, all routes are open.
+    #[arg(long = "api-keys")]
+    api_keys: Option<String>,
 
     /// Maximum running sequences at any time. If the `tgt_non_granular_index` flag is set for X-LoRA models, this will be set to 1.
     #[arg(long, default_value_t = 16)]
@@ -115,30 +150,31 @@ struct Args {
     in_situ_quant: Option<IsqType>,
 
     /// GPU memory to allocate for KV cache with PagedAttention in MBs.
-    /// PagedAttention is only supported on CUDA and is always automatically activated.
+    /// PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa-gpu-mem`, `pa-gpu-mem-usage` or `pa-ctxt-len`.
     /// The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem")]
     paged_attn_gpu_mem: Option<usize>,
 
     /// Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
     /// If this is not set and the device is CUDA, it will default to `0.9`.
-    /// PagedAttention is only supported on CUDA and is always automatically activated.
+    /// PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa-gpu-mem`, `pa-gpu-mem-usage` or `pa-ctxt-len`.
     /// The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem-usage")]
     paged_attn_gpu_mem_usage: Option<f32>,
 
     /// Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold)
-    /// when using PagedAttention, which is only supported on CUDA and is always automatically activated.
+    /// when using PagedAttention.
+    /// PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa-gpu-mem`, `pa-gpu-mem-usage` or `pa-ctxt-len`.
     /// The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
     #[arg(long = "pa-ctxt-len")]
     paged_ctxt_len: Option<usize>,
 
     /// Block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA, it will default to 32.
-    /// PagedAttention is only supported on CUDA and is always automatically activated.
+    /// PagedAttention is always automatically activated on CUDA. On other devices, it is activated by setting the KV cache size with `pa-gpu-mem`, `pa-gpu-mem-usage` or `pa-ctxt-len`.
     #[arg(long = "pa-blk-size")]
     paged_attn_block_size: Option<usize>,
 
-    /// Disable PagedAttention on CUDA.
+    /// Disable PagedAttention.
     #[arg(long = "no-paged-attn", default_value_t = false)]
     no_paged_attn: bool,
 
@@ -149,6 +185,39 @@ struct Args {
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_batchsize: Option<usize>,
+
+    /// Quantize the KV cache to reduce its memory usage: `q8` for 8-bit integers with a scale per head, or `f8e4m3` for 8-bit floats.
+    /// Defaults to `auto`, which keeps the KV cache in the model dtype, unless the TOML selector sets `kv_cache_dtype`.
+    #[arg(long = "kv-cache-dtype", value_parser = parse_kv_cache_dtype)]
+    kv_cache_dtype: Option<KvCacheDtype>,
+
+    /// Use prompt lookup speculative decoding, which needs no draft model, proposing up to this many tokens per step.
+    /// The tokens are proposed by matching the last tokens of a sequence against its prompt and output. This sets `max_seqs` to 1.
+    /// Only applies to models selected on the command line, TOML selectors use `[speculative]` instead.
+    #[arg(long = "prompt-lookup-gamma")]
+    prompt_lookup_gamma: Option<usize>,
+
+    /// Maximum number of tokens which are matched by prompt lookup speculative decoding.
+    #[arg(long = "prompt-lookup-max-ngram-size", default_value_t = 3)]
+    prompt_lookup_max_ngram_size: usize,
+
+    /// Format in which the model emits tool calls: `json`, `mistral`, `llama3`, `hermes` or `qwen`.
+    /// Defaults to detecting the format from the chat template and the tokenizer.
+    #[arg(long = "tool-call-format", value_parser = parse_tool_call_format)]
+    tool_call_format: Option<ToolCallFormat>,
+
+    /// Directory in which the files of the batch API are stored. It is created when the first file is uploaded.
+    #[arg(long = "batch-dir", default_value = "mistralrs-batches")]
+    batch_dir: String,
+
+    /// Run the requests of this JSONL batch file and exit, instead of serving. Each line has the format of the
+    /// OpenAI batch API: `{"custom_id": ..., "method": "POST", "url": "/v1/chat/completions", "body": {...}}`.
+    #[arg(long = "batch-file", requires = "batch_output")]
+    batch_file: Option<String>,
+
+    /// File to which the results of `--batch-file` are written, in JSONL.
+    #[arg(long = "batch-output", requires = "batch_file")]
+    batch_output: Option<String>,
 }
 
 #[utoipa::path(
@@ -157,15 +226,19 @@ struct Args {
     path = "/v1/models",
     responses((status = 200, description = "Served model info", body = ModelObjects))
 )]
-async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
+async fn models(State(router): State<Arc<ModelRouter>>) -> Json<ModelObjects> {
     Json(ModelObjects {
         object: "list",
-        data: vec![ModelObject {
-            id: state.get_id(),
-            object: "model",
-            created: state.get_creation_time(),
-            owned_by: "local",
-        }],
+        data: router
+            .served_models()
+            .into_iter()
+            .map(|(id, state)| ModelObject {
+                id,
+                object: "model",
+                created: state.get_creation_time(),
+                owned_by: "local",
+            })
+            .collect(),
     })
 }
 
@@ -183,6 +256,9 @@ async fn health() -> &'static str {
 struct AdapterActivationRequest {
     #[schema(example = json!(vec!["adapter_1","adapter_2"]))]
     adapter_names: Vec<String>,
+    /// Model to activate the adapters of. Defaults to the default model.
+    #[serde(default)]
+    model: Option<String>,
 }
 
 #[utoipa::path(
@@ -193,20 +269,26 @@ struct AdapterActivationRequest {
     responses((status = 200, description = "Activate a set of pre-loaded LoRA adapters"))
 )]
 async fn activate_adapters(
-    State(state): State<Arc<MistralRs>>,
+    State(router): State<Arc<ModelRouter>>,
     Json(request): Json<AdapterActivationRequest>,
-) -> String {
+) -> Result<String, String> {
+    let state = router
+        .get(request.model.as_deref().unwrap_or_default())
+        .map_err(|e| e.to_string())?;
     let repr = format!("Adapter activation: {:?}", request.adapter_names);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
     let request = Request::ActivateAdapters(request.adapter_names);
     state.get_sender().unwrap().send(request).await.unwrap();
-    repr
+    Ok(repr)
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
     ggml_type: String,
+    /// Model to quantize. Defaults to the default model.
+    #[serde(default)]
+    model: Option<String>,
 }
 
 #[utoipa::path(
@@ -217,9 +299,12 @@ struct ReIsqRequest {
     responses((status = 200, description = "Reapply ISQ to a non GGUF or GGML model."))
 )]
 async fn re_isq(
-    State(state): State<Arc<MistralRs>>,
+    State(router): State<Arc<ModelRouter>>,
     Json(request): Json<ReIsqRequest>,
 ) -> Result<String, String> {
+    let state = router
+        .get(request.model.as_deref().unwrap_or_default())
+        .map_err(|e| e.to_string())?;
     let repr = format!("Re ISQ: {:?}", request.ggml_type);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
     let request = Request::ReIsq(parse_isq_value(&request.ggml_type)?);
@@ -227,12 +312,81 @@ async fn re_isq(
     Ok(repr)
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
+#[derive(Deserialize)]
+struct LoadModelRequest {
+    /// ID to serve the model as.
+    id: String,
+    /// The model, described like a TOML selector.
+    selector: TomlSelector,
+}
+
+async fn load_model_route(
+    State(router): State<Arc<ModelRouter>>,
+    Json(request): Json<LoadModelRequest>,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let repr = format!("Load model: {:?}", request.id);
+    router
+        .load(request.id, request.selector)
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+    Ok((StatusCode::ACCEPTED, repr))
+}
+
+#[derive(Deserialize)]
+struct UnloadModelRequest {
+    id: String,
+}
+
+async fn unload_model_route(
+    State(router): State<Arc<ModelRouter>>,
+    Json(request): Json<UnloadModelRequest>,
+) -> Result<String, (StatusCode, String)> {
+    let repr = format!("Unload model: {:?}", request.id);
+    router
+        .unload(&request.id)
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    Ok(repr)
+}
+
+#[derive(Serialize)]
+struct ModelStatusObject {
+    id: String,
+    #[serde(flatten)]
+    status: ModelStatus,
+}
+
+async fn model_status(State(router): State<Arc<ModelRouter>>) -> Json<Vec<ModelStatusObject>> {
+    Json(
+        router
+            .statuses()
+            .into_iter()
+            .map(|(id, status)| ModelStatusObject { id, status })
+            .collect(),
+    )
+}
+
+/// Metrics of the served models in the Prometheus text format.
+async fn metrics(State(router): State<Arc<ModelRouter>>) -> impl IntoResponse {
+    let metrics = router
+        .served_models()
+        .into_iter()
+        .map(|(id, mistralrs)| (id, mistralrs.metrics()))
+        .collect::<Vec<_>>();
+    (
+        [(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_prometheus(&metrics),
+    )
+}
+
+fn get_router(
+    router: Arc<ModelRouter>,
+    batches: Arc<Batches>,
+    api_keys: Option<Arc<ApiKeys>>,
+) -> Router {
     #[derive(OpenApi)]
     #[openapi(
         paths(models, health, chatcompletions),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, EmbeddingRequest, TokenizeRequest, DetokenizeRequest, ApplyChatTemplateRequest, StopTokens, Message)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -253,80 +407,179 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
         .allow_origin(allow_origin);
 
-    Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
+    let inference_routes = Router::new()
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
         .route("/v1/models", get(models))
-        .route("/health", get(health))
-        .route("/", get(health))
+        .route("/v1/images/generations", post(image_generation))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+        .route("/apply_chat_template", post(apply_chat_template))
+        .route("/v1/files", post(batch::upload_file).get(batch::list_files))
+        .route(
+            "/v1/files/:file_id",
+            get(batch::retrieve_file).delete(batch::delete_file),
+        )
+        .route("/v1/files/:file_id/content", get(batch::file_content))
+        .route(
+            "/v1/batches",
+            post(batch::create_batch).get(batch::list_batches),
+        )
+        .route("/v1/batches/:batch_id", get(batch::retrieve_batch))
+        .route("/v1/batches/:batch_id/cancel", post(batch::cancel_batch))
+        .layer(Extension(batches));
+    let admin_routes = Router::new()
         .route("/activate_adapters", post(activate_adapters))
         .route("/re_isq", post(re_isq))
-        .route("/v1/images/generations", post(image_generation))
+        .route("/load_model", post(load_model_route))
+        .route("/unload_model", post(unload_model_route))
+        .route("/model_status", get(model_status))
+        .route("/metrics", get(metrics));
+    let (inference_routes, admin_routes) = match api_keys {
+        Some(api_keys) => (
+            inference_routes.route_layer(middleware::from_fn_with_state(
+                api_keys.clone(),
+                auth::require_inference,
+            )),
+            admin_routes.route_layer(middleware::from_fn_with_state(
+                api_keys,
+                auth::require_admin,
+            )),
+        ),
+        None => (inference_routes, admin_routes),
+    };
+
+    Router::new()
+        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
+        .route("/health", get(health))
+        .route("/", get(health))
+        .merge(inference_routes)
+        .merge(admin_routes)
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
-        .with_state(state)
+        .with_state(router)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut args = Args::parse();
-    initialize_logging();
-
-    #[cfg(not(feature = "flash-attn"))]
-    let use_flash_attn = false;
-    #[cfg(feature = "flash-attn")]
-    let use_flash_attn = true;
-
-    let tgt_non_granular_index = get_tgt_non_granular_index(&args.model);
-    let dtype = get_model_dtype(&args.model)?;
-
-    if tgt_non_granular_index.is_some() {
-        args.max_seqs = 1;
-    }
-
-    let prompt_batchsize = match args.prompt_batchsize {
+fn get_prompt_batchsize(args: &Args) -> Result<Option<NonZeroUsize>> {
+    match args.prompt_batchsize {
         Some(0) => {
             anyhow::bail!("`prompt_batchsize` must be a strictly positive integer, got 0.",)
         }
-        Some(x) => Some(NonZeroUsize::new(x).unwrap()),
-        None => None,
-    };
-
-    let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
-        .with_no_kv_cache(args.no_kv_cache)
-        .with_chat_template(args.chat_template)
-        .with_use_flash_attn(use_flash_attn)
-        .with_prompt_batchsize(prompt_batchsize)
-        .build()?;
+        Some(x) => Ok(Some(NonZeroUsize::new(x).unwrap())),
+        None => Ok(None),
+    }
+}
 
-    #[cfg(feature = "metal")]
-    let device = Device::new_metal(0)?;
-    #[cfg(not(feature = "metal"))]
-    let device = Device::cuda_if_available(0)?;
+/// A model to load, with the settings derived from its selector.
+struct SelectedModel {
+    loader: Box<dyn Loader>,
+    dtype: ModelDType,
+    kv_cache_dtype: KvCacheDtype,
+    max_seqs: usize,
+}
 
-    if let Some(seed) = args.seed {
-        device.set_seed(seed)?;
+impl SelectedModel {
+    fn from_model_selected(
+        args: &Args,
+        model: ModelSelected,
+        use_flash_attn: bool,
+    ) -> Result<Self> {
+        let tgt_non_granular_index = get_tgt_non_granular_index(&model);
+        let dtype = get_model_dtype(&model)?;
+        let kv_cache_dtype = match args.kv_cache_dtype {
+            Some(kv_cache_dtype) => kv_cache_dtype,
+            None => get_kv_cache_dtype(&model)?,
+        };
+
+        let max_seqs = if tgt_non_granular_index.is_some() || args.prompt_lookup_gamma.is_some() {
+            1
+        } else {
+            args.max_seqs
+        };
+
+        let loader: Box<dyn Loader> = LoaderBuilder::new(model)
+            .with_no_kv_cache(args.no_kv_cache)
+            .with_chat_template(args.chat_template.clone())
+            .with_use_flash_attn(use_flash_attn)
+            .with_prompt_batchsize(get_prompt_batchsize(args)?)
+            .build()?;
+        let loader: Box<dyn Loader> = match args.prompt_lookup_gamma {
+            Some(0) => anyhow::bail!("`prompt_lookup_gamma` must be a strictly positive integer."),
+            Some(gamma) => Box::new(SpeculativeLoader {
+                target: loader,
+                draft: None,
+                config: SpeculativeConfig {
+                    gamma,
+                    method: SpeculativeMethod::PromptLookup {
+                        max_ngram_size: args.prompt_lookup_max_ngram_size,
+                    },
+                    adaptive_gamma: None,
+                },
+            }),
+            None => loader,
+        };
+
+        Ok(Self {
+            loader,
+            dtype,
+            kv_cache_dtype,
+            max_seqs,
+        })
     }
 
-    info!(
-        "avx: {}, neon: {}, simd128: {}, f16c: {}",
-        candle_core::utils::with_avx(),
-        candle_core::utils::with_neon(),
-        candle_core::utils::with_simd128(),
-        candle_core::utils::with_f16c()
-    );
-    info!("Sampling method: penalties -> temperature -> topk -> topp -> minp -> multinomial");
-    if use_flash_attn {
-        info!("Using flash attention.");
+    fn from_toml_selector(
+        args: &Args,
+        selector: TomlSelector,
+        use_flash_attn: bool,
+    ) -> Result<Self> {
+        let dtype = get_toml_selected_model_dtype(&selector);
+        let kv_cache_dtype = match args.kv_cache_dtype {
+            Some(kv_cache_dtype) => kv_cache_dtype,
+            None => get_toml_selected_kv_cache_dtype(&selector),
+        };
+
+        let loader: Box<dyn Loader> = (
+            selector,
+            TomlLoaderArgs {
+                use_flash_attn,
+                chat_template: args.chat_template.clone(),
+                no_kv_cache: args.no_kv_cache,
+                prompt_batchsize: get_prompt_batchsize(args)?,
+            },
+        )
+            .try_into()?;
+
+        Ok(Self {
+            loader,
+            dtype,
+            kv_cache_dtype,
+            max_seqs: args.max_seqs,
+        })
     }
+}
+
+/// Load a model, returning the builder for its engine.
+async fn load_model(
+    args: &Args,
+    model: SelectedModel,
+    device: &Device,
+    use_flash_attn: bool,
+) -> Result<MistralRsBuilder> {
+    let SelectedModel {
+        loader,
+        dtype,
+        kv_cache_dtype,
+        max_seqs,
+    } = model;
+
     if use_flash_attn && loader.get_kind().is_quantized() {
         warn!("Using flash attention with a quantized model has no effect!")
     }
     info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
-    let mapper = if let Some(device_layers) = args.num_device_layers {
+    let mapper = if let Some(device_layers) = args.num_device_layers.clone() {
         if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
             let layers = device_layers[0].parse::<usize>().unwrap();
             DeviceMapMetadata::from_num_device_layers(vec![DeviceLayerMapMetadata {
@@ -372,11 +625,13 @@ async fn main() -> Result<()> {
         paged_attn_supported(),
         args.no_paged_attn,
     ) {
-        (block_size, None, None, None, true, false) => Some(PagedAttentionConfig::new(
-            block_size,
-            512,
-            MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
-        )?),
+        (block_size, None, None, None, true, false) if device.is_cuda() => {
+            Some(PagedAttentionConfig::new(
+                block_size,
+                512,
+                MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
+            )?)
+        }
         (block_size, None, None, Some(ctxt), true, false) => Some(PagedAttentionConfig::new(
             block_size,
             512,
@@ -417,13 +672,14 @@ async fn main() -> Result<()> {
             )?)
         }
         (_, _, _, _, _, _) => None,
-    };
+    }
+    .map(|cache_config| cache_config.with_kv_cache_dtype(kv_cache_dtype));
 
     let pipeline = loader.load_model_from_hf(
         None,
-        args.token_source,
+        args.token_source.clone(),
         &dtype,
-        &device,
+        device,
         false,
         mapper,
         args.in_situ_quant,
@@ -435,43 +691,162 @@ async fn main() -> Result<()> {
         // Handle case where we may have device mapping
         if let Some(ref cache_config) = pipeline.lock().await.get_metadata().cache_config {
             SchedulerConfig::PagedAttentionMeta {
-                max_num_seqs: args.max_seqs,
+                max_num_seqs: max_seqs,
                 config: cache_config.clone(),
             }
         } else {
             SchedulerConfig::DefaultScheduler {
-                method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+                method: DefaultSchedulerMethod::Fixed(max_seqs.try_into().unwrap()),
             }
         }
     } else {
         SchedulerConfig::DefaultScheduler {
-            method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+            method: DefaultSchedulerMethod::Fixed(max_seqs.try_into().unwrap()),
         }
     };
-    // Throughput logging in the server
     let builder = MistralRsBuilder::new(pipeline, scheduler_config)
-        .with_opt_log(args.log)
+        .with_opt_log(args.log.clone())
         .with_truncate_sequence(args.truncate_sequence)
         .with_no_kv_cache(args.no_kv_cache)
-        .with_prefix_cache_n(args.prefix_cache_n);
+        .with_prefix_cache_n(args.prefix_cache_n)
+        .with_kv_cache_dtype(kv_cache_dtype);
+    let builder = match args.tool_call_format {
+        Some(tool_call_format) => builder.with_tool_call_format(tool_call_format),
+        None => builder,
+    };
 
-    if args.interactive_mode {
-        interactive_mode(builder.build(), args.throughput_log).await;
-        return Ok(());
+    Ok(builder)
+}
+
+/// Loads models while the server is running, with the options of the server.
+pub struct ModelLoader {
+    args: Args,
+    device: Device,
+    use_flash_attn: bool,
+}
+
+impl ModelLoader {
+    fn finish(&self, builder: MistralRsBuilder) -> Arc<MistralRs> {
+        let builder = if self.args.throughput_log {
+            builder.with_throughput_logging()
+        } else {
+            builder
+        };
+        builder.build()
     }
 
-    let builder = if args.throughput_log {
-        builder.with_throughput_logging()
-    } else {
-        builder
+    pub async fn load(&self, selector: TomlSelector) -> Result<Arc<MistralRs>> {
+        let model = SelectedModel::from_toml_selector(&self.args, selector, self.use_flash_attn)?;
+        let builder = load_model(&self.args, model, &self.device, self.use_flash_attn).await?;
+        Ok(self.finish(builder))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = Args::parse();
+    initialize_logging();
+
+    #[cfg(not(feature = "flash-attn"))]
+    let use_flash_attn = false;
+    #[cfg(feature = "flash-attn")]
+    let use_flash_attn = true;
+
+    #[cfg(feature = "metal")]
+    let device = Device::new_metal(0)?;
+    #[cfg(not(feature = "metal"))]
+    let device = Device::cuda_if_available(0)?;
+
+    if let Some(seed) = args.seed {
+        device.set_seed(seed)?;
+    }
+
+    info!(
+        "avx: {}, neon: {}, simd128: {}, f16c: {}",
+        candle_core::utils::with_avx(),
+        candle_core::utils::with_neon(),
+        candle_core::utils::with_simd128(),
+        candle_core::utils::with_f16c()
+    );
+    info!("Sampling method: penalties -> temperature -> topk -> topp -> minp -> multinomial");
+    if use_flash_attn {
+        info!("Using flash attention.");
+    }
+
+    let port = args.port.clone();
+    let serve_ip = args.serve_ip.clone();
+    let batch_dir = args.batch_dir.clone();
+    let batch_file = args.batch_file.clone().zip(args.batch_output.clone());
+    let api_keys = ApiKeys::load(args.api_keys.as_deref())?;
+    let router = match (args.model.take(), args.multi_model.clone()) {
+        (Some(model), None) => {
+            let model = SelectedModel::from_model_selected(&args, model, use_flash_attn)?;
+            let builder = load_model(&args, model, &device, use_flash_attn).await?;
+
+            if args.interactive_mode {
+                interactive_mode(builder.build(), args.throughput_log).await;
+                return Ok(());
+            }
+
+            let loader = ModelLoader {
+                args,
+                device,
+                use_flash_attn,
+            };
+            ModelRouter::single(loader.finish(builder), loader)
+        }
+        (None, Some(file)) => {
+            if args.interactive_mode {
+                anyhow::bail!("Interactive mode does not support serving several models.");
+            }
+            let config = MultiModelConfig::from_file(&file)?;
+            let default = config.default_id().to_string();
+
+            let mut builders = IndexMap::new();
+            for ModelConfig { id, file } in config.models() {
+                info!("Loading model `{id}` from `{file}`.");
+                let model = SelectedModel::from_model_selected(
+                    &args,
+                    ModelSelected::Toml { file },
+                    use_flash_attn,
+                )?;
+                builders.insert(id, load_model(&args, model, &device, use_flash_attn).await?);
+            }
+
+            let loader = ModelLoader {
+                args,
+                device,
+                use_flash_attn,
+            };
+            let models = builders
+                .into_iter()
+                .map(|(id, builder)| (id, loader.finish(builder)))
+                .collect();
+            ModelRouter::new(models, default, loader)
+        }
+        (Some(_), Some(_)) => {
+            anyhow::bail!("A model and a multi-model config were both specified, expected one.")
+        }
+        (None, None) => anyhow::bail!(
+            "No model was specified. Select a model or a multi-model config with `--multi-model`."
+        ),
     };
-    let mistralrs = builder.build();
+    let router = Arc::new(router);
 
-    let port = args.port.expect("Interactive mode was not specified, so expected port to be specified. Perhaps you forgot `-i` or `--port`?");
+    if let Some((input, output)) = batch_file {
+        batch::run_batch_file(router, input.as_ref(), output.as_ref()).await?;
+        return Ok(());
+    }
 
-    let app = get_router(mistralrs);
+    let port = port.expect("Interactive mode was not specified, so expected port to be specified. Perhaps you forgot `-i` or `--port`?");
+
+    if api_keys.is_some() {
+        info!("API keys are required.");
+    }
+    let batches = Arc::new(Batches::new(batch_dir)?);
+    let app = get_router(router, batches, api_keys);
 
-    let ip = if let Some(ref ip) = args.serve_ip {
+    let ip = if let Some(ref ip) = serve_ip {
         ip.to_string()
     } else {
         "0.0.0.0".to_string()
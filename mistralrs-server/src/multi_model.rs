@@ -0,0 +1,433 @@
+//! Serving several models from one server. Requests are routed to a model by their `model` field,
+//! and models may be loaded and unloaded while the server is running.
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt, fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard,
+    },
+};
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use indexmap::IndexMap;
+use mistralrs_core::{MistralRs, TomlSelector};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use tracing::{info, warn};
+
+use crate::{openai::BadRequest, ModelLoader};
+
+/// The `model` field which OpenAI clients send when no model is chosen.
+const DEFAULT_MODEL_ID: &str = "default";
+
+/// A model served by the server.
+#[derive(Debug, Deserialize)]
+pub struct ModelConfig {
+    /// ID of the model in the `model` field of requests and in `/v1/models`.
+    pub id: String,
+    /// Path to the TOML selector file describing the model.
+    pub file: String,
+}
+
+/// Configuration of the models served by one server, loaded with `--multi-model`.
+#[derive(Debug, Deserialize)]
+pub struct MultiModelConfig {
+    /// ID of the model which serves requests with the `default` model or an empty one. Defaults
+    /// to the first model.
+    default: Option<String>,
+    /// The served models.
+    models: Vec<ModelConfig>,
+}
+
+impl MultiModelConfig {
+    pub fn from_file(file: &str) -> Result<Self> {
+        let config = fs::read_to_string(file)
+            .map_err(|e| anyhow::anyhow!("Could not load multi-model config at {file}: {e}"))?;
+        Self::from_toml(&config)
+    }
+
+    fn from_toml(config: &str) -> Result<Self> {
+        let config: Self = toml::from_str(config)?;
+        if config.models.is_empty() {
+            anyhow::bail!("The multi-model config must contain at least one model.");
+        }
+        let mut ids = HashSet::new();
+        for ModelConfig { id, .. } in &config.models {
+            if !ids.insert(id) {
+                anyhow::bail!("Model ID `{id}` is used by several models.");
+            }
+        }
+        if let Some(default) = &config.default {
+            if !ids.contains(default) {
+                anyhow::bail!("The default model `{default}` is not one of the models.");
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn default_id(&self) -> &str {
+        self.default.as_deref().unwrap_or(&self.models[0].id)
+    }
+
+    pub fn models(self) -> Vec<ModelConfig> {
+        self.models
+    }
+}
+
+/// Status of a model which is loaded or being loaded.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status")]
+pub enum ModelStatus {
+    #[serde(rename = "loading")]
+    Loading,
+    #[serde(rename = "loaded")]
+    Loaded,
+    #[serde(rename = "failed")]
+    Failed { error: String },
+}
+
+/// Why a request could not be routed to a model.
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    /// The model is not served. Contains the served models.
+    NotFound { model: String, served: Vec<String> },
+    /// The model is being loaded.
+    Loading { model: String },
+}
+
+impl RouteError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound { .. } => StatusCode::NOT_FOUND,
+            Self::Loading { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { model, served } => write!(
+                f,
+                "The model `{model}` does not exist. Served models: {}.",
+                served
+                    .iter()
+                    .map(|id| format!("`{id}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::Loading { model } => write!(
+                f,
+                "The model `{model}` is being loaded. Retry once `/model_status` reports it as loaded."
+            ),
+        }
+    }
+}
+
+impl Error for RouteError {}
+
+/// The status of a validation error: 404 or 503 if the model of the request is not served, 400 if
+/// the request is malformed, and 422 otherwise.
+pub fn validation_status(e: &(dyn Error + 'static)) -> StatusCode {
+    if let Some(e) = e.downcast_ref::<RouteError>() {
+        e.status()
+    } else if e.is::<BadRequest>() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    }
+}
+
+/// Routes requests to the served models by their ID. Models may be loaded and unloaded while the
+/// server is running.
+pub struct ModelRouter {
+    models: RwLock<IndexMap<String, Arc<MistralRs>>>,
+    default: String,
+    /// Models which are being loaded or failed to load, by their ID.
+    loads: Mutex<IndexMap<String, ModelStatus>>,
+    /// Whether the default model serves requests for any model. This is only the case while it
+    /// is the only model the server has served, so that a request for another model is never
+    /// answered by the wrong one.
+    any_model: AtomicBool,
+    loader: ModelLoader,
+}
+
+impl ModelRouter {
+    /// `default` must be one of the `models`.
+    pub fn new(
+        models: IndexMap<String, Arc<MistralRs>>,
+        default: String,
+        loader: ModelLoader,
+    ) -> Self {
+        assert!(models.contains_key(&default));
+        Self {
+            models: RwLock::new(models),
+            default,
+            loads: Mutex::new(IndexMap::new()),
+            any_model: AtomicBool::new(false),
+            loader,
+        }
+    }
+
+    /// Serve one model, which handles all requests regardless of their `model` field until
+    /// another model is loaded.
+    pub fn single(mistralrs: Arc<MistralRs>, loader: ModelLoader) -> Self {
+        let id = mistralrs.get_id();
+        let router = Self::new(IndexMap::from([(id.clone(), mistralrs)]), id, loader);
+        router.any_model.store(true, Ordering::Relaxed);
+        router
+    }
+
+    fn models(&self) -> RwLockReadGuard<'_, IndexMap<String, Arc<MistralRs>>> {
+        self.models.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn loads(&self) -> MutexGuard<'_, IndexMap<String, ModelStatus>> {
+        self.loads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The model which serves a request with this `model` field.
+    pub fn get(&self, model: &str) -> Result<Arc<MistralRs>, RouteError> {
+        // Lock the loads first, in the same order as `load`
+        let loads = self.loads();
+        let models = self.models();
+        let id = resolve_id(
+            models.keys(),
+            &loads,
+            &self.default,
+            model,
+            self.any_model.load(Ordering::Relaxed),
+        )?;
+        Ok(models[id].clone())
+    }
+
+    /// The served models with their IDs, in the order they were loaded.
+    pub fn served_models(&self) -> Vec<(String, Arc<MistralRs>)> {
+        self.models()
+            .iter()
+            .map(|(id, mistralrs)| (id.clone(), mistralrs.clone()))
+            .collect()
+    }
+
+    /// The status of the served models followed by the models which are being loaded or failed
+    /// to load.
+    pub fn statuses(&self) -> Vec<(String, ModelStatus)> {
+        let mut statuses = self
+            .models()
+            .keys()
+            .map(|id| (id.clone(), ModelStatus::Loaded))
+            .collect::<Vec<_>>();
+        statuses.extend(
+            self.loads()
+                .iter()
+                .map(|(id, status)| (id.clone(), status.clone())),
+        );
+        statuses
+    }
+
+    /// Load a model in the background, serving it once it is loaded.
+    pub fn load(self: &Arc<Self>, id: String, selector: TomlSelector) -> Result<()> {
+        {
+            // Lock the loads first so that a model cannot be loaded twice
+            let mut loads = self.loads();
+            if self.models().contains_key(&id) {
+                anyhow::bail!("The model `{id}` is already served.");
+            }
+            if matches!(loads.get(&id), Some(ModelStatus::Loading)) {
+                anyhow::bail!("The model `{id}` is already being loaded.");
+            }
+            loads.insert(id.clone(), ModelStatus::Loading);
+            self.any_model.store(false, Ordering::Relaxed);
+        }
+
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            info!("Loading model `{id}`.");
+            let result = Handle::current().block_on(this.loader.load(selector));
+            let mut loads = this.loads();
+            match result {
+                Ok(mistralrs) => {
+                    info!("Model `{id}` loaded.");
+                    loads.shift_remove(&id);
+                    this.models
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .insert(id, mistralrs);
+                }
+                Err(e) => {
+                    warn!("Failed to load model `{id}`: {e}");
+                    loads.insert(
+                        id,
+                        ModelStatus::Failed {
+                            error: e.to_string(),
+                        },
+                    );
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Stop serving a model. Its engine stops and its memory is freed once the requests which are
+    /// being processed by it finish. The default model cannot be unloaded.
+    pub fn unload(&self, id: &str) -> Result<()> {
+        if id == self.default {
+            anyhow::bail!("The default model `{id}` cannot be unloaded.");
+        }
+        let mut loads = self.loads();
+        if matches!(loads.get(id), Some(ModelStatus::Loading)) {
+            anyhow::bail!("The model `{id}` is being loaded.");
+        }
+        let removed = self
+            .models
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .shift_remove(id);
+        // Unloading a model which failed to load clears its status
+        let failed = loads.shift_remove(id);
+        if removed.is_none() && failed.is_none() {
+            anyhow::bail!("The model `{id}` does not exist.");
+        }
+        if removed.is_some() {
+            info!("Model `{id}` unloaded.");
+        }
+        Ok(())
+    }
+}
+
+/// Find the ID of the served model for a request with this `model` field. An empty `model` or
+/// `default` is served by the default model. With `any_model`, any other model which is not being
+/// loaded is served by the default model too.
+fn resolve_id<'a>(
+    ids: impl Iterator<Item = &'a String> + Clone,
+    loads: &IndexMap<String, ModelStatus>,
+    default: &'a str,
+    model: &str,
+    any_model: bool,
+) -> Result<&'a str, RouteError> {
+    if let Some(id) = ids.clone().find(|id| *id == model) {
+        return Ok(id.as_str());
+    }
+    if matches!(loads.get(model), Some(ModelStatus::Loading)) {
+        return Err(RouteError::Loading {
+            model: model.to_string(),
+        });
+    }
+    if model.is_empty() || model == DEFAULT_MODEL_ID || any_model {
+        return Ok(default);
+    }
+    Err(RouteError::NotFound {
+        model: model.to_string(),
+        served: ids.map(String::clone).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use indexmap::IndexMap;
+
+    use super::{resolve_id, ModelStatus, MultiModelConfig};
+
+    #[test]
+    fn test_config() {
+        let config = MultiModelConfig::from_toml(
+            r#"
+            [[models]]
+            id = "chat"
+            file = "chat.toml"
+
+            [[models]]
+            id = "flux"
+            file = "flux.toml"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.default_id(), "chat");
+        assert_eq!(config.models().len(), 2);
+
+        let config = MultiModelConfig::from_toml(
+            r#"
+            default = "flux"
+            [[models]]
+            id = "chat"
+            file = "chat.toml"
+
+            [[models]]
+            id = "flux"
+            file = "flux.toml"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.default_id(), "flux");
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(MultiModelConfig::from_toml("models = []").is_err());
+        assert!(MultiModelConfig::from_toml(
+            r#"
+            [[models]]
+            id = "chat"
+            file = "a.toml"
+
+            [[models]]
+            id = "chat"
+            file = "b.toml"
+            "#,
+        )
+        .is_err());
+        assert!(MultiModelConfig::from_toml(
+            r#"
+            default = "vision"
+            [[models]]
+            id = "chat"
+            file = "chat.toml"
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_resolve_id() {
+        let ids = ["chat".to_string(), "flux".to_string()];
+        let loads = IndexMap::new();
+        let resolve = |model| resolve_id(ids.iter(), &loads, "chat", model, false);
+        assert_eq!(resolve("flux"), Ok("flux"));
+        assert_eq!(resolve("default"), Ok("chat"));
+        assert_eq!(resolve(""), Ok("chat"));
+        let error = resolve("mistral").unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+
+        // A server which only ever served one model answers requests for any model
+        let ids = ["chat".to_string()];
+        assert_eq!(
+            resolve_id(ids.iter(), &loads, "chat", "mistral", true),
+            Ok("chat")
+        );
+    }
+
+    #[test]
+    fn test_resolve_loading_model() {
+        // `chat` is served while `vision` is being loaded
+        let ids = ["chat".to_string()];
+        let loads = IndexMap::from([("vision".to_string(), ModelStatus::Loading)]);
+        for any_model in [false, true] {
+            let error = resolve_id(ids.iter(), &loads, "chat", "vision", any_model).unwrap_err();
+            assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert_eq!(
+            resolve_id(ids.iter(), &loads, "chat", "", false),
+            Ok("chat")
+        );
+
+        // Once `vision` was unloaded, requests for it are not answered by `chat`
+        let loads = IndexMap::new();
+        let error = resolve_id(ids.iter(), &loads, "chat", "vision", false).unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+    }
+}
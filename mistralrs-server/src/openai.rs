@@ -1,7 +1,10 @@
 use either::Either;
-use mistralrs_core::{ImageGenerationResponseFormat, Tool, ToolChoice};
+use mistralrs_core::{
+    json_schema_to_yacc, Constraint, EmbeddingPooling, ImageGenerationResponseFormat,
+    RequestPriority, SamplerStage, Tool, ToolChoice,
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, ops::Deref};
+use std::{collections::HashMap, error::Error, fmt, ops::Deref};
 use utoipa::ToSchema;
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -67,6 +70,10 @@ fn default_response_format() -> ImageGenerationResponseFormat {
     ImageGenerationResponseFormat::Url
 }
 
+fn default_true() -> bool {
+    true
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(tag = "type", content = "value")]
 pub enum Grammar {
@@ -74,6 +81,79 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    #[serde(rename = "json_schema")]
+    JsonSchema(serde_json::Value),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct JsonSchemaResponseFormat {
+    pub name: String,
+    pub description: Option<String>,
+    pub schema: serde_json::Value,
+    pub strict: Option<bool>,
+}
+
+/// OpenAI `response_format`. The JSON formats are enforced with a grammar constraint.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponseFormat {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "json_object")]
+    JsonObject,
+    #[serde(rename = "json_schema")]
+    JsonSchema {
+        json_schema: JsonSchemaResponseFormat,
+    },
+}
+
+impl ResponseFormat {
+    /// Whether the output is constrained to JSON.
+    pub fn is_json(&self) -> bool {
+        !matches!(self, Self::Text)
+    }
+}
+
+/// A malformed request, which is answered with 400 Bad Request.
+#[derive(Debug)]
+pub struct BadRequest(pub String);
+
+impl fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for BadRequest {}
+
+/// The constraint of a request from its `grammar` and `response_format`, which cannot both be
+/// given. JSON schemas are compiled here, so that unsupported schemas are rejected before the
+/// request is sent to the model.
+pub fn output_constraint(
+    grammar: Option<Grammar>,
+    response_format: Option<ResponseFormat>,
+) -> Result<Constraint, BadRequest> {
+    let constraint = match (grammar, response_format) {
+        (Some(_), Some(format)) if format.is_json() => {
+            return Err(BadRequest(
+                "`grammar` cannot be specified together with a JSON `response_format`.".to_string(),
+            ))
+        }
+        (Some(Grammar::Yacc(yacc)), _) => Constraint::Yacc(yacc),
+        (Some(Grammar::Regex(regex)), _) => Constraint::Regex(regex),
+        (Some(Grammar::JsonSchema(schema)), _) => Constraint::JsonSchema(schema),
+        (None, Some(ResponseFormat::JsonObject)) => {
+            Constraint::JsonSchema(serde_json::json!({"type": "object"}))
+        }
+        (None, Some(ResponseFormat::JsonSchema { json_schema })) => {
+            Constraint::JsonSchema(json_schema.schema)
+        }
+        (None, Some(ResponseFormat::Text) | None) => Constraint::None,
+    };
+    if let Constraint::JsonSchema(schema) = &constraint {
+        json_schema_to_yacc(schema).map_err(|e| BadRequest(format!("Invalid JSON schema: {e}")))?;
+    }
+    Ok(constraint)
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -114,6 +194,18 @@ pub struct ChatCompletionRequest {
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    /// Priority class in the scheduler: `low`, `normal` (default) or `high`.
+    #[serde(default)]
+    #[schema(example = json!(Option::None::<RequestPriority>))]
+    pub priority: RequestPriority,
+    /// The end user of the request, whose sequences the scheduler shares fairly with those of
+    /// other users.
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
@@ -132,6 +224,30 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repetition_penalty_last_n: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    #[schema(example = json!(Option::None::<Vec<SamplerStage>>))]
+    pub sampler_order: Option<Vec<SamplerStage>>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub beam_width: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -186,12 +302,22 @@ pub struct CompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
-    #[serde(rename = "user")]
-    pub _user: Option<String>,
+    /// The end user of the request, whose sequences the scheduler shares fairly with those of
+    /// other users.
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+    /// Priority class in the scheduler: `low`, `normal` (default) or `high`.
+    #[serde(default)]
+    #[schema(example = json!(Option::None::<RequestPriority>))]
+    pub priority: RequestPriority,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
@@ -210,6 +336,34 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repetition_penalty_last_n: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    #[schema(example = json!(Option::None::<Vec<SamplerStage>>))]
+    pub sampler_order: Option<Vec<SamplerStage>>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub beam_width: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub prompt_logprobs: Option<usize>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub continuation: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -232,3 +386,190 @@ pub struct ImageGenerationRequest {
     #[schema(example = 1280)]
     pub width: usize,
 }
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Multi(Vec<String>),
+    Tokens(Vec<u32>),
+    MultiTokens(Vec<Vec<u32>>),
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+pub enum EmbeddingEncodingFormat {
+    #[default]
+    #[serde(rename = "float")]
+    Float,
+    /// Little-endian 32-bit floats encoded in base64
+    #[serde(rename = "base64")]
+    Base64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "The food was delicious and the waiter was friendly.")]
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub encoding_format: EmbeddingEncodingFormat,
+    /// How the hidden states of the tokens are pooled: `last` (default), `mean` or `cls`.
+    #[serde(default)]
+    pub pooling: EmbeddingPooling,
+    /// Normalize the embeddings to unit length.
+    #[serde(default = "default_true")]
+    pub normalize: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingData {
+    pub object: &'static str,
+    pub embedding: EmbeddingVector,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingResponse {
+    pub object: &'static str,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct TokenizeRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    /// Messages to render with the chat template and tokenize. Exactly one of `messages` and
+    /// `prompt` must be given.
+    pub messages: Option<Vec<Message>>,
+    /// A raw prompt to tokenize.
+    #[schema(example = json!(Option::None::<String>))]
+    pub prompt: Option<String>,
+    /// Tools to render with the messages.
+    #[schema(example = json!(Option::None::<Vec<Tool>>))]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default = "default_true")]
+    pub add_generation_prompt: bool,
+    /// Add the special tokens of the tokenizer to a prompt. The chat template adds them to
+    /// messages.
+    #[serde(default = "default_true")]
+    pub add_special_tokens: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenizeResponse {
+    pub model: String,
+    pub tokens: Vec<u32>,
+    pub count: usize,
+    /// The tokenized prompt: the messages rendered with the chat template, or the raw prompt.
+    pub prompt: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DetokenizeRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    pub tokens: Vec<u32>,
+    #[serde(default = "default_false")]
+    pub skip_special_tokens: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DetokenizeResponse {
+    pub model: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ApplyChatTemplateRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[schema(example = json!(Option::None::<Vec<Tool>>))]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default = "default_true")]
+    pub add_generation_prompt: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApplyChatTemplateResponse {
+    pub model: String,
+    pub prompt: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::Constraint;
+    use serde_json::json;
+
+    use super::{output_constraint, Grammar, JsonSchemaResponseFormat, ResponseFormat};
+
+    fn json_schema(schema: serde_json::Value) -> Option<ResponseFormat> {
+        Some(ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaResponseFormat {
+                name: "output".to_string(),
+                description: None,
+                schema,
+                strict: None,
+            },
+        })
+    }
+
+    #[test]
+    fn test_output_constraint() {
+        let schema = json!({"type": "object", "properties": {"x": {"type": "integer"}}});
+        assert!(matches!(
+            output_constraint(None, json_schema(schema.clone())),
+            Ok(Constraint::JsonSchema(s)) if s == schema
+        ));
+        assert!(matches!(
+            output_constraint(
+                Some(Grammar::Regex("a+".to_string())),
+                Some(ResponseFormat::Text)
+            ),
+            Ok(Constraint::Regex(_))
+        ));
+        assert!(matches!(
+            output_constraint(None, Some(ResponseFormat::JsonObject)),
+            Ok(Constraint::JsonSchema(_))
+        ));
+        assert!(matches!(
+            output_constraint(None, None),
+            Ok(Constraint::None)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_output_constraint() {
+        let grammar = Some(Grammar::Regex("a+".to_string()));
+        assert!(output_constraint(grammar, Some(ResponseFormat::JsonObject)).is_err());
+        let err = output_constraint(
+            None,
+            json_schema(json!({"type": "string", "pattern": "a+"})),
+        )
+        .err()
+        .unwrap();
+        assert!(err.0.contains("pattern"));
+        let grammar = Some(Grammar::JsonSchema(json!({"type": "unknown"})));
+        assert!(output_constraint(grammar, None).is_err());
+    }
+}
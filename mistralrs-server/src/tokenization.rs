@@ -0,0 +1,241 @@
+use anyhow::Result;
+use either::Either;
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::channel;
+
+use crate::{
+    chat_completion::parse_messages,
+    multi_model::{validation_status, ModelRouter},
+    openai::{
+        ApplyChatTemplateRequest, ApplyChatTemplateResponse, DetokenizeRequest, DetokenizeResponse,
+        Message, TokenizeRequest, TokenizeResponse,
+    },
+};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    DetokenizationRequest, MistralRs, Request, Tokenization, TokenizationRequest, Tool,
+};
+use serde::Serialize;
+
+pub enum TokenizationResponder<T> {
+    Json(T),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl<T: Serialize> IntoResponse for TokenizationResponder<T> {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            TokenizationResponder::Json(s) => Json(s).into_response(),
+            TokenizationResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            TokenizationResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(validation_status(&*e))
+            }
+        }
+    }
+}
+
+/// Tokenize a prompt, or messages rendered with the chat template, with the engine of the model.
+/// Messages with images are rejected by the engine.
+async fn tokenize_with<T>(
+    state: Arc<MistralRs>,
+    text: Either<Vec<Message>, String>,
+    tools: Option<Vec<Tool>>,
+    add_generation_prompt: bool,
+    add_special_tokens: bool,
+) -> Result<Tokenization, TokenizationResponder<T>> {
+    let text = match text {
+        Either::Left(messages) => match parse_messages(messages) {
+            Ok((messages, _)) => Either::Left(messages),
+            Err(e) => return Err(TokenizationResponder::ValidationError(e.into())),
+        },
+        Either::Right(prompt) => Either::Right(prompt),
+    };
+
+    let (tx, mut rx) = channel(1);
+    let request = Request::Tokenize(TokenizationRequest {
+        text,
+        tools,
+        add_generation_prompt,
+        add_special_tokens,
+        response: tx,
+    });
+    if let Err(e) = state.get_sender().unwrap().send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return Err(TokenizationResponder::InternalError(e.into()));
+    }
+
+    match rx.recv().await {
+        Some(Ok(tokenization)) => Ok(tokenization),
+        Some(Err(e)) => Err(TokenizationResponder::ValidationError(e.into())),
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            Err(TokenizationResponder::InternalError(e.into()))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/tokenize",
+    request_body = TokenizeRequest,
+    responses((status = 200, description = "Tokens of the prompt"))
+)]
+pub async fn tokenize(
+    State(router): State<Arc<ModelRouter>>,
+    Json(oairequest): Json<TokenizeRequest>,
+) -> TokenizationResponder<TokenizeResponse> {
+    let state = match router.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return TokenizationResponder::ValidationError(e.into()),
+    };
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let text = match (oairequest.messages, oairequest.prompt) {
+        (Some(messages), None) => Either::Left(messages),
+        (None, Some(prompt)) => Either::Right(prompt),
+        _ => {
+            return TokenizationResponder::ValidationError(
+                anyhow::Error::msg("Exactly one of `messages` and `prompt` must be given.").into(),
+            )
+        }
+    };
+    let tokenization = match tokenize_with(
+        state.clone(),
+        text,
+        oairequest.tools,
+        oairequest.add_generation_prompt,
+        oairequest.add_special_tokens,
+    )
+    .await
+    {
+        Ok(tokenization) => tokenization,
+        Err(responder) => return responder,
+    };
+
+    let response = TokenizeResponse {
+        model: oairequest.model,
+        count: tokenization.tokens.len(),
+        tokens: tokenization.tokens,
+        prompt: tokenization.prompt,
+    };
+    MistralRs::maybe_log_response(state, &response);
+    TokenizationResponder::Json(response)
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/apply_chat_template",
+    request_body = ApplyChatTemplateRequest,
+    responses((status = 200, description = "Messages rendered with the chat template"))
+)]
+pub async fn apply_chat_template(
+    State(router): State<Arc<ModelRouter>>,
+    Json(oairequest): Json<ApplyChatTemplateRequest>,
+) -> TokenizationResponder<ApplyChatTemplateResponse> {
+    let state = match router.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return TokenizationResponder::ValidationError(e.into()),
+    };
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let tokenization = match tokenize_with(
+        state.clone(),
+        Either::Left(oairequest.messages),
+        oairequest.tools,
+        oairequest.add_generation_prompt,
+        false,
+    )
+    .await
+    {
+        Ok(tokenization) => tokenization,
+        Err(responder) => return responder,
+    };
+
+    let response = ApplyChatTemplateResponse {
+        model: oairequest.model,
+        prompt: tokenization.prompt,
+    };
+    MistralRs::maybe_log_response(state, &response);
+    TokenizationResponder::Json(response)
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/detokenize",
+    request_body = DetokenizeRequest,
+    responses((status = 200, description = "Text of the tokens"))
+)]
+pub async fn detokenize(
+    State(router): State<Arc<ModelRouter>>,
+    Json(oairequest): Json<DetokenizeRequest>,
+) -> TokenizationResponder<DetokenizeResponse> {
+    let state = match router.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return TokenizationResponder::ValidationError(e.into()),
+    };
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let (tx, mut rx) = channel(1);
+    let request = Request::Detokenize(DetokenizationRequest {
+        tokens: oairequest.tokens,
+        skip_special_tokens: oairequest.skip_special_tokens,
+        response: tx,
+    });
+    if let Err(e) = state.get_sender().unwrap().send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return TokenizationResponder::InternalError(e.into());
+    }
+
+    let text = match rx.recv().await {
+        Some(Ok(text)) => text,
+        Some(Err(e)) => return TokenizationResponder::ValidationError(e.into()),
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            return TokenizationResponder::InternalError(e.into());
+        }
+    };
+
+    let response = DetokenizeResponse {
+        model: oairequest.model,
+        text,
+    };
+    MistralRs::maybe_log_response(state, &response);
+    TokenizationResponder::Json(response)
+}
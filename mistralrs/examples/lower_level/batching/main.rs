@@ -6,8 +6,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     initialize_logging, ChatCompletionResponse, Constraint, Device, DeviceMapMetadata,
     GGUFLoaderBuilder, GGUFSpecificConfig, MemoryGpuConfig, MistralRs, MistralRsBuilder,
-    ModelDType, NormalRequest, PagedAttentionConfig, Request, RequestMessage, ResponseOk,
-    SamplingParams, SchedulerConfig, TokenSource, Usage,
+    ModelDType, NormalRequest, PagedAttentionConfig, Request, RequestMessage, RequestPriority,
+    ResponseOk, SamplingParams, SchedulerConfig, TokenSource, Usage,
 };
 
 async fn setup() -> anyhow::Result<Arc<MistralRs>> {
@@ -85,6 +85,8 @@ async fn bench_mistralrs(n_requests: usize) -> anyhow::Result<()> {
             tools: None,
             tool_choice: None,
             logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
         });
         mistralrs.get_sender()?.send(request).await?;
         handles.push(rx);
@@ -7,8 +7,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, CustomLogitsProcessor, DefaultSchedulerMethod, Device, DeviceMapMetadata,
     MistralRs, MistralRsBuilder, ModelDType, NormalLoaderBuilder, NormalRequest,
-    NormalSpecificConfig, Request, RequestMessage, ResponseOk, Result, SamplingParams,
-    SchedulerConfig, Tensor, TokenSource,
+    NormalSpecificConfig, Request, RequestMessage, RequestPriority, ResponseOk, Result,
+    SamplingParams, SchedulerConfig, Tensor, TokenSource,
 };
 
 struct ThresholdLogitsProcessor {
@@ -99,6 +99,8 @@ fn main() -> anyhow::Result<()> {
             Arc::new(move |logits: &Tensor, _context: &[u32]| logits * random_value),
             Arc::new(ThresholdLogitsProcessor { threshold }),
         ]),
+        priority: RequestPriority::Normal,
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
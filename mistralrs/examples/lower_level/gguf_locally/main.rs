@@ -6,7 +6,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, DefaultSchedulerMethod, Device, DeviceMapMetadata, GGUFLoaderBuilder,
     GGUFSpecificConfig, MistralRs, MistralRsBuilder, ModelDType, NormalRequest, Request,
-    RequestMessage, ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource,
+    RequestMessage, RequestPriority, ResponseOk, Result, SamplingParams, SchedulerConfig,
+    TokenSource,
 };
 
 /// Gets the best device, cpu, cuda if compiled with CUDA
@@ -78,6 +79,8 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::Normal,
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -6,7 +6,7 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, DefaultSchedulerMethod, Device, DeviceMapMetadata, MistralRs, MistralRsBuilder,
     ModelDType, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, Request, RequestMessage,
-    ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource,
+    RequestPriority, ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource,
 };
 
 /// Gets the best device, cpu, cuda if compiled with CUDA
@@ -78,6 +78,8 @@ fn main() -> anyhow::Result<()> {
         tool_choice: None,
         tools: None,
         logits_processors: None,
+        priority: RequestPriority::Normal,
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
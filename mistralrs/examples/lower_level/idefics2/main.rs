@@ -5,9 +5,9 @@ use tokio::sync::mpsc::channel;
 
 use mistralrs::{
     Constraint, DefaultSchedulerMethod, Device, DeviceMapMetadata, IsqType, MistralRs,
-    MistralRsBuilder, ModelDType, NormalRequest, Request, RequestMessage, ResponseOk, Result,
-    SamplingParams, SchedulerConfig, TokenSource, VisionLoaderBuilder, VisionLoaderType,
-    VisionSpecificConfig,
+    MistralRsBuilder, ModelDType, NormalRequest, Request, RequestMessage, RequestPriority,
+    ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource, VisionLoaderBuilder,
+    VisionLoaderType, VisionSpecificConfig,
 };
 
 /// Gets the best device, cpu, cuda if compiled with CUDA
@@ -98,6 +98,8 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::Normal,
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
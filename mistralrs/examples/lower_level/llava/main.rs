@@ -6,8 +6,9 @@ use tokio::sync::mpsc::channel;
 
 use mistralrs::{
     Constraint, DefaultSchedulerMethod, Device, DeviceMapMetadata, MistralRs, MistralRsBuilder,
-    ModelDType, NormalRequest, Request, RequestMessage, ResponseOk, SamplingParams,
-    SchedulerConfig, TokenSource, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
+    ModelDType, NormalRequest, Request, RequestMessage, RequestPriority, ResponseOk,
+    SamplingParams, SchedulerConfig, TokenSource, VisionLoaderBuilder, VisionLoaderType,
+    VisionSpecificConfig,
 };
 
 fn setup() -> anyhow::Result<Arc<MistralRs>> {
@@ -72,6 +73,8 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::Normal,
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
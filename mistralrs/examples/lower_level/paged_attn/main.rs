@@ -12,7 +12,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, Device, DeviceMapMetadata, MemoryGpuConfig, MistralRs, MistralRsBuilder,
     ModelDType, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig,
-    Request, RequestMessage, ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource,
+    Request, RequestMessage, RequestPriority, ResponseOk, Result, SamplingParams, SchedulerConfig,
+    TokenSource,
 };
 
 /// Gets the best device, cpu, cuda if compiled with CUDA
@@ -96,6 +97,8 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::Normal,
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -26,6 +26,7 @@ pub struct GgufModelBuilder {
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) tool_call_format: Option<ToolCallFormat>,
 }
 
 impl GgufModelBuilder {
@@ -47,6 +48,7 @@ impl GgufModelBuilder {
             max_num_seqs: 32,
             no_kv_cache: false,
             prefix_cache_n: Some(16),
+            tool_call_format: None,
             with_logging: false,
             topology: None,
             tok_model_id: None,
@@ -138,6 +140,12 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Set the format used to parse tool calls, instead of detecting it from the chat template.
+    pub fn with_tool_call_format(mut self, tool_call_format: ToolCallFormat) -> Self {
+        self.tool_call_format = Some(tool_call_format);
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
@@ -211,6 +219,9 @@ impl GgufModelBuilder {
         if let Some(n) = self.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(tool_call_format) = self.tool_call_format {
+            runner = runner.with_tool_call_format(tool_call_format)
+        }
 
         Ok(Model::new(runner.build()))
     }
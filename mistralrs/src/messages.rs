@@ -419,6 +419,16 @@ impl RequestBuilder {
         self
     }
 
+    pub fn set_sampler_typical_p(mut self, typical_p: f64) -> Self {
+        self.sampling_params.typical_p = Some(typical_p);
+        self
+    }
+
+    pub fn set_sampler_top_a(mut self, top_a: f64) -> Self {
+        self.sampling_params.top_a = Some(top_a);
+        self
+    }
+
     pub fn set_sampler_topn_logprobs(mut self, top_n_logprobs: usize) -> Self {
         self.sampling_params.top_n_logprobs = top_n_logprobs;
         self
@@ -434,6 +444,17 @@ impl RequestBuilder {
         self
     }
 
+    /// Penalize the tokens of the last `last_n` context tokens, or of the whole context if `None`.
+    pub fn set_sampler_repetition_penalty(
+        mut self,
+        repetition_penalty: f32,
+        last_n: Option<usize>,
+    ) -> Self {
+        self.sampling_params.repetition_penalty = Some(repetition_penalty);
+        self.sampling_params.repetition_penalty_last_n = last_n;
+        self
+    }
+
     pub fn set_sampler_stop_toks(mut self, stop_toks: StopTokens) -> Self {
         self.sampling_params.stop_toks = Some(stop_toks);
         self
@@ -458,6 +479,36 @@ impl RequestBuilder {
         self.sampling_params.dry_params = Some(dry_params);
         self
     }
+
+    /// Sample with Mirostat v2, which replaces top-k, top-p, min-p, typical-p, top-a and XTC.
+    pub fn set_sampler_mirostat(mut self, mirostat: MirostatParams) -> Self {
+        self.sampling_params.mirostat = Some(mirostat);
+        self
+    }
+
+    pub fn set_sampler_xtc(mut self, xtc: XtcParams) -> Self {
+        self.sampling_params.xtc = Some(xtc);
+        self
+    }
+
+    /// Apply the sampler stages in this order instead of `SamplerStage::DEFAULT_ORDER`. Every
+    /// enabled stage must be listed.
+    pub fn set_sampler_order(mut self, order: Vec<SamplerStage>) -> Self {
+        self.sampling_params.sampler_order = Some(order);
+        self
+    }
+
+    /// Seed the RNG of each sequence, so identical requests produce identical outputs.
+    pub fn set_sampler_seed(mut self, seed: u64) -> Self {
+        self.sampling_params.seed = Some(seed);
+        self
+    }
+
+    /// Decode with beam search instead of sampling. Beam search does not support streaming.
+    pub fn set_sampler_beam_search(mut self, beam_search: BeamSearchParams) -> Self {
+        self.sampling_params.beam_search = Some(beam_search);
+        self
+    }
 }
 
 impl RequestLike for RequestBuilder {
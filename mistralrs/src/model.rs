@@ -1,5 +1,7 @@
 use anyhow::Context;
 use candle_core::{Device, Result};
+use either::Either;
+use indexmap::IndexMap;
 use mistralrs_core::*;
 use std::sync::Arc;
 use tokio::sync::mpsc::channel;
@@ -76,6 +78,8 @@ impl Model {
             tools,
             tool_choice,
             logits_processors: request.take_logits_processors(),
+            priority: RequestPriority::Normal,
+            tenant: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -117,6 +121,8 @@ impl Model {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -133,6 +139,227 @@ impl Model {
         Ok(response)
     }
 
+    /// Compute an embedding of the text by pooling the final hidden states of the model.
+    pub async fn embed(
+        &self,
+        text: impl ToString,
+        pooling: EmbeddingPooling,
+        normalize: bool,
+    ) -> anyhow::Result<Vec<f32>> {
+        let (tx, mut rx) = channel(1);
+
+        let request = Request::Normal(NormalRequest {
+            id: 0,
+            messages: RequestMessage::Embedding {
+                input: either::Either::Left(text.to_string()),
+                pooling,
+                normalize,
+            },
+            sampling_params: SamplingParams::deterministic(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        let ResponseOk::Embedding(response) = rx
+            .recv()
+            .await
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            anyhow::bail!("Got unexpected response type.")
+        };
+
+        Ok(response.embedding)
+    }
+
+    /// Compute the log-likelihood of `continuation` given `prompt`, without generating tokens.
+    /// The continuation is tokenized on its own and appended to the tokens of the prompt.
+    pub async fn score(
+        &self,
+        prompt: impl ToString,
+        continuation: impl ToString,
+    ) -> anyhow::Result<ContinuationScore> {
+        let response = self
+            .send_completion(
+                RequestMessage::Score {
+                    prompt: prompt.to_string(),
+                    continuation: continuation.to_string(),
+                },
+                SamplingParams::deterministic(),
+            )
+            .await?;
+        response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.score)
+            .context("Scoring response without a score.")
+    }
+
+    /// The logprobs of the tokens of `text` after the first one, each given the tokens before it,
+    /// with the `top_n_logprobs` most likely tokens at each position.
+    pub async fn prompt_logprobs(
+        &self,
+        text: impl ToString,
+        top_n_logprobs: usize,
+    ) -> anyhow::Result<Vec<ResponseLogprob>> {
+        let response = self
+            .send_completion(
+                RequestMessage::Completion {
+                    text: text.to_string(),
+                    echo_prompt: false,
+                    best_of: 1,
+                    prompt_logprobs: true,
+                },
+                SamplingParams {
+                    top_n_logprobs,
+                    max_len: Some(1),
+                    ..SamplingParams::deterministic()
+                },
+            )
+            .await?;
+        response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.prompt_logprobs)
+            .context("Completion response without prompt logprobs.")
+    }
+
+    async fn send_completion(
+        &self,
+        messages: RequestMessage,
+        sampling_params: SamplingParams,
+    ) -> anyhow::Result<CompletionResponse> {
+        let (tx, mut rx) = channel(1);
+
+        let request = Request::Normal(NormalRequest {
+            id: 0,
+            messages,
+            sampling_params,
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            logits_processors: None,
+            priority: RequestPriority::Normal,
+            tenant: None,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        let ResponseOk::CompletionDone(response) = rx
+            .recv()
+            .await
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            anyhow::bail!("Got unexpected response type.")
+        };
+
+        Ok(response)
+    }
+
+    /// Tokenize `text` with the tokenizer of the model, without applying the chat template.
+    pub async fn tokenize(
+        &self,
+        text: impl ToString,
+        add_special_tokens: bool,
+    ) -> anyhow::Result<Vec<u32>> {
+        Ok(self
+            .send_tokenize(
+                Either::Right(text.to_string()),
+                None,
+                false,
+                add_special_tokens,
+            )
+            .await?
+            .tokens)
+    }
+
+    /// Render the messages and tools of a request with the chat template of the model and
+    /// tokenize them, as the prompt of the request would be. Messages with images are rejected.
+    pub async fn tokenize_messages<R: RequestLike>(
+        &self,
+        mut request: R,
+        add_generation_prompt: bool,
+    ) -> anyhow::Result<Tokenization> {
+        let tools = request.take_tools().map(|(tools, _)| tools);
+        self.send_tokenize(
+            Either::Left(request.messages_ref().to_vec()),
+            tools,
+            add_generation_prompt,
+            false,
+        )
+        .await
+    }
+
+    /// Render the messages and tools of a request with the chat template of the model.
+    pub async fn apply_chat_template<R: RequestLike>(
+        &self,
+        request: R,
+        add_generation_prompt: bool,
+    ) -> anyhow::Result<String> {
+        Ok(self
+            .tokenize_messages(request, add_generation_prompt)
+            .await?
+            .prompt)
+    }
+
+    async fn send_tokenize(
+        &self,
+        text: Either<Vec<IndexMap<String, MessageContent>>, String>,
+        tools: Option<Vec<Tool>>,
+        add_generation_prompt: bool,
+        add_special_tokens: bool,
+    ) -> anyhow::Result<Tokenization> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::Tokenize(TokenizationRequest {
+            text,
+            tools,
+            add_generation_prompt,
+            add_special_tokens,
+            response: tx,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Decode tokens into text with the tokenizer of the model.
+    pub async fn detokenize(
+        &self,
+        tokens: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> anyhow::Result<String> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::Detokenize(DetokenizationRequest {
+            tokens,
+            skip_special_tokens,
+            response: tx,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
     /// Activate certain adapters on the model, they will be used for requests which do not specify unique adapters.
     pub async fn activate_adapters<A: ToString>(&self, adapters: Vec<A>) -> anyhow::Result<()> {
         let request = Request::ActivateAdapters(
@@ -151,4 +378,9 @@ impl Model {
 
         Ok(self.runner.get_sender()?.send(request).await?)
     }
+
+    /// The current metrics of the engine, such as queue depth, token counts and latencies.
+    pub fn metrics(&self) -> EngineMetrics {
+        self.runner.metrics()
+    }
 }
@@ -31,6 +31,7 @@ pub struct TextModelBuilder {
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) tool_call_format: Option<ToolCallFormat>,
 }
 
 /// Builder for PagedAttention metadata.
@@ -93,6 +94,7 @@ impl TextModelBuilder {
             max_num_seqs: 32,
             no_kv_cache: false,
             prefix_cache_n: Some(16),
+            tool_call_format: None,
             with_logging: false,
             device_mapping: None,
         }
@@ -199,6 +201,12 @@ impl TextModelBuilder {
         self
     }
 
+    /// Set the format used to parse tool calls, instead of detecting it from the chat template.
+    pub fn with_tool_call_format(mut self, tool_call_format: ToolCallFormat) -> Self {
+        self.tool_call_format = Some(tool_call_format);
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
@@ -276,6 +284,9 @@ impl TextModelBuilder {
         if let Some(n) = self.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(tool_call_format) = self.tool_call_format {
+            runner = runner.with_tool_call_format(tool_call_format)
+        }
 
         Ok(Model::new(runner.build()))
     }